@@ -0,0 +1,191 @@
+//! Allocations and time of syncing a large directory from the mock server,
+//! with each cache method. Run with
+//! `cargo bench --features test-util --bench sync`, the number of entries can
+//! be set with `LDAP_POLLER_BENCH_ENTRIES` (100000 by default).
+//!
+//! The server runs on its own runtime, and only the allocations of the
+//! thread running the poller are counted.
+#![allow(clippy::print_stdout, clippy::cast_precision_loss, clippy::cast_sign_loss)]
+use std::{
+	alloc::{GlobalAlloc, Layout, System},
+	cell::Cell,
+	error::Error,
+	sync::atomic::{AtomicIsize, AtomicUsize, Ordering},
+	time::{Duration, Instant},
+};
+
+use ldap_poller::{
+	ldap::Ldap,
+	testing::{search_entry, MockServer},
+	Config,
+};
+
+/// Allocator counting the allocations and allocated bytes of threads which
+/// enabled counting
+struct CountingAllocator;
+
+thread_local! {
+	/// Whether the allocations of this thread are counted
+	static COUNTED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Number of allocations
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+/// Total number of allocated bytes
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+/// Number of bytes allocated and not freed since the last reset
+static IN_USE: AtomicIsize = AtomicIsize::new(0);
+/// Highest value of [`IN_USE`] since the last reset
+static PEAK: AtomicIsize = AtomicIsize::new(0);
+
+/// Whether the allocations of the current thread are counted
+fn counted() -> bool {
+	COUNTED.try_with(Cell::get).unwrap_or(false)
+}
+
+// SAFETY: Only forwards to the system allocator
+unsafe impl GlobalAlloc for CountingAllocator {
+	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+		if counted() {
+			let size = isize::try_from(layout.size()).unwrap_or(isize::MAX);
+			ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+			ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+			PEAK.fetch_max(IN_USE.fetch_add(size, Ordering::Relaxed) + size, Ordering::Relaxed);
+		}
+		// SAFETY: The caller upholds the contract of `GlobalAlloc::alloc`
+		unsafe { System.alloc(layout) }
+	}
+
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+		if counted() {
+			let size = isize::try_from(layout.size()).unwrap_or(isize::MAX);
+			IN_USE.fetch_sub(size, Ordering::Relaxed);
+		}
+		// SAFETY: The caller upholds the contract of `GlobalAlloc::dealloc`
+		unsafe { System.dealloc(ptr, layout) };
+	}
+}
+
+/// The allocator of the benchmark
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Allocations during a measured operation
+struct Usage {
+	/// Number of allocations
+	allocations: usize,
+	/// Total number of allocated bytes
+	allocated: usize,
+	/// Highest number of bytes allocated at once, above those in use before
+	peak: isize,
+	/// Duration of the operation
+	elapsed: Duration,
+}
+
+impl Usage {
+	/// Measure a sync cycle
+	async fn measure(ldap: &mut Ldap) -> Result<Self, Box<dyn Error>> {
+		ALLOCATIONS.store(0, Ordering::Relaxed);
+		ALLOCATED.store(0, Ordering::Relaxed);
+		IN_USE.store(0, Ordering::Relaxed);
+		PEAK.store(0, Ordering::Relaxed);
+		let started = Instant::now();
+		COUNTED.set(true);
+		let result = ldap.sync_once(None).await;
+		COUNTED.set(false);
+		result?;
+		Ok(Usage {
+			allocations: ALLOCATIONS.load(Ordering::Relaxed),
+			allocated: ALLOCATED.load(Ordering::Relaxed),
+			peak: PEAK.load(Ordering::Relaxed),
+			elapsed: started.elapsed(),
+		})
+	}
+
+	/// Print the usage with a label
+	fn print(&self, label: &str, entries: usize) {
+		let mib = |bytes: f64| bytes / 1024.0 / 1024.0;
+		println!(
+			"{label:<32} {:>8.2?} {:>10} allocations {:>8.1} MiB allocated {:>8.1} MiB peak \
+			 ({:.0} bytes/entry)",
+			self.elapsed,
+			self.allocations,
+			mib(self.allocated as f64),
+			mib(self.peak as f64),
+			self.allocated as f64 / entries as f64,
+		);
+	}
+}
+
+/// Sync the entries of the server with the given cache method, delivering
+/// the updates to a receiver and a batch subscriber
+async fn bench(
+	server: &MockServer,
+	cache_method: &str,
+	entries: usize,
+) -> Result<(), Box<dyn Error>> {
+	let config: Config = serde_json::from_value(serde_json::json!({
+		"url": server.url(),
+		"connection": {
+			"timeout": 5,
+			"operation_timeout": { "secs": 60, "nanos": 0 },
+			"tls": { "starttls": false, "no_tls_verify": false },
+		},
+		"search_user": "admin",
+		"search_password": "secret",
+		"searches": {
+			"user_base": "ou=people",
+			"user_filter": "(objectClass=person)",
+			"page_size": 1000,
+		},
+		"attributes": {
+			"pid": "objectGUID",
+			"updated": "modifyTimestamp",
+			"additional": ["cn", "mail", "description"],
+			"attrs_to_track": ["mail"],
+			"filter_attributes": true,
+		},
+		"cache_method": cache_method,
+		"check_for_deleted_entries": true,
+	}))?;
+	let (mut ldap, mut receiver) = Ldap::new(config, None);
+	let mut batches = ldap.subscribe_batches();
+	let updates = tokio::spawn(async move { while receiver.recv().await.is_some() {} });
+	let batched = tokio::spawn(async move { while batches.recv().await.is_some() {} });
+
+	Usage::measure(&mut ldap).await?.print(&format!("{cache_method}, initial sync"), entries);
+	Usage::measure(&mut ldap).await?.print(&format!("{cache_method}, unchanged"), entries);
+	drop(ldap);
+	updates.await?;
+	batched.await?;
+	Ok(())
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+	let entries = match std::env::var("LDAP_POLLER_BENCH_ENTRIES") {
+		Ok(entries) => entries.parse()?,
+		Err(_) => 100_000,
+	};
+	let server_runtime = tokio::runtime::Runtime::new()?;
+	let server = server_runtime.block_on(MockServer::start())?;
+	for i in 0..entries {
+		let name = format!("user{i}");
+		server.add(search_entry(
+			&format!("cn={name},ou=people"),
+			"objectGUID",
+			&u64::try_from(i)?.to_be_bytes(),
+			&[
+				("objectClass", &["person"]),
+				("cn", &[&name]),
+				("mail", &[&format!("{name}@example.com")]),
+				("description", &[&"x".repeat(200)]),
+			],
+		));
+	}
+	println!("Syncing {entries} entries");
+	let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+	for cache_method in ["modification_time", "hashed", "disabled"] {
+		runtime.block_on(bench(&server, cache_method, entries))?;
+	}
+	Ok(())
+}
@@ -0,0 +1,153 @@
+//! Acknowledgment of updates for at-least-once delivery, see
+//! [`crate::ldap::Ldap::subscribe_acked`]
+use std::{
+	collections::BTreeMap,
+	sync::{Arc, Mutex, PoisonError},
+};
+
+use crate::{cache::Checkpoint, ldap::EntryStatus};
+
+/// An update which the consumer acknowledges once it was processed
+#[derive(Debug)]
+pub struct AckedUpdate {
+	/// The update
+	pub status: EntryStatus,
+	/// The handle for acknowledging the update
+	pub ack: Ack,
+}
+
+/// Handle for acknowledging an update. Dropping it without calling
+/// [`Ack::ack`] or [`Ack::detach`], e.g. because processing the update
+/// failed, delivers the update again with the next sync cycle.
+#[derive(Debug)]
+pub struct Ack {
+	/// The id of the update
+	id: u64,
+	/// The unacknowledged updates of the poller
+	pending: Arc<Mutex<PendingAcks>>,
+	/// Set once the update was acknowledged or the handle detached
+	released: bool,
+}
+
+impl Ack {
+	/// Acknowledge that the update was processed
+	pub fn ack(mut self) {
+		self.released = true;
+		lock(&self.pending).updates.remove(&self.id);
+	}
+
+	/// The sequence number of the update, which increases in the order of
+	/// delivery
+	#[must_use]
+	pub fn seq(&self) -> u64 {
+		self.id
+	}
+
+	/// Give up the handle, returning the sequence number of the update. The
+	/// update stays unacknowledged without being delivered again, until it's
+	/// acknowledged with [`crate::ldap::Ldap::ack`], e.g. once the consumer
+	/// durably applied it in another task or process.
+	#[must_use]
+	pub fn detach(mut self) -> u64 {
+		self.released = true;
+		self.id
+	}
+}
+
+impl Drop for Ack {
+	fn drop(&mut self) {
+		if !self.released {
+			if let Some(update) = lock(&self.pending).updates.get_mut(&self.id) {
+				update.dropped = true;
+			}
+		}
+	}
+}
+
+/// The updates which weren't acknowledged yet
+#[derive(Debug, Default)]
+pub(crate) struct PendingAcks {
+	/// The id of the next update
+	next_id: u64,
+	/// The unacknowledged updates by id, i.e. in the order of delivery
+	updates: BTreeMap<u64, PendingUpdate>,
+}
+
+/// An update which wasn't acknowledged yet
+#[derive(Debug)]
+pub(crate) struct PendingUpdate {
+	/// The pid of the entry of the update
+	pub(crate) pid: Option<Vec<u8>>,
+	/// The update
+	pub(crate) status: EntryStatus,
+	/// Where the sync stood when the update was first delivered
+	pub(crate) checkpoint: Checkpoint,
+	/// Set if the handle was dropped without acknowledging the update
+	dropped: bool,
+}
+
+impl PendingAcks {
+	/// Register a delivered update, returning the handle for acknowledging
+	/// it. Unacknowledged earlier updates of the same entry are superseded, so
+	/// that they aren't delivered again after the newer one.
+	pub(crate) fn register(
+		pending: &Arc<Mutex<PendingAcks>>,
+		pid: Option<Vec<u8>>,
+		status: EntryStatus,
+		checkpoint: Checkpoint,
+	) -> Ack {
+		let mut acks = lock(pending);
+		let mut checkpoint = checkpoint;
+		if let Some(pid) = &pid {
+			acks.updates.retain(|_, update| {
+				if update.pid.as_ref() != Some(pid) {
+					return true;
+				}
+				// The entry is still searched again from the earlier checkpoint
+				checkpoint = checkpoint.earliest(update.checkpoint);
+				false
+			});
+		}
+		let id = acks.next_id;
+		acks.next_id += 1;
+		acks.updates.insert(id, PendingUpdate { pid, status, checkpoint, dropped: false });
+		Ack { id, pending: pending.clone(), released: false }
+	}
+
+	/// Acknowledge the update with the given sequence number, if it's an
+	/// update of the entry with the given pid. Returns whether it was still
+	/// unacknowledged.
+	pub(crate) fn ack(&mut self, pid: &[u8], seq: u64) -> bool {
+		let matches =
+			self.updates.get(&seq).is_some_and(|update| update.pid.as_deref() == Some(pid));
+		if matches {
+			self.updates.remove(&seq);
+		}
+		matches
+	}
+
+	/// Take the updates whose handles were dropped without acknowledging
+	/// them, in the order they were delivered
+	pub(crate) fn take_dropped(&mut self) -> Vec<PendingUpdate> {
+		let ids: Vec<_> =
+			self.updates.iter().filter(|(_, update)| update.dropped).map(|(id, _)| *id).collect();
+		ids.into_iter().filter_map(|id| self.updates.remove(&id)).collect()
+	}
+
+	/// The pids of the entries with unacknowledged updates
+	pub(crate) fn pids(&self) -> impl Iterator<Item = &[u8]> {
+		self.updates.values().filter_map(|update| update.pid.as_deref())
+	}
+
+	/// The earliest checkpoint of the unacknowledged updates, if there are
+	/// any
+	pub(crate) fn checkpoint(&self) -> Option<Checkpoint> {
+		self.updates.values().map(|update| update.checkpoint).reduce(Checkpoint::earliest)
+	}
+}
+
+/// Lock a mutex, ignoring poisoning since the pending updates stay
+/// consistent when a holder panics
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+	mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
@@ -0,0 +1,584 @@
+//! Audit log of the updates emitted by the poller, see
+//! [`crate::ldap::Ldap::with_audit_log`]
+use std::{
+	borrow::Cow,
+	collections::HashMap,
+	fs::OpenOptions,
+	io::{BufRead, BufWriter, Write},
+	path::Path,
+	sync::{mpsc, Arc, Mutex, PoisonError},
+};
+
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use tokio::sync::oneshot;
+
+use crate::{
+	error::Error,
+	ldap::{EntryStatus, SkipReason},
+	membership::MembershipChange,
+	SearchEntry,
+};
+
+/// Format version of the lines of an [`AuditLog`]. Lines written before the
+/// version was recorded have the layout of version 1. Like
+/// [`crate::codec::CACHE_VERSION`], it's only increased for changes older
+/// versions of this crate can't read, not for new kinds of updates, which
+/// [`AuditLog::replay`] skips.
+pub const AUDIT_VERSION: u32 = 1;
+
+/// A sink writing every update emitted by [`crate::ldap::Ldap`] as a line of
+/// JSON, giving a trail of what the application was told which can be read
+/// back with [`AuditLog::replay`], e.g. for debugging downstream sync
+/// problems. Every line holds the format `version` (see [`AUDIT_VERSION`]),
+/// the `time` and the `sync_id` of the update, the kind of update as `event`,
+/// and the entries or the pid of the update. Pids are written as text if they
+/// are printable, as `{"hex": ...}` otherwise.
+///
+/// Attributes which are
+/// [`uncached`](crate::config::AttributeConfig::uncached) or
+/// [`encrypted_attributes`](crate::config::PersistenceConfig::encrypted_attributes)
+/// are left out of the entries, and listed as `redacted` instead.
+///
+/// The lines are written through a buffer by a background thread, so that
+/// recording doesn't block the sync. The buffer is flushed whenever no
+/// further lines are queued, and by [`AuditLog::flush`], which should be
+/// awaited before exiting.
+#[derive(Clone)]
+pub struct AuditLog {
+	/// The queue of the background thread
+	sender: mpsc::Sender<Message>,
+	/// The last error of the background thread, reported by the next call
+	error: Arc<Mutex<Option<std::io::Error>>>,
+}
+
+impl std::fmt::Debug for AuditLog {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str("AuditLog")
+	}
+}
+
+/// A request to the background thread of an [`AuditLog`]
+enum Message {
+	/// Write a line
+	Line(Vec<u8>),
+	/// Flush the buffer, and notify the sender when done
+	Flush(oneshot::Sender<()>),
+}
+
+/// An update read back from an audit log, see [`AuditLog::replay`]
+#[derive(Debug, Clone)]
+pub struct AuditRecord {
+	/// When the update was emitted
+	pub time: OffsetDateTime,
+	/// The id of the sync cycle, as in its `sync` tracing span
+	pub sync_id: u64,
+	/// The update, without the redacted attributes
+	pub status: EntryStatus,
+}
+
+/// A line of the audit log
+#[derive(Serialize, Deserialize)]
+struct AuditLine<'a> {
+	/// The format version, see [`AUDIT_VERSION`]. It's checked by reading the
+	/// [`AuditHeader`] first, so it defaults to 0 for unversioned lines.
+	#[serde(default)]
+	version: u32,
+	/// When the update was emitted
+	#[serde(with = "time::serde::rfc3339")]
+	time: OffsetDateTime,
+	/// The id of the sync cycle, as in its `sync` tracing span
+	sync_id: u64,
+	/// The update
+	#[serde(flatten)]
+	event: AuditEvent<'a>,
+}
+
+/// Only the version of a line, which is read first, as the rest of lines of
+/// a newer version may not parse
+#[derive(Deserialize)]
+struct AuditHeader {
+	/// The format version, missing for lines written before it was recorded
+	#[serde(default)]
+	version: Option<u32>,
+}
+
+/// An update, mirroring [`EntryStatus`]
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum AuditEvent<'a> {
+	/// See [`EntryStatus::New`]
+	New {
+		/// The new entry
+		entry: AuditEntry<'a>,
+	},
+	/// See [`EntryStatus::Changed`]
+	Changed {
+		/// The previous entry, if cached
+		old: Option<AuditEntry<'a>>,
+		/// The changed entry
+		new: AuditEntry<'a>,
+	},
+	/// See [`EntryStatus::Removed`]
+	Removed {
+		/// The pid of the removed entry
+		pid: AuditPid,
+		/// The last known version of the entry, if cached
+		entry: Option<AuditEntry<'a>>,
+	},
+	/// See [`EntryStatus::Moved`]
+	Moved {
+		/// The pid of the moved entry
+		pid: AuditPid,
+		/// The DN before the move
+		old_dn: Cow<'a, str>,
+		/// The DN after the move
+		new_dn: Cow<'a, str>,
+		/// The moved entry
+		entry: AuditEntry<'a>,
+	},
+	/// See [`EntryStatus::Unchanged`]
+	Unchanged {
+		/// The pid of the unchanged entry
+		pid: AuditPid,
+	},
+	/// See [`EntryStatus::MembershipChanged`]
+	MembershipChanged {
+		/// The pid of the group
+		group: AuditPid,
+		/// The DN of the group
+		group_dn: Cow<'a, str>,
+		/// The added or removed member
+		member: Cow<'a, str>,
+		/// Whether the member was added or removed
+		change: MembershipChange,
+	},
+	/// See [`EntryStatus::Skipped`]
+	Skipped {
+		/// The DN of the skipped entry
+		dn: Cow<'a, str>,
+		/// Why the entry was skipped
+		reason: SkipReason,
+	},
+	/// See [`EntryStatus::PidConflict`]
+	PidConflict {
+		/// The shared pid
+		pid: AuditPid,
+		/// The DN of the entry which was kept
+		dn: Cow<'a, str>,
+		/// The DN of the skipped entry
+		conflicting_dn: Cow<'a, str>,
+	},
+	/// A kind of update written by a later version of this crate, which is
+	/// skipped when replaying
+	#[serde(other, skip_serializing)]
+	Unknown,
+}
+
+/// A pid, as text if it's printable and hex encoded otherwise
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum AuditPid {
+	/// A printable pid
+	Text(String),
+	/// Any other pid
+	Hex {
+		/// The hex encoded pid
+		hex: String,
+	},
+}
+
+impl From<&[u8]> for AuditPid {
+	fn from(pid: &[u8]) -> Self {
+		match std::str::from_utf8(pid) {
+			Ok(pid) if !pid.chars().any(char::is_control) => AuditPid::Text(pid.to_owned()),
+			_ => AuditPid::Hex { hex: crate::cache::display_pid(pid) },
+		}
+	}
+}
+
+impl TryFrom<AuditPid> for Vec<u8> {
+	type Error = Error;
+
+	fn try_from(pid: AuditPid) -> Result<Self, Error> {
+		match pid {
+			AuditPid::Text(pid) => Ok(pid.into_bytes()),
+			AuditPid::Hex { hex } => (0..hex.len())
+				.step_by(2)
+				.map(|i| hex.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+				.collect::<Option<_>>()
+				.ok_or_else(|| Error::Codec(format!("Invalid hex encoded pid {hex}"))),
+		}
+	}
+}
+
+/// An entry of an update
+#[derive(Serialize, Deserialize)]
+struct AuditEntry<'a> {
+	/// The DN of the entry
+	dn: Cow<'a, str>,
+	/// The text attributes
+	attrs: Cow<'a, HashMap<String, Vec<String>>>,
+	/// The binary attributes
+	bin_attrs: Cow<'a, HashMap<String, Vec<Vec<u8>>>>,
+	/// The attributes which were left out
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	redacted: Vec<String>,
+}
+
+impl<'a> AuditEntry<'a> {
+	/// Borrow an entry, leaving out the given attributes
+	fn new(entry: &'a SearchEntry, redacted: &[&str]) -> Self {
+		/// Leave out the redacted attributes, without copying the attributes
+		/// if there are none
+		fn redact<'a, V: Clone>(
+			attributes: &'a HashMap<String, V>,
+			redacted: &[&str],
+		) -> Cow<'a, HashMap<String, V>> {
+			if redacted.iter().any(|name| attributes.contains_key(*name)) {
+				Cow::Owned(
+					attributes
+						.iter()
+						.filter(|(name, _)| !redacted.contains(&name.as_str()))
+						.map(|(name, values)| (name.clone(), values.clone()))
+						.collect(),
+				)
+			} else {
+				Cow::Borrowed(attributes)
+			}
+		}
+
+		let mut present: Vec<String> = redacted
+			.iter()
+			.filter(|name| entry.attrs.contains_key(**name) || entry.bin_attrs.contains_key(**name))
+			.map(|name| (*name).to_owned())
+			.collect();
+		present.sort();
+		AuditEntry {
+			dn: Cow::Borrowed(&entry.dn),
+			attrs: redact(&entry.attrs, redacted),
+			bin_attrs: redact(&entry.bin_attrs, redacted),
+			redacted: present,
+		}
+	}
+}
+
+impl From<AuditEntry<'_>> for Arc<SearchEntry> {
+	fn from(entry: AuditEntry<'_>) -> Self {
+		Arc::new(SearchEntry {
+			dn: entry.dn.into_owned(),
+			attrs: entry.attrs.into_owned(),
+			bin_attrs: entry.bin_attrs.into_owned(),
+		})
+	}
+}
+
+impl AuditLog {
+	/// Write the audit log to the given writer
+	#[must_use]
+	pub fn new(writer: impl Write + Send + 'static) -> Self {
+		let (sender, receiver) = mpsc::channel();
+		let error = Arc::default();
+		let thread_error = Arc::clone(&error);
+		std::thread::spawn(move || write_lines(BufWriter::new(writer), &receiver, &thread_error));
+		AuditLog { sender, error }
+	}
+
+	/// Append the audit log to the file at the given path, which is created
+	/// if it doesn't exist
+	pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+		Ok(Self::new(OpenOptions::new().create(true).append(true).open(path)?))
+	}
+
+	/// Queue the line of an update. Fails if a previous line couldn't be
+	/// written.
+	pub(crate) fn record(
+		&self,
+		sync_id: u64,
+		status: &EntryStatus,
+		redacted: &[&str],
+	) -> Result<(), Error> {
+		let event = match status {
+			EntryStatus::New(new) => AuditEvent::New { entry: AuditEntry::new(new, redacted) },
+			EntryStatus::Changed { old, new } => AuditEvent::Changed {
+				old: old.as_deref().map(|old| AuditEntry::new(old, redacted)),
+				new: AuditEntry::new(new, redacted),
+			},
+			EntryStatus::Removed { pid, entry: removed } => AuditEvent::Removed {
+				pid: pid[..].into(),
+				entry: removed.as_deref().map(|removed| AuditEntry::new(removed, redacted)),
+			},
+			EntryStatus::Moved { pid, old_dn, new_dn, entry: moved } => AuditEvent::Moved {
+				pid: pid[..].into(),
+				old_dn: old_dn.into(),
+				new_dn: new_dn.into(),
+				entry: AuditEntry::new(moved, redacted),
+			},
+			EntryStatus::Unchanged(pid) => AuditEvent::Unchanged { pid: pid[..].into() },
+			EntryStatus::MembershipChanged { group, group_dn, member, change } => {
+				AuditEvent::MembershipChanged {
+					group: group[..].into(),
+					group_dn: group_dn.into(),
+					member: member.into(),
+					change: *change,
+				}
+			}
+			EntryStatus::Skipped { dn, reason } => {
+				AuditEvent::Skipped { dn: dn.into(), reason: *reason }
+			}
+			EntryStatus::PidConflict { pid, dn, conflicting_dn } => AuditEvent::PidConflict {
+				pid: pid[..].into(),
+				dn: dn.into(),
+				conflicting_dn: conflicting_dn.into(),
+			},
+		};
+		let line =
+			AuditLine { version: AUDIT_VERSION, time: OffsetDateTime::now_utc(), sync_id, event };
+		let mut line = serde_json::to_vec(&line).map_err(|err| Error::Codec(err.to_string()))?;
+		line.push(b'\n');
+		self.take_error()?;
+		self.sender.send(Message::Line(line)).map_err(|_| stopped())
+	}
+
+	/// Wait until all queued lines are written and flushed
+	pub async fn flush(&self) -> Result<(), Error> {
+		let (sender, receiver) = oneshot::channel();
+		self.sender.send(Message::Flush(sender)).map_err(|_| stopped())?;
+		receiver.await.map_err(|_| stopped())?;
+		self.take_error()
+	}
+
+	/// Read back the updates of an audit log, e.g. to replay them into a
+	/// consumer while debugging. Empty lines and kinds of updates unknown to
+	/// this version of the crate are skipped. Lines of a newer format version
+	/// fail with [`Error::IncompatibleAuditLog`].
+	pub fn replay(reader: impl BufRead) -> impl Iterator<Item = Result<AuditRecord, Error>> {
+		reader.lines().filter_map(|line| match line {
+			Ok(line) if line.trim().is_empty() => None,
+			Ok(line) => parse_line(&line).transpose(),
+			Err(err) => Some(Err(err.into())),
+		})
+	}
+
+	/// Report the last error of the background thread
+	fn take_error(&self) -> Result<(), Error> {
+		match self.error.lock().unwrap_or_else(PoisonError::into_inner).take() {
+			Some(err) => Err(err.into()),
+			None => Ok(()),
+		}
+	}
+}
+
+/// The error for a background thread which stopped, which only happens if
+/// the writer panicked
+fn stopped() -> Error {
+	Error::Io(std::io::Error::other("The audit log writer stopped"))
+}
+
+/// Write the queued lines until all [`AuditLog`] clones are dropped, keeping
+/// the last error for the next call
+fn write_lines(
+	mut writer: BufWriter<impl Write>,
+	receiver: &mpsc::Receiver<Message>,
+	error: &Mutex<Option<std::io::Error>>,
+) {
+	let keep_error = |result: std::io::Result<()>| {
+		if let Err(err) = result {
+			*error.lock().unwrap_or_else(PoisonError::into_inner) = Some(err);
+		}
+	};
+	loop {
+		let message = match receiver.try_recv() {
+			Ok(message) => message,
+			Err(mpsc::TryRecvError::Empty) => {
+				keep_error(writer.flush());
+				match receiver.recv() {
+					Ok(message) => message,
+					Err(mpsc::RecvError) => return,
+				}
+			}
+			Err(mpsc::TryRecvError::Disconnected) => break,
+		};
+		match message {
+			Message::Line(line) => keep_error(writer.write_all(&line)),
+			Message::Flush(done) => {
+				keep_error(writer.flush());
+				let _ = done.send(());
+			}
+		}
+	}
+	keep_error(writer.flush());
+}
+
+/// Parse a line of the audit log, unless it holds an unknown kind of update
+fn parse_line(line: &str) -> Result<Option<AuditRecord>, Error> {
+	let header: AuditHeader =
+		serde_json::from_str(line).map_err(|err| Error::Codec(err.to_string()))?;
+	if let Some(version) = header.version.filter(|version| *version > AUDIT_VERSION) {
+		return Err(Error::IncompatibleAuditLog { found: version, supported: AUDIT_VERSION });
+	}
+	let line: AuditLine<'_> =
+		serde_json::from_str(line).map_err(|err| Error::Codec(err.to_string()))?;
+	let status = match line.event {
+		AuditEvent::New { entry } => EntryStatus::New(entry.into()),
+		AuditEvent::Changed { old, new } => {
+			EntryStatus::Changed { old: old.map(Into::into), new: new.into() }
+		}
+		AuditEvent::Removed { pid, entry } => {
+			EntryStatus::Removed { pid: pid.try_into()?, entry: entry.map(Into::into) }
+		}
+		AuditEvent::Moved { pid, old_dn, new_dn, entry } => EntryStatus::Moved {
+			pid: pid.try_into()?,
+			old_dn: old_dn.into_owned(),
+			new_dn: new_dn.into_owned(),
+			entry: entry.into(),
+		},
+		AuditEvent::Unchanged { pid } => EntryStatus::Unchanged(pid.try_into()?),
+		AuditEvent::MembershipChanged { group, group_dn, member, change } => {
+			EntryStatus::MembershipChanged {
+				group: group.try_into()?,
+				group_dn: group_dn.into_owned(),
+				member: member.into_owned(),
+				change,
+			}
+		}
+		AuditEvent::Skipped { dn, reason } => EntryStatus::Skipped { dn: dn.into_owned(), reason },
+		AuditEvent::PidConflict { pid, dn, conflicting_dn } => EntryStatus::PidConflict {
+			pid: pid.try_into()?,
+			dn: dn.into_owned(),
+			conflicting_dn: conflicting_dn.into_owned(),
+		},
+		AuditEvent::Unknown => {
+			tracing::warn!("Skipping an unknown kind of update in the audit log");
+			return Ok(None);
+		}
+	};
+	Ok(Some(AuditRecord { time: line.time, sync_id: line.sync_id, status }))
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		collections::HashMap,
+		io::Write,
+		sync::{Arc, Mutex, PoisonError},
+	};
+
+	use super::AuditLog;
+	use crate::{
+		config::Config,
+		ldap::{EntryStatus, Ldap},
+		SearchEntry,
+	};
+
+	/// A writer into a buffer shared with the test
+	#[derive(Clone, Default)]
+	struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+	impl Write for SharedBuffer {
+		fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+			self.0.lock().unwrap_or_else(PoisonError::into_inner).write(buf)
+		}
+
+		fn flush(&mut self) -> std::io::Result<()> {
+			Ok(())
+		}
+	}
+
+	#[tokio::test]
+	async fn audit_log() -> Result<(), Box<dyn std::error::Error>> {
+		let buffer = SharedBuffer::default();
+		let mut config = Config::example();
+		config.attributes.uncached = vec!["mail".to_owned()];
+		let (ldap, _receiver) = Ldap::new(config, None);
+		let audit_log = AuditLog::new(buffer.clone());
+		let mut ldap = ldap.with_audit_log(audit_log.clone());
+		let entry = |enabled: &str| SearchEntry {
+			dn: "cn=foo".to_owned(),
+			attrs: HashMap::from([
+				("enabled".to_owned(), vec![enabled.to_owned()]),
+				("mail".to_owned(), vec!["foo@example.org".to_owned()]),
+			]),
+			bin_attrs: HashMap::from([("objectGUID".to_owned(), vec![b"foo".to_vec()])]),
+		};
+		ldap.process_entries([entry("yes")]).await;
+		ldap.process_entries([entry("no")]).await;
+		audit_log.flush().await?;
+
+		let output = buffer.0.lock().unwrap_or_else(PoisonError::into_inner).clone();
+		let lines = String::from_utf8(output.clone())?
+			.lines()
+			.map(serde_json::from_str)
+			.collect::<Result<Vec<serde_json::Value>, _>>()?;
+		assert_eq!(lines.len(), 2);
+		assert_eq!(lines[0]["event"], "new");
+		assert_eq!(lines[0]["version"], super::AUDIT_VERSION);
+		assert_eq!(lines[0]["sync_id"], 0);
+		assert_eq!(lines[0]["entry"]["dn"], "cn=foo");
+		assert_eq!(lines[0]["entry"]["redacted"][0], "mail");
+		assert!(lines[0]["entry"]["attrs"].get("mail").is_none());
+		assert_eq!(lines[1]["event"], "changed");
+		assert_eq!(lines[1]["new"]["attrs"]["enabled"][0], "no");
+		assert!(lines[1]["time"].as_str().is_some_and(|time| time.ends_with('Z')));
+
+		let records = AuditLog::replay(output.as_slice()).collect::<Result<Vec<_>, _>>()?;
+		assert_eq!(records.len(), 2);
+		assert!(matches!(
+			&records[1].status,
+			EntryStatus::Changed { new, .. }
+				if new.attrs["enabled"] == ["no"] && !new.attrs.contains_key("mail")
+		));
+		Ok(())
+	}
+
+	#[test]
+	fn replay_pids() -> Result<(), Box<dyn std::error::Error>> {
+		let statuses = [
+			EntryStatus::Unchanged(b"foo".to_vec()),
+			EntryStatus::Unchanged(vec![0, 1, 0xff]),
+			EntryStatus::Removed { pid: b"bar\n".to_vec(), entry: None },
+		];
+		let mut output = Vec::new();
+		for status in &statuses {
+			let (sender, receiver) = std::sync::mpsc::channel();
+			let log = AuditLog { sender, error: Arc::default() };
+			log.record(1, status, &[])?;
+			match receiver.recv()? {
+				super::Message::Line(line) => output.extend(line),
+				super::Message::Flush(_) => unreachable!(),
+			}
+		}
+		assert!(String::from_utf8(output.clone())?.contains(r#""pid":"foo""#));
+		assert!(String::from_utf8(output.clone())?.contains(r#""pid":{"hex":"0001ff"}"#));
+
+		let pids: Vec<_> = AuditLog::replay(output.as_slice())
+			.map(|record| match record?.status {
+				EntryStatus::Unchanged(pid) | EntryStatus::Removed { pid, .. } => Ok(pid),
+				_ => Err("Unexpected status".into()),
+			})
+			.collect::<Result<_, Box<dyn std::error::Error>>>()?;
+		assert_eq!(pids, [b"foo".to_vec(), vec![0, 1, 0xff], b"bar\n".to_vec()]);
+		assert!(AuditLog::replay(&b"{\"event\":\"unchanged\"}\n"[..]).all(|record| record.is_err()));
+		Ok(())
+	}
+
+	#[test]
+	fn replay_versions() -> Result<(), Box<dyn std::error::Error>> {
+		let time = "2024-01-01T00:00:00Z";
+		let log = format!(
+			"{{\"time\":\"{time}\",\"sync_id\":1,\"event\":\"unchanged\",\"pid\":\"foo\"}}\n\
+			 {{\"version\":1,\"time\":\"{time}\",\"sync_id\":2,\"event\":\"renamed\",\"pid\":\"foo\"}}\n\
+			 {{\"version\":1,\"time\":\"{time}\",\"sync_id\":3,\"event\":\"unchanged\",\"pid\":\"bar\"}}\n\
+			 {{\"version\":2,\"time\":\"{time}\",\"sync_id\":4,\"event\":\"unchanged\"}}\n"
+		);
+		let records: Vec<_> = AuditLog::replay(log.as_bytes()).collect();
+		assert_eq!(records.len(), 3, "Unknown kinds of updates should be skipped");
+		assert!(matches!(&records[0], Ok(record) if record.sync_id == 1));
+		assert!(matches!(&records[1], Ok(record) if record.sync_id == 3));
+		assert!(matches!(
+			records[2],
+			Err(crate::error::Error::IncompatibleAuditLog { found: 2, supported: 1 })
+		));
+		Ok(())
+	}
+}
@@ -1,20 +1,307 @@
 //! Caching mechanisms to check whether user data has changed
-use std::collections::{HashMap, HashSet};
+use std::{
+	borrow::Cow,
+	collections::{HashMap, HashSet},
+	sync::{Arc, OnceLock},
+};
 
 use ldap3::SearchEntry;
+use sha2::{Digest, Sha256};
 use time::OffsetDateTime;
 
-use crate::{config::AttributeConfig, entry::SearchEntryExt};
+use crate::{
+	config::{
+		AttributeConfig, CacheMethod, ComparisonMode, Config, DnChangeMode, PidNormalization,
+	},
+	dn::Dn,
+	entry::SearchEntryExt,
+	schema::{MatchingRule, Schema},
+};
 
 /// Cache data with information about the last sync and user entries
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub struct Cache {
 	/// The time of the last sync
 	pub(crate) last_sync_time: Option<OffsetDateTime>,
+	/// The time of the last full sync, which also checked for deleted entries
+	#[serde(default)]
+	pub(crate) last_full_sync_time: Option<OffsetDateTime>,
+	/// The highest update sequence number seen in a completed search, see
+	/// [`AttributeConfig::usn`]
+	#[serde(default)]
+	pub(crate) highest_usn: Option<u64>,
 	/// Cached data entries used to check whether an entry has changed
 	pub(crate) entries: CacheEntries,
 	/// Set of missing entries during comparison
 	pub(crate) missing: HashSet<Vec<u8>>,
+	/// The number of full searches in a row which missed an entry, see
+	/// [`crate::config::RemovalConfirmation::ConsecutiveMisses`]
+	#[serde(default, with = "pid_map")]
+	pub(crate) misses: HashMap<Vec<u8>, u32>,
+	/// When each cached entry was first seen and last returned by the
+	/// server, see [`Cache::provenance`]
+	#[serde(default, with = "pid_map")]
+	pub(crate) provenance: HashMap<Vec<u8>, EntryProvenance>,
+	/// Entries invalidated by [`crate::ldap::Ldap::invalidate`], which are
+	/// emitted as new the next time they are received
+	#[serde(default)]
+	pub(crate) invalidated: HashSet<Vec<u8>>,
+	/// The settings the cache was built with, unknown for caches persisted
+	/// before they were recorded, see [`Cache::migrate`]
+	#[serde(default)]
+	pub(crate) origin: Option<CacheOrigin>,
+	/// Position of an unfinished paged search, see
+	/// [`crate::config::Searches::resume_paged_search`]
+	#[serde(default)]
+	pub(crate) paged_search: Option<PagedSearchProgress>,
+	/// The subschema of the server, used to compare tracked attributes if
+	/// [`AttributeConfig::schema_matching`] is enabled
+	#[serde(skip)]
+	pub(crate) schema: Option<Arc<Schema>>,
+	/// The pids of the cached entries by normalized DN, see
+	/// [`Cache::pid_by_dn`]
+	#[serde(skip)]
+	pub(crate) dn_index: DnIndex,
+	/// The pids changed since the cache was last published, see
+	/// [`Cache::take_changes`]
+	#[serde(skip)]
+	pub(crate) changes: ChangedPids,
+}
+
+/// Index of the pids of cached entries by normalized DN. It's built on first
+/// use and then kept up to date with the entries. As it's derived from the
+/// entries, it's neither persisted nor compared.
+#[derive(Clone, Default)]
+pub(crate) struct DnIndex(OnceLock<HashMap<String, Vec<u8>>>);
+
+impl PartialEq for DnIndex {
+	fn eq(&self, _other: &Self) -> bool {
+		true
+	}
+}
+
+impl Eq for DnIndex {}
+
+impl std::fmt::Debug for DnIndex {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str("DnIndex")
+	}
+}
+
+/// The pids whose entries or provenance changed since a point in time, or
+/// `None` if the whole cache may have changed, e.g. because it was just
+/// created or restored. Like the [`DnIndex`], it's neither persisted nor
+/// compared.
+#[derive(Clone, Default)]
+pub(crate) struct ChangedPids(Option<HashSet<Vec<u8>>>);
+
+impl ChangedPids {
+	/// Record a change of the entry with the given pid
+	fn insert(&mut self, pid: &[u8]) {
+		if let Some(pids) = &mut self.0 {
+			pids.insert(pid.to_owned());
+		}
+	}
+
+	/// Add the changes recorded after these
+	pub(crate) fn merge(&mut self, later: &ChangedPids) {
+		match (&mut self.0, &later.0) {
+			(Some(pids), Some(later)) => pids.extend(later.iter().cloned()),
+			_ => self.0 = None,
+		}
+	}
+}
+
+impl PartialEq for ChangedPids {
+	fn eq(&self, _other: &Self) -> bool {
+		true
+	}
+}
+
+impl Eq for ChangedPids {}
+
+impl std::fmt::Debug for ChangedPids {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str("ChangedPids")
+	}
+}
+
+/// Set the value of a key in a copy of a map to the one in the map, see
+/// [`Cache::refresh_copy`]
+fn refresh_key<V: Clone>(copy: &mut HashMap<Vec<u8>, V>, map: &HashMap<Vec<u8>, V>, key: &[u8]) {
+	match map.get(key) {
+		Some(value) => {
+			copy.insert(key.to_owned(), value.clone());
+		}
+		None => {
+			copy.remove(key);
+		}
+	}
+}
+
+/// The form of a DN by which entries are looked up in the [`DnIndex`]
+fn dn_key(dn: &str) -> Option<String> {
+	Dn::parse(dn).ok().map(|dn| dn.normalized())
+}
+
+/// Where the sync stood before a cycle, i.e. from where an incremental sync
+/// finds the entries changed in that cycle again
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Checkpoint {
+	/// The time of the last sync
+	pub(crate) last_sync_time: Option<OffsetDateTime>,
+	/// The highest update sequence number seen
+	pub(crate) highest_usn: Option<u64>,
+}
+
+impl Checkpoint {
+	/// The earlier of two checkpoints, where an unknown position comes
+	/// before every known one
+	pub(crate) fn earliest(self, other: Checkpoint) -> Checkpoint {
+		Checkpoint {
+			last_sync_time: self.last_sync_time.min(other.last_sync_time),
+			highest_usn: self.highest_usn.min(other.highest_usn),
+		}
+	}
+}
+
+/// Position of a paged search which was interrupted, from which it can be
+/// resumed by a later sync
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub(crate) struct PagedSearchProgress {
+	/// The search filter, which may depend on the time of the previous sync
+	pub(crate) filter: String,
+	/// Whether the search is a full sync, checking for removed entries
+	pub(crate) full_sync: bool,
+	/// When the search started, which becomes the time of the sync once the
+	/// search completes
+	pub(crate) started: OffsetDateTime,
+	/// The paged results cookie requesting the page after the last completed
+	/// one
+	pub(crate) cookie: Vec<u8>,
+	/// The number of entries received before the cookie
+	pub(crate) entries: u64,
+}
+
+/// The settings of the configuration the entries of a cache depend on
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub(crate) struct CacheOrigin {
+	/// See [`crate::config::Config::cache_method`]
+	cache_method: CacheMethod,
+	/// See [`AttributeConfig::pid`]
+	pid: String,
+	/// See [`AttributeConfig::pid_normalization`]
+	pid_normalization: PidNormalization,
+	/// See [`AttributeConfig::attrs_to_track`]
+	attrs_to_track: Vec<String>,
+	/// See [`AttributeConfig::updated`]
+	updated: Option<String>,
+	/// See [`AttributeConfig::usn`]
+	usn: Option<String>,
+	/// See [`AttributeConfig::schema_matching`]
+	schema_matching: bool,
+	/// See [`AttributeConfig::comparison`]
+	comparison: HashMap<String, ComparisonMode>,
+	/// See [`AttributeConfig::max_cached_size`]
+	max_cached_size: HashMap<String, usize>,
+	/// See [`AttributeConfig::uncached`]
+	uncached: Vec<String>,
+	/// See [`crate::config::Config::url`]
+	url: url::Url,
+	/// See [`crate::config::Searches::user_base`]
+	user_base: String,
+	/// See [`crate::config::Searches::user_filter`]
+	user_filter: String,
+}
+
+impl CacheOrigin {
+	/// Record the settings of a configuration
+	pub(crate) fn new(config: &Config) -> Self {
+		let attributes = &config.attributes;
+		CacheOrigin {
+			cache_method: config.cache_method.clone(),
+			pid: attributes.pid.clone(),
+			pid_normalization: attributes.pid_normalization,
+			attrs_to_track: attributes.attrs_to_track.clone(),
+			updated: attributes.updated.clone(),
+			usn: attributes.usn.clone(),
+			schema_matching: attributes.schema_matching,
+			comparison: attributes.comparison.clone(),
+			max_cached_size: attributes.max_cached_size.clone(),
+			uncached: attributes.uncached.clone(),
+			url: config.url.clone(),
+			user_base: config.searches.user_base.clone(),
+			user_filter: config.searches.user_filter.clone(),
+		}
+	}
+
+	/// Why entries cached with these settings can't be compared against
+	/// entries searched with the given configuration, if they can't
+	pub(crate) fn incompatibility(&self, config: &Config) -> Option<String> {
+		let attributes = &config.attributes;
+		if attributes.pid != self.pid {
+			Some(format!("The pid attribute changed from {} to {}", self.pid, attributes.pid))
+		} else if attributes.pid_normalization != self.pid_normalization {
+			Some(format!(
+				"The pid normalization changed from {:?} to {:?}",
+				self.pid_normalization, attributes.pid_normalization
+			))
+		} else if config.cache_method != self.cache_method {
+			Some(format!(
+				"The cache method changed from {:?} to {:?}",
+				self.cache_method, config.cache_method
+			))
+		} else if attributes.max_cached_size != self.max_cached_size
+			|| attributes.uncached != self.uncached
+		{
+			Some("The cached attributes changed".to_owned())
+		} else if config.cache_method == CacheMethod::Hashed
+			&& (attributes.attrs_to_track != self.attrs_to_track
+				|| attributes.updated != self.updated
+				|| attributes.schema_matching != self.schema_matching
+				|| attributes.comparison != self.comparison)
+		{
+			Some("The compared attributes changed, which the cached digests depend on".to_owned())
+		} else {
+			None
+		}
+	}
+
+	/// Whether the set of searched entries may differ with the given
+	/// configuration, so that a full sync is needed
+	pub(crate) fn changes_search_scope(&self, config: &Config) -> bool {
+		config.searches.user_base != self.user_base
+			|| config.searches.user_filter != self.user_filter
+			|| config.url != self.url
+			|| config.attributes.usn != self.usn
+	}
+}
+
+/// When a cached entry was first seen and when the server last returned it.
+/// Entries which are unchanged are only returned by full searches, so an
+/// entry which wasn't confirmed for much longer than the full sync interval
+/// may have been removed without being detected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct EntryProvenance {
+	/// When the entry was first added to the cache
+	pub first_seen: OffsetDateTime,
+	/// When a search or lookup last returned the entry
+	pub last_confirmed: OffsetDateTime,
+}
+
+/// Summary of the cached entries and their provenance, see
+/// [`Cache::stats`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+	/// The number of cached entries
+	pub entries: usize,
+	/// The number of cached entries without provenance, which were cached by
+	/// a version of this crate which didn't record it
+	pub without_provenance: usize,
+	/// The time the oldest cached entry was first seen
+	pub oldest_first_seen: Option<OffsetDateTime>,
+	/// The time of the least recent confirmation of a cached entry
+	pub oldest_confirmation: Option<OffsetDateTime>,
 }
 
 /// Possible status of a checked entry
@@ -24,11 +311,222 @@ pub(crate) enum CacheEntryStatus {
 	Missing,
 	/// The entry is present and unchanged
 	Unchanged,
-	/// The entry is present and has changed
-	Changed(SerializedSearchEntry),
+	/// The entry is present and has changed. The previous entry is only
+	/// known if the cache stores full entries.
+	Changed(Option<SerializedSearchEntry>),
+	/// The entry is present under a different DN than before, contains the
+	/// previous DN
+	Moved(String),
 }
 
 impl Cache {
+	/// Create an empty cache
+	pub(crate) fn new(entries: CacheEntries) -> Self {
+		Cache {
+			last_sync_time: None,
+			last_full_sync_time: None,
+			highest_usn: None,
+			entries,
+			missing: HashSet::new(),
+			misses: HashMap::new(),
+			provenance: HashMap::new(),
+			invalidated: HashSet::new(),
+			origin: None,
+			paged_search: None,
+			schema: None,
+			dn_index: DnIndex::default(),
+			changes: ChangedPids::default(),
+		}
+	}
+
+	/// Create a cache for the given cache method containing the given
+	/// entries, as if they were received in a previous sync. Fails with
+	/// [`crate::error::Error::MissingAttribute`] for entries without a
+	/// persistent ID.
+	pub(crate) fn from_entries(
+		cache_method: &CacheMethod,
+		attributes_config: &AttributeConfig,
+		entries: impl IntoIterator<Item = SearchEntry>,
+	) -> Result<Self, crate::error::Error> {
+		let mut cache = Cache::new(CacheEntries::from(cache_method));
+		for entry in entries {
+			cache.check_entry(&entry, attributes_config).map_err(|err| match err {
+				Error::Missing => crate::error::Error::MissingAttribute(format!(
+					"{} of {}",
+					attributes_config.pid, entry.dn
+				)),
+			})?;
+		}
+		cache.start_comparison();
+		Ok(cache)
+	}
+
+	/// The cache method the cache was created for
+	#[must_use]
+	pub fn method(&self) -> CacheMethod {
+		match self.entries {
+			CacheEntries::Modified(_) => CacheMethod::ModificationTime,
+			CacheEntries::Hashed(_) => CacheMethod::Hashed,
+			CacheEntries::None => CacheMethod::Disabled,
+		}
+	}
+
+	/// Convert the cache to another cache method, rebuilding the entries
+	/// from the stored ones with the given attribute configuration, so that
+	/// switching methods doesn't report every entry as new. Full entries can
+	/// be converted to digests, but digests can't be converted back. Caches
+	/// of [`CacheMethod::Disabled`] are converted to empty caches.
+	pub fn convert(
+		self,
+		to: &CacheMethod,
+		attributes_config: &AttributeConfig,
+	) -> Result<Self, crate::error::Error> {
+		let normalization = attributes_config.pid_normalization;
+		let entries = match (self.entries, to) {
+			(CacheEntries::Modified(cache), CacheMethod::Hashed) => CacheEntries::Hashed(
+				cache
+					.into_iter()
+					.map(|(pid, entry)| {
+						let entry =
+							normalize_pid(Arc::unwrap_or_clone(entry).into(), attributes_config);
+						let entry = HashedEntry::new(
+							&cached_form(&entry, attributes_config),
+							attributes_config,
+							self.schema.as_deref(),
+						);
+						(normalization.normalize(&pid).into_owned(), Arc::new(entry))
+					})
+					.collect(),
+			),
+			// Apply changed size limits and pid normalization to the cached
+			// entries
+			(CacheEntries::Modified(cache), CacheMethod::ModificationTime) => {
+				CacheEntries::Modified(
+					cache
+						.into_iter()
+						.map(|(pid, entry)| {
+							let entry = normalize_pid(
+								Arc::unwrap_or_clone(entry).into(),
+								attributes_config,
+							);
+							let entry = cached_form(&entry, attributes_config).into_owned();
+							(normalization.normalize(&pid).into_owned(), Arc::new(entry.into()))
+						})
+						.collect(),
+				)
+			}
+			// Digests of entries can't be normalized, so entries are reported
+			// as changed once if the normalization changed their pid
+			(CacheEntries::Hashed(cache), CacheMethod::Hashed) => CacheEntries::Hashed(
+				cache
+					.into_iter()
+					.map(|(pid, entry)| (normalization.normalize(&pid).into_owned(), entry))
+					.collect(),
+			),
+			(_, CacheMethod::Disabled) => CacheEntries::None,
+			(CacheEntries::Hashed(_), CacheMethod::ModificationTime) => {
+				return Err(crate::error::Error::CacheConversion {
+					from: CacheMethod::Hashed,
+					to: to.clone(),
+				});
+			}
+			(CacheEntries::None, method) => CacheEntries::from(method),
+		};
+		let provenance = match entries {
+			CacheEntries::None => HashMap::new(),
+			_ => self
+				.provenance
+				.into_iter()
+				.map(|(pid, provenance)| (normalization.normalize(&pid).into_owned(), provenance))
+				.collect(),
+		};
+		let invalidated = match entries {
+			CacheEntries::None => HashSet::new(),
+			_ => self
+				.invalidated
+				.into_iter()
+				.map(|pid| normalization.normalize(&pid).into_owned())
+				.collect(),
+		};
+		Ok(Cache {
+			entries,
+			missing: HashSet::new(),
+			misses: HashMap::new(),
+			provenance,
+			invalidated,
+			paged_search: None,
+			dn_index: DnIndex::default(),
+			changes: ChangedPids::default(),
+			..self
+		})
+	}
+
+	/// Check that the cache was built with a configuration compatible to the
+	/// given one, e.g. after restoring a persisted cache. Full entries are
+	/// [converted](Cache::convert) if the cache method or the cached
+	/// attributes changed, and a full sync is performed if the search scope
+	/// changed. Caches which can't be migrated, e.g. because the pid
+	/// attribute changed or the digests depend on changed tracked
+	/// attributes, are rejected with
+	/// [`crate::error::Error::IncompatibleCacheConfig`]. Caches persisted
+	/// before their settings were recorded are only checked for their cache
+	/// method.
+	pub fn migrate(self, config: &Config) -> Result<Self, crate::error::Error> {
+		let origin = CacheOrigin::new(config);
+		if self.origin.as_ref() == Some(&origin) {
+			return Ok(self);
+		}
+		let incompatibility = match &self.origin {
+			Some(previous) => previous.incompatibility(config),
+			None => (self.method() != config.cache_method).then(|| {
+				format!(
+					"The cache method changed from {:?} to {:?}",
+					self.method(),
+					config.cache_method
+				)
+			}),
+		};
+		let scope_changed =
+			self.origin.as_ref().is_some_and(|previous| previous.changes_search_scope(config));
+		let mut cache = match incompatibility {
+			None => self,
+			Some(_) if self.is_empty() => Cache::new(CacheEntries::from(&config.cache_method)),
+			// Full entries can be rebuilt for the new configuration, as long
+			// as they are still identified by the same attribute
+			Some(_)
+				if self.method() == CacheMethod::ModificationTime
+					&& self
+						.origin
+						.as_ref()
+						.is_none_or(|previous| previous.pid == config.attributes.pid) =>
+			{
+				self.convert(&config.cache_method, &config.attributes)?
+			}
+			Some(reason) => return Err(crate::error::Error::IncompatibleCacheConfig(reason)),
+		};
+		if scope_changed {
+			cache.last_sync_time = None;
+			cache.last_full_sync_time = None;
+			cache.highest_usn = None;
+			cache.paged_search = None;
+		}
+		cache.origin = Some(origin);
+		Ok(cache)
+	}
+
+	/// Where the sync stands, see [`Checkpoint`]
+	pub(crate) fn checkpoint(&self) -> Checkpoint {
+		Checkpoint { last_sync_time: self.last_sync_time, highest_usn: self.highest_usn }
+	}
+
+	/// Move the sync position back to the given checkpoint if it is earlier,
+	/// so that the entries changed since then are searched again
+	pub(crate) fn rewind(&mut self, checkpoint: Checkpoint) {
+		let Checkpoint { last_sync_time, highest_usn } = self.checkpoint().earliest(checkpoint);
+		self.last_sync_time = last_sync_time;
+		self.highest_usn = highest_usn;
+	}
+
 	/// Start a new comparison with the current entries
 	pub(crate) fn start_comparison(&mut self) {
 		self.missing = self.entries.get_expected();
@@ -41,15 +539,281 @@ impl Cache {
 		entry: &SearchEntry,
 		attributes_config: &AttributeConfig,
 	) -> Result<CacheEntryStatus, Error> {
-		let id = entry.bin_attr_first(&attributes_config.pid).ok_or(Error::Missing)?;
-		self.missing.remove(id);
-		self.entries.check_cache_entry_status(entry, attributes_config)
+		let comparison = self.compare_entry(entry, attributes_config)?;
+		Ok(self.commit_entry(comparison))
+	}
+
+	/// Compare an entry with the cached one. Only needs shared access, so
+	/// entries can be compared concurrently and stored one after the other
+	/// with [`Cache::commit_entry`].
+	pub(crate) fn compare_entry(
+		&self,
+		entry: &SearchEntry,
+		attributes_config: &AttributeConfig,
+	) -> Result<Comparison, Error> {
+		self.entries.compare(entry, attributes_config, self.schema.as_deref())
+	}
+
+	/// Store a compared entry and update expected entries. Invalidated
+	/// entries are reported as missing, so that they are emitted as new.
+	pub(crate) fn commit_entry(&mut self, comparison: Comparison) -> CacheEntryStatus {
+		let indexed = self.dn_index.0.get().is_some().then(|| comparison.pid.clone());
+		let old_key = indexed.as_deref().and_then(|pid| self.dn(pid)).and_then(dn_key);
+		let status = self.commit_comparison(comparison);
+		if let Some(pid) = indexed {
+			let new_key = self.dn(&pid).and_then(dn_key);
+			if let Some(index) = self.dn_index.0.get_mut() {
+				if let Some(old_key) = old_key.filter(|old_key| new_key.as_ref() != Some(old_key)) {
+					index.remove(&old_key);
+				}
+				if let Some(new_key) = new_key {
+					index.insert(new_key, pid);
+				}
+			}
+		}
+		status
+	}
+
+	/// Store a compared entry, see [`Cache::commit_entry`]
+	fn commit_comparison(&mut self, comparison: Comparison) -> CacheEntryStatus {
+		self.missing.remove(&comparison.pid);
+		let invalidated = self.invalidated.remove(&comparison.pid);
+		if !matches!(self.entries, CacheEntries::None) {
+			self.changes.insert(&comparison.pid);
+			let now = OffsetDateTime::now_utc();
+			self.provenance
+				.entry(comparison.pid.clone())
+				.and_modify(|provenance| provenance.last_confirmed = now)
+				.or_insert(EntryProvenance { first_seen: now, last_confirmed: now });
+		}
+		match self.entries.commit(comparison) {
+			_ if invalidated => CacheEntryStatus::Missing,
+			status => status,
+		}
+	}
+
+	/// Remove an entry from the cache, returning whether it was present
+	pub(crate) fn remove_entry(&mut self, pid: &[u8]) -> bool {
+		let key = self.dn(pid).and_then(dn_key);
+		if let (Some(index), Some(key)) = (self.dn_index.0.get_mut(), key) {
+			index.remove(&key);
+		}
+		self.changes.insert(pid);
+		self.missing.remove(pid);
+		self.provenance.remove(pid);
+		self.invalidated.remove(pid);
+		match self.entries {
+			CacheEntries::Modified(ref mut cache) => cache.remove(pid).is_some(),
+			CacheEntries::Hashed(ref mut cache) => cache.remove(pid).is_some(),
+			CacheEntries::None => false,
+		}
+	}
+
+	/// Take the pids changed since the last call, which are the entries a
+	/// copy of the cache taken at that point differs in, see
+	/// [`Cache::refresh_copy`]
+	pub(crate) fn take_changes(&mut self) -> ChangedPids {
+		std::mem::replace(&mut self.changes, ChangedPids(Some(HashSet::new())))
+	}
+
+	/// Bring an outdated copy of the cache up to date, copying only the
+	/// entries with the given changed pids. The cache is copied as a whole
+	/// without a copy to reuse or if any entry may have changed.
+	pub(crate) fn refresh_copy(&self, copy: Option<Cache>, changed: &ChangedPids) -> Cache {
+		let (Some(mut copy), Some(pids)) = (copy, &changed.0) else {
+			return self.clone();
+		};
+		if copy.method() != self.method() {
+			return self.clone();
+		}
+		for pid in pids {
+			match (&mut copy.entries, &self.entries) {
+				(CacheEntries::Modified(copy), CacheEntries::Modified(cache)) => {
+					refresh_key(copy, cache, pid);
+				}
+				(CacheEntries::Hashed(copy), CacheEntries::Hashed(cache)) => {
+					refresh_key(copy, cache, pid);
+				}
+				_ => {}
+			}
+			refresh_key(&mut copy.provenance, &self.provenance, pid);
+		}
+		Cache {
+			last_sync_time: self.last_sync_time,
+			last_full_sync_time: self.last_full_sync_time,
+			highest_usn: self.highest_usn,
+			entries: copy.entries,
+			missing: self.missing.clone(),
+			misses: self.misses.clone(),
+			provenance: copy.provenance,
+			invalidated: self.invalidated.clone(),
+			origin: self.origin.clone(),
+			paged_search: self.paged_search.clone(),
+			schema: self.schema.clone(),
+			dn_index: DnIndex::default(),
+			changes: ChangedPids::default(),
+		}
+	}
+
+	/// Find the pid of the cached entry with the given DN, building the
+	/// [`DnIndex`] on first use
+	pub(crate) fn pid_by_dn(&self, dn: &str) -> Option<Vec<u8>> {
+		let index = self.dn_index.0.get_or_init(|| {
+			self.pids().filter_map(|pid| Some((dn_key(self.dn(pid)?)?, pid.to_owned()))).collect()
+		});
+		index.get(&dn_key(dn)?).cloned()
+	}
+
+	/// Get the cached entry with the given pid, if full entries are cached
+	#[must_use]
+	pub fn entry(&self, pid: &[u8]) -> Option<SearchEntry> {
+		match self.entries {
+			CacheEntries::Modified(ref cache) => {
+				cache.get(pid).map(|entry| SearchEntry::from((**entry).clone()))
+			}
+			CacheEntries::Hashed(_) | CacheEntries::None => None,
+		}
+	}
+
+	/// Share the cached entry with the given pid without copying it, which is
+	/// only known if full entries are cached
+	pub(crate) fn shared_entry(&self, pid: &[u8]) -> Option<Arc<SerializedSearchEntry>> {
+		match self.entries {
+			CacheEntries::Modified(ref cache) => cache.get(pid).cloned(),
+			CacheEntries::Hashed(_) | CacheEntries::None => None,
+		}
+	}
+
+	/// Get the DN of the cached entry with the given pid
+	#[must_use]
+	pub fn dn(&self, pid: &[u8]) -> Option<&str> {
+		match self.entries {
+			CacheEntries::Modified(ref cache) => cache.get(pid).map(|entry| entry.dn.as_str()),
+			CacheEntries::Hashed(ref cache) => cache.get(pid).map(|entry| entry.dn.as_str()),
+			CacheEntries::None => None,
+		}
+	}
+
+	/// Iterate over the pids of all cached entries, in no particular order
+	pub fn pids(&self) -> Box<dyn Iterator<Item = &[u8]> + '_> {
+		match self.entries {
+			CacheEntries::Modified(ref cache) => Box::new(cache.keys().map(Vec::as_slice)),
+			CacheEntries::Hashed(ref cache) => Box::new(cache.keys().map(Vec::as_slice)),
+			CacheEntries::None => Box::new(std::iter::empty()),
+		}
+	}
+
+	/// The number of cached entries
+	#[must_use]
+	pub fn len(&self) -> usize {
+		match self.entries {
+			CacheEntries::Modified(ref cache) => cache.len(),
+			CacheEntries::Hashed(ref cache) => cache.len(),
+			CacheEntries::None => 0,
+		}
+	}
+
+	/// Whether no entries are cached
+	#[must_use]
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	/// When the cached entry with the given pid was first seen and last
+	/// confirmed by the server. Unknown for entries cached by a version of
+	/// this crate which didn't record it, until they are returned again.
+	#[must_use]
+	pub fn provenance(&self, pid: &[u8]) -> Option<EntryProvenance> {
+		self.provenance.get(pid).copied()
+	}
+
+	/// Iterate over the provenance of all cached entries which have one, in no
+	/// particular order
+	pub fn provenances(&self) -> impl Iterator<Item = (&[u8], EntryProvenance)> {
+		self.provenance.iter().map(|(pid, provenance)| (pid.as_slice(), *provenance))
+	}
+
+	/// Get the pids of the cached entries which weren't confirmed since the
+	/// given time, least recently confirmed first, e.g. to re-check them with
+	/// [`crate::Ldap::recheck`]
+	#[must_use]
+	pub fn unconfirmed_since(&self, time: OffsetDateTime) -> Vec<(Vec<u8>, EntryProvenance)> {
+		let mut unconfirmed: Vec<_> = self
+			.provenances()
+			.filter(|(_, provenance)| provenance.last_confirmed < time)
+			.map(|(pid, provenance)| (pid.to_owned(), provenance))
+			.collect();
+		unconfirmed.sort_by_key(|(_, provenance)| provenance.last_confirmed);
+		unconfirmed
+	}
+
+	/// Summarize the cached entries and their provenance
+	#[must_use]
+	pub fn stats(&self) -> CacheStats {
+		let entries = self.len();
+		CacheStats {
+			entries,
+			without_provenance: entries.saturating_sub(self.provenance.len()),
+			oldest_first_seen: self
+				.provenances()
+				.map(|(_, provenance)| provenance.first_seen)
+				.min(),
+			oldest_confirmation: self
+				.provenances()
+				.map(|(_, provenance)| provenance.last_confirmed)
+				.min(),
+		}
+	}
+
+	/// Export the cache as pretty-printed JSON for debugging, e.g. to find out
+	/// why an entry wasn't emitted. Unlike the persisted formats, pids are
+	/// shown as text if they are valid UTF-8 and hex encoded otherwise, and
+	/// entries are sorted by DN. Not meant to be read back.
+	pub fn to_json_pretty(&self) -> Result<String, crate::error::Error> {
+		let mut entries = match self.entries {
+			CacheEntries::Modified(ref cache) => export_entries(cache),
+			CacheEntries::Hashed(ref cache) => export_entries(cache),
+			CacheEntries::None => Ok(Vec::new()),
+		}
+		.map_err(|err| crate::error::Error::Codec(err.to_string()))?;
+		entries.sort_by(|a, b| a["dn"].as_str().cmp(&b["dn"].as_str()));
+		let method = match self.entries {
+			CacheEntries::Modified(_) => "modification_time",
+			CacheEntries::Hashed(_) => "hashed",
+			CacheEntries::None => "disabled",
+		};
+		let export = serde_json::json!({
+			"method": method,
+			"last_sync_time": self.last_sync_time,
+			"last_full_sync_time": self.last_full_sync_time,
+			"highest_usn": self.highest_usn,
+			"entries": entries,
+		});
+		serde_json::to_string_pretty(&export)
+			.map_err(|err| crate::error::Error::Codec(err.to_string()))
 	}
 
 	/// End a running comparison with the current entries
 	pub(crate) fn end_comparison_and_return_missing_entries(&mut self) -> &HashSet<Vec<u8>> {
 		&self.missing
 	}
+
+	/// Count a miss of every entry missing from the completed full search,
+	/// forgetting the misses of all other entries, and return the entries
+	/// which were missed by at least the given number of searches in a row
+	pub(crate) fn count_misses(&mut self, required: u32) -> Vec<Vec<u8>> {
+		let missing = &self.missing;
+		self.misses.retain(|pid, _| missing.contains(pid));
+		let mut confirmed = Vec::new();
+		for pid in missing {
+			let misses = self.misses.entry(pid.clone()).or_default();
+			*misses = misses.saturating_add(1);
+			if *misses >= required {
+				confirmed.push(pid.clone());
+			}
+		}
+		confirmed
+	}
 }
 
 /// Serialized version of a search entry
@@ -86,103 +850,737 @@ impl SearchEntryExt for SerializedSearchEntry {
 			.and_then(|attr| attr.first().map(String::as_bytes))
 			.or_else(|| self.bin_attrs.get(attr).and_then(|attr| attr.first().map(Vec::as_slice)))
 	}
+
+	fn bin_attr_values(&self, attr: &str) -> Vec<&[u8]> {
+		crate::entry::bin_values(&self.attrs, &self.bin_attrs, attr)
+	}
+}
+
+/// Convert cached entries to JSON values, adding their pid
+fn export_entries<V: serde::Serialize>(
+	cache: &HashMap<Vec<u8>, V>,
+) -> Result<Vec<serde_json::Value>, serde_json::Error> {
+	cache
+		.iter()
+		.map(|(pid, entry)| {
+			let mut entry = serde_json::to_value(entry)?;
+			if let Some(entry) = entry.as_object_mut() {
+				entry.insert("pid".to_owned(), display_pid(pid).into());
+			}
+			Ok(entry)
+		})
+		.collect()
+}
+
+/// Show a pid as text if it's printable UTF-8, or hex encoded otherwise
+pub(crate) fn display_pid(pid: &[u8]) -> String {
+	match std::str::from_utf8(pid) {
+		Ok(pid) if !pid.chars().any(char::is_control) => pid.to_owned(),
+		_ => pid.iter().map(|byte| format!("{byte:02x}")).collect(),
+	}
+}
+
+impl PidNormalization {
+	/// Normalize a pid value
+	pub(crate) fn normalize(self, pid: &[u8]) -> Cow<'_, [u8]> {
+		match self {
+			PidNormalization::None => Cow::Borrowed(pid),
+			PidNormalization::Lowercase => lowercase(pid),
+			PidNormalization::Uuid | PidNormalization::Guid => {
+				match uuid_bytes(pid, self == PidNormalization::Guid) {
+					Some(bytes) => Cow::Owned(format_uuid(&bytes).into_bytes()),
+					None => lowercase(pid),
+				}
+			}
+			PidNormalization::Dn => MatchingRule::DistinguishedName.normalize(pid),
+		}
+	}
+}
+
+/// Replace the pid of an entry with its normalized form, see
+/// [`AttributeConfig::pid_normalization`]
+pub(crate) fn normalize_pid(
+	mut entry: SearchEntry,
+	attributes_config: &AttributeConfig,
+) -> SearchEntry {
+	let normalization = attributes_config.pid_normalization;
+	if normalization == PidNormalization::None {
+		return entry;
+	}
+	let Some(pid) = entry.bin_attr_first(&attributes_config.pid) else {
+		return entry;
+	};
+	let attr = attributes_config.pid.clone();
+	match String::from_utf8(normalization.normalize(pid).into_owned()) {
+		Ok(pid) => {
+			entry.bin_attrs.remove(&attr);
+			entry.attrs.insert(attr, vec![pid]);
+		}
+		Err(err) => {
+			entry.attrs.remove(&attr);
+			entry.bin_attrs.insert(attr, vec![err.into_bytes()]);
+		}
+	}
+	entry
+}
+
+/// Lowercase the ASCII letters of a value
+fn lowercase(value: &[u8]) -> Cow<'_, [u8]> {
+	if value.iter().any(u8::is_ascii_uppercase) {
+		Cow::Owned(value.to_ascii_lowercase())
+	} else {
+		Cow::Borrowed(value)
+	}
+}
+
+/// The bytes of a UUID in RFC 4122 order, parsed from its text form or a
+/// binary value. Values which are printable text are never read as binary,
+/// so that e.g. a username of 16 characters isn't mistaken for a UUID. The
+/// first three fields of binary values are little endian if `mixed_endian`
+/// is set, like in Microsoft GUIDs.
+fn uuid_bytes(value: &[u8], mixed_endian: bool) -> Option<[u8; 16]> {
+	if let Ok(text) = std::str::from_utf8(value) {
+		let hex = text.trim_start_matches('{').trim_end_matches('}').replace('-', "");
+		if hex.len() == 32 {
+			let mut bytes = [0; 16];
+			for (index, byte) in bytes.iter_mut().enumerate() {
+				let digits = hex.get(index * 2..index * 2 + 2)?;
+				*byte = u8::from_str_radix(digits, 16).ok()?;
+			}
+			return Some(bytes);
+		}
+		if !text.chars().any(char::is_control) {
+			return None;
+		}
+	}
+	let mut bytes: [u8; 16] = value.try_into().ok()?;
+	if mixed_endian {
+		bytes[..4].reverse();
+		bytes[4..6].reverse();
+		bytes[6..8].reverse();
+	}
+	Some(bytes)
+}
+
+/// Format a UUID in its canonical lowercase form
+fn format_uuid(bytes: &[u8; 16]) -> String {
+	let mut uuid = String::with_capacity(36);
+	for (index, byte) in bytes.iter().enumerate() {
+		if matches!(index, 4 | 6 | 8 | 10) {
+			uuid.push('-');
+		}
+		uuid.push_str(&format!("{byte:02x}"));
+	}
+	uuid
+}
+
+/// The attributes compared to detect changes: the tracked attributes and the
+/// modification time
+fn compared_attributes(attributes_config: &AttributeConfig) -> impl Iterator<Item = &String> {
+	attributes_config.attrs_to_track.iter().chain(attributes_config.updated.as_ref())
+}
+
+/// The given attribute names which are compared when comparing entries as a
+/// whole according to a schema, sorted and without the modification time,
+/// which the server also bumps for changes that only match according to the
+/// schema, e.g. changing only the case of a `caseIgnoreMatch` value
+fn schema_compared_names<'a>(
+	names: impl Iterator<Item = &'a String>,
+	attributes_config: &AttributeConfig,
+) -> Vec<&'a str> {
+	let mut names: Vec<_> = names
+		.map(String::as_str)
+		.filter(|name| {
+			attributes_config.is_compared(name)
+				&& attributes_config.updated.as_deref() != Some(*name)
+		})
+		.collect();
+	names.sort_unstable();
+	names.dedup();
+	names
+}
+
+/// The values of a tracked attribute in the form in which they are compared,
+/// see [`AttributeConfig::comparison`]. Unless the order matters, they are
+/// sorted, so that the order in which the server returns them doesn't. Without
+/// a comparison mode but with a schema, the values are normalized according
+/// to the matching rule of the attribute.
+fn compared_values<'a>(
+	entry: &'a impl SearchEntryExt,
+	attr: &str,
+	attributes_config: &AttributeConfig,
+	schema: Option<&Schema>,
+) -> Vec<Cow<'a, [u8]>> {
+	let mode = attributes_config.comparison.get(attr).copied();
+	let rule = schema.map(|schema| schema.matching_rule(attr));
+	let mut values: Vec<_> = entry
+		.bin_attr_values(attr)
+		.into_iter()
+		.map(|value| match (mode, rule) {
+			(Some(mode), _) => mode.normalize(value),
+			(None, Some(rule)) => rule.normalize(value),
+			(None, None) => Cow::Borrowed(value),
+		})
+		.collect();
+	match mode {
+		Some(ComparisonMode::Exact) => {}
+		Some(ComparisonMode::Set) => {
+			values.sort_unstable();
+			values.dedup();
+		}
+		_ => values.sort_unstable(),
+	}
+	values
+}
+
+impl ComparisonMode {
+	/// Normalize a value, so that equal values have the same form
+	fn normalize(self, value: &[u8]) -> Cow<'_, [u8]> {
+		match (self, std::str::from_utf8(value)) {
+			(ComparisonMode::CaseInsensitive, Ok(text)) => {
+				Cow::Owned(text.to_lowercase().into_bytes())
+			}
+			(ComparisonMode::CaseInsensitive, Err(_)) => lowercase(value),
+			(ComparisonMode::Numeric, Ok(text)) => match text.trim().parse::<i128>() {
+				Ok(number) => Cow::Owned(number.to_string().into_bytes()),
+				Err(_) => Cow::Borrowed(value),
+			},
+			_ => Cow::Borrowed(value),
+		}
+	}
+}
+
+/// An entry as it is kept in the cache, without the attributes in
+/// [`AttributeConfig::uncached`] and with values exceeding
+/// [`AttributeConfig::max_cached_size`] replaced by their digest
+fn cached_form<'a>(
+	entry: &'a SearchEntry,
+	attributes_config: &AttributeConfig,
+) -> Cow<'a, SearchEntry> {
+	if attributes_config.uncached.is_empty() && attributes_config.max_cached_size.is_empty() {
+		return Cow::Borrowed(entry);
+	}
+	let mut entry = entry.clone();
+	entry.attrs.retain(|name, _| !attributes_config.uncached.contains(name));
+	entry.bin_attrs.retain(|name, _| !attributes_config.uncached.contains(name));
+	for (name, values) in &mut entry.attrs {
+		if let Some(&limit) = attributes_config.max_cached_size.get(name) {
+			for value in values {
+				if let Some(digest) = oversize_digest(value.as_bytes(), limit) {
+					*value = digest;
+				}
+			}
+		}
+	}
+	for (name, values) in &mut entry.bin_attrs {
+		if let Some(&limit) = attributes_config.max_cached_size.get(name) {
+			for value in values {
+				if let Some(digest) = oversize_digest(value, limit) {
+					*value = digest.into_bytes();
+				}
+			}
+		}
+	}
+	Cow::Owned(entry)
+}
+
+/// The digest representing a value in the cache if it exceeds the size limit.
+/// Digests are never replaced again, so that converting cached entries with
+/// the same limits doesn't change them.
+fn oversize_digest(value: &[u8], limit: usize) -> Option<String> {
+	let is_digest = value.len() == 71
+		&& value.starts_with(b"sha256:")
+		&& value[7..].iter().all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'));
+	(value.len() > limit && !is_digest).then(|| {
+		let digest: String =
+			Sha256::digest(value).iter().map(|byte| format!("{byte:02x}")).collect();
+		format!("sha256:{digest}")
+	})
 }
 
 /// Cache data entries used to check whether an entry has changed
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub enum CacheEntries {
 	/// Use the modification time attribute to check whether a user entry has
 	/// changed.
-	Modified(HashMap<Vec<u8>, SerializedSearchEntry>),
+	Modified(#[serde(with = "pid_map")] HashMap<Vec<u8>, Arc<SerializedSearchEntry>>),
+	/// Like [`CacheEntries::Modified`], but only keep digests of the tracked
+	/// attributes instead of full entries to reduce memory usage.
+	Hashed(#[serde(with = "pid_map")] HashMap<Vec<u8>, Arc<HashedEntry>>),
 	/// Don't cache anything, forward all results unconditionally
 	None,
 }
 
+impl From<&CacheMethod> for CacheEntries {
+	fn from(method: &CacheMethod) -> Self {
+		match method {
+			CacheMethod::ModificationTime => CacheEntries::Modified(HashMap::new()),
+			CacheMethod::Hashed => CacheEntries::Hashed(HashMap::new()),
+			CacheMethod::Disabled => CacheEntries::None,
+		}
+	}
+}
+
 impl CacheEntries {
 	/// Get initial hash set of expected entries
 	pub(crate) fn get_expected(&self) -> HashSet<Vec<u8>> {
 		match *self {
 			CacheEntries::Modified(ref cache) => cache.keys().cloned().collect(),
+			CacheEntries::Hashed(ref cache) => cache.keys().cloned().collect(),
 			CacheEntries::None => HashSet::new(),
 		}
 	}
 
-	/// Check whether an entry is present or changed
-	pub(crate) fn check_cache_entry_status(
-		&mut self,
+	/// Compare an entry with the cached one, see [`Cache::compare_entry`]
+	fn compare(
+		&self,
 		entry: &SearchEntry,
 		attributes_config: &AttributeConfig,
-	) -> Result<CacheEntryStatus, Error> {
-		match *self {
-			CacheEntries::Modified(ref mut cache) => {
-				match has_any_attr_changed(cache, entry, attributes_config) {
-					Ok(status) => Ok(status),
-					Err(err) => {
-						tracing::warn!("Validating modification time failed: {err}");
-						Err(err)
-					}
-				}
+		schema: Option<&Schema>,
+	) -> Result<Comparison, Error> {
+		let comparison = match *self {
+			CacheEntries::Modified(ref cache) => {
+				compare_full(cache, entry, attributes_config, schema)
 			}
-			CacheEntries::None => Ok(CacheEntryStatus::Missing),
+			CacheEntries::Hashed(ref cache) => {
+				compare_hashed(cache, entry, attributes_config, schema)
+			}
+			CacheEntries::None => {
+				let pid = entry.bin_attr_first(&attributes_config.pid).ok_or(Error::Missing)?;
+				return Ok(Comparison {
+					pid: pid.to_owned(),
+					outcome: Outcome::Missing,
+					cached: None,
+				});
+			}
+		};
+		if let Err(ref err) = comparison {
+			tracing::warn!("Validating modification time failed: {err}");
 		}
+		comparison
 	}
-}
 
-/// Check whether the modification time of an entry has changed
-fn has_any_attr_changed(
-	cache: &mut HashMap<Vec<u8>, SerializedSearchEntry>,
-	entry: &SearchEntry,
-	attributes_config: &AttributeConfig,
-) -> Result<CacheEntryStatus, Error> {
-	let id = entry.bin_attr_first(&attributes_config.pid).ok_or(Error::Missing)?;
-	match cache.get_mut(id) {
-		Some(old_entry) => {
-			if attributes_config
-				.attrs_to_track
-				.iter()
-				.chain(attributes_config.updated.iter())
-				.any(|attr| entry.bin_attr_first(attr) != old_entry.bin_attr_first(attr))
-			{
-				let old_entry_clone = old_entry.clone();
-				*old_entry = Into::<SerializedSearchEntry>::into(entry.clone());
-				Ok(CacheEntryStatus::Changed(old_entry_clone))
-			} else {
-				Ok(CacheEntryStatus::Unchanged)
+	/// Store a compared entry, returning whether it is present or changed
+	fn commit(&mut self, comparison: Comparison) -> CacheEntryStatus {
+		let Comparison { pid, outcome, cached } = comparison;
+		let old = match (self, cached) {
+			(CacheEntries::Modified(cache), Some(CachedEntry::Full(entry))) => {
+				cache.insert(pid, Arc::new(entry)).map(|old| {
+					let old = Arc::unwrap_or_clone(old);
+					(old.dn.clone(), Some(old))
+				})
 			}
-		}
-		None => {
-			cache.insert(id.to_owned(), Into::<SerializedSearchEntry>::into(entry.clone()));
-			Ok(CacheEntryStatus::Missing)
+			(CacheEntries::Hashed(cache), Some(CachedEntry::Hashed(entry))) => {
+				cache.insert(pid, Arc::new(entry)).map(|old| (old.dn.clone(), None))
+			}
+			// Unchanged, or not cached at all
+			_ if outcome == Outcome::Unchanged => return CacheEntryStatus::Unchanged,
+			_ => return CacheEntryStatus::Missing,
+		};
+		match (outcome, old) {
+			(_, None) => CacheEntryStatus::Missing,
+			(Outcome::Moved, Some((old_dn, _))) => CacheEntryStatus::Moved(old_dn),
+			(_, Some((_, old_entry))) => CacheEntryStatus::Changed(old_entry),
 		}
 	}
 }
 
-/// Errors that can occur when attempting to check if an entry has changed.
-#[derive(Debug, thiserror::Error)]
-pub(crate) enum Error {
-	/// A time value was malformed and failed to parse.
-	#[error("Malformed time")]
-	Time(#[from] time::error::Parse),
-	/// An expected attribute was not present.
-	#[error("Missing attribute")]
-	Missing,
+/// An entry compared with the cache by [`Cache::compare_entry`], to be stored
+/// by [`Cache::commit_entry`]
+#[derive(Debug)]
+pub(crate) struct Comparison {
+	/// The pid of the entry
+	pid: Vec<u8>,
+	/// How the entry differs from the cached one
+	outcome: Outcome,
+	/// The entry in the form it is cached in, unless it is unchanged
+	cached: Option<CachedEntry>,
 }
 
-#[cfg(test)]
-mod tests {
-	#![allow(clippy::unwrap_used, clippy::items_after_statements)]
+/// How a compared entry differs from the cached one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+	/// The entry is not cached
+	Missing,
+	/// The entry is unchanged
+	Unchanged,
+	/// The entry has changed
+	Changed,
+	/// The entry has a different DN than before
+	Moved,
+}
 
-	use std::collections::HashMap;
+/// An entry in the form one of the cache methods stores it in
+#[derive(Debug)]
+enum CachedEntry {
+	/// The full entry, see [`CacheEntries::Modified`]
+	Full(SerializedSearchEntry),
+	/// The digests of the entry, see [`CacheEntries::Hashed`]
+	Hashed(HashedEntry),
+}
 
-	use ldap3::SearchEntry;
+/// Parse the modification time of an entry, if the attribute is configured and
+/// present
+pub(crate) fn parse_updated(
+	entry: &SearchEntry,
+	attributes_config: &AttributeConfig,
+) -> Option<Result<OffsetDateTime, crate::error::Error>> {
+	entry.time_first(attributes_config.updated.as_deref()?)
+}
+
+/// Check whether the modification time, the DN or any tracked attribute of an
+/// entry has changed. If the modification time can't be parsed, all attributes
+/// of the entry are compared byte by byte instead.
+fn compare_full(
+	cache: &HashMap<Vec<u8>, Arc<SerializedSearchEntry>>,
+	entry: &SearchEntry,
+	attributes_config: &AttributeConfig,
+	schema: Option<&Schema>,
+) -> Result<Comparison, Error> {
+	let cached = cached_form(entry, attributes_config);
+	let entry = &*cached;
+	let pid = entry.bin_attr_first(&attributes_config.pid).ok_or(Error::Missing)?.to_owned();
+	let outcome = match cache.get(&pid) {
+		Some(old_entry)
+			if old_entry.dn != entry.dn && attributes_config.dn_changes != DnChangeMode::Ignore =>
+		{
+			moved_outcome(attributes_config)
+		}
+		Some(old_entry) => {
+			let differs = |attr: &str| {
+				compared_values(entry, attr, attributes_config, schema)
+					!= compared_values(&**old_entry, attr, attributes_config, schema)
+			};
+			let changed = if let Some(Err(_)) = parse_updated(entry, attributes_config) {
+				!compared_attrs_eq(&entry.attrs, &old_entry.attrs, attributes_config)
+					|| !compared_attrs_eq(&entry.bin_attrs, &old_entry.bin_attrs, attributes_config)
+			} else {
+				// With a schema, a new modification time is only a change if
+				// the entry differs according to it
+				let entry_differs = || {
+					let names = entry.attrs.keys().chain(entry.bin_attrs.keys());
+					let old_names = old_entry.attrs.keys().chain(old_entry.bin_attrs.keys());
+					schema_compared_names(names.chain(old_names), attributes_config)
+						.into_iter()
+						.any(differs)
+				};
+				attributes_config.attrs_to_track.iter().any(|attr| differs(attr))
+					|| (attributes_config.updated.as_deref().is_some_and(differs)
+						&& (schema.is_none() || entry_differs()))
+			};
+			if changed {
+				Outcome::Changed
+			} else {
+				Outcome::Unchanged
+			}
+		}
+		None => Outcome::Missing,
+	};
+	let cached =
+		(outcome != Outcome::Unchanged).then(|| CachedEntry::Full(cached.into_owned().into()));
+	Ok(Comparison { pid, outcome, cached })
+}
+
+/// How an entry received under another DN than the cached one is reported
+fn moved_outcome(attributes_config: &AttributeConfig) -> Outcome {
+	match attributes_config.dn_changes {
+		DnChangeMode::Changed => Outcome::Changed,
+		_ => Outcome::Moved,
+	}
+}
+
+/// Whether two sets of attributes are equal, ignoring attributes which are
+/// not compared, see [`AttributeConfig::is_compared`]
+fn compared_attrs_eq<V: PartialEq>(
+	attrs: &HashMap<String, V>,
+	old_attrs: &HashMap<String, V>,
+	attributes_config: &AttributeConfig,
+) -> bool {
+	let compared = |attrs: &'_ HashMap<String, V>| {
+		attrs.iter().filter(|(name, _)| attributes_config.is_compared(name)).count()
+	};
+	compared(attrs) == compared(old_attrs)
+		&& attrs
+			.iter()
+			.filter(|(name, _)| attributes_config.is_compared(name))
+			.all(|(name, values)| old_attrs.get(name) == Some(values))
+}
+
+/// Digests of a cached entry, used instead of the full entry by
+/// [`CacheEntries::Hashed`]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq, Eq)]
+pub struct HashedEntry {
+	/// Entry DN, kept in full to report moved entries.
+	pub dn: String,
+	/// Digests of the tracked attributes and the modification time, in the
+	/// order of the attribute configuration.
+	pub attributes: Vec<[u8; 32]>,
+	/// Digest of all attributes, compared if the modification time can't be
+	/// parsed.
+	pub entry: [u8; 32],
+}
+
+impl HashedEntry {
+	/// Compute the digests of an entry
+	fn new(
+		entry: &SearchEntry,
+		attributes_config: &AttributeConfig,
+		schema: Option<&Schema>,
+	) -> Self {
+		let attributes = compared_attributes(attributes_config)
+			.map(|attr| {
+				let mut hasher = Sha256::new();
+				match &compared_values(entry, attr, attributes_config, schema)[..] {
+					[] => {}
+					// Same digest as before multiple values were compared
+					[value] => {
+						hasher.update([1]);
+						hasher.update(value);
+					}
+					values => {
+						hasher.update([2]);
+						hash_attribute(&mut hasher, attr, values.iter().map(AsRef::as_ref));
+					}
+				}
+				hasher.finalize().into()
+			})
+			.collect();
+
+		let mut hasher = Sha256::new();
+		if schema.is_some() {
+			let names = entry.attrs.keys().chain(entry.bin_attrs.keys());
+			for name in schema_compared_names(names, attributes_config) {
+				let values = compared_values(entry, name, attributes_config, schema);
+				hash_attribute(&mut hasher, name, values.iter().map(AsRef::as_ref));
+			}
+			return HashedEntry {
+				dn: entry.dn.clone(),
+				attributes,
+				entry: hasher.finalize().into(),
+			};
+		}
+		let mut attrs: Vec<_> =
+			entry.attrs.iter().filter(|(name, _)| attributes_config.is_compared(name)).collect();
+		attrs.sort_unstable();
+		for (name, values) in attrs {
+			hash_attribute(&mut hasher, name, values.iter().map(String::as_bytes));
+		}
+		let mut bin_attrs: Vec<_> = entry
+			.bin_attrs
+			.iter()
+			.filter(|(name, _)| attributes_config.is_compared(name))
+			.collect();
+		bin_attrs.sort_unstable();
+		for (name, values) in bin_attrs {
+			hash_attribute(&mut hasher, name, values.iter().map(Vec::as_slice));
+		}
+
+		HashedEntry { dn: entry.dn.clone(), attributes, entry: hasher.finalize().into() }
+	}
+}
+
+/// Feed an attribute with all its values into a hasher, prefixing everything
+/// with its length to keep the encoding unambiguous
+fn hash_attribute<'a>(hasher: &mut Sha256, name: &str, values: impl Iterator<Item = &'a [u8]>) {
+	hasher.update((name.len() as u64).to_le_bytes());
+	hasher.update(name);
+	for value in values {
+		hasher.update((value.len() as u64).to_le_bytes());
+		hasher.update(value);
+	}
+	hasher.update(u64::MAX.to_le_bytes());
+}
+
+/// Same as [`compare_full`], but comparing digests instead of full entries.
+/// The previous entry is not known for changed entries.
+fn compare_hashed(
+	cache: &HashMap<Vec<u8>, Arc<HashedEntry>>,
+	entry: &SearchEntry,
+	attributes_config: &AttributeConfig,
+	schema: Option<&Schema>,
+) -> Result<Comparison, Error> {
+	let entry = &*cached_form(entry, attributes_config);
+	let pid = entry.bin_attr_first(&attributes_config.pid).ok_or(Error::Missing)?.to_owned();
+	let new_entry = HashedEntry::new(entry, attributes_config, schema);
+	let outcome = match cache.get(&pid) {
+		Some(old_entry)
+			if old_entry.dn != entry.dn && attributes_config.dn_changes != DnChangeMode::Ignore =>
+		{
+			moved_outcome(attributes_config)
+		}
+		Some(old_entry) => {
+			let changed = if let Some(Err(_)) = parse_updated(entry, attributes_config) {
+				new_entry.entry != old_entry.entry
+			} else {
+				// The digest of the modification time comes last, and with a
+				// schema, a new one is only a change if the entry differs
+				// according to it
+				let tracked = attributes_config.attrs_to_track.len();
+				new_entry.attributes.get(..tracked) != old_entry.attributes.get(..tracked)
+					|| (new_entry.attributes.get(tracked..) != old_entry.attributes.get(tracked..)
+						&& (schema.is_none() || new_entry.entry != old_entry.entry))
+			};
+			if changed {
+				Outcome::Changed
+			} else {
+				Outcome::Unchanged
+			}
+		}
+		None => Outcome::Missing,
+	};
+	let cached = (outcome != Outcome::Unchanged).then_some(CachedEntry::Hashed(new_entry));
+	Ok(Comparison { pid, outcome, cached })
+}
+
+/// (De)serialization of maps keyed by pid. Human-readable formats such as JSON
+/// only support string keys, so the map is represented as a sequence of
+/// key-value pairs there.
+mod pid_map {
+	use std::{collections::HashMap, fmt, marker::PhantomData};
+
+	use serde::{
+		de::{SeqAccess, Visitor},
+		Deserialize, Deserializer, Serialize, Serializer,
+	};
+
+	/// Serialize a map keyed by pid
+	pub(super) fn serialize<S: Serializer, V: Serialize>(
+		map: &HashMap<Vec<u8>, V>,
+		serializer: S,
+	) -> Result<S::Ok, S::Error> {
+		if serializer.is_human_readable() {
+			serializer.collect_seq(map)
+		} else {
+			serializer.collect_map(map)
+		}
+	}
+
+	/// Deserialize a map keyed by pid
+	pub(super) fn deserialize<'de, D: Deserializer<'de>, V: Deserialize<'de>>(
+		deserializer: D,
+	) -> Result<HashMap<Vec<u8>, V>, D::Error> {
+		if deserializer.is_human_readable() {
+			deserializer.deserialize_seq(PairsVisitor(PhantomData))
+		} else {
+			HashMap::deserialize(deserializer)
+		}
+	}
+
+	/// Visitor inserting a sequence of key-value pairs into a map, without
+	/// collecting them into a vector first. The map itself is still built in
+	/// memory as a whole.
+	struct PairsVisitor<V>(PhantomData<V>);
+
+	impl<'de, V: Deserialize<'de>> Visitor<'de> for PairsVisitor<V> {
+		type Value = HashMap<Vec<u8>, V>;
+
+		fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+			formatter.write_str("a sequence of key-value pairs")
+		}
+
+		fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+			let mut map = HashMap::with_capacity(seq.size_hint().unwrap_or_default().min(4096));
+			while let Some((key, value)) = seq.next_element()? {
+				map.insert(key, value);
+			}
+			Ok(map)
+		}
+	}
+}
+
+/// Errors that can occur when attempting to check if an entry has changed.
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum Error {
+	/// An expected attribute was not present.
+	#[error("Missing attribute")]
+	Missing,
+}
+
+#[cfg(test)]
+mod tests {
+	#![allow(clippy::unwrap_used, clippy::items_after_statements)]
+
+	use std::{collections::HashMap, sync::Arc};
+
+	use ldap3::SearchEntry;
 	use time::{Duration, OffsetDateTime};
 
 	use crate::{
-		cache::CacheEntryStatus,
-		config::{AttributeConfig, TIME_FORMAT},
+		cache::{Cache, CacheEntryStatus, CacheOrigin},
+		config::{
+			AttributeConfig, CacheMethod, ComparisonMode, Config, DnChangeMode, PidNormalization,
+			TIME_FORMAT,
+		},
 		entry::SearchEntryExt,
+		schema::Schema,
 	};
 
+	/// Compare an entry with full cached entries and store it
+	fn check_full(
+		cache: &mut HashMap<Vec<u8>, Arc<super::SerializedSearchEntry>>,
+		entry: &SearchEntry,
+		attributes: &AttributeConfig,
+		schema: Option<&Schema>,
+	) -> Result<CacheEntryStatus, super::Error> {
+		let mut entries = super::CacheEntries::Modified(std::mem::take(cache));
+		let status =
+			entries.compare(entry, attributes, schema).map(|compared| entries.commit(compared));
+		if let super::CacheEntries::Modified(entries) = entries {
+			*cache = entries;
+		}
+		status
+	}
+
+	/// Compare an entry with cached digests and store it
+	fn check_hashed(
+		cache: &mut HashMap<Vec<u8>, Arc<super::HashedEntry>>,
+		entry: &SearchEntry,
+		attributes: &AttributeConfig,
+		schema: Option<&Schema>,
+	) -> Result<CacheEntryStatus, super::Error> {
+		let mut entries = super::CacheEntries::Hashed(std::mem::take(cache));
+		let status =
+			entries.compare(entry, attributes, schema).map(|compared| entries.commit(compared));
+		if let super::CacheEntries::Hashed(entries) = entries {
+			*cache = entries;
+		}
+		status
+	}
+
+	#[test]
+	fn pid_normalization() {
+		let normalize = |normalization: PidNormalization, pid: &[u8]| {
+			String::from_utf8_lossy(&normalization.normalize(pid)).into_owned()
+		};
+		let uuid = "f81d4fae-7dec-11d0-a765-00a0c91e6bf6";
+		for text in
+			[uuid, "F81D4FAE-7DEC-11D0-A765-00A0C91E6BF6", "{f81d4fae7dec11d0a76500a0c91e6bf6}"]
+		{
+			assert_eq!(normalize(PidNormalization::Uuid, text.as_bytes()), uuid);
+			assert_eq!(normalize(PidNormalization::Guid, text.as_bytes()), uuid);
+		}
+		let rfc4122 = [
+			0xf8, 0x1d, 0x4f, 0xae, 0x7d, 0xec, 0x11, 0xd0, 0xa7, 0x65, 0x00, 0xa0, 0xc9, 0x1e,
+			0x6b, 0xf6,
+		];
+		let guid = [
+			0xae, 0x4f, 0x1d, 0xf8, 0xec, 0x7d, 0xd0, 0x11, 0xa7, 0x65, 0x00, 0xa0, 0xc9, 0x1e,
+			0x6b, 0xf6,
+		];
+		assert_eq!(normalize(PidNormalization::Uuid, &rfc4122), uuid);
+		assert_eq!(normalize(PidNormalization::Guid, &guid), uuid);
+		assert_eq!(normalize(PidNormalization::Uuid, b"John_Doe"), "john_doe");
+		assert_eq!(normalize(PidNormalization::Guid, b"Sixteen_Chars_Id"), "sixteen_chars_id");
+		let ascii_uuid = *b"\x01\x02\x03\x04\x05\x06\x07\x08\x09\x0a\x0b\x0c\x0d\x0e\x0f\x10";
+		assert_eq!(
+			normalize(PidNormalization::Uuid, &ascii_uuid),
+			"01020304-0506-0708-090a-0b0c0d0e0f10"
+		);
+		assert_eq!(normalize(PidNormalization::Lowercase, b"John_Doe"), "john_doe");
+		assert_eq!(normalize(PidNormalization::None, b"John_Doe"), "John_Doe");
+		assert_eq!(normalize(PidNormalization::Dn, b"CN=John , OU=People"), "cn=john,ou=people");
+	}
+
 	#[test]
 	fn attr_first() {
 		let entry = super::SerializedSearchEntry {
@@ -225,12 +1623,12 @@ mod tests {
 		};
 
 		assert_eq!(
-			super::has_any_attr_changed(&mut cache, &entry, &attributes)?,
+			check_full(&mut cache, &entry, &attributes, None)?,
 			CacheEntryStatus::Missing,
 			"Newly inserted entry should be considered missing",
 		);
 		assert_eq!(
-			super::has_any_attr_changed(&mut cache, &entry, &attributes)?,
+			check_full(&mut cache, &entry, &attributes, None)?,
 			CacheEntryStatus::Unchanged,
 			"Unmodified entry should not be considered changed",
 		);
@@ -243,13 +1641,13 @@ mod tests {
 			.insert(attributes.updated.as_ref().unwrap().clone(), vec![now.format(&TIME_FORMAT)?]);
 
 		assert_eq!(
-			super::has_any_attr_changed(&mut cache, &entry, &attributes)?,
-			CacheEntryStatus::Changed(old.into()),
+			check_full(&mut cache, &entry, &attributes, None)?,
+			CacheEntryStatus::Changed(Some(old.into())),
 			"Modified entry should be considered changed",
 		);
 
 		assert_eq!(
-			super::has_any_attr_changed(&mut cache, &entry, &attributes)?,
+			check_full(&mut cache, &entry, &attributes, None)?,
 			CacheEntryStatus::Unchanged,
 			"Unmodified entry should not be considered changed",
 		);
@@ -259,11 +1657,653 @@ mod tests {
 		entry.attrs.insert("enabled".into(), vec!["no".into()]);
 
 		assert_eq!(
-			super::has_any_attr_changed(&mut cache, &entry, &attributes)?,
-			CacheEntryStatus::Changed(old.into()),
+			check_full(&mut cache, &entry, &attributes, None)?,
+			CacheEntryStatus::Changed(Some(old.into())),
+			"Modified entry should be considered changed",
+		);
+
+		let old = entry.clone();
+		entry.attrs.insert("enabled".into(), vec!["no".into(), "yes".into()]);
+
+		assert_eq!(
+			check_full(&mut cache, &entry, &attributes, None)?,
+			CacheEntryStatus::Changed(Some(old.into())),
+			"Added value should be considered changed",
+		);
+
+		entry.attrs.insert("enabled".into(), vec!["yes".into(), "no".into()]);
+
+		assert_eq!(
+			check_full(&mut cache, &entry, &attributes, None)?,
+			CacheEntryStatus::Unchanged,
+			"Reordered values should not be considered changed",
+		);
+
+		Ok(())
+	}
+
+	#[test]
+	fn has_any_attr_changed_unparsable_time() -> Result<(), Box<dyn std::error::Error>> {
+		let mut cache = HashMap::new();
+
+		let attributes = AttributeConfig::example();
+		let mut entry = SearchEntry {
+			dn: "uid=foo,ou=people,dc=example,dc=com".to_owned(),
+			attrs: HashMap::from([
+				(attributes.pid.clone(), vec!["john_doe".to_owned()]),
+				(attributes.updated.clone().unwrap(), vec!["yesterday".to_owned()]),
+				("admin".into(), vec!["FALSE".into()]),
+			]),
+			bin_attrs: HashMap::new(),
+		};
+
+		assert!(super::parse_updated(&entry, &attributes).unwrap().is_err());
+		check_full(&mut cache, &entry, &attributes, None)?;
+
+		let old = entry.clone();
+		entry.attrs.insert("admin".into(), vec!["TRUE".into()]);
+
+		assert_eq!(
+			check_full(&mut cache, &entry, &attributes, None)?,
+			CacheEntryStatus::Changed(Some(old.into())),
+			"Untracked attributes should be compared if the modification time is unparsable",
+		);
+
+		Ok(())
+	}
+
+	#[test]
+	fn schema_matching() -> Result<(), Box<dyn std::error::Error>> {
+		let mut cache = HashMap::new();
+		let mut digests = HashMap::new();
+		let schema = Schema::from_attribute_types(&[
+			"( 2.5.4.3 NAME 'cn' EQUALITY caseIgnoreMatch )",
+			"( 1.2.3 NAME 'enabled' EQUALITY booleanMatch )",
+		]);
+
+		let mut attributes = AttributeConfig::example();
+		attributes.attrs_to_track = vec!["cn".to_owned(), "enabled".to_owned()];
+		let mut entry = SearchEntry {
+			dn: "uid=foo,ou=people,dc=example,dc=com".to_owned(),
+			attrs: HashMap::from([
+				(attributes.pid.clone(), vec!["john_doe".to_owned()]),
+				("cn".into(), vec!["John Doe".into()]),
+				("enabled".into(), vec!["TRUE".into()]),
+				("mtime".into(), vec!["20230516200520Z".into()]),
+			]),
+			bin_attrs: HashMap::new(),
+		};
+		check_full(&mut cache, &entry, &attributes, Some(&schema))?;
+		check_hashed(&mut digests, &entry, &attributes, Some(&schema))?;
+
+		entry.attrs.insert("cn".into(), vec!["john  DOE".into()]);
+		entry.attrs.insert("mtime".into(), vec!["20230516200521Z".into()]);
+		assert_eq!(
+			check_full(&mut cache.clone(), &entry, &attributes, Some(&schema))?,
+			CacheEntryStatus::Unchanged,
+			"Values matching according to the schema should not be considered changed",
+		);
+		assert_eq!(
+			check_hashed(&mut digests, &entry, &attributes, Some(&schema))?,
+			CacheEntryStatus::Unchanged,
+		);
+		assert!(matches!(
+			check_full(&mut cache.clone(), &entry, &attributes, None)?,
+			CacheEntryStatus::Changed(_)
+		));
+
+		entry.attrs.insert("mail".into(), vec!["john@example.com".into()]);
+		entry.attrs.insert("mtime".into(), vec!["20230516200522Z".into()]);
+		assert!(
+			matches!(
+				check_full(&mut cache.clone(), &entry, &attributes, Some(&schema))?,
+				CacheEntryStatus::Changed(_)
+			),
+			"Changes of untracked attributes should still be detected with a schema",
+		);
+		assert!(matches!(
+			check_hashed(&mut digests.clone(), &entry, &attributes, Some(&schema))?,
+			CacheEntryStatus::Changed(_)
+		));
+
+		entry.attrs.remove("mail");
+		entry.attrs.insert("enabled".into(), vec!["true".into()]);
+		assert!(matches!(
+			check_full(&mut cache, &entry, &attributes, Some(&schema))?,
+			CacheEntryStatus::Changed(_)
+		));
+
+		Ok(())
+	}
+
+	#[test]
+	fn count_misses() -> Result<(), Box<dyn std::error::Error>> {
+		let attributes = AttributeConfig::example();
+		let entry = |pid: &str| SearchEntry {
+			dn: format!("cn={pid}"),
+			attrs: HashMap::from([(attributes.pid.clone(), vec![pid.to_owned()])]),
+			bin_attrs: HashMap::new(),
+		};
+		let mut cache =
+			Cache::from_entries(&CacheMethod::Hashed, &attributes, [entry("foo"), entry("bar")])?;
+		assert_eq!(cache.count_misses(2), Vec::<Vec<u8>>::new());
+
+		cache.start_comparison();
+		cache.check_entry(&entry("foo"), &attributes)?;
+		assert_eq!(cache.count_misses(2), [b"bar".to_vec()]);
+
+		cache.start_comparison();
+		cache.check_entry(&entry("bar"), &attributes)?;
+		assert_eq!(cache.count_misses(2), Vec::<Vec<u8>>::new(), "Misses should be consecutive");
+		assert_eq!(cache.misses.get(b"foo".as_slice()), Some(&1));
+		assert_eq!(cache.misses.get(b"bar".as_slice()), None);
+		Ok(())
+	}
+
+	#[test]
+	fn origin_compatibility() {
+		let old = CacheOrigin::new(&Config::example());
+
+		let mut new = Config::example();
+		new.search_password = "rotated".to_owned();
+		assert!(old.incompatibility(&new).is_none() && !old.changes_search_scope(&new));
+
+		new.searches.user_filter = "(objectClass=inetOrgPerson)".to_owned();
+		assert!(old.incompatibility(&new).is_none() && old.changes_search_scope(&new));
+
+		let mut new = Config::example();
+		new.attributes.attrs_to_track.push("admin".to_owned());
+		assert!(old.incompatibility(&new).is_some(), "Digests depend on the tracked attributes");
+		new.cache_method = CacheMethod::ModificationTime;
+		assert!(old.incompatibility(&new).is_some());
+
+		let mut new = Config::example();
+		new.attributes.pid = "entryUUID".to_owned();
+		assert!(old.incompatibility(&new).is_some());
+	}
+
+	#[test]
+	fn migrate() -> Result<(), Box<dyn std::error::Error>> {
+		let mut config = Config::example();
+		config.cache_method = CacheMethod::ModificationTime;
+		let entry = SearchEntry {
+			dn: "cn=foo".to_owned(),
+			attrs: HashMap::from([("mtime".to_owned(), vec!["20231026120000Z".to_owned()])]),
+			bin_attrs: HashMap::from([("objectGUID".to_owned(), vec![b"foo".to_vec()])]),
+		};
+		let cache = Cache::from_entries(&config.cache_method, &config.attributes, [entry])?;
+		let mut cache = cache.migrate(&config)?;
+		assert!(cache.origin.is_some(), "The settings should be recorded");
+		cache.last_sync_time = Some(OffsetDateTime::now_utc());
+
+		// Full entries can be converted to digests
+		config.cache_method = CacheMethod::Hashed;
+		let cache = cache.migrate(&config)?;
+		assert_eq!((cache.method(), cache.len()), (CacheMethod::Hashed, 1));
+		assert!(cache.last_sync_time.is_some());
+
+		config.searches.user_base = "ou=staff".to_owned();
+		let cache = cache.migrate(&config)?;
+		assert_eq!(cache.len(), 1);
+		assert!(cache.last_sync_time.is_none(), "A changed scope needs a full sync");
+
+		config.attributes.attrs_to_track.push("mail".to_owned());
+		assert!(matches!(
+			cache.migrate(&config),
+			Err(crate::error::Error::IncompatibleCacheConfig(_))
+		));
+		Ok(())
+	}
+
+	#[test]
+	fn provenance() -> Result<(), Box<dyn std::error::Error>> {
+		let attributes = AttributeConfig::example();
+		let entry = |pid: &str| SearchEntry {
+			dn: format!("cn={pid}"),
+			attrs: HashMap::from([(attributes.pid.clone(), vec![pid.to_owned()])]),
+			bin_attrs: HashMap::new(),
+		};
+		let mut cache =
+			Cache::from_entries(&CacheMethod::Hashed, &attributes, [entry("foo"), entry("bar")])?;
+		let seen = cache.provenance(b"foo").ok_or("Provenance should be recorded")?;
+		assert_eq!(seen.first_seen, seen.last_confirmed);
+
+		let checked = OffsetDateTime::now_utc();
+		cache.check_entry(&entry("foo"), &attributes)?;
+		let confirmed = cache.provenance(b"foo").ok_or("Provenance should be kept")?;
+		assert_eq!(confirmed.first_seen, seen.first_seen);
+		assert!(confirmed.last_confirmed >= checked);
+		let unconfirmed: Vec<_> =
+			cache.unconfirmed_since(checked).into_iter().map(|(pid, _)| pid).collect();
+		assert_eq!(unconfirmed, [b"bar".to_vec()]);
+
+		let stats = cache.stats();
+		assert_eq!(stats.entries, 2);
+		assert_eq!(stats.without_provenance, 0);
+		assert_eq!(stats.oldest_confirmation, cache.provenance(b"bar").map(|p| p.last_confirmed));
+
+		cache.provenance.remove(b"bar".as_slice());
+		assert_eq!(cache.stats().without_provenance, 1);
+		assert!(cache.remove_entry(b"foo"));
+		assert_eq!(cache.provenance(b"foo"), None);
+		Ok(())
+	}
+
+	#[test]
+	fn comparison_modes() -> Result<(), Box<dyn std::error::Error>> {
+		let mut attributes = AttributeConfig::example();
+		attributes.attrs_to_track =
+			["mail", "manager", "uidNumber", "roles"].map(Into::into).to_vec();
+		attributes.comparison = HashMap::from([
+			("mail".to_owned(), ComparisonMode::Set),
+			("manager".to_owned(), ComparisonMode::CaseInsensitive),
+			("uidNumber".to_owned(), ComparisonMode::Numeric),
+			("roles".to_owned(), ComparisonMode::Exact),
+		]);
+		let entry = |attrs: &[(&str, &[&str])]| SearchEntry {
+			dn: "uid=foo,ou=people".to_owned(),
+			attrs: attrs
+				.iter()
+				.map(|(name, values)| {
+					((*name).to_owned(), values.iter().map(|&value| value.to_owned()).collect())
+				})
+				.chain([(attributes.pid.clone(), vec!["foo".to_owned()])])
+				.collect(),
+			bin_attrs: HashMap::new(),
+		};
+		let old = entry(&[
+			("mail", &["a@example.com", "b@example.com"]),
+			("manager", &["cn=Boss,ou=people"]),
+			("uidNumber", &["1000"]),
+			("roles", &["admin", "user"]),
+		]);
+		let mut cache = HashMap::new();
+		let mut digests = HashMap::new();
+		check_full(&mut cache, &old, &attributes, None)?;
+		check_hashed(&mut digests, &old, &attributes, None)?;
+
+		let same = entry(&[
+			("mail", &["b@example.com", "a@example.com", "a@example.com"]),
+			("manager", &["CN=boss,OU=People"]),
+			("uidNumber", &[" 01000"]),
+			("roles", &["admin", "user"]),
+		]);
+		assert_eq!(
+			check_full(&mut cache.clone(), &same, &attributes, None)?,
+			CacheEntryStatus::Unchanged,
+			"Values equal according to their comparison mode should not be considered changed",
+		);
+		assert_eq!(
+			check_hashed(&mut digests.clone(), &same, &attributes, None)?,
+			CacheEntryStatus::Unchanged,
+		);
+
+		let reordered = entry(&[
+			("mail", &["a@example.com", "b@example.com"]),
+			("manager", &["cn=Boss,ou=people"]),
+			("uidNumber", &["1000"]),
+			("roles", &["user", "admin"]),
+		]);
+		assert!(matches!(
+			check_full(&mut cache, &reordered, &attributes, None)?,
+			CacheEntryStatus::Changed(_)
+		));
+		assert!(matches!(
+			check_hashed(&mut digests, &reordered, &attributes, None)?,
+			CacheEntryStatus::Changed(_)
+		));
+		Ok(())
+	}
+
+	#[test]
+	fn size_limits() -> Result<(), Box<dyn std::error::Error>> {
+		let mut attributes = AttributeConfig::example();
+		attributes.max_cached_size = HashMap::from([("jpegPhoto".to_owned(), 4)]);
+		attributes.uncached = vec!["userCertificate".to_owned()];
+		let mut entry = SearchEntry {
+			dn: "uid=foo,ou=people,dc=example,dc=com".to_owned(),
+			attrs: HashMap::from([
+				(attributes.pid.clone(), vec!["john_doe".to_owned()]),
+				(attributes.updated.clone().unwrap(), vec!["yesterday".to_owned()]),
+			]),
+			bin_attrs: HashMap::from([
+				("jpegPhoto".to_owned(), vec![vec![0; 4], vec![1; 1024]]),
+				("userCertificate".to_owned(), vec![vec![2; 1024]]),
+			]),
+		};
+		let cache =
+			Cache::from_entries(&CacheMethod::ModificationTime, &attributes, [entry.clone()])?;
+		let super::CacheEntries::Modified(ref entries) = cache.entries else {
+			panic!("Unexpected cache method");
+		};
+		let cached = &entries[b"john_doe".as_slice()];
+		assert_eq!(cached.bin_attrs.get("userCertificate"), None);
+		let photos = &cached.bin_attrs["jpegPhoto"];
+		assert_eq!(photos[0], vec![0; 4]);
+		assert!(photos[1].starts_with(b"sha256:"));
+		assert_eq!(
+			cache.clone().convert(&CacheMethod::ModificationTime, &attributes)?.entries,
+			cache.entries,
+			"Converting with the same limits should not change cached entries",
+		);
+
+		let mut converted = cache.convert(&CacheMethod::Hashed, &attributes)?;
+		assert_eq!(converted.check_entry(&entry, &attributes)?, CacheEntryStatus::Unchanged);
+		entry.bin_attrs.insert("userCertificate".to_owned(), vec![vec![3; 1024]]);
+		assert_eq!(converted.check_entry(&entry, &attributes)?, CacheEntryStatus::Unchanged);
+		entry.bin_attrs.insert("jpegPhoto".to_owned(), vec![vec![0; 4], vec![4; 1024]]);
+		assert_eq!(converted.check_entry(&entry, &attributes)?, CacheEntryStatus::Changed(None));
+
+		Ok(())
+	}
+
+	#[test]
+	fn operational_metadata_not_compared() -> Result<(), Box<dyn std::error::Error>> {
+		let mut cache = HashMap::new();
+		let mut digests = HashMap::new();
+
+		let mut attributes = AttributeConfig::example();
+		attributes.operational_metadata = true;
+		let mut entry = SearchEntry {
+			dn: "uid=foo,ou=people,dc=example,dc=com".to_owned(),
+			attrs: HashMap::from([
+				(attributes.pid.clone(), vec!["john_doe".to_owned()]),
+				(attributes.updated.clone().unwrap(), vec!["yesterday".to_owned()]),
+				("modifiersName".into(), vec!["cn=admin".into()]),
+			]),
+			bin_attrs: HashMap::new(),
+		};
+		check_full(&mut cache, &entry, &attributes, None)?;
+		check_hashed(&mut digests, &entry, &attributes, None)?;
+
+		entry.attrs.insert("modifiersName".into(), vec!["cn=sync".into()]);
+		assert_eq!(
+			check_full(&mut cache, &entry, &attributes, None)?,
+			CacheEntryStatus::Unchanged,
+			"Operational metadata should not be compared unless tracked",
+		);
+		assert_eq!(
+			check_hashed(&mut digests, &entry, &attributes, None)?,
+			CacheEntryStatus::Unchanged,
+		);
+
+		attributes.attrs_to_track.push("modifiersName".into());
+		entry.attrs.insert("modifiersName".into(), vec!["cn=other".into()]);
+		assert!(matches!(
+			check_full(&mut cache, &entry, &attributes, None)?,
+			CacheEntryStatus::Changed(_)
+		));
+
+		Ok(())
+	}
+
+	#[test]
+	fn has_dn_changed() -> Result<(), Box<dyn std::error::Error>> {
+		let mut cache = HashMap::new();
+
+		let attributes = AttributeConfig::example();
+		let mut entry = SearchEntry {
+			dn: "uid=foo,ou=people,dc=example,dc=com".to_owned(),
+			attrs: HashMap::from([
+				(attributes.pid.clone(), vec!["john_doe".to_owned()]),
+				("enabled".into(), vec!["yes".into()]),
+			]),
+			bin_attrs: HashMap::new(),
+		};
+
+		check_full(&mut cache, &entry, &attributes, None)?;
+
+		let old = entry.clone();
+		entry.dn = "uid=foo,ou=admins,dc=example,dc=com".to_owned();
+
+		assert_eq!(
+			check_full(&mut cache, &entry, &attributes, None)?,
+			CacheEntryStatus::Moved(old.dn),
+			"Entry with a new DN should be considered moved",
+		);
+		assert_eq!(
+			check_full(&mut cache, &entry, &attributes, None)?,
+			CacheEntryStatus::Unchanged,
+			"Moved entry should not be reported twice",
+		);
+
+		let mut attributes = attributes;
+		attributes.dn_changes = DnChangeMode::Changed;
+		let old = entry.clone();
+		entry.dn = "uid=foo,ou=people,dc=example,dc=com".to_owned();
+
+		assert_eq!(
+			check_full(&mut cache, &entry, &attributes, None)?,
+			CacheEntryStatus::Changed(Some(old.into())),
+			"Entry with a new DN should be considered changed if configured",
+		);
+
+		attributes.dn_changes = DnChangeMode::Ignore;
+		entry.dn = "uid=foo,ou=admins,dc=example,dc=com".to_owned();
+
+		assert_eq!(
+			check_full(&mut cache, &entry, &attributes, None)?,
+			CacheEntryStatus::Unchanged,
+			"DN changes should be ignored if configured",
+		);
+
+		Ok(())
+	}
+
+	#[test]
+	fn from_entries() {
+		let attributes = AttributeConfig::example();
+		let entry = SearchEntry {
+			dn: "uid=foo,ou=people,dc=example,dc=com".to_owned(),
+			attrs: HashMap::new(),
+			bin_attrs: HashMap::new(),
+		};
+		let result = Cache::from_entries(&CacheMethod::ModificationTime, &attributes, [entry]);
+		assert!(matches!(
+			result,
+			Err(crate::error::Error::MissingAttribute(attr))
+				if attr == "objectGUID of uid=foo,ou=people,dc=example,dc=com"
+		));
+	}
+
+	#[test]
+	fn pid_by_dn() -> Result<(), Box<dyn std::error::Error>> {
+		let attributes = AttributeConfig::example();
+		let entry = |uid: &str, dn: &str| SearchEntry {
+			dn: dn.to_owned(),
+			attrs: HashMap::from([(attributes.pid.clone(), vec![uid.to_owned()])]),
+			bin_attrs: HashMap::new(),
+		};
+		let mut cache = Cache::new(super::CacheEntries::Modified(HashMap::new()));
+
+		cache.check_entry(&entry("foo", "uid=foo,ou=people,dc=example,dc=com"), &attributes)?;
+		assert_eq!(cache.pid_by_dn("UID=foo, ou=people,dc=example,dc=com"), Some(b"foo".to_vec()));
+
+		cache.check_entry(&entry("bar", "uid=bar,ou=people,dc=example,dc=com"), &attributes)?;
+		assert_eq!(
+			cache.pid_by_dn("uid=bar,ou=people,dc=example,dc=com"),
+			Some(b"bar".to_vec()),
+			"Entries stored after the index was built should be found",
+		);
+
+		cache.check_entry(&entry("foo", "uid=foo,ou=admins,dc=example,dc=com"), &attributes)?;
+		assert_eq!(cache.pid_by_dn("uid=foo,ou=people,dc=example,dc=com"), None);
+		assert_eq!(cache.pid_by_dn("uid=foo,ou=admins,dc=example,dc=com"), Some(b"foo".to_vec()));
+
+		assert!(cache.remove_entry(b"bar"));
+		assert_eq!(cache.pid_by_dn("uid=bar,ou=people,dc=example,dc=com"), None);
+		assert_eq!(cache.pid_by_dn("not a dn"), None);
+
+		Ok(())
+	}
+
+	#[test]
+	fn refresh_copy() -> Result<(), Box<dyn std::error::Error>> {
+		let attributes = AttributeConfig::example();
+		let entry = |uid: &str, mail: &str| SearchEntry {
+			dn: format!("uid={uid},ou=people,dc=example,dc=com"),
+			attrs: HashMap::from([
+				(attributes.pid.clone(), vec![uid.to_owned()]),
+				("mail".to_owned(), vec![mail.to_owned()]),
+			]),
+			bin_attrs: HashMap::new(),
+		};
+		let mut cache = Cache::new(super::CacheEntries::Modified(HashMap::new()));
+		cache.check_entry(&entry("foo", "foo@example.com"), &attributes)?;
+		cache.check_entry(&entry("bar", "bar@example.com"), &attributes)?;
+		cache.check_entry(&entry("qux", "qux@example.com"), &attributes)?;
+		assert_eq!(cache.refresh_copy(None, &cache.clone().take_changes()), cache);
+
+		let copy = cache.clone();
+		assert!(cache.take_changes().0.is_none(), "New caches should be copied as a whole");
+		cache.check_entry(&entry("foo", "foo@example.org"), &attributes)?;
+		cache.check_entry(&entry("baz", "baz@example.com"), &attributes)?;
+		assert!(cache.remove_entry(b"bar"));
+		cache.last_sync_time = Some(OffsetDateTime::now_utc());
+		let changed = cache.take_changes();
+		assert_eq!(cache.refresh_copy(Some(copy.clone()), &changed), cache);
+
+		// Only the changed entries are copied
+		let mut outdated = copy;
+		outdated.provenance.clear();
+		let refreshed = cache.refresh_copy(Some(outdated), &changed);
+		assert!(refreshed.provenance(b"qux").is_none());
+		assert!(refreshed.provenance(b"foo").is_some());
+		assert_eq!(refreshed.entries, cache.entries);
+		assert_eq!(refreshed.last_sync_time, cache.last_sync_time);
+
+		Ok(())
+	}
+
+	#[test]
+	fn has_any_digest_changed() -> Result<(), Box<dyn std::error::Error>> {
+		let mut cache = HashMap::new();
+
+		let attributes = AttributeConfig::example();
+		let mut entry = SearchEntry {
+			dn: "uid=foo,ou=people,dc=example,dc=com".to_owned(),
+			attrs: HashMap::from([
+				(attributes.pid.clone(), vec!["john_doe".to_owned()]),
+				(attributes.updated.clone().unwrap(), vec!["20231019182736Z".to_owned()]),
+				("enabled".into(), vec!["yes".into()]),
+				("admin".into(), vec!["FALSE".into()]),
+			]),
+			bin_attrs: HashMap::new(),
+		};
+
+		assert_eq!(
+			check_hashed(&mut cache, &entry, &attributes, None)?,
+			CacheEntryStatus::Missing,
+			"Newly inserted entry should be considered missing",
+		);
+		assert_eq!(
+			check_hashed(&mut cache, &entry, &attributes, None)?,
+			CacheEntryStatus::Unchanged,
+			"Unmodified entry should not be considered changed",
+		);
+
+		entry.attrs.insert("admin".into(), vec!["TRUE".into()]);
+		assert_eq!(
+			check_hashed(&mut cache, &entry, &attributes, None)?,
+			CacheEntryStatus::Unchanged,
+			"Untracked attributes should not be compared",
+		);
+
+		entry.attrs.insert("enabled".into(), vec!["no".into()]);
+		assert_eq!(
+			check_hashed(&mut cache, &entry, &attributes, None)?,
+			CacheEntryStatus::Changed(None),
 			"Modified entry should be considered changed",
 		);
 
+		entry.attrs.insert("enabled".into(), vec!["no".into(), "maybe".into()]);
+		assert_eq!(
+			check_hashed(&mut cache, &entry, &attributes, None)?,
+			CacheEntryStatus::Changed(None),
+			"Additional values of tracked attributes should be compared",
+		);
+		entry.attrs.insert("enabled".into(), vec!["maybe".into(), "no".into()]);
+		assert_eq!(
+			check_hashed(&mut cache, &entry, &attributes, None)?,
+			CacheEntryStatus::Unchanged,
+			"The order of values should not matter",
+		);
+
+		let old_dn = entry.dn.clone();
+		entry.dn = "uid=foo,ou=admins,dc=example,dc=com".to_owned();
+		assert_eq!(
+			check_hashed(&mut cache, &entry, &attributes, None)?,
+			CacheEntryStatus::Moved(old_dn),
+			"Entry with a new DN should be considered moved",
+		);
+
+		entry.attrs.insert(attributes.updated.clone().unwrap(), vec!["yesterday".to_owned()]);
+		check_hashed(&mut cache, &entry, &attributes, None)?;
+		entry.attrs.insert("admin".into(), vec!["FALSE".into()]);
+		assert_eq!(
+			check_hashed(&mut cache, &entry, &attributes, None)?,
+			CacheEntryStatus::Changed(None),
+			"Untracked attributes should be compared if the modification time is unparsable",
+		);
+
+		Ok(())
+	}
+
+	#[test]
+	fn inspection() -> Result<(), Box<dyn std::error::Error>> {
+		let attributes = AttributeConfig::example();
+		let entry = |dn: &str, pid: &[u8]| SearchEntry {
+			dn: dn.to_owned(),
+			attrs: HashMap::from([("enabled".to_owned(), vec!["yes".to_owned()])]),
+			bin_attrs: HashMap::from([("objectGUID".to_owned(), vec![pid.to_vec()])]),
+		};
+		let cache = Cache::from_entries(
+			&CacheMethod::Hashed,
+			&attributes,
+			[entry("cn=foo", b"foo"), entry("cn=bar", &[0, 255])],
+		)?;
+
+		assert_eq!(cache.len(), 2);
+		let mut pids: Vec<_> = cache.pids().collect();
+		pids.sort_unstable();
+		assert_eq!(pids, [&[0, 255][..], b"foo"]);
+		assert_eq!(cache.dn(b"foo"), Some("cn=foo"));
+		assert!(cache.entry(b"foo").is_none(), "Hashed caches don't keep entries");
+
+		let export: serde_json::Value = serde_json::from_str(&cache.to_json_pretty()?)?;
+		assert_eq!(export["method"], "hashed");
+		assert_eq!(export["entries"][0]["dn"], "cn=bar");
+		assert_eq!(export["entries"][0]["pid"], "00ff", "Binary pids should be hex encoded");
+		assert_eq!(export["entries"][1]["pid"], "foo");
+		Ok(())
+	}
+
+	#[test]
+	fn convert() -> Result<(), Box<dyn std::error::Error>> {
+		let attributes = AttributeConfig::example();
+		let entry = SearchEntry {
+			dn: "cn=foo".to_owned(),
+			attrs: HashMap::from([("enabled".to_owned(), vec!["yes".to_owned()])]),
+			bin_attrs: HashMap::from([("objectGUID".to_owned(), vec![b"foo".to_vec()])]),
+		};
+		let cache =
+			Cache::from_entries(&CacheMethod::ModificationTime, &attributes, [entry.clone()])?;
+
+		let mut converted = cache.clone().convert(&CacheMethod::Hashed, &attributes)?;
+		assert_eq!(converted.method(), CacheMethod::Hashed);
+		assert_eq!(
+			converted.entries,
+			Cache::from_entries(&CacheMethod::Hashed, &attributes, [entry.clone()])?.entries,
+			"Converted entries should match entries cached with the new method"
+		);
+		assert_eq!(
+			converted.check_entry(&entry, &attributes)?,
+			CacheEntryStatus::Unchanged,
+			"Converted entries shouldn't be reported again"
+		);
+
+		assert!(converted.clone().convert(&CacheMethod::ModificationTime, &attributes).is_err());
+		assert!(converted.convert(&CacheMethod::Disabled, &attributes)?.is_empty());
 		Ok(())
 	}
 }
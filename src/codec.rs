@@ -0,0 +1,587 @@
+//! Serialization formats for persisting the cache
+//!
+//! Snapshots are wrapped in an envelope carrying [`CACHE_VERSION`], so that
+//! snapshots written by an incompatible version of this crate are rejected
+//! with [`Error::IncompatibleCache`] instead of being misinterpreted.
+//! Unversioned JSON or CBOR snapshots, as written before the envelope was
+//! introduced, are still accepted.
+use std::{
+	fs,
+	io::{Read, Write},
+	path::Path,
+	sync::Arc,
+};
+
+use chacha20poly1305::{
+	aead::{Aead, AeadCore, KeyInit, OsRng},
+	XChaCha20Poly1305, XNonce,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+	cache::{Cache, CacheEntries},
+	config::CacheFormat,
+	error::Error,
+};
+
+/// The version of the snapshot format written by this version of the crate
+pub const CACHE_VERSION: u32 = 7;
+
+/// The oldest version of snapshots in self-describing formats which can still
+/// be read, since fields added later have defaults. Bincode snapshots have to
+/// match [`CACHE_VERSION`] exactly.
+const OLDEST_SELF_DESCRIBING_VERSION: u32 = 1;
+
+/// Envelope written around serialized caches
+#[derive(Serialize)]
+struct Envelope<'a> {
+	/// Snapshot format version
+	version: u32,
+	/// The cache itself
+	cache: &'a Cache,
+}
+
+/// Envelope read from serialized caches, once the version is known to be
+/// compatible
+#[derive(Deserialize)]
+struct OwnedEnvelope {
+	/// Snapshot format version
+	#[allow(dead_code)]
+	version: u32,
+	/// The cache itself
+	cache: Cache,
+}
+
+/// Only the version of a snapshot in a self-describing format, which is
+/// missing for unversioned snapshots
+#[derive(Deserialize)]
+struct Header {
+	/// Snapshot format version
+	#[serde(default)]
+	version: Option<u32>,
+}
+
+/// Check whether a snapshot of the given version can be read
+fn check_version(version: u32, oldest: u32) -> Result<(), Error> {
+	if (oldest..=CACHE_VERSION).contains(&version) {
+		Ok(())
+	} else {
+		Err(Error::IncompatibleCache { found: version, supported: CACHE_VERSION })
+	}
+}
+
+/// Deserialize JSON, mapping the error
+fn from_json<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Error> {
+	serde_json::from_slice(bytes).map_err(|err| Error::Codec(err.to_string()))
+}
+
+/// Deserialize CBOR, mapping the error
+fn from_cbor<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Error> {
+	ciborium::from_reader(bytes).map_err(|err| Error::Codec(err.to_string()))
+}
+
+/// A serialization format for [`Cache`] snapshots
+pub trait CacheCodec {
+	/// Serialize the cache
+	fn encode(&self, cache: &Cache) -> Result<Vec<u8>, Error>;
+
+	/// Deserialize a cache previously serialized with [`CacheCodec::encode`]
+	fn decode(&self, bytes: &[u8]) -> Result<Cache, Error>;
+
+	/// Deserialize a cache from a reader. Formats that support it decode the
+	/// entries straight from the reader instead of buffering the encoded
+	/// snapshot first, which the default implementation does. Either way,
+	/// the whole cache ends up in memory, as there is no other cache backend.
+	fn decode_reader(&self, reader: &mut dyn Read) -> Result<Cache, Error> {
+		let mut bytes = Vec::new();
+		reader.read_to_end(&mut bytes)?;
+		self.decode(&bytes)
+	}
+}
+
+/// Human-readable JSON
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+impl CacheCodec for Json {
+	fn encode(&self, cache: &Cache) -> Result<Vec<u8>, Error> {
+		serde_json::to_vec(&Envelope { version: CACHE_VERSION, cache })
+			.map_err(|err| Error::Codec(err.to_string()))
+	}
+
+	fn decode(&self, bytes: &[u8]) -> Result<Cache, Error> {
+		match from_json::<Header>(bytes)?.version {
+			// Snapshots from before versioning share the layout of version 1
+			None => from_json(bytes),
+			Some(version) => {
+				check_version(version, OLDEST_SELF_DESCRIBING_VERSION)?;
+				Ok(from_json::<OwnedEnvelope>(bytes)?.cache)
+			}
+		}
+	}
+}
+
+/// Compact and fast [bincode](https://docs.rs/bincode), which is not
+/// self-describing
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bincode;
+
+impl CacheCodec for Bincode {
+	fn encode(&self, cache: &Cache) -> Result<Vec<u8>, Error> {
+		bincode::serialize(&Envelope { version: CACHE_VERSION, cache })
+			.map_err(|err| Error::Codec(err.to_string()))
+	}
+
+	fn decode(&self, bytes: &[u8]) -> Result<Cache, Error> {
+		// The version is always the leading field, and bincode snapshots were
+		// never written without it
+		let version: u32 =
+			bincode::deserialize(bytes).map_err(|err| Error::Codec(err.to_string()))?;
+		check_version(version, CACHE_VERSION)?;
+		bincode::deserialize::<OwnedEnvelope>(bytes)
+			.map(|envelope| envelope.cache)
+			.map_err(|err| Error::Codec(err.to_string()))
+	}
+
+	fn decode_reader(&self, mut reader: &mut dyn Read) -> Result<Cache, Error> {
+		// The envelope is encoded as the version followed by the cache
+		let version: u32 =
+			bincode::deserialize_from(&mut reader).map_err(|err| Error::Codec(err.to_string()))?;
+		check_version(version, CACHE_VERSION)?;
+		bincode::deserialize_from(reader).map_err(|err| Error::Codec(err.to_string()))
+	}
+}
+
+/// Compact, self-describing [CBOR](https://www.rfc-editor.org/rfc/rfc8949.html)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Cbor;
+
+impl CacheCodec for Cbor {
+	fn encode(&self, cache: &Cache) -> Result<Vec<u8>, Error> {
+		let mut bytes = Vec::new();
+		ciborium::into_writer(&Envelope { version: CACHE_VERSION, cache }, &mut bytes)
+			.map_err(|err| Error::Codec(err.to_string()))?;
+		Ok(bytes)
+	}
+
+	fn decode(&self, bytes: &[u8]) -> Result<Cache, Error> {
+		match from_cbor::<Header>(bytes)?.version {
+			// Snapshots from before versioning share the layout of version 1
+			None => from_cbor(bytes),
+			Some(version) => {
+				check_version(version, OLDEST_SELF_DESCRIBING_VERSION)?;
+				Ok(from_cbor::<OwnedEnvelope>(bytes)?.cache)
+			}
+		}
+	}
+}
+
+impl CacheCodec for CacheFormat {
+	fn encode(&self, cache: &Cache) -> Result<Vec<u8>, Error> {
+		match self {
+			CacheFormat::Json => Json.encode(cache),
+			CacheFormat::Bincode => Bincode.encode(cache),
+			CacheFormat::Cbor => Cbor.encode(cache),
+		}
+	}
+
+	fn decode(&self, bytes: &[u8]) -> Result<Cache, Error> {
+		match self {
+			CacheFormat::Json => Json.decode(bytes),
+			CacheFormat::Bincode => Bincode.decode(bytes),
+			CacheFormat::Cbor => Cbor.decode(bytes),
+		}
+	}
+
+	fn decode_reader(&self, reader: &mut dyn Read) -> Result<Cache, Error> {
+		match self {
+			CacheFormat::Json => Json.decode_reader(reader),
+			CacheFormat::Bincode => Bincode.decode_reader(reader),
+			CacheFormat::Cbor => Cbor.decode_reader(reader),
+		}
+	}
+}
+
+/// Prefix of the names under which encrypted attribute values are stored in
+/// the binary attributes of an entry. It can't occur in LDAP attribute
+/// descriptions, so it never collides with a real attribute.
+const ENCRYPTED_PREFIX: &str = "!encrypted:";
+
+/// Encrypt data with XChaCha20-Poly1305, prepending the random nonce
+fn encrypt(key: &[u8; 32], plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+	let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+	let ciphertext = XChaCha20Poly1305::new(key.into())
+		.encrypt(&nonce, plaintext)
+		.map_err(|_| Error::Codec("Encryption failed".to_owned()))?;
+	Ok([nonce.as_slice(), &ciphertext].concat())
+}
+
+/// Decrypt data encrypted with [`encrypt`]
+fn decrypt(key: &[u8; 32], bytes: &[u8]) -> Result<Vec<u8>, Error> {
+	let nonce_len = XNonce::default().len();
+	if bytes.len() < nonce_len {
+		return Err(Error::Codec("Encrypted data is truncated".to_owned()));
+	}
+	let (nonce, ciphertext) = bytes.split_at(nonce_len);
+	XChaCha20Poly1305::new(key.into())
+		.decrypt(XNonce::from_slice(nonce), ciphertext)
+		.map_err(|_| Error::Codec("Decryption failed".to_owned()))
+}
+
+/// Magic bytes at the start of cache files written by [`Cache::save_to_file`]
+const FILE_MAGIC: &[u8; 4] = b"LDPC";
+
+/// Length of the header of cache files: the magic bytes, the format, and the
+/// SHA-256 checksum of the snapshot
+const FILE_HEADER_LEN: usize = FILE_MAGIC.len() + 1 + 32;
+
+impl CacheFormat {
+	/// Tag of the format in cache files
+	fn tag(self) -> u8 {
+		match self {
+			CacheFormat::Json => 0,
+			CacheFormat::Bincode => 1,
+			CacheFormat::Cbor => 2,
+		}
+	}
+
+	/// The format with the given tag
+	fn from_tag(tag: u8) -> Option<Self> {
+		[CacheFormat::Json, CacheFormat::Bincode, CacheFormat::Cbor]
+			.into_iter()
+			.find(|format| format.tag() == tag)
+	}
+}
+
+impl Cache {
+	/// Persist the cache to a file in the given format, together with a
+	/// checksum. The snapshot is written to a temporary file next to it which
+	/// then replaces the file, so a crash while writing never leaves a
+	/// truncated snapshot behind.
+	pub fn save_to_file(&self, path: impl AsRef<Path>, format: CacheFormat) -> Result<(), Error> {
+		let path = path.as_ref();
+		let snapshot = format.encode(self)?;
+		let mut temp_name = path.file_name().unwrap_or_default().to_owned();
+		temp_name.push(".tmp");
+		let temp_path = path.with_file_name(temp_name);
+
+		let mut file = fs::File::create(&temp_path)?;
+		file.write_all(FILE_MAGIC)?;
+		file.write_all(&[format.tag()])?;
+		file.write_all(&Sha256::digest(&snapshot))?;
+		file.write_all(&snapshot)?;
+		file.sync_all()?;
+		drop(file);
+		fs::rename(&temp_path, path)?;
+		// Persist the rename itself, where directories can be synced
+		#[cfg(unix)]
+		if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+			fs::File::open(dir)?.sync_all()?;
+		}
+		Ok(())
+	}
+
+	/// Load a cache saved with [`Cache::save_to_file`]. Fails with
+	/// [`Error::Codec`] if the file is corrupted.
+	pub fn load_from_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+		let bytes = fs::read(path)?;
+		if bytes.len() < FILE_HEADER_LEN || !bytes.starts_with(FILE_MAGIC) {
+			return Err(Error::Codec("Not a cache file".to_owned()));
+		}
+		let (header, snapshot) = bytes.split_at(FILE_HEADER_LEN);
+		let format = CacheFormat::from_tag(header[FILE_MAGIC.len()])
+			.ok_or_else(|| Error::Codec("Unknown cache file format".to_owned()))?;
+		if Sha256::digest(snapshot).as_slice() != &header[FILE_MAGIC.len() + 1..] {
+			return Err(Error::Codec("Cache file checksum mismatch".to_owned()));
+		}
+		format.decode(snapshot)
+	}
+
+	/// Serialize the cache and encrypt it with XChaCha20-Poly1305 using the
+	/// given key, so it can be persisted without exposing user data. A random
+	/// nonce is generated and prepended to the ciphertext.
+	pub fn to_encrypted_bytes(&self, key: &[u8; 32]) -> Result<Vec<u8>, Error> {
+		encrypt(key, &Bincode.encode(self)?)
+	}
+
+	/// Decrypt and deserialize a cache encrypted with
+	/// [`Cache::to_encrypted_bytes`]. Fails if the key is wrong or the data
+	/// has been tampered with.
+	pub fn from_encrypted_bytes(bytes: &[u8], key: &[u8; 32]) -> Result<Self, Error> {
+		Bincode.decode(&decrypt(key, bytes)?)
+	}
+
+	/// Encrypt the values of the given attributes of all cached entries with
+	/// XChaCha20-Poly1305, e.g. before persisting the cache. DNs, pids and all
+	/// other attributes are left as they are. The cache can't be used for
+	/// syncing until [`Cache::decrypt_attributes`] is called.
+	pub fn encrypt_attributes(
+		&mut self,
+		attributes: &[String],
+		key: &[u8; 32],
+	) -> Result<(), Error> {
+		let CacheEntries::Modified(ref mut entries) = self.entries else {
+			return Ok(());
+		};
+		for entry in entries.values_mut() {
+			let entry = Arc::make_mut(entry);
+			for name in attributes {
+				let values = (entry.attrs.remove(name), entry.bin_attrs.remove(name));
+				if values == (None, None) {
+					continue;
+				}
+				let plaintext =
+					bincode::serialize(&values).map_err(|err| Error::Codec(err.to_string()))?;
+				entry
+					.bin_attrs
+					.insert(format!("{ENCRYPTED_PREFIX}{name}"), vec![encrypt(key, &plaintext)?]);
+			}
+		}
+		Ok(())
+	}
+
+	/// Decrypt attribute values encrypted with [`Cache::encrypt_attributes`],
+	/// e.g. after restoring a persisted cache. Fails if the key is wrong.
+	pub fn decrypt_attributes(&mut self, key: &[u8; 32]) -> Result<(), Error> {
+		let CacheEntries::Modified(ref mut entries) = self.entries else {
+			return Ok(());
+		};
+		for entry in entries.values_mut() {
+			let entry = Arc::make_mut(entry);
+			let names: Vec<String> = entry
+				.bin_attrs
+				.keys()
+				.filter(|name| name.starts_with(ENCRYPTED_PREFIX))
+				.cloned()
+				.collect();
+			for encrypted_name in names {
+				let Some(ciphertext) = entry.bin_attrs.remove(&encrypted_name) else {
+					continue;
+				};
+				let plaintext = decrypt(key, ciphertext.first().map_or(&[][..], Vec::as_slice))?;
+				let (attrs, bin_attrs): (Option<Vec<String>>, Option<Vec<Vec<u8>>>) =
+					bincode::deserialize(&plaintext)
+						.map_err(|err| Error::Codec(err.to_string()))?;
+				let name = &encrypted_name[ENCRYPTED_PREFIX.len()..];
+				if let Some(attrs) = attrs {
+					entry.attrs.insert(name.to_owned(), attrs);
+				}
+				if let Some(bin_attrs) = bin_attrs {
+					entry.bin_attrs.insert(name.to_owned(), bin_attrs);
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		collections::{HashMap, HashSet},
+		sync::Arc,
+	};
+
+	use ldap3::SearchEntry;
+	use time::macros::datetime;
+
+	use super::{CacheCodec, Json, CACHE_VERSION};
+	use crate::{
+		cache::{
+			Cache, CacheEntries, CacheOrigin, ChangedPids, DnIndex, EntryProvenance,
+			PagedSearchProgress,
+		},
+		config::{CacheFormat, Config},
+		error::Error,
+	};
+
+	#[test]
+	fn roundtrip() -> Result<(), Box<dyn std::error::Error>> {
+		let entry = SearchEntry {
+			dn: "uid=foo,ou=people,dc=example,dc=com".to_owned(),
+			attrs: HashMap::from([("cn".to_owned(), vec!["foo".to_owned()])]),
+			bin_attrs: HashMap::from([("objectGUID".to_owned(), vec![vec![0, 1, 2, 255]])]),
+		};
+		let cache = Cache {
+			last_sync_time: Some(datetime!(2023-05-16 20:05:20 UTC)),
+			last_full_sync_time: None,
+			highest_usn: Some(4711),
+			entries: CacheEntries::Modified(HashMap::from([(
+				vec![0, 1, 2, 255],
+				Arc::new(entry.into()),
+			)])),
+			missing: HashSet::from([vec![3, 4]]),
+			misses: HashMap::from([(vec![3, 4], 2)]),
+			provenance: HashMap::from([(
+				vec![0, 1, 2, 255],
+				EntryProvenance {
+					first_seen: datetime!(2023-05-16 20:05:20 UTC),
+					last_confirmed: datetime!(2023-05-17 20:05:20 UTC),
+				},
+			)]),
+			invalidated: HashSet::from([vec![0, 1, 2, 255]]),
+			origin: Some(CacheOrigin::new(&Config::example())),
+			paged_search: Some(PagedSearchProgress {
+				filter: "(objectClass=person)".to_owned(),
+				full_sync: true,
+				started: datetime!(2023-05-16 20:05:20 UTC),
+				cookie: vec![1, 2, 3],
+				entries: 500,
+			}),
+			schema: None,
+			dn_index: DnIndex::default(),
+			changes: ChangedPids::default(),
+		};
+
+		for format in [CacheFormat::Json, CacheFormat::Bincode, CacheFormat::Cbor] {
+			let encoded = format.encode(&cache)?;
+			assert_eq!(format.decode(&encoded)?, cache, "{format:?} should roundtrip");
+			assert_eq!(
+				format.decode_reader(&mut encoded.as_slice())?,
+				cache,
+				"{format:?} should roundtrip from a reader"
+			);
+		}
+
+		Ok(())
+	}
+
+	#[test]
+	fn versioning() -> Result<(), Box<dyn std::error::Error>> {
+		let cache = Cache::new(CacheEntries::Modified(HashMap::new()));
+
+		let unversioned = serde_json::to_vec(&cache)?;
+		assert_eq!(Json.decode(&unversioned)?, cache, "Unversioned snapshots should be accepted");
+
+		let future = serde_json::to_vec(
+			&serde_json::json!({ "version": CACHE_VERSION + 1, "cache": cache }),
+		)?;
+		assert!(
+			matches!(
+				Json.decode(&future),
+				Err(Error::IncompatibleCache { found, supported: CACHE_VERSION })
+					if found == CACHE_VERSION + 1
+			),
+			"Snapshots of unknown versions should be rejected"
+		);
+
+		let future = bincode::serialize(&(CACHE_VERSION + 1, &cache))?;
+		assert!(
+			matches!(CacheFormat::Bincode.decode(&future), Err(Error::IncompatibleCache { .. })),
+			"Snapshots of unknown versions should be rejected"
+		);
+
+		let old = serde_json::to_vec(&serde_json::json!({ "version": 1, "cache": cache }))?;
+		assert_eq!(Json.decode(&old)?, cache, "Older JSON snapshots should be accepted");
+		let old = bincode::serialize(&(CACHE_VERSION - 1, &cache))?;
+		assert!(
+			matches!(CacheFormat::Bincode.decode(&old), Err(Error::IncompatibleCache { .. })),
+			"Older bincode snapshots have a different layout"
+		);
+
+		Ok(())
+	}
+
+	#[test]
+	fn encryption() -> Result<(), Box<dyn std::error::Error>> {
+		let cache = Cache::new(CacheEntries::Modified(HashMap::from([(
+			b"john_doe".to_vec(),
+			Arc::new(
+				SearchEntry {
+					dn: "uid=john_doe,ou=people,dc=example,dc=com".to_owned(),
+					attrs: HashMap::from([(
+						"mail".to_owned(),
+						vec!["john@example.com".to_owned()],
+					)]),
+					bin_attrs: HashMap::new(),
+				}
+				.into(),
+			),
+		)])));
+
+		let encrypted = cache.to_encrypted_bytes(&[7; 32])?;
+		assert!(
+			!encrypted.windows(8).any(|window| window == b"john_doe"),
+			"Encrypted cache should not contain plaintext"
+		);
+		assert_eq!(Cache::from_encrypted_bytes(&encrypted, &[7; 32])?, cache);
+		assert!(Cache::from_encrypted_bytes(&encrypted, &[8; 32]).is_err(), "Wrong key must fail");
+		assert!(Cache::from_encrypted_bytes(&encrypted[..10], &[7; 32]).is_err());
+
+		Ok(())
+	}
+
+	#[test]
+	fn attribute_encryption() -> Result<(), Box<dyn std::error::Error>> {
+		let mut cache = Cache::new(CacheEntries::Modified(HashMap::from([(
+			b"john_doe".to_vec(),
+			Arc::new(
+				SearchEntry {
+					dn: "uid=john_doe,ou=people,dc=example,dc=com".to_owned(),
+					attrs: HashMap::from([
+						("mail".to_owned(), vec!["john@example.com".to_owned()]),
+						("enabled".to_owned(), vec!["TRUE".to_owned()]),
+					]),
+					bin_attrs: HashMap::new(),
+				}
+				.into(),
+			),
+		)])));
+		let original = cache.clone();
+
+		cache.encrypt_attributes(&["mail".to_owned(), "phone".to_owned()], &[7; 32])?;
+		let json = String::from_utf8(Json.encode(&cache)?)?;
+		assert!(!json.contains("john@example.com"), "Encrypted attributes should be hidden");
+		assert!(json.contains("TRUE"), "Other attributes should stay readable");
+
+		let mut restored = Json.decode(json.as_bytes())?;
+		assert!(restored.clone().decrypt_attributes(&[8; 32]).is_err(), "Wrong key must fail");
+		restored.decrypt_attributes(&[7; 32])?;
+		assert_eq!(restored, original);
+
+		Ok(())
+	}
+
+	#[test]
+	fn file_roundtrip() -> Result<(), Box<dyn std::error::Error>> {
+		let cache = Cache::new(CacheEntries::Modified(HashMap::from([(
+			b"john_doe".to_vec(),
+			Arc::new(
+				SearchEntry {
+					dn: "uid=john_doe,ou=people,dc=example,dc=com".to_owned(),
+					attrs: HashMap::from([(
+						"mail".to_owned(),
+						vec!["john@example.com".to_owned()],
+					)]),
+					bin_attrs: HashMap::new(),
+				}
+				.into(),
+			),
+		)])));
+		let dir = std::env::temp_dir().join(format!("ldap-poller-{}", std::process::id()));
+		std::fs::create_dir_all(&dir)?;
+		let path = dir.join("cache.bin");
+
+		for format in [CacheFormat::Json, CacheFormat::Bincode, CacheFormat::Cbor] {
+			cache.save_to_file(&path, format)?;
+			assert_eq!(Cache::load_from_file(&path)?, cache, "{format:?} should roundtrip");
+		}
+		assert!(!dir.join("cache.bin.tmp").exists(), "The temporary file should be renamed");
+
+		let mut bytes = std::fs::read(&path)?;
+		if let Some(last) = bytes.last_mut() {
+			*last ^= 1;
+		}
+		std::fs::write(&path, &bytes)?;
+		assert!(
+			matches!(Cache::load_from_file(&path), Err(Error::Codec(_))),
+			"Corrupted files should be rejected"
+		);
+		std::fs::write(&path, b"garbage")?;
+		assert!(matches!(Cache::load_from_file(&path), Err(Error::Codec(_))));
+
+		std::fs::remove_dir_all(&dir)?;
+		Ok(())
+	}
+}
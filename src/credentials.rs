@@ -0,0 +1,57 @@
+//! Providers for the credentials used to bind to the directory
+use std::fmt;
+
+use async_trait::async_trait;
+
+use crate::{config::Config, error::Error};
+
+/// Credentials of the search user
+#[derive(Clone, PartialEq, Eq)]
+pub struct Credentials {
+	/// The bind DN or username of the search user
+	pub user: String,
+	/// The password of the search user
+	pub password: String,
+}
+
+impl fmt::Debug for Credentials {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("Credentials").field("user", &self.user).field("password", &"***").finish()
+	}
+}
+
+/// Source of the credentials to bind with, which is asked again before every
+/// sync cycle. This allows fetching passwords from a secret store and
+/// rotating them without restarting the poller.
+///
+/// [`Config`] implements this with its static `search_user` and
+/// `search_password`, which are used unless a provider is set with
+/// [`crate::ldap::Ldap::with_credentials_provider`].
+#[async_trait]
+pub trait CredentialsProvider: fmt::Debug + Send + Sync {
+	/// Get the current credentials
+	async fn get_credentials(&self) -> Result<Credentials, Error>;
+}
+
+#[async_trait]
+impl CredentialsProvider for Config {
+	async fn get_credentials(&self) -> Result<Credentials, Error> {
+		let password = crate::config::secret::resolve(&self.search_password)
+			.map_err(|err| Error::Credentials(err.into()))?;
+		Ok(Credentials { user: self.search_user.clone(), password: password.into_owned() })
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::Credentials;
+
+	#[test]
+	fn debug_redacts_password() {
+		let credentials =
+			Credentials { user: "cn=admin".to_owned(), password: "verysecret".to_owned() };
+		let debug = format!("{credentials:?}");
+		assert!(debug.contains("cn=admin"));
+		assert!(!debug.contains("verysecret"), "The password must not be logged");
+	}
+}
@@ -0,0 +1,313 @@
+//! Structured diagnostics about problems encountered while syncing, for
+//! operator-facing health information.
+use std::{sync::Arc, time::Duration};
+
+use ldap3::SearchEntry;
+use time::OffsetDateTime;
+
+use crate::{config::TLSConfig, entry::SearchEntryExt, error::Error, ldap::PAGED_RESULTS_OID};
+
+/// How severe a problem is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+	/// The sync continued, but some data may be incomplete
+	Warning,
+	/// The sync cycle failed
+	Error,
+}
+
+/// What the poller is doing right now, see
+/// [`crate::ldap::Ldap::subscribe_state`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PollerState {
+	/// No sync cycle is running, e.g. before the first one or after
+	/// [`crate::ldap::Ldap::sync_once`]
+	Idle,
+	/// Establishing the connection to the server
+	Connecting,
+	/// Binding as the search user
+	Binding,
+	/// Searching for entries and processing them, receiving the given result
+	/// page, counted from 1
+	Searching {
+		/// The result page being received
+		page: u64,
+	},
+	/// Waiting for the next cycle of [`crate::ldap::Ldap::sync`]
+	Sleeping {
+		/// When the next cycle starts
+		until: OffsetDateTime,
+	},
+	/// The last sync cycle failed. Kept until the next cycle starts.
+	Failed {
+		/// The error the cycle failed with
+		error: String,
+	},
+}
+
+/// The phase of a sync cycle in which a problem occurred
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+	/// Establishing the connection to the server
+	Connect,
+	/// Binding as the search user
+	Bind,
+	/// Searching for entries
+	Search,
+	/// Comparing entries with the cache
+	Compare,
+	/// Detecting removed entries
+	Removal,
+	/// Pushing updates to the channel
+	Send,
+}
+
+/// A problem encountered while syncing
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+	/// How severe the problem is
+	pub severity: Severity,
+	/// The phase in which the problem occurred
+	pub phase: Phase,
+	/// Whether the problem is likely to go away by itself when retrying
+	pub retryable: bool,
+	/// A human-readable description of the problem
+	pub message: String,
+	/// The underlying error, if any
+	pub source: Option<Arc<Error>>,
+}
+
+impl Diagnostic {
+	/// A warning which is not caused by an error
+	pub(crate) fn warning(phase: Phase, message: String) -> Self {
+		Diagnostic { severity: Severity::Warning, phase, retryable: false, message, source: None }
+	}
+
+	/// An error that made the phase fail
+	pub(crate) fn error(phase: Phase, source: Error) -> Self {
+		Diagnostic {
+			severity: Severity::Error,
+			phase,
+			retryable: source.is_retryable(),
+			message: source.to_string(),
+			source: Some(Arc::new(source)),
+		}
+	}
+}
+
+/// Result of a successful connectivity probe, see
+/// [`crate::ldap::Ldap::check_connection`]
+#[derive(Debug, Clone)]
+pub struct ConnectionCheck {
+	/// Time taken to establish the connection, including the TLS handshake
+	pub connect_latency: Duration,
+	/// Time taken to bind
+	pub bind_latency: Duration,
+	/// The authorization identity of the connection as reported by the "Who
+	/// am I?" extended operation, empty for anonymous binds. `None` if the
+	/// server doesn't support the operation.
+	pub authz_id: Option<String>,
+	/// Identity of the server, if the RootDSE was read
+	pub server: Option<ServerInfo>,
+	/// How the connection is secured
+	pub tls: TlsInfo,
+}
+
+/// Server identity and capabilities as advertised in the RootDSE
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerInfo {
+	/// The `vendorName` attribute
+	pub vendor_name: Option<String>,
+	/// The `vendorVersion` attribute
+	pub vendor_version: Option<String>,
+	/// The `namingContexts` attribute, i.e. the served suffixes
+	pub naming_contexts: Vec<String>,
+	/// The `supportedLDAPVersion` attribute
+	pub supported_ldap_versions: Vec<String>,
+	/// The `supportedSASLMechanisms` attribute
+	pub supported_sasl_mechanisms: Vec<String>,
+	/// The `supportedControl` attribute, i.e. the OIDs of the supported
+	/// request controls
+	pub supported_controls: Vec<String>,
+	/// The `supportedExtension` attribute, i.e. the OIDs of the supported
+	/// extended operations
+	pub supported_extensions: Vec<String>,
+	/// The `supportedFeatures` attribute
+	pub supported_features: Vec<String>,
+}
+
+/// Ways of keeping track of changes in a directory, see
+/// [`ServerInfo::sync_strategy`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncStrategy {
+	/// The LDAP Content Synchronization Operation (RFC 4533), as supported by
+	/// OpenLDAP
+	SyncRepl,
+	/// The Active Directory DirSync control
+	DirSync,
+	/// Periodic searches comparing entries with the cache, which works with
+	/// every server
+	Polling,
+}
+
+impl ServerInfo {
+	/// Attributes to request when reading the RootDSE
+	pub(crate) const ATTRIBUTES: [&'static str; 8] = [
+		"vendorName",
+		"vendorVersion",
+		"namingContexts",
+		"supportedLDAPVersion",
+		"supportedSASLMechanisms",
+		"supportedControl",
+		"supportedExtension",
+		"supportedFeatures",
+	];
+
+	/// OID of the LDAP Content Synchronization control
+	const SYNC_REQUEST_OID: &'static str = "1.3.6.1.4.1.4203.1.9.1.1";
+
+	/// OID of the Active Directory DirSync control
+	const DIRSYNC_OID: &'static str = "1.2.840.113556.1.4.841";
+
+	/// Extract the server identity from the RootDSE entry
+	pub(crate) fn from_root_dse(entry: &SearchEntry) -> Self {
+		let values = |attr: &str| entry.attrs.get(attr).cloned().unwrap_or_default();
+		ServerInfo {
+			vendor_name: entry.attr_first("vendorName").map(ToOwned::to_owned),
+			vendor_version: entry.attr_first("vendorVersion").map(ToOwned::to_owned),
+			naming_contexts: values("namingContexts"),
+			supported_ldap_versions: values("supportedLDAPVersion"),
+			supported_sasl_mechanisms: values("supportedSASLMechanisms"),
+			supported_controls: values("supportedControl"),
+			supported_extensions: values("supportedExtension"),
+			supported_features: values("supportedFeatures"),
+		}
+	}
+
+	/// Whether the server advertises support for the control with the given
+	/// OID
+	#[must_use]
+	pub fn supports_control(&self, oid: &str) -> bool {
+		self.supported_controls.iter().any(|supported| supported == oid)
+	}
+
+	/// Whether the server is known not to support paged searches, i.e. it
+	/// advertises its supported controls, but not the simple paged results
+	/// control. Servers hiding their controls are assumed to support it.
+	#[must_use]
+	pub fn lacks_paged_results(&self) -> bool {
+		!self.supported_controls.is_empty() && !self.supports_control(PAGED_RESULTS_OID)
+	}
+
+	/// The most efficient way of keeping track of changes the server
+	/// supports. The poller itself always polls, so this tells whether a
+	/// server would allow for a more efficient change tracking.
+	#[must_use]
+	pub fn sync_strategy(&self) -> SyncStrategy {
+		if self.supports_control(Self::SYNC_REQUEST_OID) {
+			SyncStrategy::SyncRepl
+		} else if self.supports_control(Self::DIRSYNC_OID) {
+			SyncStrategy::DirSync
+		} else {
+			SyncStrategy::Polling
+		}
+	}
+}
+
+/// How a connection is secured, as configured
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TlsInfo {
+	/// Whether the connection is encrypted, either via `ldaps` or StartTLS
+	pub encrypted: bool,
+	/// Whether StartTLS was used
+	pub starttls: bool,
+	/// Whether the server certificate was verified
+	pub verified: bool,
+	/// Whether a client certificate was presented
+	pub client_certificate: bool,
+}
+
+impl TlsInfo {
+	/// Describe a connection to the given URL with the given TLS settings
+	pub(crate) fn new(url: &url::Url, tls: &TLSConfig) -> Self {
+		let starttls = tls.starttls && url.scheme() == "ldap";
+		let encrypted = starttls || url.scheme() == "ldaps";
+		TlsInfo {
+			encrypted,
+			starttls,
+			verified: encrypted && !tls.no_tls_verify,
+			client_certificate: encrypted && tls.client_certificate_path.is_some(),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	#![allow(clippy::unwrap_used)]
+	use std::collections::HashMap;
+
+	use ldap3::SearchEntry;
+
+	use super::{Diagnostic, Phase, ServerInfo, Severity, SyncStrategy, TlsInfo};
+	use crate::{config::Config, error::Error};
+
+	#[test]
+	fn error_diagnostic() {
+		let diagnostic = Diagnostic::error(
+			Phase::Connect,
+			Error::Io(std::io::ErrorKind::ConnectionRefused.into()),
+		);
+		assert_eq!(diagnostic.severity, Severity::Error);
+		assert!(diagnostic.retryable, "IO errors should be retryable");
+		assert!(diagnostic.source.is_some());
+
+		let diagnostic = Diagnostic::error(Phase::Bind, Error::Invalid("TIME_FORMAT".to_owned()));
+		assert!(!diagnostic.retryable, "Invalid data should not be retryable");
+	}
+
+	#[test]
+	fn server_info() {
+		let entry = SearchEntry {
+			dn: String::new(),
+			attrs: HashMap::from([
+				("vendorName".to_owned(), vec!["OpenLDAP".to_owned()]),
+				("namingContexts".to_owned(), vec!["dc=example,dc=org".to_owned()]),
+				("supportedLDAPVersion".to_owned(), vec!["3".to_owned()]),
+			]),
+			bin_attrs: HashMap::new(),
+		};
+		let info = ServerInfo::from_root_dse(&entry);
+		assert_eq!(info.vendor_name.as_deref(), Some("OpenLDAP"));
+		assert_eq!(info.vendor_version, None);
+		assert_eq!(info.naming_contexts, ["dc=example,dc=org"]);
+		assert_eq!(info.supported_ldap_versions, ["3"]);
+		assert!(info.supported_sasl_mechanisms.is_empty());
+		assert!(!info.lacks_paged_results(), "Hidden controls should be assumed supported");
+		assert_eq!(info.sync_strategy(), SyncStrategy::Polling);
+
+		let info = ServerInfo {
+			supported_controls: vec![
+				"1.3.6.1.4.1.4203.1.9.1.1".to_owned(),
+				"2.16.840.1.113730.3.4.18".to_owned(),
+			],
+			..info
+		};
+		assert!(info.lacks_paged_results());
+		assert_eq!(info.sync_strategy(), SyncStrategy::SyncRepl);
+	}
+
+	#[test]
+	fn tls_info() {
+		let mut tls = Config::example().connection.tls;
+		let info = TlsInfo::new(&"ldap://localhost".parse().unwrap(), &tls);
+		assert!(!info.encrypted && !info.verified);
+
+		tls.starttls = true;
+		let info = TlsInfo::new(&"ldap://localhost".parse().unwrap(), &tls);
+		assert!(info.encrypted && info.starttls && info.verified);
+
+		tls.no_tls_verify = true;
+		let info = TlsInfo::new(&"ldaps://localhost".parse().unwrap(), &tls);
+		assert!(info.encrypted && !info.starttls && !info.verified);
+	}
+}
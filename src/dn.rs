@@ -0,0 +1,314 @@
+//! Parsing and comparison of distinguished names as described in RFC 4514,
+//! so that DNs don't have to be handled with ad hoc string matching
+use std::{
+	fmt,
+	hash::{Hash, Hasher},
+	str::FromStr,
+};
+
+use crate::{error::Error, schema::collapse_spaces};
+
+/// A parsed distinguished name. DNs compare equal if they only differ in
+/// the case of attribute types and values, in insignificant spaces, in
+/// escaping, or in the order of the values of multi-valued RDNs, which is
+/// how directory servers compare the DNs of entries in practice.
+///
+/// ```
+/// use ldap_poller::dn::Dn;
+///
+/// let dn: Dn = "CN=John Doe, OU=People,DC=example,DC=com".parse()?;
+/// let base: Dn = "ou=people,dc=example,dc=com".parse()?;
+/// assert!(dn.is_under(&base));
+/// assert_eq!(dn.rdn().and_then(|rdn| rdn.value("cn")), Some("John Doe"));
+/// assert_eq!(dn.parent(), Some(base));
+/// assert_eq!(dn.normalized(), "cn=john doe,ou=people,dc=example,dc=com");
+/// # Ok::<(), ldap_poller::error::Error>(())
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Dn {
+	/// The RDNs, starting with the one of the entry itself
+	rdns: Vec<Rdn>,
+}
+
+/// A relative distinguished name, i.e. one component of a DN, which
+/// consists of several attribute values if it is multi-valued
+#[derive(Debug, Clone)]
+pub struct Rdn {
+	/// The attribute values, in their original order
+	avas: Vec<Ava>,
+}
+
+/// An attribute value assertion of an RDN, e.g. `cn=John Doe`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ava {
+	/// The attribute type, e.g. `cn`
+	pub attr: String,
+	/// The unescaped value
+	pub value: String,
+}
+
+impl Dn {
+	/// Parse a DN in its string representation. The empty string is the DN
+	/// of the root.
+	pub fn parse(dn: &str) -> Result<Self, Error> {
+		parse_dn(dn).map_err(Error::Invalid)
+	}
+
+	/// Whether this is the DN of the root, i.e. empty
+	#[must_use]
+	pub fn is_root(&self) -> bool {
+		self.rdns.is_empty()
+	}
+
+	/// The RDNs, starting with the one of the entry itself
+	#[must_use]
+	pub fn rdns(&self) -> &[Rdn] {
+		&self.rdns
+	}
+
+	/// The RDN of the entry itself, unless this is the root
+	#[must_use]
+	pub fn rdn(&self) -> Option<&Rdn> {
+		self.rdns.first()
+	}
+
+	/// The DN of the parent entry, unless this is the root
+	#[must_use]
+	pub fn parent(&self) -> Option<Dn> {
+		let (_, parent) = self.rdns.split_first()?;
+		Some(Dn { rdns: parent.to_vec() })
+	}
+
+	/// Whether the DN is the given base or below it, i.e. whether a subtree
+	/// search of the base could return the entry
+	#[must_use]
+	pub fn is_under(&self, base: &Dn) -> bool {
+		self.rdns.len() >= base.rdns.len()
+			&& self.rdns[self.rdns.len() - base.rdns.len()..] == base.rdns[..]
+	}
+
+	/// The normalized string representation, with lowercase attribute types
+	/// and values, insignificant spaces removed, and the values of
+	/// multi-valued RDNs sorted. Equal DNs have the same normalized form.
+	#[must_use]
+	pub fn normalized(&self) -> String {
+		self.rdns.iter().map(Rdn::normalized).collect::<Vec<_>>().join(",")
+	}
+}
+
+impl Rdn {
+	/// The attribute values, in their original order
+	#[must_use]
+	pub fn avas(&self) -> &[Ava] {
+		&self.avas
+	}
+
+	/// The value of the given attribute type, which is matched ignoring case
+	#[must_use]
+	pub fn value(&self, attr: &str) -> Option<&str> {
+		self.avas.iter().find(|ava| ava.attr.eq_ignore_ascii_case(attr)).map(|ava| &*ava.value)
+	}
+
+	/// The normalized string representation, see [`Dn::normalized`]
+	fn normalized(&self) -> String {
+		let mut avas: Vec<_> = self
+			.avas
+			.iter()
+			.map(|ava| {
+				let value = collapse_spaces(&ava.value).to_lowercase();
+				format!("{}={}", ava.attr.to_ascii_lowercase(), Escaped(&value))
+			})
+			.collect();
+		avas.sort_unstable();
+		avas.join("+")
+	}
+}
+
+impl PartialEq for Dn {
+	fn eq(&self, other: &Self) -> bool {
+		self.rdns == other.rdns
+	}
+}
+
+impl Eq for Dn {}
+
+impl Hash for Dn {
+	fn hash<H: Hasher>(&self, state: &mut H) {
+		self.normalized().hash(state);
+	}
+}
+
+impl PartialEq for Rdn {
+	fn eq(&self, other: &Self) -> bool {
+		self.normalized() == other.normalized()
+	}
+}
+
+impl Eq for Rdn {}
+
+impl FromStr for Dn {
+	type Err = Error;
+
+	fn from_str(dn: &str) -> Result<Self, Self::Err> {
+		Dn::parse(dn)
+	}
+}
+
+/// Formats the DN with its original case, escaping special characters
+impl fmt::Display for Dn {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		for (index, rdn) in self.rdns.iter().enumerate() {
+			if index > 0 {
+				f.write_str(",")?;
+			}
+			write!(f, "{rdn}")?;
+		}
+		Ok(())
+	}
+}
+
+impl fmt::Display for Rdn {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		for (index, ava) in self.avas.iter().enumerate() {
+			if index > 0 {
+				f.write_str("+")?;
+			}
+			write!(f, "{}={}", ava.attr, Escaped(&ava.value))?;
+		}
+		Ok(())
+	}
+}
+
+/// An attribute value, formatted with the characters escaped which have to
+/// be escaped in DNs
+struct Escaped<'a>(&'a str);
+
+impl fmt::Display for Escaped<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let last = self.0.chars().count().saturating_sub(1);
+		for (index, char) in self.0.chars().enumerate() {
+			match char {
+				'"' | '+' | ',' | ';' | '<' | '>' | '\\' => write!(f, "\\{char}")?,
+				'#' if index == 0 => f.write_str("\\#")?,
+				' ' if index == 0 || index == last => f.write_str("\\ ")?,
+				'\0' => f.write_str("\\00")?,
+				char => write!(f, "{char}")?,
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Parse a DN, describing the syntax error if it is invalid
+pub(crate) fn parse_dn(dn: &str) -> Result<Dn, String> {
+	let mut rdns = Vec::new();
+	if dn.is_empty() {
+		return Ok(Dn { rdns });
+	}
+	let mut avas = Vec::new();
+	let mut attr = None;
+	// The type or value being parsed, and the length up to its last escaped
+	// character, as escaped spaces at the end are significant
+	let mut text = Vec::new();
+	let mut escaped_len = 0;
+	let mut chars = dn.chars();
+	loop {
+		let char = chars.next();
+		match char {
+			Some('\\') => {
+				let escaped = chars.next().ok_or_else(|| format!("Trailing escape in DN {dn}"))?;
+				if escaped.is_ascii_hexdigit() {
+					let byte = chars
+						.next()
+						.and_then(|low| u8::from_str_radix(&format!("{escaped}{low}"), 16).ok())
+						.ok_or_else(|| format!("Invalid escape in DN {dn}"))?;
+					text.push(byte);
+				} else {
+					text.extend_from_slice(escaped.encode_utf8(&mut [0; 4]).as_bytes());
+				}
+				escaped_len = text.len();
+			}
+			Some('=') if attr.is_none() => {
+				let name = trimmed(&mut text, 0);
+				let descriptor = name.starts_with(|char: char| char.is_ascii_alphabetic())
+					&& name.chars().all(|char| char.is_ascii_alphanumeric() || char == '-');
+				if !descriptor && !crate::config::is_oid(&name) {
+					return Err(format!("Invalid attribute type `{name}` in DN {dn}"));
+				}
+				attr = Some(name);
+				escaped_len = 0;
+			}
+			Some(' ') if text.is_empty() => {}
+			None | Some(',' | '+') => {
+				let value = trimmed(&mut text, escaped_len);
+				let Some(attr) = attr.take() else {
+					return Err(format!("Missing `=` in `{value}` of DN {dn}"));
+				};
+				escaped_len = 0;
+				avas.push(Ava { attr, value });
+				if char != Some('+') {
+					rdns.push(Rdn { avas: std::mem::take(&mut avas) });
+				}
+				if char.is_none() {
+					return Ok(Dn { rdns });
+				}
+			}
+			Some(char) => text.extend_from_slice(char.encode_utf8(&mut [0; 4]).as_bytes()),
+		}
+	}
+}
+
+/// Take the parsed text, removing spaces at the end after the first `keep`
+/// bytes. Escaped bytes which aren't valid UTF-8 are replaced.
+fn trimmed(text: &mut Vec<u8>, keep: usize) -> String {
+	while text.len() > keep && text.last() == Some(&b' ') {
+		text.pop();
+	}
+	String::from_utf8_lossy(&std::mem::take(text)).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashSet;
+
+	use super::{parse_dn, Dn};
+
+	#[test]
+	fn parse() -> Result<(), Box<dyn std::error::Error>> {
+		for dn in ["", "dc=example,dc=com", r"cn=a\,b+uid=c,2.5.4.11=x", "cn="] {
+			assert!(parse_dn(dn).is_ok(), "{dn}");
+		}
+		for dn in ["example", "cn=a,", "1cn=a", "cn=a\\", "c n=a", r"cn=\4"] {
+			assert!(parse_dn(dn).is_err(), "{dn}");
+		}
+
+		let dn = Dn::parse(r"cn = \ Doe\2C John\20 +uid=jd , ou=People")?;
+		let rdn = dn.rdn().ok_or("Missing RDN")?;
+		assert_eq!(rdn.avas().len(), 2);
+		assert_eq!(rdn.value("CN"), Some(" Doe, John "));
+		assert_eq!(rdn.value("uid"), Some("jd"));
+		assert_eq!(dn.to_string(), r"cn=\ Doe\, John\ +uid=jd,ou=People");
+		assert_eq!(Dn::parse(&dn.to_string())?, dn, "Formatted DNs should parse to themselves");
+		assert!(Dn::parse("")?.is_root());
+		Ok(())
+	}
+
+	#[test]
+	fn compare() -> Result<(), Box<dyn std::error::Error>> {
+		let dn = Dn::parse("CN=John  Doe+UID=jd,OU=People,DC=example")?;
+		let same = Dn::parse("uid=JD+cn=john doe, ou=people, dc=example")?;
+		assert_eq!(dn, same);
+		assert_eq!(HashSet::from([dn.clone(), same]).len(), 1);
+		assert_ne!(dn, Dn::parse("cn=john doe,ou=people,dc=example")?);
+
+		let base = Dn::parse("ou=people,dc=example")?;
+		assert!(dn.is_under(&base));
+		assert!(base.is_under(&base));
+		assert!(dn.is_under(&Dn::default()), "Every DN is under the root");
+		assert!(!base.is_under(&dn));
+		assert!(!Dn::parse("ou=people,dc=other")?.is_under(&base));
+		assert_eq!(dn.parent().and_then(|parent| parent.parent()), Dn::parse("dc=example").ok());
+		assert_eq!(Dn::default().parent(), None);
+		Ok(())
+	}
+}
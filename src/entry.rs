@@ -1,7 +1,12 @@
 //! Helper methods for extracting data from search results.
+use std::collections::{BTreeMap, HashMap};
+
 use ldap3::SearchEntry;
+use time::{
+	macros::datetime, Date, Duration, Month, OffsetDateTime, PrimitiveDateTime, Time, UtcOffset,
+};
 
-use crate::error::Error;
+use crate::{dn::Dn, error::Error, ldap::EntryStatus};
 
 /// An extension trait for [`SearchEntry`] that provides convenience methods for
 /// extracting data.
@@ -13,6 +18,29 @@ pub trait SearchEntryExt {
 	/// Get the first value of an attribute, in binary form
 	fn bin_attr_first(&self, attr: &str) -> Option<&[u8]>;
 
+	/// Get all values of an attribute, in binary form. Only returns the
+	/// first value unless overridden by the implementation.
+	fn bin_attr_values(&self, attr: &str) -> Vec<&[u8]> {
+		self.bin_attr_first(attr).into_iter().collect()
+	}
+
+	/// Get the first value of a required attribute, using one of the other
+	/// getters, e.g. `entry.required("enabled", SearchEntryExt::bool_first)`.
+	/// Fails with [`Error::MissingAttribute`] if the attribute is missing.
+	fn required<'a, T>(
+		&'a self,
+		attr: &str,
+		get: impl FnOnce(&'a Self, &str) -> Option<Result<T, Error>>,
+	) -> Result<T, Error> {
+		get(self, attr).unwrap_or_else(|| Err(Error::MissingAttribute(attr.to_owned())))
+	}
+
+	/// Get the first value of an attribute, parsed with [`std::str::FromStr`]
+	fn parse_first<T: std::str::FromStr>(&self, attr: &str) -> Option<Result<T, Error>> {
+		let value = self.attr_first(attr)?;
+		Some(value.parse().map_err(|_| Error::Invalid(attr.to_owned())))
+	}
+
 	/// Get the first value of an attribute, interpreted as a boolean.
 	fn bool_first(&self, attr: &str) -> Option<Result<bool, Error>> {
 		match self.attr_first(attr) {
@@ -22,6 +50,271 @@ pub trait SearchEntryExt {
 			None => None,
 		}
 	}
+
+	/// Get the first value of an attribute, parsed as a DN, e.g. `manager`
+	fn dn_first(&self, attr: &str) -> Option<Result<Dn, Error>> {
+		let value = self.attr_first(attr)?;
+		Some(Dn::parse(value).map_err(|_| Error::Invalid(attr.to_owned())))
+	}
+
+	/// Get the first value of an attribute, interpreted as a point in time.
+	/// Supports Generalized Time (e.g. `modifyTimestamp`, or `whenChanged` in
+	/// Active Directory), including fractions and time zone offsets, as well as
+	/// the integer FILETIME format Active Directory uses for attributes like
+	/// `pwdLastSet`. FILETIME values which mean "never" (`0` and `i64::MAX`)
+	/// are considered invalid.
+	fn time_first(&self, attr: &str) -> Option<Result<OffsetDateTime, Error>> {
+		let value = self.attr_first(attr)?;
+		Some(parse_time(value).ok_or_else(|| Error::Invalid(attr.to_owned())))
+	}
+
+	/// Get the first value of a binary GUID attribute such as Active
+	/// Directory's `objectGUID`, formatted as a canonical lowercase UUID
+	/// string. The first three fields are stored in little endian byte order.
+	fn guid_first(&self, attr: &str) -> Option<Result<String, Error>> {
+		let bytes = self.bin_attr_first(attr)?;
+		let Ok(bytes) = <[u8; 16]>::try_from(bytes) else {
+			return Some(Err(Error::Invalid(attr.to_owned())));
+		};
+		let hex = |bytes: &[u8]| bytes.iter().map(|byte| format!("{byte:02x}")).collect::<String>();
+		let reversed = |bytes: &[u8]| bytes.iter().rev().copied().collect::<Vec<_>>();
+		Some(Ok(format!(
+			"{}-{}-{}-{}-{}",
+			hex(&reversed(&bytes[0..4])),
+			hex(&reversed(&bytes[4..6])),
+			hex(&reversed(&bytes[6..8])),
+			hex(&bytes[8..10]),
+			hex(&bytes[10..16]),
+		)))
+	}
+
+	/// Get the first value of a binary security identifier attribute such as
+	/// Active Directory's `objectSid`, formatted in its string form, e.g.
+	/// `S-1-5-21-1004336348-1177238915-682003330-512`.
+	fn sid_first(&self, attr: &str) -> Option<Result<String, Error>> {
+		let bytes = self.bin_attr_first(attr)?;
+		Some(format_sid(bytes).ok_or_else(|| Error::Invalid(attr.to_owned())))
+	}
+
+	/// Get the flags of an Active Directory account from its
+	/// `userAccountControl` attribute. Flags which Active Directory only
+	/// computes on request, such as [`UserAccountControl::LOCKOUT`] and
+	/// [`UserAccountControl::PASSWORD_EXPIRED`], are included if the entry
+	/// also has the `msDS-User-Account-Control-Computed` attribute.
+	fn user_account_control(&self) -> Option<Result<UserAccountControl, Error>> {
+		let parse = |attr: &str| {
+			let value = self.parse_first::<i64>(attr)?;
+			// Active Directory presents the 32 bit value as signed integer
+			Some(value.and_then(|value| {
+				u32::try_from(value)
+					.or_else(|_| i32::try_from(value).map(|value| value as u32))
+					.map_err(|_| Error::Invalid(attr.to_owned()))
+			}))
+		};
+		let flags = match parse(UserAccountControl::ATTRIBUTE)? {
+			Ok(flags) => flags,
+			Err(err) => return Some(Err(err)),
+		};
+		let computed = match parse(UserAccountControl::COMPUTED_ATTRIBUTE) {
+			Some(Ok(computed)) => computed,
+			Some(Err(err)) => return Some(Err(err)),
+			None => 0,
+		};
+		Some(Ok(UserAccountControl::from_bits_retain(flags | computed)))
+	}
+}
+
+bitflags::bitflags! {
+	/// Flags of an Active Directory account, see
+	/// [`SearchEntryExt::user_account_control`]
+	#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+	pub struct UserAccountControl: u32 {
+		/// The logon script is run
+		const SCRIPT = 0x0000_0001;
+		/// The account is disabled
+		const ACCOUNTDISABLE = 0x0000_0002;
+		/// A home folder is required
+		const HOMEDIR_REQUIRED = 0x0000_0008;
+		/// The account is locked out
+		const LOCKOUT = 0x0000_0010;
+		/// No password is required
+		const PASSWD_NOTREQD = 0x0000_0020;
+		/// The user can't change the password
+		const PASSWD_CANT_CHANGE = 0x0000_0040;
+		/// The password may be stored with reversible encryption
+		const ENCRYPTED_TEXT_PWD_ALLOWED = 0x0000_0080;
+		/// An account for a user whose primary account is in another domain
+		const TEMP_DUPLICATE_ACCOUNT = 0x0000_0100;
+		/// A default account of a typical user
+		const NORMAL_ACCOUNT = 0x0000_0200;
+		/// An account of a domain trusting other domains
+		const INTERDOMAIN_TRUST_ACCOUNT = 0x0000_0800;
+		/// A computer account of a workstation or member server
+		const WORKSTATION_TRUST_ACCOUNT = 0x0000_1000;
+		/// A computer account of a domain controller
+		const SERVER_TRUST_ACCOUNT = 0x0000_2000;
+		/// The password never expires
+		const DONT_EXPIRE_PASSWORD = 0x0001_0000;
+		/// A Majority Node Set logon account
+		const MNS_LOGON_ACCOUNT = 0x0002_0000;
+		/// The user must log on with a smart card
+		const SMARTCARD_REQUIRED = 0x0004_0000;
+		/// The account is trusted for Kerberos delegation
+		const TRUSTED_FOR_DELEGATION = 0x0008_0000;
+		/// The security context of the user is never delegated
+		const NOT_DELEGATED = 0x0010_0000;
+		/// Only DES encryption types are used for keys
+		const USE_DES_KEY_ONLY = 0x0020_0000;
+		/// No Kerberos pre-authentication is required
+		const DONT_REQ_PREAUTH = 0x0040_0000;
+		/// The password has expired
+		const PASSWORD_EXPIRED = 0x0080_0000;
+		/// The account is trusted to authenticate for delegation
+		const TRUSTED_TO_AUTH_FOR_DELEGATION = 0x0100_0000;
+		/// A read-only domain controller
+		const PARTIAL_SECRETS_ACCOUNT = 0x0400_0000;
+	}
+}
+
+impl UserAccountControl {
+	/// The attribute holding the flags
+	pub const ATTRIBUTE: &'static str = "userAccountControl";
+
+	/// The constructed attribute holding the flags which are computed on
+	/// request, which has to be requested explicitly
+	pub const COMPUTED_ATTRIBUTE: &'static str = "msDS-User-Account-Control-Computed";
+}
+
+/// Mapping of search entries into typed structs, so that missing and invalid
+/// attributes are reported uniformly rather than checked at every call site.
+/// Implementations usually use the getters of [`SearchEntryExt`], e.g.
+///
+/// ```
+/// use ldap_poller::{
+/// 	entry::FromSearchEntry, error::Error, SearchEntry, SearchEntryExt,
+/// };
+///
+/// struct User {
+/// 	id: String,
+/// 	display_name: Option<String>,
+/// 	enabled: bool,
+/// }
+///
+/// impl FromSearchEntry for User {
+/// 	fn from_search_entry(entry: &SearchEntry) -> Result<Self, Error> {
+/// 		Ok(User {
+/// 			id: entry.required("objectGUID", SearchEntryExt::guid_first)?,
+/// 			display_name: entry
+/// 				.attr_first("displayName")
+/// 				.map(ToOwned::to_owned),
+/// 			enabled: entry
+/// 				.required("enabled", SearchEntryExt::bool_first)?,
+/// 		})
+/// 	}
+/// }
+/// ```
+pub trait FromSearchEntry: Sized {
+	/// Map an entry, failing with [`Error::MissingAttribute`] or
+	/// [`Error::Invalid`] if an attribute is missing or malformed
+	fn from_search_entry(entry: &SearchEntry) -> Result<Self, Error>;
+}
+
+/// Format a binary security identifier in its string form
+fn format_sid(bytes: &[u8]) -> Option<String> {
+	let (&[revision, count], rest) = bytes.split_first_chunk::<2>()?;
+	let (authority, sub_authorities) = rest.split_first_chunk::<6>()?;
+	if sub_authorities.len() != usize::from(count) * 4 {
+		return None;
+	}
+	let authority = authority.iter().fold(0_u64, |acc, byte| (acc << 8) | u64::from(*byte));
+	let mut sid = format!("S-{revision}-{authority}");
+	for sub_authority in sub_authorities.chunks_exact(4) {
+		let sub_authority = u32::from_le_bytes(sub_authority.try_into().ok()?);
+		sid.push_str(&format!("-{sub_authority}"));
+	}
+	Some(sid)
+}
+
+/// Parse a Generalized Time or FILETIME value
+fn parse_time(value: &str) -> Option<OffsetDateTime> {
+	if value.bytes().all(|byte| byte.is_ascii_digit()) {
+		/// Start of the FILETIME epoch
+		const FILETIME_EPOCH: OffsetDateTime = datetime!(1601-01-01 0:00 UTC);
+		let intervals: i64 = value.parse().ok()?;
+		if intervals == 0 || intervals == i64::MAX {
+			return None;
+		}
+		let duration = Duration::new(
+			intervals / 10_000_000,
+			i32::try_from(intervals % 10_000_000).ok()? * 100,
+		);
+		return FILETIME_EPOCH.checked_add(duration);
+	}
+	parse_generalized_time(value)
+}
+
+/// Parse a Generalized Time value (RFC 4517 section 3.3.13). Besides the
+/// common `YYYYMMDDHHMMSSZ` form, minutes and seconds may be omitted, the last
+/// present unit may have a fraction, and the time zone may be a numeric offset.
+fn parse_generalized_time(value: &str) -> Option<OffsetDateTime> {
+	/// The value, if it consists of digits only
+	fn digits(value: &str) -> Option<&str> {
+		(!value.is_empty() && value.bytes().all(|byte| byte.is_ascii_digit())).then_some(value)
+	}
+
+	// The value is sliced by byte positions below
+	if !value.is_ascii() {
+		return None;
+	}
+	let (time, offset) = match value.strip_suffix('Z') {
+		Some(time) => (time, UtcOffset::UTC),
+		None => {
+			let (time, offset) = value.split_at(value.rfind(['+', '-'])?);
+			let (sign, offset) = offset.split_at(1);
+			let (hours, minutes) = match offset.len() {
+				2 => (offset, "00"),
+				4 => offset.split_at(2),
+				_ => return None,
+			};
+			let hours = digits(hours)?.parse::<i8>().ok().filter(|hours| *hours < 24)?;
+			let minutes = digits(minutes)?.parse::<i8>().ok().filter(|minutes| *minutes < 60)?;
+			let sign = if sign == "-" { -1 } else { 1 };
+			(time, UtcOffset::from_hms(sign * hours, sign * minutes, 0).ok()?)
+		}
+	};
+	let (time, fraction) = match time.split_once(['.', ',']) {
+		Some((time, fraction)) => (time, Some(digits(fraction)?)),
+		None => (time, None),
+	};
+	if !matches!(time.len(), 10 | 12 | 14) {
+		return None;
+	}
+	let field = |range: std::ops::Range<usize>| -> Option<u8> {
+		time.get(range).map_or(Some(0), |field| digits(field)?.parse().ok())
+	};
+	let year = digits(&time[0..4])?.parse().ok()?;
+	let date =
+		Date::from_calendar_date(year, Month::try_from(field(4..6)?).ok()?, field(6..8)?).ok()?;
+	// A leap second is represented as the first second of the next minute
+	let (second, leap_second) = match field(12..14)? {
+		60 => (59, Duration::SECOND),
+		second => (second, Duration::ZERO),
+	};
+	let time_of_day = Time::from_hms(field(8..10)?, field(10..12)?, second).ok()?;
+	// The fraction applies to the last present unit
+	let unit_nanos: u128 = match time.len() {
+		10 => 3_600_000_000_000,
+		12 => 60_000_000_000,
+		_ => 1_000_000_000,
+	};
+	let fraction = fraction.map_or(Some(0), |fraction| {
+		let fraction = &fraction[..fraction.len().min(18)];
+		let nanos =
+			fraction.parse::<u128>().ok()? * unit_nanos / 10_u128.pow(fraction.len() as u32);
+		i64::try_from(nanos).ok()
+	})?;
+	let time = PrimitiveDateTime::new(date, time_of_day).assume_offset(offset);
+	Some((time + leap_second + Duration::nanoseconds(fraction)).to_offset(UtcOffset::UTC))
 }
 
 impl SearchEntryExt for SearchEntry {
@@ -40,15 +333,133 @@ impl SearchEntryExt for SearchEntry {
 		}
 		None
 	}
+
+	fn bin_attr_values(&self, attr: &str) -> Vec<&[u8]> {
+		bin_values(&self.attrs, &self.bin_attrs, attr)
+	}
+}
+
+/// All values of an attribute in the text or binary attributes of an entry,
+/// in binary form
+pub(crate) fn bin_values<'a>(
+	attrs: &'a HashMap<String, Vec<String>>,
+	bin_attrs: &'a HashMap<String, Vec<Vec<u8>>>,
+	attr: &str,
+) -> Vec<&'a [u8]> {
+	match (attrs.get(attr), bin_attrs.get(attr)) {
+		(Some(values), _) => values.iter().map(String::as_bytes).collect(),
+		(None, Some(values)) => values.iter().map(Vec::as_slice).collect(),
+		(None, None) => Vec::new(),
+	}
+}
+
+/// The attribute-level difference between two versions of an entry. Values
+/// are compared regardless of their order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EntryDiff {
+	/// Text attributes which only the new entry has, with their values
+	pub added: BTreeMap<String, Vec<String>>,
+	/// Text attributes which only the old entry has, with their values
+	pub removed: BTreeMap<String, Vec<String>>,
+	/// Text attributes whose values differ
+	pub modified: BTreeMap<String, ValueChange<String>>,
+	/// Binary attributes which only the new entry has, with their values
+	pub bin_added: BTreeMap<String, Vec<Vec<u8>>>,
+	/// Binary attributes which only the old entry has, with their values
+	pub bin_removed: BTreeMap<String, Vec<Vec<u8>>>,
+	/// Binary attributes whose values differ
+	pub bin_modified: BTreeMap<String, ValueChange<Vec<u8>>>,
+}
+
+/// The old and new values of a modified attribute
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[allow(missing_docs)]
+pub struct ValueChange<T> {
+	pub old: Vec<T>,
+	pub new: Vec<T>,
+}
+
+impl EntryDiff {
+	/// Compute the difference between the attributes of `old` and `new`
+	#[must_use]
+	pub fn new(old: &SearchEntry, new: &SearchEntry) -> Self {
+		let (added, removed, modified) = diff_attributes(&old.attrs, &new.attrs);
+		let (bin_added, bin_removed, bin_modified) =
+			diff_attributes(&old.bin_attrs, &new.bin_attrs);
+		Self { added, removed, modified, bin_added, bin_removed, bin_modified }
+	}
+
+	/// Whether no attribute differs
+	#[must_use]
+	pub fn is_empty(&self) -> bool {
+		self.added.is_empty()
+			&& self.removed.is_empty()
+			&& self.modified.is_empty()
+			&& self.bin_added.is_empty()
+			&& self.bin_removed.is_empty()
+			&& self.bin_modified.is_empty()
+	}
+}
+
+/// Added, removed and modified attributes between two attribute maps
+type AttributeDiff<T> =
+	(BTreeMap<String, Vec<T>>, BTreeMap<String, Vec<T>>, BTreeMap<String, ValueChange<T>>);
+
+/// Compare two attribute maps of an entry
+fn diff_attributes<T: Clone + Ord>(
+	old: &HashMap<String, Vec<T>>,
+	new: &HashMap<String, Vec<T>>,
+) -> AttributeDiff<T> {
+	let sorted = |values: &[T]| {
+		let mut values = values.to_vec();
+		values.sort_unstable();
+		values
+	};
+	let mut diff = AttributeDiff::default();
+	for (name, new_values) in new {
+		match old.get(name) {
+			None => {
+				diff.0.insert(name.clone(), new_values.clone());
+			}
+			Some(old_values) if sorted(old_values) != sorted(new_values) => {
+				diff.2.insert(
+					name.clone(),
+					ValueChange { old: old_values.clone(), new: new_values.clone() },
+				);
+			}
+			Some(_) => {}
+		}
+	}
+	for (name, old_values) in old {
+		if !new.contains_key(name) {
+			diff.1.insert(name.clone(), old_values.clone());
+		}
+	}
+	diff
+}
+
+impl EntryStatus {
+	/// The attribute-level difference of a [`EntryStatus::Changed`] entry.
+	/// `None` for other updates, or if the previous entry isn't known because
+	/// only hashes or modification times are cached.
+	#[must_use]
+	pub fn diff(&self) -> Option<EntryDiff> {
+		match self {
+			EntryStatus::Changed { old: Some(old), new } => Some(EntryDiff::new(old, new)),
+			_ => None,
+		}
+	}
 }
 
 #[cfg(test)]
 mod tests {
-	use std::collections::HashMap;
+	use std::{collections::HashMap, sync::Arc};
 
 	use ldap3::SearchEntry;
+	use time::macros::datetime;
 
-	use super::SearchEntryExt;
+	use super::{EntryDiff, FromSearchEntry, SearchEntryExt, UserAccountControl, ValueChange};
+	use crate::{error::Error, ldap::EntryStatus};
 
 	#[test]
 	fn attr_first() {
@@ -70,4 +481,258 @@ mod tests {
 		assert_eq!(entry.attr_first("name"), Some("Foo Bar"), "Should return the first value");
 		assert_ne!(entry.attr_first("name"), Some("Bar McBaz"), "Should return the correct value");
 	}
+
+	#[test]
+	fn guid_and_sid_first() {
+		let entry = SearchEntry {
+			dn: String::from("dontcare"),
+			attrs: HashMap::default(),
+			bin_attrs: [
+				(
+					String::from("objectGUID"),
+					vec![vec![
+						0x3f, 0x2b, 0x5c, 0x8d, 0x1a, 0x9e, 0x4b, 0x47, 0x8a, 0x0c, 0x12, 0x34,
+						0x56, 0x78, 0x9a, 0xbc,
+					]],
+				),
+				(
+					String::from("objectSid"),
+					vec![vec![
+						0x01, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05, 0x15, 0x00, 0x00, 0x00,
+						0xdc, 0xf4, 0xdc, 0x3b, 0x83, 0x3d, 0x2b, 0x46, 0x82, 0x8b, 0xa6, 0x28,
+						0x00, 0x02, 0x00, 0x00,
+					]],
+				),
+				(String::from("truncated"), vec![vec![0x01, 0x05, 0x00]]),
+			]
+			.into_iter()
+			.collect(),
+		};
+		assert_eq!(
+			entry.guid_first("objectGUID").map(Result::ok),
+			Some(Some(String::from("8d5c2b3f-9e1a-474b-8a0c-123456789abc")))
+		);
+		assert_eq!(
+			entry.sid_first("objectSid").map(Result::ok),
+			Some(Some(String::from("S-1-5-21-1004336348-1177238915-682003330-512")))
+		);
+		assert!(entry.guid_first("truncated").is_some_and(|guid| guid.is_err()));
+		assert!(entry.sid_first("truncated").is_some_and(|sid| sid.is_err()));
+		assert!(entry.sid_first("attribute_does_not_exist").is_none());
+	}
+
+	#[test]
+	fn user_account_control() {
+		let entry = |values: &[(&str, &str)]| SearchEntry {
+			dn: String::from("dontcare"),
+			attrs: values
+				.iter()
+				.map(|(attr, value)| (String::from(*attr), vec![String::from(*value)]))
+				.collect(),
+			bin_attrs: HashMap::default(),
+		};
+		assert_eq!(
+			entry(&[("userAccountControl", "514")]).user_account_control().map(Result::ok),
+			Some(Some(UserAccountControl::NORMAL_ACCOUNT | UserAccountControl::ACCOUNTDISABLE))
+		);
+		assert_eq!(
+			entry(&[
+				("userAccountControl", "66048"),
+				("msDS-User-Account-Control-Computed", "8388624"),
+			])
+			.user_account_control()
+			.map(Result::ok),
+			Some(Some(
+				UserAccountControl::NORMAL_ACCOUNT
+					| UserAccountControl::DONT_EXPIRE_PASSWORD
+					| UserAccountControl::LOCKOUT
+					| UserAccountControl::PASSWORD_EXPIRED
+			))
+		);
+		assert_eq!(
+			entry(&[("userAccountControl", "-2147483136")]).user_account_control().map(Result::ok),
+			Some(Some(UserAccountControl::from_bits_retain(0x8000_0200)))
+		);
+		assert!(entry(&[("userAccountControl", "yes")])
+			.user_account_control()
+			.is_some_and(|flags| flags.is_err()));
+		assert!(entry(&[]).user_account_control().is_none());
+	}
+
+	#[test]
+	fn time_first() {
+		let entry = SearchEntry {
+			dn: String::from("dontcare"),
+			attrs: [
+				(String::from("modifyTimestamp"), vec![String::from("20130516200520Z")]),
+				(String::from("whenChanged"), vec![String::from("20130516200520.0Z")]),
+				(String::from("pwdLastSet"), vec![String::from("130132083200000000")]),
+				(String::from("accountExpires"), vec![String::from("9223372036854775807")]),
+				(String::from("name"), vec![String::from("Foo Bar")]),
+			]
+			.into_iter()
+			.collect(),
+			bin_attrs: HashMap::default(),
+		};
+		let expected = datetime!(2013-05-16 20:05:20 UTC);
+		for (value, time) in [
+			("201305162005Z", datetime!(2013-05-16 20:05 UTC)),
+			("2013051620Z", datetime!(2013-05-16 20:00 UTC)),
+			("20130516200520.5Z", datetime!(2013-05-16 20:05:20.5 UTC)),
+			("20130516200520,25Z", datetime!(2013-05-16 20:05:20.25 UTC)),
+			("2013051620.5Z", datetime!(2013-05-16 20:30 UTC)),
+			("201305162005.5Z", datetime!(2013-05-16 20:05:30 UTC)),
+			("20130516220520+0200", expected),
+			("20130516150520.000-05", expected),
+			("20161231235960Z", datetime!(2017-01-01 0:00 UTC)),
+		] {
+			assert_eq!(super::parse_time(value), Some(time), "{value}");
+		}
+		for value in [
+			"20130516200520.Z",
+			"20131316200520Z",
+			"20130516200520+2",
+			"2013051620052Z",
+			"20130516200520+2400",
+			"2013-05-16T20:05:20Z",
+			"000é00000Z",
+			"20130516200520+1é1",
+		] {
+			assert_eq!(super::parse_time(value), None, "{value}");
+		}
+		assert_eq!(entry.time_first("attribute_does_not_exist").map(Result::ok), None);
+		assert_eq!(entry.time_first("modifyTimestamp").map(Result::ok), Some(Some(expected)));
+		assert_eq!(entry.time_first("whenChanged").map(Result::ok), Some(Some(expected)));
+		assert_eq!(entry.time_first("pwdLastSet").map(Result::ok), Some(Some(expected)));
+		assert!(entry.time_first("accountExpires").is_some_and(|time| time.is_err()));
+		assert!(entry.time_first("name").is_some_and(|time| time.is_err()));
+	}
+
+	#[test]
+	fn diff() {
+		let old = SearchEntry {
+			dn: String::from("cn=foo"),
+			attrs: [
+				(String::from("cn"), vec![String::from("foo")]),
+				(String::from("mail"), vec![String::from("a@x"), String::from("b@x")]),
+				(String::from("enabled"), vec![String::from("TRUE")]),
+				(String::from("title"), vec![String::from("Boss")]),
+			]
+			.into_iter()
+			.collect(),
+			bin_attrs: [(String::from("photo"), vec![vec![1]])].into_iter().collect(),
+		};
+		let mut new = old.clone();
+		new.attrs.insert(String::from("mail"), vec![String::from("b@x"), String::from("a@x")]);
+		new.attrs.insert(String::from("enabled"), vec![String::from("FALSE")]);
+		new.attrs.remove("title");
+		new.attrs.insert(String::from("sn"), vec![String::from("Foo")]);
+		new.bin_attrs.insert(String::from("photo"), vec![vec![2]]);
+
+		let diff = EntryDiff::new(&old, &new);
+		assert_eq!(diff.added, [(String::from("sn"), vec![String::from("Foo")])].into());
+		assert_eq!(diff.removed, [(String::from("title"), vec![String::from("Boss")])].into());
+		assert_eq!(
+			diff.modified,
+			[(
+				String::from("enabled"),
+				ValueChange { old: vec![String::from("TRUE")], new: vec![String::from("FALSE")] }
+			)]
+			.into(),
+			"Reordered values should not be considered modified"
+		);
+		assert_eq!(
+			diff.bin_modified,
+			[(String::from("photo"), ValueChange { old: vec![vec![1]], new: vec![vec![2]] })]
+				.into()
+		);
+		assert!(diff.bin_added.is_empty() && diff.bin_removed.is_empty());
+		assert!(EntryDiff::new(&old, &old).is_empty());
+
+		let (old, new) = (Arc::new(old), Arc::new(new));
+		let changed = EntryStatus::Changed { old: Some(old.clone()), new: new.clone() };
+		assert_eq!(changed.diff(), Some(diff));
+		assert_eq!(EntryStatus::Changed { old: None, new }.diff(), None);
+		assert_eq!(EntryStatus::New(old).diff(), None);
+	}
+
+	#[test]
+	fn from_search_entry() {
+		#[derive(Debug)]
+		struct User {
+			name: String,
+			enabled: bool,
+			uid_number: u32,
+		}
+
+		impl FromSearchEntry for User {
+			fn from_search_entry(entry: &SearchEntry) -> Result<Self, Error> {
+				Ok(User {
+					name: entry
+						.required("cn", |entry, attr| entry.attr_first(attr).map(Ok))?
+						.to_owned(),
+					enabled: entry.required("enabled", SearchEntryExt::bool_first)?,
+					uid_number: entry.required("uidNumber", SearchEntryExt::parse_first)?,
+				})
+			}
+		}
+
+		let mut entry = SearchEntry {
+			dn: String::from("dontcare"),
+			attrs: [
+				(String::from("cn"), vec![String::from("foo")]),
+				(String::from("enabled"), vec![String::from("TRUE")]),
+				(String::from("uidNumber"), vec![String::from("1000")]),
+			]
+			.into_iter()
+			.collect(),
+			bin_attrs: HashMap::default(),
+		};
+		assert!(matches!(
+			User::from_search_entry(&entry),
+			Ok(User { name, enabled: true, uid_number: 1000 }) if name == "foo"
+		));
+
+		entry.attrs.insert(String::from("uidNumber"), vec![String::from("foo")]);
+		assert!(matches!(
+			User::from_search_entry(&entry),
+			Err(Error::Invalid(attr)) if attr == "uidNumber"
+		));
+
+		entry.attrs.remove("enabled");
+		assert!(matches!(
+			User::from_search_entry(&entry),
+			Err(Error::MissingAttribute(attr)) if attr == "enabled"
+		));
+	}
+
+	#[test]
+	fn bin_attr_values() {
+		/// An implementation only providing the required methods
+		struct Single(Vec<u8>);
+
+		impl SearchEntryExt for Single {
+			fn attr_first(&self, _attr: &str) -> Option<&str> {
+				std::str::from_utf8(&self.0).ok()
+			}
+
+			fn bin_attr_first(&self, _attr: &str) -> Option<&[u8]> {
+				Some(&self.0)
+			}
+		}
+
+		assert_eq!(Single(b"foo".to_vec()).bin_attr_values("cn"), [b"foo"]);
+
+		let entry = SearchEntry {
+			dn: String::from("dontcare"),
+			attrs: HashMap::from([(
+				String::from("cn"),
+				vec![String::from("a"), String::from("b")],
+			)]),
+			bin_attrs: HashMap::from([(String::from("objectGUID"), vec![vec![1], vec![2]])]),
+		};
+		assert_eq!(entry.bin_attr_values("cn"), [b"a", b"b"]);
+		assert_eq!(entry.bin_attr_values("objectGUID"), [[1], [2]]);
+		assert!(entry.bin_attr_values("mail").is_empty());
+	}
 }
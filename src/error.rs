@@ -6,6 +6,10 @@ pub enum Error {
 	/// A required attribute in a search result was missing.
 	#[error("Missing data")]
 	Missing,
+	/// A required attribute of an entry was missing, see
+	/// [`crate::entry::SearchEntryExt::required`].
+	#[error("Missing attribute: {0}")]
+	MissingAttribute(String),
 	/// The contents of an attribute did not confirm to the expected syntax.
 	#[error("Malformed data: {0}")]
 	Invalid(String),
@@ -18,7 +22,82 @@ pub enum Error {
 	#[error(transparent)]
 	Io(#[from] std::io::Error),
 
+	/// The cache could not be serialized or deserialized.
+	#[error("Cache codec error: {0}")]
+	Codec(String),
+
+	/// A persisted cache was written in a snapshot format version that this
+	/// version of the crate can't read.
+	#[error("Incompatible cache snapshot version {found}, supported is {supported}")]
+	IncompatibleCache {
+		/// Version of the snapshot
+		found: u32,
+		/// Version supported by this crate
+		supported: u32,
+	},
+
+	/// A line of an audit log was written in a format version that this
+	/// version of the crate can't read, see [`crate::audit::AUDIT_VERSION`]
+	#[error("Incompatible audit log version {found}, supported is {supported}")]
+	IncompatibleAuditLog {
+		/// Version of the line
+		found: u32,
+		/// Version supported by this crate
+		supported: u32,
+	},
+
+	/// A cache can't be converted to the requested cache method, because it
+	/// doesn't store the data needed for it.
+	#[error("Can't convert a {from:?} cache to {to:?}")]
+	CacheConversion {
+		/// Cache method of the cache
+		from: crate::config::CacheMethod,
+		/// Requested cache method
+		to: crate::config::CacheMethod,
+	},
+
+	/// A persisted cache was built with a configuration whose entries can't
+	/// be compared against the current one, see
+	/// [`crate::Cache::migrate`].
+	#[error("The cache doesn't match the configuration: {0}")]
+	IncompatibleCacheConfig(String),
+
+	/// The credentials could not be retrieved from the configured
+	/// [`crate::credentials::CredentialsProvider`].
+	#[error("Failed to get credentials: {0}")]
+	Credentials(#[source] Box<dyn std::error::Error + Send + Sync>),
+
+	/// A sync cycle didn't finish before its deadline, see
+	/// [`crate::ldap::SyncOptions::deadline`].
+	#[error("The sync cycle didn't finish within {0:?}")]
+	Deadline(std::time::Duration),
+
 	/// An underlying Rustls error occurred.
 	#[error(transparent)]
 	Rustls(#[from] rustls::Error),
 }
+
+impl Error {
+	/// Whether the error is likely transient, so that retrying the failed
+	/// operation later may succeed
+	#[must_use]
+	pub fn is_retryable(&self) -> bool {
+		match self {
+			Error::Io(_) => true,
+			Error::Ldap(err) => match err {
+				ldap3::LdapError::Io { .. }
+				| ldap3::LdapError::Timeout { .. }
+				| ldap3::LdapError::EndOfStream
+				| ldap3::LdapError::OpSend { .. }
+				| ldap3::LdapError::ResultRecv { .. } => true,
+				// busy, unavailable, unwillingToPerform, adminLimitExceeded,
+				// timeLimitExceeded
+				ldap3::LdapError::LdapResult { result } => {
+					matches!(result.rc, 51 | 52 | 53 | 11 | 3)
+				}
+				_ => false,
+			},
+			_ => false,
+		}
+	}
+}
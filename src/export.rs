@@ -0,0 +1,257 @@
+//! Converting entries to formats which other tools understand, e.g. to hand
+//! emitted entries to downstream systems or to inspect them while debugging
+//!
+//! Both formats are canonical: attributes are sorted by name, so the same
+//! entry is always exported the same way. Binary attributes are base64
+//! encoded.
+use std::{
+	collections::{BTreeMap, HashMap},
+	io::Write,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ldap3::SearchEntry;
+use serde::{Deserialize, Serialize};
+
+use crate::{cache::SerializedSearchEntry, error::Error};
+
+/// The maximum length of an LDIF line before it is folded
+const LDIF_LINE_LENGTH: usize = 76;
+
+/// An entry with sorted attributes, which serializes to JSON with base64
+/// encoded binary values, and can be written as LDIF
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportedEntry {
+	/// Entry DN
+	pub dn: String,
+	/// Attributes with text values
+	#[serde(default)]
+	pub attrs: BTreeMap<String, Vec<String>>,
+	/// Attributes with binary values, base64 encoded in JSON
+	#[serde(default, with = "base64_values")]
+	pub bin_attrs: BTreeMap<String, Vec<Vec<u8>>>,
+}
+
+impl ExportedEntry {
+	/// Sort the attributes of an entry
+	fn new(
+		dn: &str,
+		attrs: &HashMap<String, Vec<String>>,
+		bin_attrs: &HashMap<String, Vec<Vec<u8>>>,
+	) -> Self {
+		ExportedEntry {
+			dn: dn.to_owned(),
+			attrs: attrs.iter().map(|(name, values)| (name.clone(), values.clone())).collect(),
+			bin_attrs: bin_attrs
+				.iter()
+				.map(|(name, values)| (name.clone(), values.clone()))
+				.collect(),
+		}
+	}
+
+	/// Serialize the entry as a JSON object with the fields `dn`, `attrs`
+	/// and `bin_attrs`
+	pub fn to_json(&self) -> Result<String, Error> {
+		serde_json::to_string(self).map_err(|err| Error::Codec(err.to_string()))
+	}
+
+	/// Write the entry as an LDIF record (RFC 2849), without the trailing
+	/// empty line separating it from the next record. Values which aren't
+	/// safe to write as they are, and all binary values, are base64
+	/// encoded, and long lines are folded.
+	#[must_use]
+	pub fn to_ldif(&self) -> String {
+		let mut ldif = String::new();
+		push_ldif_line(&mut ldif, "dn", self.dn.as_bytes(), !is_safe_string(&self.dn));
+		let mut attrs: Vec<_> = self
+			.attrs
+			.iter()
+			.flat_map(|(name, values)| {
+				values.iter().map(move |value| (name, value.as_bytes(), !is_safe_string(value)))
+			})
+			.chain(self.bin_attrs.iter().flat_map(|(name, values)| {
+				values.iter().map(move |value| (name, value.as_slice(), true))
+			}))
+			.collect();
+		// Stable, so values keep their order within an attribute
+		attrs.sort_by_key(|(name, ..)| *name);
+		for (name, value, encode) in attrs {
+			push_ldif_line(&mut ldif, name, value, encode);
+		}
+		ldif
+	}
+}
+
+impl From<&SearchEntry> for ExportedEntry {
+	fn from(entry: &SearchEntry) -> Self {
+		ExportedEntry::new(&entry.dn, &entry.attrs, &entry.bin_attrs)
+	}
+}
+
+impl From<&SerializedSearchEntry> for ExportedEntry {
+	fn from(entry: &SerializedSearchEntry) -> Self {
+		ExportedEntry::new(&entry.dn, &entry.attrs, &entry.bin_attrs)
+	}
+}
+
+impl From<ExportedEntry> for SearchEntry {
+	fn from(entry: ExportedEntry) -> Self {
+		SearchEntry {
+			dn: entry.dn,
+			attrs: entry.attrs.into_iter().collect(),
+			bin_attrs: entry.bin_attrs.into_iter().collect(),
+		}
+	}
+}
+
+/// Serialize an entry as JSON, see [`ExportedEntry::to_json`]
+pub fn to_json(entry: &SearchEntry) -> Result<String, Error> {
+	ExportedEntry::from(entry).to_json()
+}
+
+/// Write an entry as an LDIF record, see [`ExportedEntry::to_ldif`]
+#[must_use]
+pub fn to_ldif(entry: &SearchEntry) -> String {
+	ExportedEntry::from(entry).to_ldif()
+}
+
+/// Write entries as an LDIF file, starting with a version line and
+/// separating the records by empty lines
+pub fn write_ldif<'a>(
+	mut writer: impl Write,
+	entries: impl IntoIterator<Item = &'a SearchEntry>,
+) -> Result<(), Error> {
+	writer.write_all(b"version: 1\n")?;
+	for entry in entries {
+		writer.write_all(b"\n")?;
+		writer.write_all(to_ldif(entry).as_bytes())?;
+	}
+	Ok(())
+}
+
+/// Whether a value can be written to LDIF as it is, i.e. is a `SAFE-STRING`
+/// of RFC 2849. Values with trailing spaces are encoded as well, since
+/// readers commonly strip them.
+fn is_safe_string(value: &str) -> bool {
+	!value.starts_with([' ', ':', '<'])
+		&& !value.ends_with(' ')
+		&& value.bytes().all(|byte| byte.is_ascii() && !matches!(byte, b'\0' | b'\n' | b'\r'))
+}
+
+/// Append an attribute line to an LDIF record, folding it after
+/// [`LDIF_LINE_LENGTH`] characters
+fn push_ldif_line(ldif: &mut String, name: &str, value: &[u8], encode: bool) {
+	let line = if encode {
+		format!("{name}:: {}", STANDARD.encode(value))
+	} else {
+		format!("{name}: {}", String::from_utf8_lossy(value))
+	};
+	// Fold by characters, in case an attribute name isn't ASCII
+	let mut chars = line.chars().peekable();
+	let mut width = LDIF_LINE_LENGTH;
+	while chars.peek().is_some() {
+		ldif.extend(chars.by_ref().take(width));
+		ldif.push('\n');
+		if chars.peek().is_some() {
+			ldif.push(' ');
+			width = LDIF_LINE_LENGTH - 1;
+		}
+	}
+}
+
+/// (De)serialization of binary attribute values as base64 strings
+mod base64_values {
+	use std::collections::BTreeMap;
+
+	use base64::{engine::general_purpose::STANDARD, Engine};
+	use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+	/// Serialize the values as base64 strings
+	pub(super) fn serialize<S: Serializer>(
+		attrs: &BTreeMap<String, Vec<Vec<u8>>>,
+		serializer: S,
+	) -> Result<S::Ok, S::Error> {
+		attrs
+			.iter()
+			.map(|(name, values)| {
+				(name, values.iter().map(|value| STANDARD.encode(value)).collect::<Vec<_>>())
+			})
+			.collect::<BTreeMap<_, _>>()
+			.serialize(serializer)
+	}
+
+	/// Deserialize values from base64 strings
+	pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+		deserializer: D,
+	) -> Result<BTreeMap<String, Vec<Vec<u8>>>, D::Error> {
+		BTreeMap::<String, Vec<String>>::deserialize(deserializer)?
+			.into_iter()
+			.map(|(name, values)| {
+				let values = values
+					.iter()
+					.map(|value| STANDARD.decode(value).map_err(D::Error::custom))
+					.collect::<Result<_, _>>()?;
+				Ok((name, values))
+			})
+			.collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+
+	use ldap3::SearchEntry;
+
+	use super::{to_ldif, write_ldif, ExportedEntry};
+
+	/// An entry with values which need to be encoded
+	fn entry() -> SearchEntry {
+		SearchEntry {
+			dn: "cn=Jürgen,ou=people,dc=example,dc=com".to_owned(),
+			attrs: HashMap::from([
+				("sn".to_owned(), vec!["Müller".to_owned()]),
+				("cn".to_owned(), vec!["Jürgen".to_owned(), "jm".to_owned()]),
+				("description".to_owned(), vec!["x".repeat(80)]),
+				("title".to_owned(), vec![" padded".to_owned()]),
+			]),
+			bin_attrs: HashMap::from([("objectGUID".to_owned(), vec![vec![0, 1, 2, 255]])]),
+		}
+	}
+
+	#[test]
+	fn ldif() -> Result<(), Box<dyn std::error::Error>> {
+		let expected = [
+			"dn:: Y249SsO8cmdlbixvdT1wZW9wbGUsZGM9ZXhhbXBsZSxkYz1jb20=",
+			"cn:: SsO8cmdlbg==",
+			"cn: jm",
+			&format!("description: {}", "x".repeat(63)),
+			&format!(" {}", "x".repeat(17)),
+			"objectGUID:: AAEC/w==",
+			"sn:: TcO8bGxlcg==",
+			"title:: IHBhZGRlZA==",
+			"",
+		]
+		.join("\n");
+		assert_eq!(to_ldif(&entry()), expected);
+
+		let mut file = Vec::new();
+		write_ldif(&mut file, [&entry(), &entry()])?;
+		assert_eq!(String::from_utf8(file)?, format!("version: 1\n\n{expected}\n{expected}"));
+		Ok(())
+	}
+
+	#[test]
+	fn json() -> Result<(), Box<dyn std::error::Error>> {
+		let exported = ExportedEntry::from(&entry());
+		let json = exported.to_json()?;
+		let value: serde_json::Value = serde_json::from_str(&json)?;
+		assert_eq!(value["bin_attrs"]["objectGUID"][0], "AAEC/w==");
+		assert_eq!(value["attrs"]["cn"], serde_json::json!(["Jürgen", "jm"]));
+
+		let parsed: ExportedEntry = serde_json::from_str(&json)?;
+		assert_eq!(parsed, exported);
+		assert_eq!(ExportedEntry::from(&SearchEntry::from(parsed)), exported);
+		Ok(())
+	}
+}
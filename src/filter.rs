@@ -0,0 +1,204 @@
+//! Construction of LDAP search filters with [RFC 4515] escaping.
+//!
+//! [RFC 4515]: https://www.rfc-editor.org/rfc/rfc4515.html
+use std::fmt::Write;
+
+use crate::error::Error;
+
+/// Escape a string for use as an assertion value in a search filter
+#[must_use]
+pub fn escape_value(value: &str) -> String {
+	ldap3::ldap_escape(value).into_owned()
+}
+
+/// Escape a binary value (such as an `objectGUID`) for use as an assertion
+/// value in a search filter. Every byte is escaped.
+#[must_use]
+pub fn escape_bytes(value: &[u8]) -> String {
+	value.iter().fold(String::with_capacity(value.len() * 3), |mut escaped, byte| {
+		let _ = write!(escaped, "\\{byte:02x}");
+		escaped
+	})
+}
+
+/// Builder for search filters. Assertion values are escaped, attribute names
+/// are validated when building the filter, and raw filters (e.g. from the
+/// configuration) are parsed before use, so composed filters can't be
+/// malformed or injected into.
+///
+/// ```
+/// use ldap_poller::filter::FilterBuilder;
+///
+/// let filter = FilterBuilder::raw("(objectClass=person)")?
+/// 	.and(FilterBuilder::eq("mail", "*@example.com"))
+/// 	.build()?;
+/// assert_eq!(filter, r"(&(objectClass=person)(mail=\2a@example.com))");
+/// # Ok::<(), ldap_poller::error::Error>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct FilterBuilder(Node);
+
+/// A node of the filter tree
+#[derive(Debug, Clone)]
+enum Node {
+	/// A complete, already validated filter
+	Raw(String),
+	/// Comparison of an attribute with an escaped value using the given
+	/// operator
+	Compare(String, &'static str, String),
+	/// Presence of an attribute
+	Present(String),
+	/// All filters match
+	And(Vec<FilterBuilder>),
+	/// Any filter matches
+	Or(Vec<FilterBuilder>),
+	/// The filter doesn't match
+	Not(Box<FilterBuilder>),
+}
+
+impl FilterBuilder {
+	/// Use an existing filter, which is validated to be well-formed
+	pub fn raw(filter: &str) -> Result<Self, Error> {
+		ldap3::parse_filter(filter)
+			.map_err(|()| Error::Invalid(format!("Malformed search filter: {filter}")))?;
+		Ok(Self(Node::Raw(filter.to_owned())))
+	}
+
+	/// Match entries where the attribute equals the value
+	#[must_use]
+	pub fn eq(attr: &str, value: &str) -> Self {
+		Self(Node::Compare(attr.to_owned(), "=", escape_value(value)))
+	}
+
+	/// Match entries where the attribute equals the binary value
+	#[must_use]
+	pub fn eq_bytes(attr: &str, value: &[u8]) -> Self {
+		Self(Node::Compare(attr.to_owned(), "=", escape_bytes(value)))
+	}
+
+	/// Match entries where the attribute is greater than or equal to the value
+	#[must_use]
+	pub fn ge(attr: &str, value: &str) -> Self {
+		Self(Node::Compare(attr.to_owned(), ">=", escape_value(value)))
+	}
+
+	/// Match entries where the attribute is less than or equal to the value
+	#[must_use]
+	pub fn le(attr: &str, value: &str) -> Self {
+		Self(Node::Compare(attr.to_owned(), "<=", escape_value(value)))
+	}
+
+	/// Match entries which have the attribute
+	#[must_use]
+	pub fn present(attr: &str) -> Self {
+		Self(Node::Present(attr.to_owned()))
+	}
+
+	/// Match entries matching both this and the other filter
+	#[must_use]
+	pub fn and(self, other: Self) -> Self {
+		match self {
+			Self(Node::And(mut filters)) => {
+				filters.push(other);
+				Self(Node::And(filters))
+			}
+			filter => Self(Node::And(vec![filter, other])),
+		}
+	}
+
+	/// Match entries matching either this or the other filter
+	#[must_use]
+	pub fn or(self, other: Self) -> Self {
+		match self {
+			Self(Node::Or(mut filters)) => {
+				filters.push(other);
+				Self(Node::Or(filters))
+			}
+			filter => Self(Node::Or(vec![filter, other])),
+		}
+	}
+
+	/// Match entries not matching this filter
+	#[must_use]
+	pub fn negate(self) -> Self {
+		Self(Node::Not(Box::new(self)))
+	}
+
+	/// Build the string representation of the filter, validating the used
+	/// attribute names
+	pub fn build(&self) -> Result<String, Error> {
+		let mut filter = String::new();
+		self.write(&mut filter)?;
+		Ok(filter)
+	}
+
+	/// Write the string representation of the filter
+	fn write(&self, out: &mut String) -> Result<(), Error> {
+		match &self.0 {
+			Node::Raw(filter) => out.push_str(filter),
+			Node::Compare(attr, op, value) => {
+				validate_attribute(attr)?;
+				let _ = write!(out, "({attr}{op}{value})");
+			}
+			Node::Present(attr) => {
+				validate_attribute(attr)?;
+				let _ = write!(out, "({attr}=*)");
+			}
+			Node::And(filters) | Node::Or(filters) => {
+				out.push_str(if matches!(self.0, Node::And(_)) { "(&" } else { "(|" });
+				for filter in filters {
+					filter.write(out)?;
+				}
+				out.push(')');
+			}
+			Node::Not(filter) => {
+				out.push_str("(!");
+				filter.write(out)?;
+				out.push(')');
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Check that an attribute description only consists of the characters
+/// allowed by RFC 4512 (descriptors, numeric OIDs and options)
+fn validate_attribute(attr: &str) -> Result<(), Error> {
+	if !attr.is_empty()
+		&& attr.bytes().all(|byte| byte.is_ascii_alphanumeric() || b"-.;".contains(&byte))
+	{
+		Ok(())
+	} else {
+		Err(Error::Invalid(format!("Invalid attribute name in search filter: {attr}")))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{escape_bytes, escape_value, FilterBuilder};
+
+	#[test]
+	fn escaping() {
+		assert_eq!(escape_value("a*(b)\\c\0"), r"a\2a\28b\29\5cc\00");
+		assert_eq!(escape_value("plain"), "plain");
+		assert_eq!(escape_bytes(&[0x00, 0xab, 0x2a]), r"\00\ab\2a");
+	}
+
+	#[test]
+	fn builder() -> Result<(), Box<dyn std::error::Error>> {
+		let filter = FilterBuilder::raw("(objectClass=inetOrgPerson)")?
+			.and(FilterBuilder::ge("modifyTimestamp", "20230516200520Z"))
+			.and(FilterBuilder::eq("cn", "foo)(uid=*").negate())
+			.and(FilterBuilder::present("mail").or(FilterBuilder::eq_bytes("objectGUID", &[1])));
+		assert_eq!(
+			filter.build()?,
+			r"(&(objectClass=inetOrgPerson)(modifyTimestamp>=20230516200520Z)(!(cn=foo\29\28uid=\2a))(|(mail=*)(objectGUID=\01)))"
+		);
+		assert!(ldap3::parse_filter(filter.build()?).is_ok());
+
+		assert!(FilterBuilder::raw("(objectClass=inetOrgPerson").is_err());
+		assert!(FilterBuilder::eq("cn=foo)(uid", "bar").build().is_err());
+
+		Ok(())
+	}
+}
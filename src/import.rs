@@ -0,0 +1,206 @@
+//! Seeding the cache from identity mappings exported by other tools
+//!
+//! When migrating from another identity sync tool, the poller would otherwise
+//! start with an empty cache and report every entry as new. Importing the
+//! entries known to the previous tool avoids this. For entries to be
+//! considered unchanged on the first sync, the export has to contain the
+//! modification time attribute and all tracked attributes, see
+//! [`AttributeConfig`].
+use std::{collections::HashMap, io::Read};
+
+use ldap3::SearchEntry;
+use serde::Deserialize;
+
+use crate::{
+	cache::Cache,
+	config::{AttributeConfig, CacheMethod},
+	error::Error,
+};
+
+/// An entry of an exported identity mapping
+#[derive(Debug, Clone, Deserialize)]
+pub struct IdentityRecord {
+	/// The persistent ID of the entry, encoded as given by [`PidEncoding`]
+	pub pid: String,
+	/// The DN of the entry
+	pub dn: String,
+	/// Values of further attributes of the entry
+	#[serde(default)]
+	pub attributes: HashMap<String, Vec<String>>,
+}
+
+/// How persistent IDs are encoded in an export
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PidEncoding {
+	/// The persistent ID is a string attribute, e.g. `entryUUID`
+	#[default]
+	Text,
+	/// The persistent ID is a binary attribute, e.g. `objectGUID`, encoded
+	/// as hexadecimal digits
+	Hex,
+}
+
+/// Read identity records from a JSON array of objects with the fields of
+/// [`IdentityRecord`]
+pub fn read_json(reader: impl Read) -> Result<Vec<IdentityRecord>, Error> {
+	serde_json::from_reader(reader).map_err(|err| Error::Codec(err.to_string()))
+}
+
+/// Read identity records from CSV. The header row must contain `pid` and `dn`
+/// columns, all other columns are read as single-valued attributes named
+/// after their header. Empty cells are treated as absent attributes.
+pub fn read_csv(reader: impl Read) -> Result<Vec<IdentityRecord>, Error> {
+	let mut reader = csv::Reader::from_reader(reader);
+	let headers = reader.headers().map_err(|err| Error::Codec(err.to_string()))?.clone();
+	let column = |name: &str| {
+		headers
+			.iter()
+			.position(|header| header == name)
+			.ok_or_else(|| Error::Codec(format!("Missing column `{name}`")))
+	};
+	let (pid, dn) = (column("pid")?, column("dn")?);
+
+	reader
+		.records()
+		.map(|record| {
+			let record = record.map_err(|err| Error::Codec(err.to_string()))?;
+			let attributes = headers
+				.iter()
+				.zip(record.iter())
+				.enumerate()
+				.filter(|(index, (_, value))| *index != pid && *index != dn && !value.is_empty())
+				.map(|(_, (header, value))| (header.to_owned(), vec![value.to_owned()]))
+				.collect();
+			Ok(IdentityRecord {
+				pid: record.get(pid).unwrap_or_default().to_owned(),
+				dn: record.get(dn).unwrap_or_default().to_owned(),
+				attributes,
+			})
+		})
+		.collect()
+}
+
+/// Create a cache for the given [`crate::config::Config::cache_method`] and
+/// [`crate::config::Config::attributes`] containing the given records, to pass
+/// to [`crate::ldap::Ldap::new`]. The first sync is always a full search, so
+/// entries missing from the directory are reported as removed if
+/// `check_for_deleted_entries` is enabled.
+pub fn seed_cache(
+	cache_method: &CacheMethod,
+	attributes_config: &AttributeConfig,
+	pid_encoding: PidEncoding,
+	records: impl IntoIterator<Item = IdentityRecord>,
+) -> Result<Cache, Error> {
+	let entries = records
+		.into_iter()
+		.map(|record| to_search_entry(record, pid_encoding, attributes_config))
+		.collect::<Result<Vec<_>, _>>()?;
+	Cache::from_entries(cache_method, attributes_config, entries)
+}
+
+/// Convert a record to the search entry the directory would return for it
+fn to_search_entry(
+	record: IdentityRecord,
+	pid_encoding: PidEncoding,
+	attributes_config: &AttributeConfig,
+) -> Result<SearchEntry, Error> {
+	let mut entry =
+		SearchEntry { dn: record.dn, attrs: record.attributes, bin_attrs: HashMap::new() };
+	match pid_encoding {
+		PidEncoding::Text => {
+			entry.attrs.insert(attributes_config.pid.clone(), vec![record.pid]);
+		}
+		PidEncoding::Hex => {
+			entry.attrs.remove(&attributes_config.pid);
+			entry.bin_attrs.insert(attributes_config.pid.clone(), vec![decode_hex(&record.pid)?]);
+		}
+	}
+	Ok(entry)
+}
+
+/// Decode a string of hexadecimal digits
+fn decode_hex(hex: &str) -> Result<Vec<u8>, Error> {
+	if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+		return Err(Error::Invalid(format!("Invalid hexadecimal persistent ID: {hex}")));
+	}
+	(0..hex.len())
+		.step_by(2)
+		.map(|index| {
+			u8::from_str_radix(&hex[index..index + 2], 16)
+				.map_err(|_| Error::Invalid(format!("Invalid hexadecimal persistent ID: {hex}")))
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	#![allow(clippy::unwrap_used)]
+
+	use std::collections::HashMap;
+
+	use ldap3::SearchEntry;
+
+	use super::{read_csv, read_json, PidEncoding};
+	use crate::{
+		cache::CacheEntryStatus,
+		config::{AttributeConfig, CacheMethod},
+	};
+
+	#[test]
+	fn read_records() -> Result<(), Box<dyn std::error::Error>> {
+		let csv = "dn,pid,enabled,admin\n\"uid=foo,dc=example,dc=com\",0a0b,yes,\n";
+		let json =
+			r#"[{"pid":"0a0b","dn":"uid=foo,dc=example,dc=com","attributes":{"enabled":["yes"]}}]"#;
+
+		for records in [read_csv(csv.as_bytes()), read_json(json.as_bytes())] {
+			let record = records?.pop().unwrap();
+			assert_eq!(record.pid, "0a0b");
+			assert_eq!(record.dn, "uid=foo,dc=example,dc=com");
+			assert_eq!(
+				record.attributes,
+				HashMap::from([("enabled".to_owned(), vec!["yes".to_owned()])]),
+				"Empty cells should be skipped"
+			);
+		}
+
+		assert!(read_csv("dn,enabled\n".as_bytes()).is_err(), "The pid column is required");
+
+		Ok(())
+	}
+
+	#[test]
+	fn seed_cache() -> Result<(), Box<dyn std::error::Error>> {
+		let attributes = AttributeConfig::example();
+		let records =
+			read_csv("pid,dn,enabled,mtime\n0a0b,uid=foo,yes,20231019182736Z\n".as_bytes())?;
+
+		let mut cache = super::seed_cache(
+			&CacheMethod::ModificationTime,
+			&attributes,
+			PidEncoding::Hex,
+			records,
+		)?;
+		let mut entry = SearchEntry {
+			dn: "uid=foo".to_owned(),
+			attrs: HashMap::from([
+				("enabled".to_owned(), vec!["yes".to_owned()]),
+				("mtime".to_owned(), vec!["20231019182736Z".to_owned()]),
+			]),
+			bin_attrs: HashMap::from([(attributes.pid.clone(), vec![vec![10, 11]])]),
+		};
+		assert_eq!(
+			cache.check_entry(&entry, &attributes)?,
+			CacheEntryStatus::Unchanged,
+			"Imported entries should be known to the cache"
+		);
+
+		entry.bin_attrs.insert(attributes.pid.clone(), vec![vec![10, 12]]);
+		assert_eq!(
+			cache.check_entry(&entry, &attributes)?,
+			CacheEntryStatus::Missing,
+			"Other entries should still be new"
+		);
+
+		Ok(())
+	}
+}
@@ -1,190 +1,4538 @@
 //! Client for connecting to LDAP and syncing entries
 
 use std::{
-	collections::{HashMap, HashSet},
-	sync::Arc,
+	borrow::Cow,
+	collections::{HashMap, HashSet, VecDeque},
+	sync::{
+		atomic::{AtomicBool, AtomicU64, Ordering},
+		Arc,
+	},
+	time::{Duration, Instant, SystemTime},
 };
 
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
 use ldap3::{
 	adapters::{Adapter, EntriesOnly, PagedResults},
-	LdapConnAsync, Scope, SearchEntry,
+	controls::{Control, ControlType, PagedResults as PagedResultsControl, RawControl},
+	exop::WhoAmI,
+	LdapConnAsync, LdapConnSettings, LdapResult, ResultEntry, Scope, SearchEntry, SearchStream,
 };
 use time::OffsetDateTime;
-use tokio::sync::{mpsc, RwLock};
-use tracing::{error, warn};
+use tokio::sync::{mpsc, watch, Mutex, RwLock};
+use tracing::{debug, error, field, info, info_span, warn, Instrument};
 
-pub use crate::cache::Cache;
 use crate::{
-	cache::{CacheEntries, CacheEntryStatus},
-	config::{CacheMethod, Config},
+	ack::{AckedUpdate, PendingAcks},
+	audit::AuditLog,
+	cache::{
+		self, CacheEntries, CacheEntryStatus, CacheOrigin, CacheStats, ChangedPids, Checkpoint,
+		SerializedSearchEntry,
+	},
+	codec::CacheCodec,
+	config::{BindMethod, Config, RemovalConfirmation, Searches, TimestampFormat, TombstoneConfig},
+	credentials::CredentialsProvider,
+	diagnostics::{ConnectionCheck, Diagnostic, Phase, PollerState, ServerInfo, Severity, TlsInfo},
+	dn::Dn,
+	entry::SearchEntryExt,
 	error::Error,
+	filter::FilterBuilder,
+	membership::{self, MembershipChange},
+	report::{Latencies, ResourceUsage, SyncInfo},
+	schedule::SyncSchedule,
+	schema::Schema,
 };
+pub use crate::{cache::Cache, report::SyncReport};
+
+/// A replaced snapshot of the cache with the pids changed since it was
+/// published, see [`Ldap::publish_snapshot`]
+type RetiredSnapshot = (Arc<Cache>, ChangedPids);
 
 /// Holds data and provides interface for interactions with an LDAP server.
 #[derive(Debug, Clone)]
 pub struct Ldap {
 	/// The configuration of the LDAP client.
 	config: Arc<Config>,
-	/// The sender half of the channel where changes to user data are pushed.
-	sender: mpsc::Sender<EntryStatus>,
+	/// The sender halves of the channels of all subscribers, where changes
+	/// to user data are pushed. See [`Ldap::subscribe`].
+	senders: Arc<std::sync::Mutex<Vec<mpsc::Sender<EntryStatus>>>>,
+	/// Updates which could not be sent because all receivers were dropped, to
+	/// be re-emitted once a new receiver subscribes
+	failed_sends: Arc<std::sync::Mutex<FailedSends>>,
+	/// Notified whenever the re-emission of failed updates made room for
+	/// further updates or stopped, see [`Ldap::queue_behind_failed`]
+	resent: Arc<tokio::sync::Notify>,
+	/// The sender half of a separate channel for removals, if split delivery
+	/// is used. Otherwise removals are pushed to `sender` as well.
+	removal_sender: Option<mpsc::Sender<EntryStatus>>,
 	/// Data for the cache
 	cache: Arc<RwLock<Cache>>,
+	/// Immutable copy of the cache, published at the end of every sync cycle
+	/// so that readers never contend with the sync. Cached entries are shared
+	/// with the cache, and only the changed ones are copied when the replaced
+	/// snapshot can be reused, see [`Ldap::publish_snapshot`].
+	snapshot: Arc<ArcSwap<Cache>>,
+	/// The snapshot replaced by the last publication
+	retired_snapshot: Arc<std::sync::Mutex<Option<RetiredSnapshot>>>,
+	/// Set to `true` once the first sync cycle has completed successfully
+	initial_sync: Arc<watch::Sender<bool>>,
+	/// What the poller is doing, see [`Ldap::subscribe_state`]
+	state: Arc<watch::Sender<PollerState>>,
+	/// Connection settings kept across connections for TLS session resumption
+	settings: Arc<Mutex<CachedSettings>>,
+	/// The connection kept open between sync cycles, see
+	/// [`crate::config::ConnectionConfig::reuse`]
+	connection: Arc<std::sync::Mutex<Option<IdleConnection>>>,
+	/// The sender halves of the channels for diagnostics, one per receiver
+	/// returned by [`Ldap::diagnostics`]
+	diagnostics: Arc<std::sync::Mutex<Vec<mpsc::Sender<Diagnostic>>>>,
+	/// Set if modification times have only second granularity while syncs
+	/// happen more than once per second, so incremental searches overlap
+	/// with the previous sync by at least [`TIMESTAMP_OVERLAP`]
+	widen_overlap: Arc<AtomicBool>,
+	/// Configuration to switch to before the next sync cycle
+	pending_config: Arc<std::sync::Mutex<Option<Config>>>,
+	/// Provider of the bind credentials, if they are not taken from the
+	/// configuration
+	credentials: Option<Arc<dyn CredentialsProvider>>,
+	/// Changes held back by [`Config::debounce`], by pid
+	pending_changes: Arc<std::sync::Mutex<HashMap<Vec<u8>, PendingChange>>>,
+	/// Latencies of recent sync cycles, see [`Config::latency_thresholds`]
+	latencies: Arc<std::sync::Mutex<Latencies>>,
+	/// Set during a dry run, see [`SyncOptions::dry_run`]
+	dry_run: bool,
+	/// When the current cycle has to be finished, and the configured
+	/// deadline, see [`SyncOptions::deadline`]
+	deadline: Option<(Instant, Duration)>,
+	/// The senders of all batch subscribers, see [`Ldap::subscribe_batches`]
+	batch_senders: Arc<std::sync::Mutex<Vec<mpsc::Sender<SyncBatch>>>>,
+	/// Updates of the current sync cycle collected for batch subscribers
+	batch: Arc<std::sync::Mutex<Vec<EntryStatus>>>,
+	/// The server capabilities, once probed by the first sync cycle
+	server_info: Arc<std::sync::Mutex<Option<ServerInfo>>>,
+	/// The page size adapted to the limits of the server, see
+	/// [`crate::config::Searches::adaptive_page_size`]
+	page_size: Arc<std::sync::Mutex<Option<i32>>>,
+	/// The subschema of the server, once read for
+	/// [`crate::config::AttributeConfig::schema_matching`]
+	schema: Arc<std::sync::Mutex<Option<Arc<Schema>>>>,
+	/// The number of sync cycles started, identifying them in tracing spans
+	sync_ids: Arc<AtomicU64>,
+	/// The number of warnings sent as diagnostics during the current cycle
+	warnings: Arc<AtomicU64>,
+	/// Summary of the most recent sync cycle, see [`Ldap::last_sync_info`]
+	last_sync_info: Arc<std::sync::Mutex<Option<SyncInfo>>>,
+	/// Predicate selecting the synced entries, see [`Ldap::with_entry_filter`]
+	entry_filter: Option<EntryFilter>,
+	/// Transformation of received entries, see [`Ldap::with_entry_transform`]
+	entry_transform: Option<EntryTransform>,
+	/// Pids of the entries to look up by [`Ldap::recheck`] during the next
+	/// cycle
+	rechecks: Arc<std::sync::Mutex<HashSet<Vec<u8>>>>,
+	/// Pids of the entries received during the current cycle, to detect
+	/// entries sharing a pid
+	cycle_pids: Arc<std::sync::Mutex<CyclePids>>,
+	/// Sink for a record of every emitted update, see [`Ldap::with_audit_log`]
+	audit_log: Option<AuditLog>,
+	/// The sender half of the channel for updates which have to be
+	/// acknowledged, if it was requested, see [`Ldap::subscribe_acked`]
+	acked_sender: Arc<std::sync::Mutex<Option<mpsc::Sender<AckedUpdate>>>>,
+	/// Updates which weren't acknowledged yet
+	pending_acks: Arc<std::sync::Mutex<PendingAcks>>,
+}
+
+/// A predicate selecting the synced entries, see [`Ldap::with_entry_filter`]
+#[derive(Clone)]
+struct EntryFilter(Arc<dyn Fn(&SearchEntry) -> bool + Send + Sync>);
+
+impl std::fmt::Debug for EntryFilter {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str("EntryFilter")
+	}
+}
+
+/// A transformation of received entries, see [`Ldap::with_entry_transform`]
+#[derive(Clone)]
+struct EntryTransform(Arc<dyn Fn(SearchEntry) -> SearchEntry + Send + Sync>);
+
+impl std::fmt::Debug for EntryTransform {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str("EntryTransform")
+	}
+}
+
+/// Overrides for a single sync cycle, see [`Ldap::sync_once_with`]
+#[derive(Debug, Clone, Default)]
+pub struct SyncOptions {
+	/// Search with this filter instead of the configured one. As the found
+	/// entries are only a subset, removals aren't detected by a full search,
+	/// and the update sequence number isn't advanced.
+	pub filter: Option<String>,
+	/// Perform a full search, even if an incremental one would be due
+	pub full_sync: bool,
+	/// Compare the entries without pushing any updates or modifying the
+	/// cache. The [`SyncReport::updates`] tell what would have been pushed.
+	pub dry_run: bool,
+	/// Use this page size instead of the configured one
+	pub page_size: Option<i32>,
+	/// Abort the cycle with [`Error::Deadline`] if it didn't finish within
+	/// this time, including retries, so that a hung server can't block it
+	/// indefinitely. The search is abandoned by dropping the connection.
+	/// Only LDAP operations are cut short, updates being pushed to a slow
+	/// receiver are still delivered, so that no change is lost.
+	pub deadline: Option<Duration>,
+}
+
+/// The pids of the entries received during a sync cycle
+#[derive(Debug, Default)]
+struct CyclePids {
+	/// DNs of the processed entries by pid
+	received: HashMap<Vec<u8>, String>,
+	/// Entries received under another DN than the cached one by pid, which
+	/// are processed at the end of the search
+	moved: HashMap<Vec<u8>, SearchEntry>,
+}
+
+/// The result of [`Ldap::pid_conflict`]
+enum PidCheck {
+	/// No other entry with the pid was received
+	Unique,
+	/// The entry was already received at the same DN in this cycle, and is
+	/// processed or waiting to be processed
+	Duplicate,
+	/// An entry with the pid was received at the given DN
+	Conflict(Vec<u8>, String),
+	/// The entry was cached under another DN
+	Moved(Vec<u8>),
+}
+
+/// A change held back to merge it with further changes of the same entry
+#[derive(Debug)]
+struct PendingChange {
+	/// The entry before the first held back change
+	old: Option<Arc<SearchEntry>>,
+	/// The entry after the latest change
+	new: Arc<SearchEntry>,
+	/// When the first change was held back
+	since: Instant,
+	/// Where the sync stood when the first change was held back
+	checkpoint: Checkpoint,
+}
+
+/// Maximum number of updates kept for re-emission after failed sends, unless
+/// configured otherwise, see [`Config::max_failed_sends`]
+const DEFAULT_MAX_FAILED_SENDS: usize = 1024;
+
+/// Updates which could not be sent because all receivers were dropped, see
+/// [`Ldap::subscribe`]
+#[derive(Debug, Default)]
+struct FailedSends {
+	/// The updates in the order they were sent, including those queued
+	/// behind them while they are re-emitted
+	updates: VecDeque<EntryStatus>,
+	/// Whether a task re-emitting the updates is running, see
+	/// [`Ldap::start_resend`]
+	resending: bool,
+}
+
+/// Overlap of incremental searches with the previous sync if modification
+/// times have only second granularity
+const TIMESTAMP_OVERLAP: time::Duration = time::Duration::SECOND;
+
+/// Connection settings which are reused across connections
+#[derive(Default)]
+struct CachedSettings {
+	/// The settings, once created
+	settings: Option<LdapConnSettings>,
+	/// Modification times of the certificate files the settings were created
+	/// from, if they are reloaded on changes
+	mtimes: Vec<Option<SystemTime>>,
+}
+
+impl std::fmt::Debug for CachedSettings {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("CachedSettings")
+			.field("settings", &self.settings.as_ref().map(|_| "LdapConnSettings"))
+			.field("mtimes", &self.mtimes)
+			.finish()
+	}
+}
+
+/// A bound connection kept open between sync cycles
+#[derive(Debug)]
+struct IdleConnection {
+	/// The handle of the connection
+	ldap: ldap3::Ldap,
+	/// The task reading the RootDSE periodically, if a keepalive is
+	/// configured
+	keepalive: Option<tokio::task::JoinHandle<()>>,
+}
+
+impl Drop for IdleConnection {
+	fn drop(&mut self) {
+		if let Some(keepalive) = &self.keepalive {
+			keepalive.abort();
+		}
+	}
 }
 
-/// Possible status of an entry
+/// Possible status of an entry. Entries are shared rather than copied when an
+/// update is delivered to several receivers, e.g. batch subscribers.
 #[derive(Debug, Clone)]
 pub enum EntryStatus {
 	/// The entry is new
-	New(SearchEntry),
-	/// The entry has changed
+	New(Arc<SearchEntry>),
+	/// The entry has changed. The previous entry is only available if full
+	/// entries are cached, see [`crate::config::CacheMethod`].
 	#[allow(missing_docs)]
-	Changed { old: SearchEntry, new: SearchEntry },
-	/// The entry was removed
-	Removed(Vec<u8>),
+	Changed { old: Option<Arc<SearchEntry>>, new: Arc<SearchEntry> },
+	/// The entry with the given persistent ID was removed. The last known
+	/// version of the entry is only available if full entries are cached,
+	/// see [`crate::config::CacheMethod`].
+	#[allow(missing_docs)]
+	Removed { pid: Vec<u8>, entry: Option<Arc<SearchEntry>> },
+	/// The entry was moved to a new DN (e.g. via a modDN operation), but kept
+	/// its persistent ID
+	#[allow(missing_docs)]
+	Moved { pid: Vec<u8>, old_dn: String, new_dn: String, entry: Arc<SearchEntry> },
+	/// The entry was found and has not changed. Only emitted if
+	/// [`Config::emit_unchanged`] is enabled.
+	Unchanged(Vec<u8>),
+	/// A member was added to or removed from a group, identified by its
+	/// persistent ID and DN. Only emitted with group sync, see
+	/// [`crate::config::AttributeConfig::members`].
+	#[allow(missing_docs)]
+	MembershipChanged { group: Vec<u8>, group_dn: String, member: String, change: MembershipChange },
+	/// The entry with the given DN was received, but couldn't be synced.
+	/// Skipped entries are also reported as warnings, see
+	/// [`Ldap::diagnostics`].
+	#[allow(missing_docs)]
+	Skipped { dn: String, reason: SkipReason },
+	/// An entry with another DN had the same persistent ID as the entry at
+	/// `dn`, which was received in the same sync cycle, e.g. because the pid
+	/// attribute isn't unique. The entry at the cached DN is kept, otherwise
+	/// the one received first. The conflicting entry was skipped, so the
+	/// cache keeps the entry at `dn`.
+	#[allow(missing_docs)]
+	PidConflict { pid: Vec<u8>, dn: String, conflicting_dn: String },
+}
+
+/// Why an entry was skipped, see [`EntryStatus::Skipped`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+	/// The entry doesn't have the persistent ID attribute, see
+	/// [`crate::config::AttributeConfig::pid`]. The attribute may be missing
+	/// from the schema or not readable by the search user.
+	MissingPid,
+}
+
+impl std::fmt::Display for SkipReason {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			SkipReason::MissingPid => f.write_str("missing persistent ID attribute"),
+		}
+	}
+}
+
+/// All updates of a sync cycle, see [`Ldap::subscribe_batches`]
+#[derive(Debug, Clone, Default)]
+pub struct SyncBatch {
+	/// The updates in the order they were pushed
+	pub updates: Vec<EntryStatus>,
+	/// Whether the sync cycle failed after pushing the updates. The updates
+	/// are cached nonetheless, so they aren't repeated by the next cycle.
+	pub failed: bool,
+}
+
+impl SyncBatch {
+	/// The new entries
+	pub fn new_entries(&self) -> impl Iterator<Item = &SearchEntry> {
+		self.updates.iter().filter_map(|status| match status {
+			EntryStatus::New(entry) => Some(&**entry),
+			_ => None,
+		})
+	}
+
+	/// The changed entries, with their previous version if known
+	pub fn changed(&self) -> impl Iterator<Item = (Option<&SearchEntry>, &SearchEntry)> {
+		self.updates.iter().filter_map(|status| match status {
+			EntryStatus::Changed { old, new } => Some((old.as_deref(), &**new)),
+			_ => None,
+		})
+	}
+
+	/// The moved entries, with their previous DN
+	pub fn moved(&self) -> impl Iterator<Item = (&str, &SearchEntry)> {
+		self.updates.iter().filter_map(|status| match status {
+			EntryStatus::Moved { old_dn, entry, .. } => Some((old_dn.as_str(), &**entry)),
+			_ => None,
+		})
+	}
+
+	/// The persistent IDs of the removed entries
+	pub fn removed(&self) -> impl Iterator<Item = &[u8]> {
+		self.updates.iter().filter_map(|status| match status {
+			EntryStatus::Removed { pid, .. } => Some(pid.as_slice()),
+			_ => None,
+		})
+	}
+
+	/// The membership changes, with the DN of the group and the member
+	pub fn membership_changes(&self) -> impl Iterator<Item = (&str, &str, MembershipChange)> {
+		self.updates.iter().filter_map(|status| match status {
+			EntryStatus::MembershipChanged { group_dn, member, change, .. } => {
+				Some((group_dn.as_str(), member.as_str(), *change))
+			}
+			_ => None,
+		})
+	}
 }
 
 impl Ldap {
 	/// Create a new [`Ldap`] with the given configuration and optional saved
 	/// cache. Also returns a channel receiver which will be used to push
-	/// updates to user data.
+	/// updates to user data. Further receivers can be added with
+	/// [`Ldap::subscribe`].
+	///
+	/// The cache is [migrated](Cache::migrate) to the configuration. If it
+	/// can't be, a warning is logged and the client starts with an empty
+	/// cache, reporting all entries as new; use [`Ldap::try_new`] to get an
+	/// error instead.
 	#[must_use]
 	pub fn new(config: Config, cache: Option<Cache>) -> (Self, mpsc::Receiver<EntryStatus>) {
 		let (sender, receiver) = mpsc::channel::<EntryStatus>(1024);
-		let cache: Cache = if let Some(cache) = cache {
-			cache
+		(Self::with_senders(config, cache, sender, None), receiver)
+	}
+
+	/// Create a new [`Ldap`] like [`Ldap::new`], but fail if the saved cache
+	/// doesn't match the configuration and can't be migrated, e.g. because
+	/// the pid attribute changed since it was persisted
+	pub fn try_new(
+		config: Config,
+		cache: Option<Cache>,
+	) -> Result<(Self, mpsc::Receiver<EntryStatus>), Error> {
+		let cache = cache.map(|cache| cache.migrate(&config)).transpose()?;
+		Ok(Self::new(config, cache))
+	}
+
+	/// Create a new [`Ldap`] like [`Ldap::new`], but deliver removals on a
+	/// separate channel. Returns the receiver for new, changed and moved
+	/// entries first, and the receiver for [`EntryStatus::Removed`] second.
+	///
+	/// This allows consumers to e.g. apply additions immediately while
+	/// deprovisioning goes through slower batch processing or human review.
+	#[must_use]
+	pub fn new_split(
+		config: Config,
+		cache: Option<Cache>,
+	) -> (Self, mpsc::Receiver<EntryStatus>, mpsc::Receiver<EntryStatus>) {
+		let (sender, receiver) = mpsc::channel::<EntryStatus>(1024);
+		let (removal_sender, removal_receiver) = mpsc::channel::<EntryStatus>(1024);
+		(
+			Self::with_senders(config, cache, sender, Some(removal_sender)),
+			receiver,
+			removal_receiver,
+		)
+	}
+
+	/// Construct the client from the given channel senders
+	fn with_senders(
+		config: Config,
+		cache: Option<Cache>,
+		sender: mpsc::Sender<EntryStatus>,
+		removal_sender: Option<mpsc::Sender<EntryStatus>>,
+	) -> Self {
+		let cache = migrated_cache(
+			cache.unwrap_or_else(|| Cache::new(CacheEntries::from(&config.cache_method))),
+			&config,
+		);
+		Ldap {
+			config: Arc::new(config),
+			senders: Arc::new(std::sync::Mutex::new(vec![sender])),
+			failed_sends: Arc::default(),
+			resent: Arc::default(),
+			removal_sender,
+			snapshot: Arc::new(ArcSwap::from_pointee(cache.clone())),
+			retired_snapshot: Arc::default(),
+			cache: Arc::new(RwLock::new(cache)),
+			initial_sync: Arc::new(watch::channel(false).0),
+			state: Arc::new(watch::channel(PollerState::Idle).0),
+			settings: Arc::default(),
+			connection: Arc::default(),
+			diagnostics: Arc::default(),
+			widen_overlap: Arc::default(),
+			pending_config: Arc::default(),
+			pending_changes: Arc::default(),
+			credentials: None,
+			latencies: Arc::default(),
+			dry_run: false,
+			deadline: None,
+			batch_senders: Arc::default(),
+			batch: Arc::default(),
+			server_info: Arc::default(),
+			page_size: Arc::default(),
+			schema: Arc::default(),
+			sync_ids: Arc::default(),
+			warnings: Arc::default(),
+			last_sync_info: Arc::default(),
+			entry_filter: None,
+			entry_transform: None,
+			rechecks: Arc::default(),
+			cycle_pids: Arc::default(),
+			audit_log: None,
+			acked_sender: Arc::default(),
+			pending_acks: Arc::default(),
+		}
+	}
+
+	/// Create a connection to an ldap server based on the settings and url
+	/// specified in the configuration. Returns the task driving the
+	/// connection in the background.
+	async fn connect(&self) -> Result<(ldap3::Ldap, tokio::task::JoinHandle<()>), Error> {
+		let tls = &self.config.connection.tls;
+		let settings = if tls.session_resumption {
+			let mut cached = self.settings.lock().await;
+			if tls.reload_certificates {
+				let mtimes = tls.certificate_mtimes().await?;
+				if mtimes != cached.mtimes {
+					if cached.settings.is_some() {
+						tracing::info!("Certificate files changed, reloading them");
+					}
+					cached.settings = None;
+					cached.mtimes = mtimes;
+				}
+			}
+			match &cached.settings {
+				Some(settings) => settings.clone(),
+				None => cached.settings.insert(self.config.connection.to_settings().await?).clone(),
+			}
 		} else {
-			let cache_entries = match config.cache_method {
-				CacheMethod::ModificationTime => CacheEntries::Modified(HashMap::new()),
-				CacheMethod::Disabled => CacheEntries::None,
-			};
-			Cache { last_sync_time: None, entries: cache_entries, missing: HashSet::new() }
+			self.config.connection.to_settings().await?
 		};
-		(Ldap { config: Arc::new(config), sender, cache: Arc::new(RwLock::new(cache)) }, receiver)
+		let (conn, ldap) = LdapConnAsync::from_url_with_settings(settings, &self.config.url)
+			.await
+			.map_err(crate::tls::connect_error)?;
+		let conn = tokio::spawn(async move {
+			if let Err(err) = conn.drive().await {
+				warn!("Ldap connection error {err}");
+			}
+		});
+		Ok((ldap, conn))
 	}
 
-	/// Create a connection to an ldap server based on the settings and url
-	/// specified in the configuration.
-	async fn connect(&self) -> Result<(LdapConnAsync, ldap3::Ldap), Error> {
-		let settings = self.config.connection.to_settings().await?;
-		let (conn, ldap) =
-			LdapConnAsync::from_url_with_settings(settings, &self.config.url).await?;
-		Ok((conn, ldap))
+	/// Connect, run an operation outside of sync cycles on the connection,
+	/// which is expected to bind first, and unbind and close the connection
+	/// again. Fails if connecting fails, otherwise returns the result of the
+	/// operation.
+	async fn with_connection<T, E, F>(
+		&self,
+		operation: impl FnOnce(ldap3::Ldap) -> F,
+	) -> Result<Result<T, E>, Error>
+	where
+		F: std::future::Future<Output = Result<T, E>>,
+	{
+		let (mut ldap, conn) = self.connect().await?;
+		let result = operation(ldap.clone()).await;
+		let _ = ldap.with_timeout(self.operation_timeout()).unbind().await;
+		conn.abort();
+		Ok(result)
 	}
 
-	/// Perform a sync repeatedly forever
-	pub async fn sync(
-		&mut self,
-		duration_between_searches: std::time::Duration,
+	/// Take the connection kept from the previous sync cycle if it still
+	/// responds, or connect and bind otherwise. Returns the task driving the
+	/// connection, unless it was kept.
+	async fn open_connection(
+		&self,
+		phase: &mut Phase,
+		report: &mut SyncReport,
+	) -> Result<(ldap3::Ldap, Option<tokio::task::JoinHandle<()>>), Error> {
+		let idle = lock(&self.connection).take();
+		if let Some(idle) = idle {
+			let mut ldap = idle.ldap.clone();
+			drop(idle);
+			if !ldap.is_closed() && ping(&mut ldap, self.operation_timeout()).await {
+				return Ok((ldap, None));
+			}
+			debug!("The kept connection doesn't respond anymore, reconnecting");
+		}
+
+		self.set_state(PollerState::Connecting);
+		let (mut ldap, conn) = self.before_deadline(self.connect()).await?;
+		*phase = Phase::Bind;
+		self.set_state(PollerState::Binding);
+		let bind_started = Instant::now();
+		let bound = self.bind(&mut ldap).await;
+		// Also if the bind failed, e.g. because it timed out
+		report.bind_latency = Some(bind_started.elapsed());
+		bound?;
+		Ok((ldap, Some(conn)))
+	}
+
+	/// Keep the connection for the next sync cycle if configured, or unbind
+	/// and wait for the connection to be closed otherwise
+	async fn close_connection(
+		&self,
+		mut ldap: ldap3::Ldap,
+		conn: Option<tokio::task::JoinHandle<()>>,
 	) -> Result<(), Error> {
+		let connection = &self.config.connection;
+		if connection.reuse {
+			let keepalive = connection.keepalive.map(|interval| {
+				let mut ldap = ldap.clone();
+				let timeout = connection.operation_timeout;
+				tokio::spawn(async move {
+					let mut ticks =
+						tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+					loop {
+						ticks.tick().await;
+						if !ping(&mut ldap, timeout).await {
+							debug!("Keepalive of the idle connection failed");
+							break;
+						}
+					}
+				})
+			});
+			*lock(&self.connection) = Some(IdleConnection { ldap, keepalive });
+			return Ok(());
+		}
+
+		ldap.with_timeout(self.operation_timeout()).unbind().await?;
+		if let Some(conn) = conn {
+			if let Err(err) = conn.await {
+				warn!("Failed to join background task: {err}");
+			}
+		}
+		Ok(())
+	}
+
+	/// Bind with the configured method and credentials
+	async fn bind(&self, ldap: &mut ldap3::Ldap) -> Result<(), Error> {
+		match self.config.bind_method {
+			BindMethod::Anonymous => {
+				ldap.with_timeout(self.operation_timeout()).simple_bind("", "").await?.success()?;
+			}
+			BindMethod::Simple => {
+				let credentials = match &self.credentials {
+					Some(provider) => provider.get_credentials().await?,
+					None => self.config.get_credentials().await?,
+				};
+				if credentials.user.is_empty() || credentials.password.is_empty() {
+					return Err(Error::Credentials(
+						"Simple binds need a user and password, use the anonymous bind method \
+						 for anonymous access"
+							.into(),
+					));
+				}
+				ldap.with_timeout(self.operation_timeout())
+					.simple_bind(&credentials.user, &credentials.password)
+					.await?
+					.success()?;
+			}
+			BindMethod::SaslExternal => {
+				ldap.with_timeout(self.operation_timeout())
+					.sasl_external_bind()
+					.await?
+					.success()?;
+			}
+		}
+		Ok(())
+	}
+
+	/// Probe the connectivity to the server by connecting, binding, and
+	/// reading the RootDSE if requested, e.g. for a health endpoint. Failures
+	/// are reported with the phase in which they occurred.
+	pub async fn check_connection(
+		&self,
+		read_root_dse: bool,
+	) -> Result<ConnectionCheck, Diagnostic> {
+		let started = Instant::now();
+		let (connect_latency, bind_latency, authz_id, server) = self
+			.with_connection(|mut ldap| async move {
+				let connect_latency = started.elapsed();
+				let started = Instant::now();
+				self.bind(&mut ldap).await.map_err(|err| Diagnostic::error(Phase::Bind, err))?;
+				let bind_latency = started.elapsed();
+				// Not every server supports the operation, e.g. Active Directory
+				let authz_id = self.query_authz_id(&mut ldap).await.ok();
+				let server =
+					if read_root_dse { Some(self.read_root_dse(&mut ldap).await?) } else { None };
+				Ok((connect_latency, bind_latency, authz_id, server))
+			})
+			.await
+			.map_err(|err| Diagnostic::error(Phase::Connect, err))??;
+		Ok(ConnectionCheck {
+			connect_latency,
+			bind_latency,
+			authz_id,
+			server,
+			tls: TlsInfo::new(&self.config.url, &self.config.connection.tls),
+		})
+	}
+
+	/// Connect, bind, and return the authorization identity the server
+	/// associates with the connection, using the "Who am I?" extended
+	/// operation (RFC 4532). An empty identity means that the bind was
+	/// anonymous.
+	pub async fn who_am_i(&self) -> Result<String, Error> {
+		self.with_connection(|mut ldap| async move {
+			self.bind(&mut ldap).await?;
+			self.query_authz_id(&mut ldap).await
+		})
+		.await?
+	}
+
+	/// Connect, bind, and perform a single search with the configured
+	/// connection, TLS and bind settings, e.g. to look up a user on login
+	/// between sync cycles. The entries are returned as the server sent them,
+	/// without touching the cache or emitting updates. A base which doesn't
+	/// exist yields no entries.
+	pub async fn search_raw(
+		&self,
+		base: &str,
+		scope: Scope,
+		filter: &str,
+		attrs: &[&str],
+	) -> Result<Vec<SearchEntry>, Error> {
+		self.with_connection(|mut ldap| async move {
+			self.bind(&mut ldap).await?;
+			let result = ldap
+				.with_timeout(self.operation_timeout())
+				.search(base, scope, filter, attrs.to_vec())
+				.await?;
+			if result.1.rc == NO_SUCH_OBJECT {
+				return Ok(Vec::new());
+			}
+			Ok(result.success()?.0.into_iter().map(SearchEntry::construct).collect())
+		})
+		.await?
+	}
+
+	/// Perform the "Who am I?" extended operation on a bound connection
+	async fn query_authz_id(&self, ldap: &mut ldap3::Ldap) -> Result<String, Error> {
+		let (exop, _) =
+			ldap.with_timeout(self.operation_timeout()).extended(WhoAmI).await?.success()?;
+		Ok(exop.val.map(|val| String::from_utf8_lossy(&val).into_owned()).unwrap_or_default())
+	}
+
+	/// Log the identity the sync is bound as, warning about anonymous binds
+	/// which would otherwise only show up as missing search results
+	async fn log_authz_id(&self, ldap: &mut ldap3::Ldap) {
+		match self.query_authz_id(ldap).await {
+			Ok(authz_id) if authz_id.is_empty() && !self.config.search_user.is_empty() => {
+				self.diagnose(Diagnostic::warning(
+					Phase::Bind,
+					format!(
+						"Bound anonymously instead of as {}, searches may not return any entries",
+						self.config.search_user
+					),
+				));
+				warn!("Bound anonymously instead of as {}", self.config.search_user);
+			}
+			Ok(authz_id) if authz_id.is_empty() => info!("Bound anonymously"),
+			Ok(authz_id) => info!("Bound as {authz_id}"),
+			Err(err) => debug!("Could not determine the bound identity: {err}"),
+		}
+	}
+
+	/// Read the capabilities of the server from the RootDSE, unless that
+	/// already happened. Warns if paged searches are configured, but not
+	/// supported by the server, in which case searches aren't paged.
+	async fn probe_server(&self, ldap: &mut ldap3::Ldap) {
+		if lock(&self.server_info).is_some() {
+			return;
+		}
+		let info = match self.read_root_dse(ldap).await {
+			Ok(info) => info,
+			Err(diagnostic) => {
+				debug!("Could not read the RootDSE: {}", diagnostic.message);
+				return;
+			}
+		};
+		info!(
+			"Connected to {} {}, supporting {:?}",
+			info.vendor_name.as_deref().unwrap_or("an unknown server"),
+			info.vendor_version.as_deref().unwrap_or_default(),
+			info.sync_strategy()
+		);
+		if self.config.searches.page_size.is_some() && info.lacks_paged_results() {
+			let message = "The server doesn't support paged searches, searching without paging";
+			warn!("{message}");
+			self.diagnose(Diagnostic::warning(Phase::Search, message.to_owned()));
+		}
+		*lock(&self.server_info) = Some(info);
+	}
+
+	/// The capabilities of the server as advertised in its RootDSE, once
+	/// they were read by the first sync cycle. `None` if no cycle connected
+	/// yet, or the RootDSE couldn't be read.
+	#[must_use]
+	pub fn server_info(&self) -> Option<ServerInfo> {
+		lock(&self.server_info).clone()
+	}
+
+	/// Read the subschema of the server if values are compared using its
+	/// matching rules, unless that already happened, and hand it to the cache
+	async fn load_schema(&self, ldap: &mut ldap3::Ldap) -> Result<(), Error> {
+		if !self.config.attributes.schema_matching {
+			self.cache.write().await.schema = None;
+			return Ok(());
+		}
+		let loaded = lock(&self.schema).clone();
+		let schema = match loaded {
+			Some(schema) => schema,
+			None => {
+				let schema = Arc::new(self.read_schema(ldap).await?);
+				*lock(&self.schema) = Some(schema.clone());
+				schema
+			}
+		};
+		self.cache.write().await.schema = Some(schema);
+		Ok(())
+	}
+
+	/// Read the attribute types from the subschema entry named by the
+	/// RootDSE
+	async fn read_schema(&self, ldap: &mut ldap3::Ldap) -> Result<Schema, Error> {
+		let timeout = self.operation_timeout();
+		let (entries, _) = ldap
+			.with_timeout(timeout)
+			.search("", Scope::Base, "(objectClass=*)", [Schema::SUBSCHEMA_SUBENTRY])
+			.await?
+			.success()?;
+		let subschema = entries
+			.into_iter()
+			.next()
+			.map(SearchEntry::construct)
+			.and_then(|entry| entry.attr_first(Schema::SUBSCHEMA_SUBENTRY).map(ToOwned::to_owned))
+			.ok_or_else(|| Error::MissingAttribute(Schema::SUBSCHEMA_SUBENTRY.to_owned()))?;
+		let (entries, _) = ldap
+			.with_timeout(timeout)
+			.search(&subschema, Scope::Base, "(objectClass=subschema)", [Schema::ATTRIBUTE_TYPES])
+			.await?
+			.success()?;
+		let definitions = entries
+			.into_iter()
+			.next()
+			.map(SearchEntry::construct)
+			.and_then(|mut entry| entry.attrs.remove(Schema::ATTRIBUTE_TYPES))
+			.ok_or_else(|| Error::MissingAttribute(Schema::ATTRIBUTE_TYPES.to_owned()))?;
+		let schema = Schema::from_attribute_types(&definitions);
+		debug!("Read {} attribute types from {subschema}", definitions.len());
+		Ok(schema)
+	}
+
+	/// Read the server identity from the RootDSE
+	async fn read_root_dse(&self, ldap: &mut ldap3::Ldap) -> Result<ServerInfo, Diagnostic> {
+		let (entries, _) = ldap
+			.with_timeout(self.operation_timeout())
+			.search("", Scope::Base, "(objectClass=*)", ServerInfo::ATTRIBUTES)
+			.await
+			.and_then(ldap3::SearchResult::success)
+			.map_err(|err| Diagnostic::error(Phase::Search, err.into()))?;
+		let entry = entries
+			.into_iter()
+			.next()
+			.map(SearchEntry::construct)
+			.ok_or_else(|| Diagnostic::error(Phase::Search, Error::Missing))?;
+		Ok(ServerInfo::from_root_dse(&entry))
+	}
+
+	/// Perform a sync repeatedly forever, e.g. every few seconds when given
+	/// a [`std::time::Duration`], or according to a [`SyncSchedule`]
+	pub async fn sync(&mut self, schedule: impl Into<SyncSchedule>) -> Result<(), Error> {
+		let schedule = schedule.into();
 		loop {
-			let new_time = OffsetDateTime::now_utc();
-			let last_time = self.cache.read().await.last_sync_time;
-			if let Err(e) = self.sync_once(last_time).await {
+			self.sync_scheduled(&schedule).await;
+			let now = OffsetDateTime::now_utc();
+			let delay = schedule.delay(now);
+			if !matches!(*self.state.borrow(), PollerState::Failed { .. }) {
+				self.set_state(PollerState::Sleeping { until: now + delay });
+			}
+			tokio::time::sleep(delay).await;
+		}
+	}
+
+	/// Perform a single sync cycle of [`Ldap::sync`], continuing from the
+	/// time of the last sync recorded in the cache
+	pub(crate) async fn sync_scheduled(&mut self, schedule: &SyncSchedule) {
+		self.apply_pending_config().await;
+		let mut new_time = Some(OffsetDateTime::now_utc());
+		let last_time = self.cache.read().await.last_sync_time;
+		let mut phase = Phase::Connect;
+		match self.sync_cycle(last_time, &SyncOptions::default(), &mut phase).await {
+			Ok(report) => {
+				self.check_report(&report, schedule);
+				new_time = report.resumed_from.or(new_time);
+				// Entries beyond the size limit are searched again
+				if report.size_limit_exceeded {
+					new_time = last_time;
+				}
+			}
+			Err(e) => {
 				tracing::error!("after_sync: {e}");
+				self.diagnose(Diagnostic::error(phase, e));
+			}
+		}
+		self.cache.write().await.last_sync_time = new_time;
+		self.publish_snapshot().await;
+	}
+
+	/// Share the TLS settings and the connection kept between cycles with
+	/// another client for the same server, see [`crate::pipeline::Pipelines`]
+	pub(crate) fn share_connection(&mut self, other: &Ldap) {
+		self.settings = other.settings.clone();
+		self.connection = other.connection.clone();
+	}
+
+	/// Get the bind credentials from the given provider instead of the
+	/// `search_user` and `search_password` of the configuration
+	#[must_use]
+	pub fn with_credentials_provider(mut self, provider: Arc<dyn CredentialsProvider>) -> Self {
+		self.credentials = Some(provider);
+		self
+	}
+
+	/// Only sync entries for which the given predicate returns `true`, for
+	/// conditions which can't be expressed in the search filter, e.g. a
+	/// regular expression on the mail domain. Other entries are neither
+	/// cached nor emitted. Entries which were synced before are reported as
+	/// removed by the next full sync checking for deleted entries, like
+	/// entries which no longer match the search filter.
+	#[must_use]
+	pub fn with_entry_filter(
+		mut self,
+		filter: impl Fn(&SearchEntry) -> bool + Send + Sync + 'static,
+	) -> Self {
+		self.entry_filter = Some(EntryFilter(Arc::new(filter)));
+		self
+	}
+
+	/// Transform every received entry before it is compared, cached and
+	/// emitted, e.g. to redact sensitive attributes or to normalize values
+	/// centrally instead of in every consumer. Runs before the
+	/// [entry filter](Ldap::with_entry_filter). The transformation must keep
+	/// the pid attribute. Changing it may report entries as changed once.
+	#[must_use]
+	pub fn with_entry_transform(
+		mut self,
+		transform: impl Fn(SearchEntry) -> SearchEntry + Send + Sync + 'static,
+	) -> Self {
+		self.entry_transform = Some(EntryTransform(Arc::new(transform)));
+		self
+	}
+
+	/// Write a record of every emitted update to the given audit log
+	#[must_use]
+	pub fn with_audit_log(mut self, audit_log: AuditLog) -> Self {
+		self.audit_log = Some(audit_log);
+		self
+	}
+
+	/// Replace the configuration, e.g. to rotate credentials or adjust
+	/// filters. Can be called on any clone of the client, the client
+	/// performing the syncs switches to the new configuration before its next
+	/// sync cycle. If the
+	/// cached entries can't be compared with the new configuration, e.g.
+	/// because the pid attribute changed, the cache is cleared and all
+	/// entries are reported as new again, unless full entries are cached which
+	/// can be [converted](Cache::convert). If the search scope changed, a full
+	/// sync is performed, ignoring the time of the last sync passed to
+	/// [`Ldap::sync_once`].
+	pub fn update_config(&self, config: Config) {
+		*lock(&self.pending_config) = Some(config);
+	}
+
+	/// Switch to the configuration passed to [`Ldap::update_config`], if any.
+	/// Returns whether the next cycle has to be a full sync, because the
+	/// search scope changed or the cache was cleared.
+	async fn apply_pending_config(&mut self) -> bool {
+		let Some(config) = lock(&self.pending_config).take() else {
+			return false;
+		};
+		let reset = {
+			let mut cache = self.cache.write().await;
+			let previous = std::mem::replace(
+				&mut *cache,
+				Cache::new(CacheEntries::from(&config.cache_method)),
+			);
+			let cached = !previous.is_empty();
+			*cache = migrated_cache(previous, &config);
+			CacheOrigin::new(&self.config).changes_search_scope(&config)
+				|| (cached && cache.is_empty())
+		};
+		*self.settings.lock().await = CachedSettings::default();
+		*lock(&self.page_size) = None;
+		// The schema may have changed along with the server
+		*lock(&self.schema) = None;
+		// The kept connection was opened and bound with the previous settings
+		let idle = lock(&self.connection).take();
+		drop(idle);
+		self.config = Arc::new(config);
+		reset
+	}
+
+	/// Warn about problems found during a sync cycle, and widen the overlap of
+	/// incremental searches if modification times are too coarse for the
+	/// schedule
+	fn check_report(&self, report: &SyncReport, schedule: &SyncSchedule) {
+		if report.unparsable_updated > 0 {
+			warn!(
+				"{} entries had an unparsable modification time, e.g. {:?}",
+				report.unparsable_updated, report.unparsable_updated_samples
+			);
+		}
+		if report.updated_whole_seconds + report.updated_subsecond == 0 {
+			return;
+		}
+		// A wide enough configured overlap already covers it
+		let widen = report.has_coarse_updated()
+			&& schedule.is_sub_second()
+			&& self.config.searches.sync_overlap.is_none_or(|overlap| TIMESTAMP_OVERLAP > overlap);
+		if self.widen_overlap.swap(widen, Ordering::Relaxed) != widen && widen {
+			self.diagnose(Diagnostic::warning(
+				Phase::Search,
+				"Modification times have only second granularity, but syncs happen more than once \
+				 per second. Incremental searches now overlap with the previous sync."
+					.to_owned(),
+			));
+		}
+	}
+
+	/// Track the latencies of a sync cycle, and warn when their 95th
+	/// percentile crosses the configured thresholds
+	fn check_latencies(&self, report: &SyncReport) {
+		let thresholds = self.config.latency_thresholds;
+		let latencies = &mut *lock(&self.latencies);
+		for (operation, phase, window, latency, threshold) in [
+			("bind", Phase::Bind, &mut latencies.bind, report.bind_latency, thresholds.bind),
+			(
+				"first_page",
+				Phase::Search,
+				&mut latencies.first_page,
+				report.first_page_latency,
+				thresholds.first_page,
+			),
+		] {
+			let Some(latency) = latency else {
+				continue;
+			};
+			window.record(latency);
+			#[cfg(feature = "metrics")]
+			metrics::histogram!(format!("ldap_poller_{operation}_latency_seconds"))
+				.record(latency.as_secs_f64());
+			let degraded = threshold.and_then(|threshold| window.update_degraded(threshold));
+			#[cfg(feature = "metrics")]
+			window.record_metrics(operation);
+			match (degraded, window.percentile(95), threshold) {
+				(Some(true), Some(p95), Some(threshold)) => {
+					let message = format!(
+						"The {operation} latency is degraded: its 95th percentile of {p95:?} \
+						 exceeds the threshold of {threshold:?}"
+					);
+					warn!("{message}");
+					self.diagnose(Diagnostic::warning(phase, message));
+				}
+				(Some(false), ..) => info!("The {operation} latency recovered"),
+				_ => {}
 			}
-			self.cache.write().await.last_sync_time = Some(new_time);
-			tokio::time::sleep(duration_between_searches).await;
 		}
 	}
 
 	/// Perform a search of all available users, pushing any entries which have
 	/// changed
-	pub async fn sync_once(&mut self, last_sync_time: Option<OffsetDateTime>) -> Result<(), Error> {
-		// TODO: more LDAP server configurations.
-		let (conn, mut ldap) = self.connect().await?;
-		let conn = tokio::spawn(async move {
-			if let Err(err) = conn.drive().await {
-				warn!("Ldap connection error {err}");
+	pub async fn sync_once(
+		&mut self,
+		last_sync_time: Option<OffsetDateTime>,
+	) -> Result<SyncReport, Error> {
+		self.sync_once_with(last_sync_time, SyncOptions::default()).await
+	}
+
+	/// Perform a sync like [`Ldap::sync_once`] with overrides for this cycle
+	/// only, e.g. for admin tooling running special cycles without changing
+	/// the configuration
+	pub async fn sync_once_with(
+		&mut self,
+		last_sync_time: Option<OffsetDateTime>,
+		options: SyncOptions,
+	) -> Result<SyncReport, Error> {
+		// The given time of the last sync doesn't apply to the new
+		// configuration
+		let last_sync_time = if self.apply_pending_config().await { None } else { last_sync_time };
+		if !options.dry_run {
+			let result = self.sync_cycle(last_sync_time, &options, &mut Phase::Connect).await;
+			self.publish_snapshot().await;
+			return result;
+		}
+		// A private copy of the cache, so that neither the changes of the dry
+		// run reach the shared cache nor those of concurrent cycles get lost
+		let mut dry_run = self.clone();
+		dry_run.cache = Arc::new(RwLock::new(self.cache.read().await.clone()));
+		dry_run.dry_run = true;
+		dry_run.sync_cycle(last_sync_time, &options, &mut Phase::Connect).await
+	}
+
+	/// Determine the filter and kind of the search of a sync cycle. Returns
+	/// the position of an interrupted paged search if it is resumed, which is
+	/// removed from the cache, so that the next cycle starts from scratch if
+	/// the server rejects the cookie. Otherwise returns the start of a new
+	/// search.
+	async fn plan_search(
+		&self,
+		last_sync_time: Option<OffsetDateTime>,
+		options: &SyncOptions,
+		page_size: Option<i32>,
+	) -> Result<(cache::PagedSearchProgress, bool), Error> {
+		if self.config.searches.resume_paged_search
+			&& page_size.is_some()
+			&& options.filter.is_none()
+			&& !options.full_sync
+			&& !self.dry_run
+		{
+			if let Some(progress) = self.cache.write().await.paged_search.take() {
+				info!(
+					"Resuming the search started at {} after {} entries",
+					progress.started, progress.entries
+				);
+				return Ok((progress, true));
+			}
+		}
+		let started = OffsetDateTime::now_utc();
+		let full_sync = options.filter.is_none()
+			&& (options.full_sync || self.is_full_sync(last_sync_time, started).await);
+		let highest_usn = self.cache.read().await.highest_usn;
+		let filter = match &options.filter {
+			Some(filter) => filter.clone(),
+			None => self.search_filter(full_sync, last_sync_time, highest_usn)?,
+		};
+		let progress = cache::PagedSearchProgress {
+			filter,
+			full_sync,
+			started,
+			cookie: Vec::new(),
+			entries: 0,
+		};
+		Ok((progress, false))
+	}
+
+	/// Record a completed search of a sync cycle in the cache
+	async fn complete_search(
+		&self,
+		plan: &cache::PagedSearchProgress,
+		options: &SyncOptions,
+		report: &SyncReport,
+	) {
+		let mut cache = self.cache.write().await;
+		if plan.full_sync {
+			cache.last_full_sync_time = Some(plan.started);
+		}
+		if !self.dry_run {
+			cache.paged_search = None;
+		}
+		// Only advanced after a complete search, as results are not ordered by
+		// their sequence number
+		if options.filter.is_none() {
+			cache.highest_usn = cache.highest_usn.max(report.highest_usn);
+		}
+	}
+
+	/// Check the result of the search. Results truncated by the size limit
+	/// of the server are accepted with a warning.
+	fn check_search_result(
+		&self,
+		result: LdapResult,
+		report: &mut SyncReport,
+	) -> Result<(), Error> {
+		if result.rc != SIZE_LIMIT_EXCEEDED {
+			result.success()?;
+			return Ok(());
+		}
+		report.size_limit_exceeded = true;
+		self.diagnose(Diagnostic::warning(
+			Phase::Search,
+			format!(
+				"The server truncated the search results after {} entries due to its size limit, \
+				 removals aren't detected in this cycle",
+				report.entries_received
+			),
+		));
+		Ok(())
+	}
+
+	/// Perform a sync like [`Ldap::sync_once_with`], keeping track of the
+	/// phase the sync is in. The cycle runs in a `sync` tracing span carrying
+	/// a sync id, the server URL and the search base, so that logs of
+	/// concurrent pollers can be told apart. The span also records the
+	/// fetched `pages`, and the `entries_scanned` and `changes_emitted` once
+	/// the cycle finished.
+	async fn sync_cycle(
+		&mut self,
+		last_sync_time: Option<OffsetDateTime>,
+		options: &SyncOptions,
+		phase: &mut Phase,
+	) -> Result<SyncReport, Error> {
+		let span = info_span!(
+			"sync",
+			id = self.sync_ids.fetch_add(1, Ordering::Relaxed) + 1,
+			url = %self.config.url,
+			base = %self.config.searches.user_base,
+			pages = field::Empty,
+			entries_scanned = field::Empty,
+			changes_emitted = field::Empty,
+		);
+		let started_at = OffsetDateTime::now_utc();
+		let started = Instant::now();
+		self.warnings.store(0, Ordering::Relaxed);
+		let mut report = SyncReport::default();
+		let mut options = Cow::Borrowed(options);
+		self.deadline = options.deadline.map(|deadline| (started + deadline, deadline));
+		let result =
+			self.run_attempts(last_sync_time, &mut options, phase, &mut report, &span).await;
+		let result = match (result, self.deadline.take()) {
+			(Err(_), Some((at, deadline))) if Instant::now() >= at => {
+				Err(Error::Deadline(deadline))
+			}
+			(result, _) => result,
+		};
+		if let (Ok(()), None, Some(page_size)) = (&result, options.page_size, report.page_size) {
+			if let Some(adaptive) = self.config.searches.adaptive_page_size {
+				*lock(&self.page_size) = Some(adaptive.grow(page_size));
+			}
+		}
+		if !self.dry_run {
+			if result.is_err() {
+				self.send_batch(true).await;
 			}
+			let errors = self.warnings.load(Ordering::Relaxed) + u64::from(result.is_err());
+			let info = SyncInfo::new(started_at, started.elapsed(), &report, errors);
+			*lock(&self.last_sync_info) = Some(info);
+		}
+		self.set_state(match &result {
+			Ok(()) => PollerState::Idle,
+			Err(err) => PollerState::Failed { error: err.to_string() },
 		});
+		if result.is_ok() {
+			let updates = &report.updates;
+			let changes = updates.new + updates.changed + updates.removed + updates.moved;
+			span.record("pages", report.resources.pages_fetched);
+			span.record("entries_scanned", report.entries_received);
+			span.record("changes_emitted", changes);
+			span.in_scope(|| {
+				info!(
+					entries_scanned = report.entries_received,
+					changes_emitted = changes,
+					pages = report.resources.pages_fetched,
+					"Sync cycle finished"
+				);
+			});
+		}
+		result.map(|()| report)
+	}
 
-		ldap.with_timeout(self.config.connection.operation_timeout)
-			.simple_bind(&self.config.search_user, &self.config.search_password)
-			.await?;
+	/// The timeout of LDAP operations, cut short by the deadline of the
+	/// current cycle, see [`SyncOptions::deadline`]
+	fn operation_timeout(&self) -> Duration {
+		let timeout = self.config.connection.operation_timeout;
+		match self.deadline {
+			Some((at, _)) => timeout.min(at.saturating_duration_since(Instant::now())),
+			None => timeout,
+		}
+	}
+
+	/// Abandon an LDAP operation without an operation timeout, e.g.
+	/// connecting, once the deadline of the current cycle passed
+	async fn before_deadline<T>(
+		&self,
+		operation: impl std::future::Future<Output = Result<T, Error>>,
+	) -> Result<T, Error> {
+		match self.deadline {
+			Some((at, deadline)) => tokio::time::timeout_at(at.into(), operation)
+				.await
+				.unwrap_or(Err(Error::Deadline(deadline))),
+			None => operation.await,
+		}
+	}
+
+	/// The configured page size, or the one adapted to the limits of the
+	/// server
+	fn page_size(&self) -> Option<i32> {
+		lock(&self.page_size).or(self.config.searches.page_size)
+	}
+
+	/// Reduce the page size after the server rejected it, returning the new
+	/// one unless it can't be reduced any further
+	fn reduce_page_size(&self) -> Option<i32> {
+		let adaptive = self.config.searches.adaptive_page_size?;
+		let page_size = adaptive.reduce(self.page_size()?)?;
+		*lock(&self.page_size) = Some(page_size);
+		Some(page_size)
+	}
+
+	/// Run a sync cycle, repeating it after transient errors, truncated
+	/// results and rejected page sizes as configured
+	async fn run_attempts(
+		&mut self,
+		last_sync_time: Option<OffsetDateTime>,
+		options: &mut Cow<'_, SyncOptions>,
+		phase: &mut Phase,
+		report: &mut SyncReport,
+		span: &tracing::Span,
+	) -> Result<(), Error> {
+		loop {
+			// Only the latencies of the last attempt are reported
+			report.bind_latency = None;
+			report.first_page_latency = None;
+			let result = self
+				.run_cycle(last_sync_time, options, phase, report)
+				.instrument(span.clone())
+				.await;
+			// Also if the attempt failed, as failures are often slow
+			if !self.dry_run {
+				self.check_latencies(report);
+			}
+			// Also if the cycle failed, e.g. to not keep the pids of a large
+			// directory in memory until the next cycle
+			*lock(&self.cycle_pids) = CyclePids::default();
+			let retry = self.config.retry;
+			let searches = &self.config.searches;
+			let paged = options.page_size.or(searches.page_size).is_some();
+			match result {
+				Ok(()) if report.size_limit_exceeded && !paged => {
+					let Some(page_size) = searches.size_limit_page_size else {
+						break Ok(());
+					};
+					info!(parent: span, "Repeating the truncated search as paged search");
+					options.to_mut().page_size = Some(page_size);
+					report.size_limit_exceeded = false;
+				}
+				Err(err)
+					if is_admin_limit_exceeded(&err)
+						&& options.page_size.is_none()
+						&& searches.adaptive_page_size.is_some() =>
+				{
+					let Some(page_size) = self.reduce_page_size() else {
+						break Err(err);
+					};
+					warn!(
+						parent: span,
+						"The server rejected the page size, repeating the search with {page_size}"
+					);
+				}
+				Err(err)
+					if err.is_retryable()
+						&& report.retries < retry.attempts
+						&& self.deadline.is_none_or(|(at, _)| Instant::now() < at) =>
+				{
+					report.retries += 1;
+					warn!(
+						parent: span,
+						"Sync cycle failed in the {phase:?} phase, retrying ({}/{}): {err}",
+						report.retries,
+						retry.attempts
+					);
+					tokio::time::sleep(retry.delay(report.retries)).await;
+				}
+				result => break result,
+			}
+		}
+	}
+
+	/// Perform the steps of a sync cycle, see [`Ldap::sync_cycle`]
+	async fn run_cycle(
+		&mut self,
+		last_sync_time: Option<OffsetDateTime>,
+		options: &SyncOptions,
+		phase: &mut Phase,
+		report: &mut SyncReport,
+	) -> Result<(), Error> {
+		*phase = Phase::Connect;
+		if !self.dry_run {
+			self.start_resend();
+			self.redeliver_unacknowledged().await;
+		}
+		// TODO: more LDAP server configurations.
+		let (mut ldap, conn) = self.open_connection(phase, report).await?;
+		if !*self.initial_sync.borrow() {
+			self.log_authz_id(&mut ldap).await;
+			self.probe_server(&mut ldap).await;
+		}
 
 		// Prepare search parameters
+		*phase = Phase::Search;
+		self.set_state(PollerState::Searching { page: 1 });
+		self.load_schema(&mut ldap).await?;
+		let pages = Arc::new(AtomicU64::new(0));
 		let mut adapters: Vec<Box<dyn Adapter<_, _>>> = vec![Box::new(EntriesOnly::new())];
-		if let Some(page_size) = self.config.searches.page_size {
+		let page_size = options.page_size.or_else(|| self.page_size()).filter(|_| {
+			!lock(&self.server_info).as_ref().is_some_and(ServerInfo::lacks_paged_results)
+		});
+		report.page_size = page_size;
+		if let Some(page_size) = page_size {
 			adapters.push(Box::new(PagedResults::new(page_size)));
 		}
+		let state = (!self.dry_run).then(|| self.state.clone());
+		adapters.push(Box::new(PageCounter { pages: pages.clone(), state, in_page: false }));
+		if let Some(page_size) = page_size {
+			let page_size = usize::try_from(page_size).unwrap_or(usize::MAX);
+			adapters.push(Box::new(PageBackPressure { senders: self.senders.clone(), page_size }));
+		}
 		let attributes = self.config.attributes.clone();
-		let filter = match (
-			self.config.check_for_deleted_entries,
-			last_sync_time,
-			&self.config.attributes.updated,
-		) {
-			(false, Some(last_sync_time), Some(updated_attr)) => {
-				format!(
-					"(&{}({}>={}))",
-					self.config.searches.user_filter,
-					updated_attr,
-					last_sync_time
-						.format(&crate::config::TIME_FORMAT)
-						.map_err(|_| Error::Invalid("TIME_FORMAT is invalid".to_owned()))?,
-				)
-			}
-			_ => self.config.searches.user_filter.clone(),
-		};
+		let (plan, resumed) = self.plan_search(last_sync_time, options, page_size).await?;
+		let full_sync = plan.full_sync;
+		if page_size.is_some()
+			&& self.config.searches.resume_paged_search
+			&& options.filter.is_none()
+			&& !self.dry_run
+		{
+			adapters
+				.push(Box::new(PageProgress { cache: self.cache.clone(), progress: plan.clone() }));
+		}
+		report.resumed_from = resumed.then_some(plan.started);
 
+		let controls = self.config.searches.request_controls();
+		if !controls.is_empty() {
+			ldap.with_controls(controls);
+		}
+		let search_started = Instant::now();
 		let mut search = ldap
-			.with_timeout(self.config.connection.operation_timeout)
+			.with_timeout(self.operation_timeout())
 			.streaming_search_with(
 				adapters,
 				&self.config.searches.user_base,
 				Scope::Subtree,
-				&filter,
+				&plan.filter,
 				attributes.get_attr_filter(),
 			)
 			.await?;
 
-		self.cache.write().await.start_comparison();
+		// A resumed full sync continues the comparison of the interrupted one
+		if !resumed {
+			self.with_cache(&mut report.resources, Cache::start_comparison).await;
+		}
 
 		// Perform the search
-		while let Some(entry) = search.next().await?.map(SearchEntry::construct) {
-			let status = self.cache.write().await.check_entry(&entry, &self.config.attributes);
-			match status {
-				Ok(CacheEntryStatus::Missing) => {
-					self.send_channel_update(EntryStatus::New(entry)).await;
-				}
-				Ok(CacheEntryStatus::Unchanged) => continue,
-				Ok(CacheEntryStatus::Changed(old)) => {
-					self.send_channel_update(EntryStatus::Changed { old: old.into(), new: entry })
-						.await;
-				}
-				Err(err) => {
-					error!("Validating cache entry failed: {err}");
-					continue;
-				}
-			}
+		*lock(&self.cycle_pids) = CyclePids::default();
+		let received_before = report.entries_received;
+		self.receive_entries(&mut search, search_started, report).await?;
+		self.process_moved(report).await;
+		report.first_page_latency.get_or_insert_with(|| search_started.elapsed());
+		self.check_search_result(search.finish().await, report)?;
+		report.resources.pages_fetched = pages.load(Ordering::Relaxed);
+		if full_sync && !report.size_limit_exceeded {
+			let received = report.entries_received - received_before;
+			report.entries_matched = Some(received + plan.entries);
 		}
-		search.finish().await.success()?;
 
-		if self.config.check_for_deleted_entries {
-			let missing =
-				self.cache.write().await.end_comparison_and_return_missing_entries().clone();
-			for id in missing {
-				self.send_channel_update(EntryStatus::Removed(id.clone())).await;
-			}
+		self.refetch_invalidated(&mut ldap, report).await?;
+		self.recheck_entries(&mut ldap, report).await?;
+
+		*phase = Phase::Removal;
+		if !report.size_limit_exceeded {
+			self.detect_removals(&mut ldap, full_sync, last_sync_time, report).await?;
+			self.complete_search(&plan, options, report).await;
 		}
 
-		ldap.with_timeout(self.config.connection.operation_timeout).unbind().await?;
+		self.close_connection(ldap, conn).await?;
 
-		if let Err(err) = conn.await {
-			warn!("Failed to join background task: {err}");
+		if self.dry_run {
+			return Ok(());
 		}
 
+		if let Some(debounce) = self.config.debounce {
+			self.flush_changes_older_than(debounce).await;
+		}
+		self.send_batch(false).await;
+
+		// Truncated results don't complete the enumeration
+		if !report.size_limit_exceeded {
+			self.initial_sync.send_replace(true);
+		}
+		#[cfg(feature = "metrics")]
+		report.record_metrics();
+
 		Ok(())
 	}
 
-	/// Helper function to send an update to the user data channel
-	async fn send_channel_update(&mut self, status: EntryStatus) {
-		if let Err(e) = self.sender.send(status).await {
-			error!("Sending update failed: {e}");
+	/// Run entries obtained from another source, e.g. an LDIF file or a SCIM
+	/// dump, through the same comparison against the cache as search results,
+	/// pushing any new, changed or moved entries. Removals aren't detected,
+	/// as the entries aren't known to be complete.
+	pub async fn process_entries(
+		&mut self,
+		entries: impl IntoIterator<Item = SearchEntry>,
+	) -> SyncReport {
+		self.apply_pending_config().await;
+		let mut report = SyncReport::default();
+		*lock(&self.cycle_pids) = CyclePids::default();
+		for entry in entries {
+			report.resources.record_entry(&entry);
+			self.process_entry(entry, &mut report).await;
 		}
+		self.process_moved(&mut report).await;
+		*lock(&self.cycle_pids) = CyclePids::default();
+		if let Some(debounce) = self.config.debounce {
+			self.flush_changes_older_than(debounce).await;
+		}
+		self.send_batch(false).await;
+		self.publish_snapshot().await;
+		report
 	}
 
-	/// Persist the cache
-	pub async fn persist_cache(&self) -> Cache {
-		self.cache.read().await.clone()
+	/// Whether a sync starting now has to search for all entries, either
+	/// because there was no previous sync, or to check for deleted entries
+	async fn is_full_sync(
+		&self,
+		last_sync_time: Option<OffsetDateTime>,
+		now: OffsetDateTime,
+	) -> bool {
+		if last_sync_time.is_none() {
+			return true;
+		}
+		if !self.config.check_for_deleted_entries {
+			return false;
+		}
+		match (self.config.full_sync_interval, self.cache.read().await.last_full_sync_time) {
+			(Some(interval), Some(last_full_sync_time)) => now - last_full_sync_time >= interval,
+			_ => true,
+		}
+	}
+
+	/// The search filter to use for a sync, which only matches entries
+	/// modified since the last sync unless a full sync is performed
+	fn search_filter(
+		&self,
+		full_sync: bool,
+		last_sync_time: Option<OffsetDateTime>,
+		highest_usn: Option<u64>,
+	) -> Result<String, Error> {
+		if let (false, Some(usn_attr), Some(highest_usn)) =
+			(full_sync, &self.config.attributes.usn, highest_usn)
+		{
+			return usn_filter(&self.config.searches.user_filter, usn_attr, highest_usn);
+		}
+		match (full_sync, last_sync_time, &self.config.attributes.updated) {
+			(false, Some(last_sync_time), Some(updated_attr)) => incremental_filter(
+				&self.config.searches,
+				updated_attr,
+				self.config.attributes.updated_format,
+				last_sync_time - self.sync_overlap(),
+			),
+			_ => Ok(self.config.searches.user_filter.clone()),
+		}
+	}
+
+	/// The overlap of incremental searches with the previous sync, see
+	/// [`crate::config::Searches::sync_overlap`], which is at least
+	/// [`TIMESTAMP_OVERLAP`] if modification times are too coarse for the
+	/// schedule
+	fn sync_overlap(&self) -> time::Duration {
+		let configured = self.config.searches.sync_overlap.unwrap_or_default();
+		let configured = time::Duration::try_from(configured).unwrap_or(time::Duration::MAX);
+		if self.widen_overlap.load(Ordering::Relaxed) {
+			configured.max(TIMESTAMP_OVERLAP)
+		} else {
+			configured
+		}
+	}
+
+	/// Compare a search result with the cache and push the resulting update
+	async fn process_entry(&mut self, entry: SearchEntry, report: &mut SyncReport) {
+		let entry = self.prepare_entry(entry, report);
+		if let Some(pid) = entry.bin_attr_first(&self.config.attributes.pid) {
+			if self.config.canary.is_some_and(|canary| !canary.includes(pid)) {
+				return;
+			}
+		}
+		let entry = match &self.entry_transform {
+			Some(transform) => (transform.0)(entry),
+			None => entry,
+		};
+		if self.entry_filter.as_ref().is_some_and(|filter| !(filter.0)(&entry)) {
+			return;
+		}
+		if self.config.attributes.disabled.as_ref().is_some_and(|disabled| disabled.matches(&entry))
+		{
+			self.remove_disabled(&entry, report).await;
+			return;
+		}
+		match self.pid_conflict(&entry).await {
+			PidCheck::Unique => {}
+			PidCheck::Duplicate => return,
+			PidCheck::Conflict(pid, dn) => {
+				self.push_pid_conflict(pid, dn, entry, report).await;
+				return;
+			}
+			PidCheck::Moved(pid) => {
+				lock(&self.cycle_pids).moved.insert(pid, entry);
+				return;
+			}
+		}
+		self.compare_received(entry, report).await;
+	}
+
+	/// Compare an entry which passed all checks of [`Ldap::process_entry`]
+	/// with the cache and push the resulting update
+	async fn compare_received(&mut self, entry: SearchEntry, report: &mut SyncReport) {
+		match cache::parse_updated(&entry, &self.config.attributes) {
+			Some(Ok(updated)) => report.record_updated(updated),
+			Some(Err(_)) => {
+				report.record_unparsable_updated(&entry.dn);
+				self.diagnose(Diagnostic::warning(
+					Phase::Compare,
+					format!("Unparsable modification time in {}", entry.dn),
+				));
+			}
+			None => {}
+		}
+		let (status, old_group) = self.check_entry(&entry, report).await;
+		// The cache only stores the new members if the entry was updated
+		let memberships = match (&status, old_group) {
+			(Ok(CacheEntryStatus::Unchanged) | Err(_), _) | (_, None) => Vec::new(),
+			(Ok(_), Some(old_group)) => self.membership_updates(&entry, old_group),
+		};
+		match status {
+			Ok(CacheEntryStatus::Missing) => {
+				self.push_update(EntryStatus::New(Arc::new(entry)), report).await;
+			}
+			Ok(CacheEntryStatus::Unchanged) => {
+				if let Some(pid) = entry
+					.bin_attr_first(&self.config.attributes.pid)
+					.filter(|_| self.config.emit_unchanged)
+				{
+					self.push_update(EntryStatus::Unchanged(pid.to_owned()), report).await;
+				}
+			}
+			Ok(CacheEntryStatus::Changed(old)) => {
+				self.push_update(
+					EntryStatus::Changed {
+						old: old.map(|old| Arc::new(old.into())),
+						new: Arc::new(entry),
+					},
+					report,
+				)
+				.await;
+			}
+			Ok(CacheEntryStatus::Moved(old_dn)) => {
+				if let Some(pid) = entry.bin_attr_first(&self.config.attributes.pid) {
+					let pid = pid.to_owned();
+					let new_dn = entry.dn.clone();
+					let entry = Arc::new(entry);
+					self.push_update(EntryStatus::Moved { pid, old_dn, new_dn, entry }, report)
+						.await;
+				}
+			}
+			Err(cache::Error::Missing) => {
+				let reason = SkipReason::MissingPid;
+				warn!("Skipping {}: {reason}", entry.dn);
+				self.diagnose(Diagnostic::warning(
+					Phase::Compare,
+					format!("Skipping {}: {reason}", entry.dn),
+				));
+				self.push_update(EntryStatus::Skipped { dn: entry.dn, reason }, report).await;
+			}
+		}
+		for membership in memberships {
+			self.push_update(membership, report).await;
+		}
+	}
+
+	/// Compare an entry with the cache and store it, returning its status and
+	/// the cached version if the entry is a group. The cached version is
+	/// shared rather than copied, as it's only needed if the group changed.
+	/// The entry is compared under the read lock, so that compare workers
+	/// don't wait for each other, only storing it needs the write lock.
+	async fn check_entry(
+		&self,
+		entry: &SearchEntry,
+		report: &mut SyncReport,
+	) -> (Result<CacheEntryStatus, cache::Error>, Option<Option<Arc<SerializedSearchEntry>>>) {
+		let attributes = &self.config.attributes;
+		let (comparison, old_group) = {
+			let cache = self.cache.read().await;
+			let old_group = attributes.members.as_ref().map(|_| {
+				cache.shared_entry(entry.bin_attr_first(&attributes.pid).unwrap_or_default())
+			});
+			(cache.compare_entry(entry, attributes), old_group)
+		};
+		let status = match comparison {
+			Ok(comparison) => Ok(self
+				.with_cache(&mut report.resources, |cache| cache.commit_entry(comparison))
+				.await),
+			Err(err) => Err(err),
+		};
+		(status, old_group)
+	}
+
+	/// Receive the entries of a search and process them, by the compare
+	/// workers if there are any
+	async fn receive_entries<'a, S, A>(
+		&mut self,
+		search: &mut SearchStream<'a, S, A>,
+		search_started: Instant,
+		report: &mut SyncReport,
+	) -> Result<(), Error>
+	where
+		S: AsRef<str> + Send + Sync + 'a,
+		A: AsRef<[S]> + Send + Sync + 'a,
+	{
+		let mut pipeline = Pipeline::start(self, self.config.compare_workers);
+		let result = loop {
+			let next = search.next().await;
+			report.first_page_latency.get_or_insert_with(|| search_started.elapsed());
+			let entry = match next {
+				Ok(Some(entry)) => SearchEntry::construct(entry),
+				Ok(None) => break Ok(()),
+				Err(err) => break Err(err.into()),
+			};
+			report.entries_received += 1;
+			report.resources.record_entry(&entry);
+			report.resources.record_in_flight(self.queued(), report.entries_received);
+			pipeline.process(self, entry, report).await;
+		};
+		// Entries handed to the workers are processed even if the search failed
+		pipeline.finish(report).await;
+		result
+	}
+
+	/// Normalize the pid and limit the values of a received entry, and record
+	/// its update sequence number
+	fn prepare_entry(&self, entry: SearchEntry, report: &mut SyncReport) -> SearchEntry {
+		let entry = cache::normalize_pid(entry, &self.config.attributes);
+		let entry = self.config.attributes.limit_values(entry);
+		if let Some(usn) = self.config.attributes.usn.as_ref().and_then(|usn| entry.attr_first(usn))
+		{
+			match usn.parse() {
+				Ok(usn) => report.record_usn(usn),
+				Err(_) => warn!("Unparsable update sequence number in {}: {usn}", entry.dn),
+			}
+		}
+		entry
+	}
+
+	/// Record the pid of an entry as received in the current cycle, and
+	/// check whether an entry with another DN has the same pid. If the
+	/// entry was cached under another DN, it is only processed at the end of
+	/// the search, see [`Ldap::process_moved`], so that an entry received at
+	/// the cached DN wins regardless of the order of the results.
+	async fn pid_conflict(&self, entry: &SearchEntry) -> PidCheck {
+		let Some(pid) = entry.bin_attr_first(&self.config.attributes.pid) else {
+			return PidCheck::Unique;
+		};
+		let cached_dn = self.cache.read().await.dn(pid).map(ToOwned::to_owned);
+		let mut cycle_pids = lock(&self.cycle_pids);
+		if let Some(dn) = cycle_pids.received.get(pid) {
+			// Compare workers would otherwise compare both copies against
+			// the same cached entry and each emit an update
+			return if same_dn(dn, &entry.dn) {
+				PidCheck::Duplicate
+			} else {
+				PidCheck::Conflict(pid.to_owned(), dn.clone())
+			};
+		}
+		let is_cached_dn = cached_dn.as_deref().is_none_or(|dn| same_dn(dn, &entry.dn));
+		match cycle_pids.moved.get(pid) {
+			Some(moved) if same_dn(&moved.dn, &entry.dn) => PidCheck::Duplicate,
+			Some(moved) if !is_cached_dn => PidCheck::Conflict(pid.to_owned(), moved.dn.clone()),
+			None if !is_cached_dn => PidCheck::Moved(pid.to_owned()),
+			_ => {
+				cycle_pids.received.insert(pid.to_owned(), entry.dn.clone());
+				PidCheck::Unique
+			}
+		}
+	}
+
+	/// Process the entries of the cycle which were received under another DN
+	/// than the cached one, unless the entry was also received at the cached
+	/// DN, see [`Ldap::pid_conflict`]
+	async fn process_moved(&mut self, report: &mut SyncReport) {
+		let moved = std::mem::take(&mut lock(&self.cycle_pids).moved);
+		for (pid, entry) in moved {
+			let received = lock(&self.cycle_pids).received.get(&pid).cloned();
+			match received {
+				Some(dn) => self.push_pid_conflict(pid, dn, entry, report).await,
+				None => {
+					lock(&self.cycle_pids).received.insert(pid, entry.dn.clone());
+					self.compare_received(entry, report).await;
+				}
+			}
+		}
+	}
+
+	/// Skip an entry which has the same pid as the entry at `dn`
+	async fn push_pid_conflict(
+		&mut self,
+		pid: Vec<u8>,
+		dn: String,
+		entry: SearchEntry,
+		report: &mut SyncReport,
+	) {
+		let message = format!("Skipping {}, it has the same persistent ID as {dn}", entry.dn);
+		warn!("{message}");
+		self.diagnose(Diagnostic::warning(Phase::Compare, message));
+		let conflict = EntryStatus::PidConflict { pid, dn, conflicting_dn: entry.dn };
+		self.push_update(conflict, report).await;
+	}
+
+	/// The membership changes between the cached version of a group entry,
+	/// if it was cached, and the entry
+	fn membership_updates(
+		&self,
+		entry: &SearchEntry,
+		old_group: Option<Arc<SerializedSearchEntry>>,
+	) -> Vec<EntryStatus> {
+		let attributes = &self.config.attributes;
+		let (Some(members), Some(pid)) =
+			(&attributes.members, entry.bin_attr_first(&attributes.pid))
+		else {
+			return Vec::new();
+		};
+		let old_members = old_group
+			.as_ref()
+			.and_then(|group| group.attrs.get(members))
+			.map_or(&[][..], Vec::as_slice);
+		let new_members = entry.attrs.get(members).map_or(&[][..], Vec::as_slice);
+		membership::membership_updates(pid, &entry.dn, old_members, new_members)
+	}
+
+	/// Push the removal of an entry with its last known version, followed by
+	/// the removal of all members if it is a group, see
+	/// [`crate::config::AttributeConfig::members`]
+	async fn push_removal(
+		&mut self,
+		pid: Vec<u8>,
+		entry: Option<SearchEntry>,
+		report: &mut SyncReport,
+	) {
+		let memberships = match (&self.config.attributes.members, &entry) {
+			(Some(members), Some(entry)) => {
+				let members = entry.attrs.get(members).map_or(&[][..], Vec::as_slice);
+				membership::membership_updates(&pid, &entry.dn, members, &[])
+			}
+			_ => Vec::new(),
+		};
+		let entry = entry.map(Arc::new);
+		self.push_update(EntryStatus::Removed { pid, entry }, report).await;
+		for membership in memberships {
+			self.push_update(membership, report).await;
+		}
+	}
+
+	/// Push removals of entries which were missing from a full search, or
+	/// which have a tombstone
+	async fn detect_removals(
+		&mut self,
+		ldap: &mut ldap3::Ldap,
+		full_sync: bool,
+		last_sync_time: Option<OffsetDateTime>,
+		report: &mut SyncReport,
+	) -> Result<(), Error> {
+		if full_sync && self.config.check_for_deleted_entries {
+			let confirmation = self.config.removal_confirmation;
+			let missing = self
+				.with_cache(&mut report.resources, |cache| match confirmation {
+					RemovalConfirmation::ConsecutiveMisses(required) => {
+						cache.count_misses(required)
+					}
+					RemovalConfirmation::None | RemovalConfirmation::Lookup => {
+						cache.end_comparison_and_return_missing_entries().iter().cloned().collect()
+					}
+				})
+				.await;
+			for id in missing {
+				if self.config.canary.is_some_and(|canary| !canary.includes(&id)) {
+					continue;
+				}
+				if confirmation == RemovalConfirmation::Lookup
+					&& !self.confirm_removal(ldap, &id, report).await?
+				{
+					continue;
+				}
+				let entry = self.cache.read().await.entry(&id);
+				self.push_removal(id, entry, report).await;
+			}
+		} else if let (false, Some(tombstones), Some(last_sync_time)) =
+			(full_sync, &self.config.tombstones, last_sync_time)
+		{
+			for id in self.find_tombstones(ldap, tombstones, last_sync_time).await? {
+				if self.config.canary.is_some_and(|canary| !canary.includes(&id)) {
+					continue;
+				}
+				let entry = self.cache.read().await.entry(&id);
+				if self.with_cache(&mut report.resources, |cache| cache.remove_entry(&id)).await {
+					self.push_removal(id, entry, report).await;
+				}
+			}
+		}
+		Ok(())
+	}
+
+	/// Look up an entry missing from a full search at its cached DN, see
+	/// [`RemovalConfirmation::Lookup`]. Returns whether it was removed, and
+	/// processes it like a search result otherwise.
+	async fn confirm_removal(
+		&mut self,
+		ldap: &mut ldap3::Ldap,
+		pid: &[u8],
+		report: &mut SyncReport,
+	) -> Result<bool, Error> {
+		let Some(dn) = self.cache.read().await.dn(pid).map(ToOwned::to_owned) else {
+			return Ok(true);
+		};
+		// E.g. after the base was changed, the entry is out of scope
+		if let (Ok(parsed), Ok(base)) = (Dn::parse(&dn), Dn::parse(&self.config.searches.user_base))
+		{
+			if !parsed.is_under(&base) {
+				return Ok(true);
+			}
+		}
+		let result = ldap
+			.with_timeout(self.operation_timeout())
+			.search(
+				&dn,
+				Scope::Base,
+				&self.config.searches.user_filter,
+				self.config.attributes.get_attr_filter(),
+			)
+			.await?;
+		if result.1.rc == NO_SUCH_OBJECT {
+			return Ok(true);
+		}
+		let Some(entry) = result.success()?.0.into_iter().next() else {
+			return Ok(true);
+		};
+		let entry = cache::normalize_pid(SearchEntry::construct(entry), &self.config.attributes);
+		let replaced = entry.bin_attr_first(&self.config.attributes.pid) != Some(pid);
+		debug!("{dn} is still present");
+		self.process_entry(entry, report).await;
+		Ok(replaced)
+	}
+
+	/// Look up the entries invalidated by [`Ldap::invalidate`] which the
+	/// search of the cycle didn't return at their cached DN, so that they are
+	/// emitted again, reporting them as removed if they are gone
+	async fn refetch_invalidated(
+		&mut self,
+		ldap: &mut ldap3::Ldap,
+		report: &mut SyncReport,
+	) -> Result<(), Error> {
+		if self.dry_run {
+			return Ok(());
+		}
+		let invalidated = self.cache.read().await.invalidated.clone();
+		for pid in invalidated {
+			self.recheck_entry(ldap, &pid, report).await?;
+			// Also if the entry was skipped, e.g. by the entry filter
+			self.with_cache(&mut report.resources, |cache| cache.invalidated.remove(&pid)).await;
+		}
+		Ok(())
+	}
+
+	/// Look up the entries requested by [`Ldap::recheck`] at their cached DN,
+	/// reporting them as removed if they are gone
+	async fn recheck_entries(
+		&mut self,
+		ldap: &mut ldap3::Ldap,
+		report: &mut SyncReport,
+	) -> Result<(), Error> {
+		if self.dry_run {
+			return Ok(());
+		}
+		let rechecks = lock(&self.rechecks).clone();
+		for pid in rechecks {
+			self.recheck_entry(ldap, &pid, report).await?;
+			lock(&self.rechecks).remove(&pid);
+		}
+		Ok(())
+	}
+
+	/// Look up a cached entry at its DN, processing it like a search result
+	/// if it is still present and reporting it as removed otherwise
+	async fn recheck_entry(
+		&mut self,
+		ldap: &mut ldap3::Ldap,
+		pid: &[u8],
+		report: &mut SyncReport,
+	) -> Result<(), Error> {
+		if self.confirm_removal(ldap, pid, report).await? {
+			let entry = self.cache.read().await.entry(pid);
+			if self.with_cache(&mut report.resources, |cache| cache.remove_entry(pid)).await {
+				self.push_removal(pid.to_owned(), entry, report).await;
+			}
+		}
+		Ok(())
+	}
+
+	/// Report an entry matching [`crate::config::AttributeConfig::disabled`]
+	/// as removed, if it was synced before
+	async fn remove_disabled(&mut self, entry: &SearchEntry, report: &mut SyncReport) {
+		let Some(pid) = entry.bin_attr_first(&self.config.attributes.pid) else {
+			return;
+		};
+		let old = self.cache.read().await.entry(pid);
+		if self.with_cache(&mut report.resources, |cache| cache.remove_entry(pid)).await {
+			self.push_removal(pid.to_owned(), old, report).await;
+		}
+	}
+
+	/// Run a function with write access to the cache, recording how long the
+	/// lock was held
+	async fn with_cache<T>(
+		&self,
+		usage: &mut ResourceUsage,
+		f: impl FnOnce(&mut Cache) -> T + Send,
+	) -> T {
+		let mut cache = self.cache.write().await;
+		let started = Instant::now();
+		let result = f(&mut cache);
+		usage.record_lock_hold(started.elapsed());
+		result
+	}
+
+	/// Publish what the poller is doing, unless this is a dry run
+	fn set_state(&self, state: PollerState) {
+		if !self.dry_run {
+			self.state.send_replace(state);
+		}
+	}
+
+	/// Get a receiver of what the poller is doing right now, e.g. connecting
+	/// or searching, for a UI or a health endpoint. Only the latest state is
+	/// kept, so short-lived states may be missed.
+	#[must_use]
+	pub fn subscribe_state(&self) -> watch::Receiver<PollerState> {
+		self.state.subscribe()
+	}
+
+	/// What the poller is doing right now, see [`Ldap::subscribe_state`]
+	#[must_use]
+	pub fn state(&self) -> PollerState {
+		self.state.borrow().clone()
+	}
+
+	/// Wait until the first sync cycle of this client (or any of its clones)
+	/// has completed successfully, i.e. until the cache is warm. Returns
+	/// immediately if that already happened.
+	pub async fn wait_for_initial_sync(&self) {
+		let mut receiver = self.initial_sync.subscribe();
+		// The sender is owned by `self`, so it can't be dropped while waiting
+		let _ = receiver.wait_for(|done| *done).await;
+	}
+
+	/// Get a receiver whose value becomes `true` once the first sync cycle
+	/// has completed successfully, like [`Ldap::wait_for_initial_sync`], e.g.
+	/// to report readiness from a health endpoint without waiting. Cycles
+	/// truncated by the size limit of the server don't count.
+	#[must_use]
+	pub fn subscribe_initial_sync(&self) -> watch::Receiver<bool> {
+		self.initial_sync.subscribe()
+	}
+
+	/// Search for tombstones of entries deleted since the given time, and
+	/// return the pids of the deleted entries
+	async fn find_tombstones(
+		&self,
+		ldap: &mut ldap3::Ldap,
+		tombstones: &TombstoneConfig,
+		since: OffsetDateTime,
+	) -> Result<Vec<Vec<u8>>, Error> {
+		let filter = tombstones.filter(since)?;
+		let mut controls = self.config.searches.request_controls();
+		if let TombstoneConfig::ActiveDirectory { .. } = tombstones {
+			controls.push(RawControl {
+				ctype: TombstoneConfig::SHOW_DELETED_OID.to_owned(),
+				crit: true,
+				val: None,
+			});
+		}
+		if !controls.is_empty() {
+			ldap.with_controls(controls);
+		}
+		let (entries, _) = ldap
+			.with_timeout(self.operation_timeout())
+			.search(
+				tombstones.base(),
+				Scope::Subtree,
+				&filter,
+				vec![self.config.attributes.pid.as_str(), "reqDN"],
+			)
+			.await?
+			.success()?;
+
+		let mut pids = Vec::new();
+		for entry in entries.into_iter().map(SearchEntry::construct) {
+			let pid = match tombstones {
+				TombstoneConfig::ActiveDirectory { .. } => {
+					entry.bin_attr_first(&self.config.attributes.pid).map(|pid| {
+						self.config.attributes.pid_normalization.normalize(pid).into_owned()
+					})
+				}
+				TombstoneConfig::AccessLog { .. } => match entry.attr_first("reqDN") {
+					Some(dn) => self.cache.read().await.pid_by_dn(dn),
+					None => None,
+				},
+			};
+			if let Some(pid) = pid {
+				pids.push(pid);
+			}
+		}
+		Ok(pids)
+	}
+
+	/// Count an update in the report and push it, unless this is a dry run
+	async fn push_update(&mut self, status: EntryStatus, report: &mut SyncReport) {
+		report.updates.record(&status);
+		if !self.dry_run {
+			self.send_channel_update(status).await;
+		}
+	}
+
+	/// Helper function to send an update to the user data channel, or the
+	/// removal channel for removals if one is configured. Changes are held
+	/// back instead if they are debounced.
+	async fn send_channel_update(&mut self, status: EntryStatus) {
+		if self.config.debounce.is_some() {
+			if let Some(pid) = self.update_pid(&status) {
+				let checkpoint = self.cache.read().await.checkpoint();
+				let pending = lock(&self.pending_changes).remove(&pid);
+				if let EntryStatus::Changed { old, new } = status {
+					let change = match pending {
+						Some(pending) => PendingChange { new, ..pending },
+						None => PendingChange { old, new, since: Instant::now(), checkpoint },
+					};
+					lock(&self.pending_changes).insert(pid, change);
+					return;
+				}
+				// Keep the order of updates of the same entry
+				if let Some(PendingChange { old, new, .. }) = pending {
+					self.deliver(EntryStatus::Changed { old, new }).await;
+				}
+			}
+		}
+		self.deliver(status).await;
+	}
+
+	/// The pid of the entry of an update
+	fn update_pid(&self, status: &EntryStatus) -> Option<Vec<u8>> {
+		match status {
+			EntryStatus::New(entry) | EntryStatus::Changed { new: entry, .. } => {
+				entry.bin_attr_first(&self.config.attributes.pid).map(ToOwned::to_owned)
+			}
+			EntryStatus::Removed { pid, .. }
+			| EntryStatus::Moved { pid, .. }
+			| EntryStatus::Unchanged(pid)
+			| EntryStatus::MembershipChanged { group: pid, .. } => Some(pid.clone()),
+			EntryStatus::Skipped { .. } | EntryStatus::PidConflict { .. } => None,
+		}
+	}
+
+	/// Emit changes held back by [`Config::debounce`] for at least the given
+	/// time
+	async fn flush_changes_older_than(&mut self, age: Duration) {
+		let due: Vec<_> = {
+			let mut pending = lock(&self.pending_changes);
+			let due: Vec<_> = pending
+				.iter()
+				.filter(|(_, change)| change.since.elapsed() >= age)
+				.map(|(pid, _)| pid.clone())
+				.collect();
+			due.iter().filter_map(|pid| pending.remove(pid)).collect()
+		};
+		for PendingChange { old, new, .. } in due {
+			self.deliver(EntryStatus::Changed { old, new }).await;
+		}
+	}
+
+	/// Emit all changes held back by [`Config::debounce`] immediately, e.g.
+	/// before shutting down
+	pub async fn flush_changes(&mut self) {
+		self.flush_changes_older_than(Duration::ZERO).await;
+		self.send_batch(false).await;
+	}
+
+	/// Send an update to the subscribers, or the removal channel for removals
+	/// if one is configured
+	async fn deliver(&mut self, status: EntryStatus) {
+		let acked_sender = self.acked_sender();
+		let pid = acked_sender.as_ref().and_then(|_| self.update_pid(&status));
+		let rename = |entry: Arc<SearchEntry>| {
+			let attributes = &self.config.attributes;
+			if attributes.rename.is_empty() {
+				return entry;
+			}
+			Arc::new(attributes.rename_attributes(Arc::unwrap_or_clone(entry)))
+		};
+		let status = match status {
+			EntryStatus::New(entry) => EntryStatus::New(rename(entry)),
+			EntryStatus::Changed { old, new } => {
+				EntryStatus::Changed { old: old.map(rename), new: rename(new) }
+			}
+			EntryStatus::Moved { pid, old_dn, new_dn, entry } => {
+				EntryStatus::Moved { pid, old_dn, new_dn, entry: rename(entry) }
+			}
+			EntryStatus::Removed { pid, entry } => {
+				EntryStatus::Removed { pid, entry: entry.map(rename) }
+			}
+			status @ (EntryStatus::Unchanged(_)
+			| EntryStatus::MembershipChanged { .. }
+			| EntryStatus::Skipped { .. }
+			| EntryStatus::PidConflict { .. }) => status,
+		};
+		if let Some(audit_log) = &self.audit_log {
+			let attributes = &self.config.attributes;
+			let redacted: Vec<&str> = attributes
+				.uncached
+				.iter()
+				.chain(&self.config.persistence.encrypted_attributes)
+				.map(|name| attributes.rename.get(name).unwrap_or(name).as_str())
+				.collect();
+			let sync_id = self.sync_ids.load(Ordering::Relaxed);
+			if let Err(err) = audit_log.record(sync_id, &status, &redacted) {
+				self.diagnose(Diagnostic::warning(
+					Phase::Send,
+					format!("Writing the audit log failed: {err}"),
+				));
+			}
+		}
+		if let Some(sender) = acked_sender {
+			let checkpoint = self.cache.read().await.checkpoint();
+			self.send_acked(&sender, pid, status.clone(), checkpoint).await;
+		}
+		if let (EntryStatus::Removed { .. }, Some(removal_sender)) = (&status, &self.removal_sender)
+		{
+			if let Err(e) = removal_sender.send(status).await {
+				error!("Sending update failed: {e}");
+				self.diagnose(Diagnostic::warning(
+					Phase::Send,
+					format!("Sending update failed: {e}"),
+				));
+			}
+			return;
+		}
+
+		if self.add_to_batch(&status) && lock(&self.senders).iter().all(mpsc::Sender::is_closed) {
+			return;
+		}
+		let Some(status) = self.queue_behind_failed(status).await else {
+			return;
+		};
+		if let Err(status) = self.broadcast(status).await {
+			self.keep_failed(status);
+		}
+	}
+
+	/// Keep an update which could not be sent for re-emission, dropping the
+	/// oldest kept update if there are too many, see
+	/// [`Config::max_failed_sends`]
+	fn keep_failed(&self, status: EntryStatus) {
+		error!("Sending update failed, keeping it until a new receiver subscribes");
+		self.diagnose(Diagnostic::warning(
+			Phase::Send,
+			"Sending update failed, all receivers were dropped".to_owned(),
+		));
+		let max_failed_sends = self.config.max_failed_sends.unwrap_or(DEFAULT_MAX_FAILED_SENDS);
+		let mut failed_sends = lock(&self.failed_sends);
+		if failed_sends.updates.len() >= max_failed_sends {
+			failed_sends.updates.pop_front();
+			warn!("Dropping oldest failed update, at most {max_failed_sends} are kept");
+		}
+		if max_failed_sends > 0 {
+			failed_sends.updates.push_back(status);
+		}
+	}
+
+	/// Queue an update behind the failed updates, so that it isn't delivered
+	/// before them. While they are re-emitted, this only waits if the queue
+	/// is full. Returns the update if there are no failed updates.
+	async fn queue_behind_failed(&self, status: EntryStatus) -> Option<EntryStatus> {
+		self.start_resend();
+		let max_failed_sends = self.config.max_failed_sends.unwrap_or(DEFAULT_MAX_FAILED_SENDS);
+		loop {
+			let resent = self.resent.notified();
+			tokio::pin!(resent);
+			resent.as_mut().enable();
+			{
+				let mut failed_sends = lock(&self.failed_sends);
+				if failed_sends.updates.is_empty() && !failed_sends.resending {
+					return Some(status);
+				}
+				if !failed_sends.resending {
+					// There is still no receiver to re-emit the updates to
+					drop(failed_sends);
+					self.keep_failed(status);
+					return None;
+				}
+				if failed_sends.updates.len() < max_failed_sends {
+					failed_sends.updates.push_back(status);
+					return None;
+				}
+			}
+			resent.await;
+		}
+	}
+
+	/// Start re-emitting the failed updates in the background, unless there
+	/// are none, there is no receiver to re-emit them to, or they are already
+	/// being re-emitted
+	fn start_resend(&self) {
+		let mut failed_sends = lock(&self.failed_sends);
+		if failed_sends.resending
+			|| failed_sends.updates.is_empty()
+			|| lock(&self.senders).iter().all(mpsc::Sender::is_closed)
+		{
+			return;
+		}
+		// Subscribing outside of a runtime defers the re-emission to the next
+		// update or cycle
+		let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+			return;
+		};
+		failed_sends.resending = true;
+		runtime.spawn(self.clone().resend_failed().instrument(tracing::Span::current()));
+	}
+
+	/// Collect an update for the batch of the current cycle, returning
+	/// whether there are batch subscribers
+	fn add_to_batch(&self, status: &EntryStatus) -> bool {
+		let mut batch_senders = lock(&self.batch_senders);
+		batch_senders.retain(|sender| !sender.is_closed());
+		if batch_senders.is_empty() {
+			return false;
+		}
+		lock(&self.batch).push(status.clone());
+		true
+	}
+
+	/// Send the updates collected since the last batch to the batch
+	/// subscribers, unless there are none
+	async fn send_batch(&self, failed: bool) {
+		let updates = std::mem::take(&mut *lock(&self.batch));
+		if updates.is_empty() {
+			return;
+		}
+		let batch = SyncBatch { updates, failed };
+		let senders = lock(&self.batch_senders).clone();
+		for sender in senders {
+			if let Err(e) = sender.send(batch.clone()).await {
+				warn!("Sending batch failed: {e}");
+			}
+		}
+	}
+
+	/// Re-emit updates which could not be sent before, in order, as long as
+	/// there is a receiver, throttled by [`Config::resend_interval`]. Run in
+	/// the background by [`Ldap::start_resend`].
+	async fn resend_failed(self) {
+		let mut resent = false;
+		loop {
+			let next = {
+				let mut failed_sends = lock(&self.failed_sends);
+				let next = failed_sends.updates.pop_front();
+				failed_sends.resending = next.is_some();
+				next
+			};
+			self.resent.notify_waiters();
+			let Some(status) = next else {
+				return;
+			};
+			if let (true, Some(interval)) = (resent, self.config.resend_interval) {
+				tokio::time::sleep(interval).await;
+			}
+			if let Err(status) = self.broadcast(status).await {
+				let mut failed_sends = lock(&self.failed_sends);
+				failed_sends.updates.push_front(status);
+				failed_sends.resending = false;
+				drop(failed_sends);
+				self.resent.notify_waiters();
+				return;
+			}
+			resent = true;
+		}
+	}
+
+	/// The sender of updates which have to be acknowledged, unless there is
+	/// none or its receiver was dropped
+	fn acked_sender(&self) -> Option<mpsc::Sender<AckedUpdate>> {
+		let mut acked_sender = lock(&self.acked_sender);
+		if acked_sender.as_ref().is_some_and(mpsc::Sender::is_closed) {
+			debug!("The receiver of acknowledged updates was dropped");
+			*acked_sender = None;
+		}
+		acked_sender.clone()
+	}
+
+	/// Send an update to the acknowledging subscriber, keeping it until it
+	/// is acknowledged
+	async fn send_acked(
+		&self,
+		sender: &mpsc::Sender<AckedUpdate>,
+		pid: Option<Vec<u8>>,
+		status: EntryStatus,
+		checkpoint: Checkpoint,
+	) {
+		let ack = PendingAcks::register(&self.pending_acks, pid, status.clone(), checkpoint);
+		// The dropped handle marks the update for redelivery to the next
+		// subscriber
+		if sender.send(AckedUpdate { status, ack }).await.is_err() {
+			debug!("The receiver of acknowledged updates was dropped");
+		}
+	}
+
+	/// Deliver the updates again whose acknowledgment handles were dropped
+	/// without acknowledging them
+	async fn redeliver_unacknowledged(&self) {
+		let Some(sender) = self.acked_sender() else {
+			return;
+		};
+		let dropped = lock(&self.pending_acks).take_dropped();
+		if !dropped.is_empty() {
+			info!("Delivering {} unacknowledged updates again", dropped.len());
+		}
+		for update in dropped {
+			self.send_acked(&sender, update.pid, update.status, update.checkpoint).await;
+		}
+	}
+
+	/// Send an update to all subscribers, waiting for space in each of their
+	/// channels. Subscribers whose receiver was dropped are removed. Returns
+	/// the update if there are no subscribers left.
+	async fn broadcast(&self, status: EntryStatus) -> Result<(), EntryStatus> {
+		let senders = lock(&self.senders).clone();
+		let mut delivered = false;
+		for sender in &senders {
+			delivered |= sender.send(status.clone()).await.is_ok();
+		}
+		lock(&self.senders).retain(|sender| !sender.is_closed());
+		if delivered {
+			Ok(())
+		} else {
+			Err(status)
+		}
+	}
+
+	/// The largest number of updates waiting in the channel of a subscriber
+	fn queued(&self) -> usize {
+		lock(&self.senders)
+			.iter()
+			.map(|sender| sender.max_capacity() - sender.capacity())
+			.max()
+			.unwrap_or_default()
+	}
+
+	/// Get an additional receiver for updates. Every update is pushed to all
+	/// subscribed receivers, including the one returned on construction, so
+	/// the slowest of them determines how fast syncing progresses. Dropping a
+	/// receiver unsubscribes it.
+	///
+	/// This allows consumers to attach and detach over the lifetime of the
+	/// client, e.g. to reconnect after a consumer crashed. Updates which could
+	/// not be sent because all receivers were dropped are re-emitted to the
+	/// new receiver in the background before any further updates, paced by
+	/// [`Config::resend_interval`]. If this is called outside of a Tokio
+	/// runtime, the re-emission starts with the next update or sync cycle.
+	/// Removals delivered on a separate channel (see [`Ldap::new_split`]) are
+	/// not affected.
+	#[must_use]
+	pub fn subscribe(&self) -> mpsc::Receiver<EntryStatus> {
+		let (sender, receiver) = mpsc::channel::<EntryStatus>(1024);
+		lock(&self.senders).push(sender);
+		self.start_resend();
+		receiver
+	}
+
+	/// Get a receiver for all updates of a sync cycle at once, sent at the end
+	/// of the cycle, e.g. to commit them to a database in a single
+	/// transaction. Updates are still pushed to the per-update receivers as
+	/// well, but don't need to be consumed from there once a batch receiver
+	/// exists: they are dropped if all per-update receivers were dropped.
+	/// Removals delivered on a separate channel (see [`Ldap::new_split`]) are
+	/// not part of the batches. Cycles without updates send no batch, and the
+	/// updates a failed cycle pushed before failing are sent as a batch
+	/// marked as [failed](SyncBatch::failed).
+	#[must_use]
+	pub fn subscribe_batches(&self) -> mpsc::Receiver<SyncBatch> {
+		let (sender, receiver) = mpsc::channel(16);
+		lock(&self.batch_senders).push(sender);
+		receiver
+	}
+
+	/// Get a receiver for updates which have to be acknowledged, for
+	/// at-least-once delivery. Updates whose [`crate::ack::Ack`] handle is
+	/// dropped without acknowledging them are delivered again with the next
+	/// sync cycle, unless a newer update of the same entry was delivered in
+	/// the meantime. Once the receiver is dropped, unacknowledged updates are
+	/// kept for the next receiver.
+	///
+	/// Entries with unacknowledged updates are left out of persisted caches,
+	/// and the persisted sync position is held back to before the oldest of
+	/// these updates, so that they are searched and emitted as new again
+	/// after a restart. Unacknowledged removals are only delivered again
+	/// while running. Updates are still pushed to the other receivers as
+	/// well. Only the most recently returned receiver gets updates.
+	#[must_use]
+	pub fn subscribe_acked(&self) -> mpsc::Receiver<AckedUpdate> {
+		let (sender, receiver) = mpsc::channel(1024);
+		*lock(&self.acked_sender) = Some(sender);
+		receiver
+	}
+
+	/// Acknowledge the update with the given sequence number of the entry
+	/// with the given pid, which was delivered to [`Ldap::subscribe_acked`]
+	/// and whose handle was detached, see [`crate::ack::Ack::detach`]. This
+	/// lets the persisted sync position move past the update. Returns
+	/// whether the update was still unacknowledged, which it isn't if it was
+	/// acknowledged before or superseded by a newer update of the entry.
+	#[must_use]
+	pub fn ack(&self, pid: &[u8], seq: u64) -> bool {
+		lock(&self.pending_acks).ack(pid, seq)
+	}
+
+	/// Get a receiver for structured diagnostics about problems encountered
+	/// while syncing. Every returned receiver gets all diagnostics from then
+	/// on. Diagnostics are dropped for a receiver while its buffer is full,
+	/// so a slow consumer never holds up syncing.
+	#[must_use]
+	pub fn diagnostics(&self) -> mpsc::Receiver<Diagnostic> {
+		let (sender, receiver) = mpsc::channel(256);
+		lock(&self.diagnostics).push(sender);
+		receiver
+	}
+
+	/// Push a diagnostic to all diagnostics channels
+	fn diagnose(&self, diagnostic: Diagnostic) {
+		if diagnostic.severity == Severity::Warning {
+			self.warnings.fetch_add(1, Ordering::Relaxed);
+		}
+		let mut senders = lock(&self.diagnostics);
+		senders.retain(|sender| !sender.is_closed());
+		for sender in senders.iter() {
+			let _ = sender.try_send(diagnostic.clone());
+		}
+	}
+
+	/// Publish the current state of the cache as the snapshot returned by
+	/// [`Ldap::snapshot`]. Once no reader holds the snapshot replaced by the
+	/// last publication anymore, it's updated with the entries changed since
+	/// instead of copying the whole cache again.
+	async fn publish_snapshot(&self) {
+		let mut cache = self.cache.write().await;
+		let changed = cache.take_changes();
+		let mut retired = lock(&self.retired_snapshot);
+		let (outdated, mut stale) = match retired.take() {
+			Some((snapshot, stale)) => (Arc::try_unwrap(snapshot).ok(), stale),
+			None => (None, ChangedPids::default()),
+		};
+		stale.merge(&changed);
+		let snapshot = cache.refresh_copy(outdated, &stale);
+		*retired = Some((self.snapshot.swap(Arc::new(snapshot)), changed));
+	}
+
+	/// Summary of the most recent sync cycle, successful or not, e.g. for
+	/// admin endpoints. `None` until the first cycle finished. Dry runs are
+	/// not recorded.
+	#[must_use]
+	pub fn last_sync_info(&self) -> Option<SyncInfo> {
+		*lock(&self.last_sync_info)
+	}
+
+	/// Get the state of the cache at the end of the last sync cycle. Unlike
+	/// [`Ldap::persist_cache`], this never waits for a running sync.
+	#[must_use]
+	pub fn snapshot(&self) -> Arc<Cache> {
+		self.snapshot.load_full()
+	}
+
+	/// Get the cached entry with the given persistent ID as of the end of the
+	/// last sync cycle. Only available if full entries are cached, see
+	/// [`crate::config::CacheMethod`].
+	#[must_use]
+	pub fn get_entry(&self, pid: &[u8]) -> Option<SearchEntry> {
+		self.snapshot.load().entry(&self.config.attributes.pid_normalization.normalize(pid))
+	}
+
+	/// Get the cached entry with the given persistent ID, including changes
+	/// of a running sync cycle, e.g. to answer point lookups without keeping
+	/// a copy of all entries. Unlike [`Ldap::get_entry`], this waits while
+	/// the cache is being written. Only available if full entries are cached,
+	/// see [`crate::config::CacheMethod`].
+	pub async fn get_cached(&self, pid: &[u8]) -> Option<SearchEntry> {
+		let pid = self.config.attributes.pid_normalization.normalize(pid);
+		self.cache.read().await.entry(&pid)
+	}
+
+	/// Mark the entry with the given persistent ID as invalid, so that the
+	/// next sync cycle emits it as [`EntryStatus::New`] again, e.g. when
+	/// processing its update failed downstream. Entries not returned by an
+	/// incremental search are read by their DN, and reported as
+	/// [`EntryStatus::Removed`] if they are gone. The mark is persisted with
+	/// the cache. Returns whether the entry was cached.
+	pub async fn invalidate(&self, pid: &[u8]) -> bool {
+		let pid = self.config.attributes.pid_normalization.normalize(pid);
+		let mut cache = self.cache.write().await;
+		if cache.dn(&pid).is_none() {
+			return false;
+		}
+		cache.invalidated.insert(pid.into_owned());
+		true
+	}
+
+	/// Look up the cached entry with the given persistent ID at its DN during
+	/// the next sync cycle, e.g. because it wasn't confirmed for a long time,
+	/// see [`Cache::unconfirmed_since`]. The entry is compared like a search
+	/// result if it is still present, and reported as
+	/// [`EntryStatus::Removed`] otherwise. Returns whether the entry is
+	/// cached.
+	pub async fn recheck(&self, pid: &[u8]) -> bool {
+		let pid = self.config.attributes.pid_normalization.normalize(pid);
+		if self.cache.read().await.dn(&pid).is_none() {
+			return false;
+		}
+		lock(&self.rechecks).insert(pid.into_owned());
+		true
+	}
+
+	/// Summarize the cache as of the end of the last sync cycle, see
+	/// [`Cache::stats`]
+	#[must_use]
+	pub fn cache_stats(&self) -> CacheStats {
+		self.snapshot.load().stats()
+	}
+
+	/// Get the persistent IDs of all cached entries as of the end of the last
+	/// sync cycle
+	#[must_use]
+	pub fn cached_pids(&self) -> Vec<Vec<u8>> {
+		self.snapshot.load().pids().map(ToOwned::to_owned).collect()
+	}
+
+	/// Export the cache as of the end of the last sync cycle as pretty JSON
+	/// for debugging, see [`Cache::to_json_pretty`]
+	pub fn export_cache_json(&self) -> Result<String, Error> {
+		self.snapshot.load().to_json_pretty()
+	}
+
+	/// Persist the cache. Entries with unacknowledged updates, see
+	/// [`Ldap::subscribe_acked`], or with changes held back by
+	/// [`Config::debounce`] are left out, and the sync position is moved back
+	/// to before the oldest of these updates, so that they are searched and
+	/// emitted as new again after a restart.
+	pub async fn persist_cache(&self) -> Cache {
+		let mut cache = self.cache.read().await.clone();
+		let pending_acks = lock(&self.pending_acks);
+		for pid in pending_acks.pids() {
+			cache.remove_entry(pid);
+		}
+		if let Some(checkpoint) = pending_acks.checkpoint() {
+			cache.rewind(checkpoint);
+		}
+		for (pid, change) in lock(&self.pending_changes).iter() {
+			cache.remove_entry(pid);
+			cache.rewind(change.checkpoint);
+		}
+		cache
+	}
+
+	/// Persist the cache, serialized in the configured format. It can be
+	/// restored with the [`CacheCodec`] implementation of that format.
+	pub async fn persist_cache_bytes(&self) -> Result<Vec<u8>, Error> {
+		self.config.persistence.format.encode(&self.persist_cache().await)
+	}
+
+	/// Persist the cache like [`Ldap::persist_cache_bytes`], but encrypt the
+	/// attributes listed in
+	/// [`crate::config::PersistenceConfig::encrypted_attributes`] with the
+	/// given key. After restoring, they have to be decrypted with
+	/// [`Cache::decrypt_attributes`] before passing the cache to
+	/// [`Ldap::new`].
+	pub async fn persist_cache_bytes_encrypted(&self, key: &[u8; 32]) -> Result<Vec<u8>, Error> {
+		let mut cache = self.persist_cache().await;
+		cache.encrypt_attributes(&self.config.persistence.encrypted_attributes, key)?;
+		self.config.persistence.format.encode(&cache)
+	}
+}
+
+/// Check whether a connection responds, by reading the RootDSE without
+/// requesting any attributes
+async fn ping(ldap: &mut ldap3::Ldap, timeout: Duration) -> bool {
+	match ldap.with_timeout(timeout).search("", Scope::Base, "(objectClass=*)", ["1.1"]).await {
+		Ok(result) => result.success().is_ok(),
+		Err(err) => {
+			debug!("Reading the RootDSE failed: {err}");
+			false
+		}
+	}
+}
+
+/// Lock a mutex, ignoring poisoning since the protected data stays consistent
+/// when a holder panics
+fn lock<T>(mutex: &std::sync::Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+	mutex.lock().unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+/// OID of the simple paged results control
+pub(crate) const PAGED_RESULTS_OID: &str = "1.2.840.113556.1.4.319";
+
+/// Result code of searches truncated by the size limit of the server
+const SIZE_LIMIT_EXCEEDED: u32 = 4;
+
+/// Result code of operations exceeding a limit set by the administrator
+const ADMIN_LIMIT_EXCEEDED: u32 = 11;
+
+/// Whether the server rejected an operation with adminLimitExceeded
+fn is_admin_limit_exceeded(err: &Error) -> bool {
+	matches!(
+		err,
+		Error::Ldap(ldap3::LdapError::LdapResult { result }) if result.rc == ADMIN_LIMIT_EXCEEDED
+	)
+}
+
+/// Result code of operations on entries which don't exist
+const NO_SUCH_OBJECT: u32 = 32;
+
+/// Search adapter recording the position of a paged search in the cache after
+/// every page, see [`Searches::resume_paged_search`]. Placed after the paged
+/// results adapter like [`PageCounter`], it can also replace the cookie of the
+/// first page request to resume an interrupted search.
+#[derive(Debug, Clone)]
+struct PageProgress {
+	/// The cache to record the position in, see [`Ldap::cache`]
+	cache: Arc<RwLock<Cache>>,
+	/// The position of the search, starting with the cookie to resume from
+	progress: cache::PagedSearchProgress,
+}
+
+#[async_trait]
+impl<'a, S, A> Adapter<'a, S, A> for PageProgress
+where
+	S: AsRef<str> + Send + Sync + 'a,
+	A: AsRef<[S]> + Send + Sync + 'a,
+{
+	async fn start(
+		&mut self,
+		stream: &mut SearchStream<'a, S, A>,
+		base: &str,
+		scope: Scope,
+		filter: &str,
+		attrs: A,
+	) -> ldap3::result::Result<()> {
+		if !self.progress.cookie.is_empty() {
+			for control in stream.ldap_handle().controls.iter_mut().flatten() {
+				if control.ctype == PAGED_RESULTS_OID {
+					let size = control.parse::<PagedResultsControl>().size;
+					*control =
+						PagedResultsControl { size, cookie: self.progress.cookie.clone() }.into();
+				}
+			}
+		}
+		stream.start(base, scope, filter, attrs).await
+	}
+
+	async fn next(
+		&mut self,
+		stream: &mut SearchStream<'a, S, A>,
+	) -> ldap3::result::Result<Option<ResultEntry>> {
+		let next = stream.next().await;
+		match next {
+			Ok(Some(_)) => self.progress.entries += 1,
+			Ok(None) => {
+				if let Some(cookie) = next_page_cookie(stream) {
+					self.progress.cookie = cookie;
+					self.cache.write().await.paged_search = Some(self.progress.clone());
+				}
+			}
+			Err(_) => {}
+		}
+		next
+	}
+
+	async fn finish(&mut self, stream: &mut SearchStream<'a, S, A>) -> LdapResult {
+		stream.finish().await
+	}
+}
+
+/// The search filter matching entries modified since the given time, built
+/// from the template if one is configured
+fn incremental_filter(
+	searches: &Searches,
+	updated_attr: &str,
+	updated_format: TimestampFormat,
+	since: OffsetDateTime,
+) -> Result<String, Error> {
+	let since = updated_format.format(updated_attr, since)?;
+	match &searches.incremental_filter {
+		Some(template) => {
+			let filter = template
+				.replace("{filter}", &searches.user_filter)
+				.replace("{updated}", updated_attr)
+				.replace("{timestamp}", &since);
+			FilterBuilder::raw(&filter)?.build()
+		}
+		None => FilterBuilder::raw(&searches.user_filter)?
+			.and(FilterBuilder::ge(updated_attr, &since))
+			.build(),
+	}
+}
+
+/// The search filter matching entries with a higher update sequence number
+/// than the given one
+fn usn_filter(user_filter: &str, usn_attr: &str, highest_usn: u64) -> Result<String, Error> {
+	let next = highest_usn.saturating_add(1).to_string();
+	FilterBuilder::raw(user_filter)?.and(FilterBuilder::ge(usn_attr, &next)).build()
+}
+
+/// Migrate a cache to the given configuration, see [`Cache::migrate`],
+/// starting over with an empty cache if it can't be migrated
+fn migrated_cache(cache: Cache, config: &Config) -> Cache {
+	cache.migrate(config).unwrap_or_else(|err| {
+		warn!("{err}, clearing the cache");
+		let mut cache = Cache::new(CacheEntries::from(&config.cache_method));
+		cache.origin = Some(CacheOrigin::new(config));
+		cache
+	})
+}
+
+/// Whether two DNs name the same entry, comparing them like DNs if both are
+/// valid
+fn same_dn(a: &str, b: &str) -> bool {
+	match (Dn::parse(a), Dn::parse(b)) {
+		(Ok(a), Ok(b)) => a == b,
+		_ => a == b,
+	}
+}
+
+/// Compares received entries against the cache and pushes their updates,
+/// either directly or on a pool of worker tasks, see
+/// [`Config::compare_workers`]
+enum Pipeline {
+	/// Entries are processed one after the other by the sync cycle
+	Inline,
+	/// Entries are processed by worker tasks, each with its own report
+	Workers {
+		/// The sender of the queue the workers take entries from
+		sender: mpsc::Sender<SearchEntry>,
+		/// The workers, returning what they recorded
+		workers: Vec<tokio::task::JoinHandle<SyncReport>>,
+	},
+}
+
+impl Pipeline {
+	/// Start the given number of workers, if any, processing entries with
+	/// clones of the client
+	fn start(ldap: &Ldap, workers: Option<usize>) -> Self {
+		let Some(workers) = workers.filter(|workers| *workers > 0) else {
+			return Pipeline::Inline;
+		};
+		let (sender, receiver) = mpsc::channel(workers * 2);
+		let receiver = Arc::new(Mutex::new(receiver));
+		let workers = (0..workers)
+			.map(|_| {
+				let (mut ldap, receiver) = (ldap.clone(), receiver.clone());
+				let worker = async move {
+					let mut report = SyncReport::default();
+					loop {
+						// Bound by `let`, so the lock is released before processing
+						let entry = receiver.lock().await.recv().await;
+						let Some(entry) = entry else { break };
+						ldap.process_entry(entry, &mut report).await;
+					}
+					report
+				};
+				tokio::spawn(worker.instrument(tracing::Span::current()))
+			})
+			.collect();
+		Pipeline::Workers { sender, workers }
+	}
+
+	/// Process an entry, waiting for a free worker if all of them are busy
+	async fn process(&mut self, ldap: &mut Ldap, entry: SearchEntry, report: &mut SyncReport) {
+		let entry = match self {
+			Pipeline::Inline => entry,
+			Pipeline::Workers { sender, .. } => match sender.send(entry).await {
+				Ok(()) => return,
+				// All workers panicked
+				Err(mpsc::error::SendError(entry)) => entry,
+			},
+		};
+		ldap.process_entry(entry, report).await;
+	}
+
+	/// Wait until all entries have been processed, adding what the workers
+	/// recorded to the report
+	async fn finish(mut self, report: &mut SyncReport) {
+		let Pipeline::Workers { sender, workers } = &mut self else {
+			return;
+		};
+		// Closing the queue lets the workers stop once it is empty
+		*sender = mpsc::channel(1).0;
+		for worker in workers.iter_mut() {
+			match worker.await {
+				Ok(processed) => report.merge_processed(processed),
+				Err(err) => error!("Comparing entries failed: {err}"),
+			}
+		}
+		workers.clear();
+	}
+}
+
+impl Drop for Pipeline {
+	/// Stop the workers of a pipeline which was not finished, e.g. because
+	/// the sync cycle was cancelled, so that they don't keep emitting updates
+	/// of an abandoned cycle
+	fn drop(&mut self) {
+		if let Pipeline::Workers { workers, .. } = self {
+			for worker in workers.iter() {
+				worker.abort();
+			}
+		}
+	}
+}
+
+/// Search adapter counting the result pages returned by the server. Placed
+/// last in the adapter chain, it sees the end of every page, since the paged
+/// results adapter only requests the next page afterwards.
+#[derive(Debug, Clone)]
+struct PageCounter {
+	/// The number of completed pages
+	pages: Arc<AtomicU64>,
+	/// The state to publish the page being received in, unless this is a dry
+	/// run
+	state: Option<Arc<watch::Sender<PollerState>>>,
+	/// Whether an entry of the current page was received
+	in_page: bool,
+}
+
+#[async_trait]
+impl<'a, S, A> Adapter<'a, S, A> for PageCounter
+where
+	S: AsRef<str> + Send + Sync + 'a,
+	A: AsRef<[S]> + Send + Sync + 'a,
+{
+	async fn start(
+		&mut self,
+		stream: &mut SearchStream<'a, S, A>,
+		base: &str,
+		scope: Scope,
+		filter: &str,
+		attrs: A,
+	) -> ldap3::result::Result<()> {
+		stream.start(base, scope, filter, attrs).await
+	}
+
+	async fn next(
+		&mut self,
+		stream: &mut SearchStream<'a, S, A>,
+	) -> ldap3::result::Result<Option<ResultEntry>> {
+		let next = stream.next().await;
+		match next {
+			Ok(None) => {
+				let pages = self.pages.fetch_add(1, Ordering::Relaxed) + 1;
+				tracing::Span::current().record("pages", pages);
+				self.in_page = false;
+			}
+			// Only the first entry shows that another page was requested
+			Ok(Some(_)) if !self.in_page => {
+				self.in_page = true;
+				if let Some(state) = &self.state {
+					let page = self.pages.load(Ordering::Relaxed) + 1;
+					state.send_if_modified(|state| {
+						let searching = PollerState::Searching { page };
+						let modified = *state != searching;
+						*state = searching;
+						modified
+					});
+				}
+			}
+			_ => {}
+		}
+		next
+	}
+
+	async fn finish(&mut self, stream: &mut SearchStream<'a, S, A>) -> LdapResult {
+		stream.finish().await
+	}
+}
+
+/// Search adapter delaying the request for the next result page until every
+/// subscriber has room for a full page of updates. Placed last in the adapter
+/// chain like [`PageCounter`], so that slow consumers bound the number of
+/// entries buffered by the connection, rather than only the number of
+/// updates queued in the channels.
+#[derive(Debug, Clone)]
+struct PageBackPressure {
+	/// The senders of all subscribers, see [`Ldap::senders`]
+	senders: Arc<std::sync::Mutex<Vec<mpsc::Sender<EntryStatus>>>>,
+	/// The number of entries requested per page
+	page_size: usize,
+}
+
+impl PageBackPressure {
+	/// Wait until every subscriber can take a full page of updates
+	async fn wait_for_capacity(&self) {
+		let senders = lock(&self.senders).clone();
+		for sender in senders {
+			// Closed channels are pruned when the next update is sent
+			let _ = sender.reserve_many(self.page_size.min(sender.max_capacity())).await;
+		}
+	}
+}
+
+/// The cookie requesting the next page, if the server announced further
+/// pages in the result of the page that just ended
+fn next_page_cookie<S, A>(stream: &SearchStream<'_, S, A>) -> Option<Vec<u8>> {
+	stream.res.as_ref()?.ctrls.iter().find_map(|ctrl| match ctrl {
+		Control(Some(ControlType::PagedResults), raw) => {
+			Some(raw.parse::<PagedResultsControl>().cookie).filter(|cookie| !cookie.is_empty())
+		}
+		_ => None,
+	})
+}
+
+#[async_trait]
+impl<'a, S, A> Adapter<'a, S, A> for PageBackPressure
+where
+	S: AsRef<str> + Send + Sync + 'a,
+	A: AsRef<[S]> + Send + Sync + 'a,
+{
+	async fn start(
+		&mut self,
+		stream: &mut SearchStream<'a, S, A>,
+		base: &str,
+		scope: Scope,
+		filter: &str,
+		attrs: A,
+	) -> ldap3::result::Result<()> {
+		stream.start(base, scope, filter, attrs).await
+	}
+
+	async fn next(
+		&mut self,
+		stream: &mut SearchStream<'a, S, A>,
+	) -> ldap3::result::Result<Option<ResultEntry>> {
+		let next = stream.next().await;
+		if let Ok(None) = next {
+			if next_page_cookie(stream).is_some() {
+				self.wait_for_capacity().await;
+			}
+		}
+		next
+	}
+
+	async fn finish(&mut self, stream: &mut SearchStream<'a, S, A>) -> LdapResult {
+		stream.finish().await
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{collections::HashMap, sync::Arc};
+
+	use time::macros::datetime;
+
+	use super::{
+		incremental_filter, lock, usn_filter, Diagnostic, EntryStatus, Error, Ldap,
+		PageBackPressure, Phase, SkipReason, SyncReport, TIMESTAMP_OVERLAP,
+	};
+	use crate::{
+		config::{
+			CacheMethod, Config, DisabledCondition, PidNormalization, Searches, TimestampFormat,
+		},
+		entry::SearchEntryExt,
+		membership::MembershipChange,
+	};
+
+	/// Build an entry with the given DN, binary `objectGUID` and text
+	/// attributes, like [`crate::testing::search_entry`], which is only
+	/// available with the test-util feature
+	fn test_entry(dn: &str, pid: &[u8], attributes: &[(&str, &[&str])]) -> crate::SearchEntry {
+		crate::SearchEntry {
+			dn: dn.to_owned(),
+			attrs: attributes
+				.iter()
+				.map(|(name, values)| {
+					((*name).to_owned(), values.iter().map(|value| (*value).to_owned()).collect())
+				})
+				.collect(),
+			bin_attrs: HashMap::from([("objectGUID".to_owned(), vec![pid.to_owned()])]),
+		}
+	}
+
+	#[test]
+	fn incremental_filter_overlap() -> Result<(), Box<dyn std::error::Error>> {
+		let searches = Config::example().searches;
+		let last_sync_time = datetime!(2023-10-26 12:00:00.5 UTC);
+		assert_eq!(
+			incremental_filter(
+				&searches,
+				"modifyTimestamp",
+				TimestampFormat::Auto,
+				last_sync_time
+			)?,
+			"(&(objectClass=person)(modifyTimestamp>=20231026120000Z))"
+		);
+		assert_eq!(
+			incremental_filter(
+				&searches,
+				"modifyTimestamp",
+				TimestampFormat::Auto,
+				last_sync_time - TIMESTAMP_OVERLAP
+			)?,
+			"(&(objectClass=person)(modifyTimestamp>=20231026115959Z))",
+			"The overlap should include the previous second"
+		);
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn sync_overlap() -> Result<(), Box<dyn std::error::Error>> {
+		let mut config = Config::example();
+		config.attributes.updated = Some("modifyTimestamp".to_owned());
+		config.searches.sync_overlap = Some(std::time::Duration::from_secs(300));
+		let (ldap, _receiver) = Ldap::new(config, None);
+		let last_sync_time = datetime!(2023-10-26 12:00:00 UTC);
+		assert_eq!(
+			ldap.search_filter(false, Some(last_sync_time), None)?,
+			"(&(objectClass=person)(modifyTimestamp>=20231026115500Z))"
+		);
+		ldap.widen_overlap.store(true, std::sync::atomic::Ordering::Relaxed);
+		assert_eq!(
+			ldap.search_filter(false, Some(last_sync_time), None)?,
+			"(&(objectClass=person)(modifyTimestamp>=20231026115500Z))",
+			"A wide enough overlap shouldn't be widened further"
+		);
+		assert_eq!(ldap.search_filter(true, Some(last_sync_time), None)?, "(objectClass=person)");
+
+		let mut config = (*ldap.config).clone();
+		config.searches.sync_overlap = None;
+		let (ldap, _receiver) = Ldap::new(config, None);
+		ldap.widen_overlap.store(true, std::sync::atomic::Ordering::Relaxed);
+		assert_eq!(
+			ldap.search_filter(false, Some(last_sync_time), None)?,
+			"(&(objectClass=person)(modifyTimestamp>=20231026115959Z))"
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn incremental_filter_format() -> Result<(), Box<dyn std::error::Error>> {
+		let mut searches = Config::example().searches;
+		let last_sync_time = datetime!(2023-10-26 12:00:00 UTC);
+		let filter = |searches: &Searches, attribute: &str, format| {
+			incremental_filter(searches, attribute, format, last_sync_time)
+		};
+		assert_eq!(
+			filter(&searches, "whenChanged", TimestampFormat::Auto)?,
+			"(&(objectClass=person)(whenChanged>=20231026120000.0Z))"
+		);
+		assert_eq!(
+			filter(&searches, "whenChanged", TimestampFormat::GeneralizedTime)?,
+			"(&(objectClass=person)(whenChanged>=20231026120000Z))"
+		);
+		assert_eq!(
+			filter(&searches, "mtime", TimestampFormat::ActiveDirectory)?,
+			"(&(objectClass=person)(mtime>=20231026120000.0Z))"
+		);
+
+		searches.incremental_filter =
+			Some("(&{filter}(|({updated}>={timestamp})(uSNChanged>=4711)))".to_owned());
+		assert_eq!(
+			filter(&searches, "whenChanged", TimestampFormat::Auto)?,
+			"(&(objectClass=person)(|(whenChanged>=20231026120000.0Z)(uSNChanged>=4711)))"
+		);
+		searches.incremental_filter = Some("({updated}>={timestamp}".to_owned());
+		assert!(
+			filter(&searches, "whenChanged", TimestampFormat::Auto).is_err(),
+			"Malformed templates should be rejected"
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn usn_filter_after_highest() -> Result<(), Box<dyn std::error::Error>> {
+		assert_eq!(
+			usn_filter("(objectClass=person)", "uSNChanged", 4711)?,
+			"(&(objectClass=person)(uSNChanged>=4712))"
+		);
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn resend_failed_updates() {
+		let (mut ldap, receiver) = Ldap::new(Config::example(), None);
+		drop(receiver);
+
+		ldap.send_channel_update(EntryStatus::Removed { pid: vec![1], entry: None }).await;
+		ldap.send_channel_update(EntryStatus::Removed { pid: vec![2], entry: None }).await;
+
+		let mut receiver = ldap.subscribe();
+		ldap.send_channel_update(EntryStatus::Removed { pid: vec![3], entry: None }).await;
+		for pid in 1..=3 {
+			assert!(
+				matches!(receiver.recv().await, Some(EntryStatus::Removed { pid: removed, .. }) if removed == [pid]),
+				"Failed updates should be re-emitted in order"
+			);
+		}
+		assert!(receiver.try_recv().is_err());
+	}
+
+	#[tokio::test]
+	async fn throttled_resend() {
+		use std::time::{Duration, Instant};
+
+		let mut config = Config::example();
+		config.resend_interval = Some(Duration::from_millis(50));
+		config.max_failed_sends = Some(2);
+		let (mut ldap, receiver) = Ldap::new(config, None);
+		drop(receiver);
+		for pid in 1..=3 {
+			ldap.send_channel_update(EntryStatus::Removed { pid: vec![pid], entry: None }).await;
+		}
+
+		// Syncing continues while the updates are re-emitted
+		let mut receiver = ldap.subscribe();
+		let start = Instant::now();
+		ldap.send_channel_update(EntryStatus::Removed { pid: vec![4], entry: None }).await;
+		assert!(start.elapsed() < Duration::from_millis(50));
+		for pid in 2..=4 {
+			assert!(
+				matches!(receiver.recv().await, Some(EntryStatus::Removed { pid: removed, .. }) if removed == [pid]),
+				"Only the latest failed updates should be kept"
+			);
+		}
+		assert!(start.elapsed() >= Duration::from_millis(100));
+	}
+
+	#[tokio::test]
+	async fn fan_out() {
+		let (mut ldap, first) = Ldap::new(Config::example(), None);
+		let mut second = ldap.subscribe();
+
+		ldap.send_channel_update(EntryStatus::Removed { pid: vec![1], entry: None }).await;
+		drop(first);
+		ldap.send_channel_update(EntryStatus::Removed { pid: vec![2], entry: None }).await;
+
+		for pid in 1..=2 {
+			assert!(
+				matches!(second.try_recv(), Ok(EntryStatus::Removed { pid: removed, .. }) if removed == [pid])
+			);
+		}
+		assert!(second.try_recv().is_err());
+	}
+
+	#[tokio::test]
+	async fn page_back_pressure() -> Result<(), Box<dyn std::error::Error>> {
+		let (mut ldap, mut receiver) = Ldap::new(Config::example(), None);
+		let back_pressure = PageBackPressure { senders: ldap.senders.clone(), page_size: 1000 };
+		for pid in 0..30 {
+			ldap.send_channel_update(EntryStatus::Removed { pid: vec![pid], entry: None }).await;
+		}
+
+		let wait = tokio::spawn(async move { back_pressure.wait_for_capacity().await });
+		tokio::task::yield_now().await;
+		assert!(!wait.is_finished(), "There's no room for a full page");
+
+		for _ in 0..6 {
+			receiver.recv().await;
+		}
+		// There's room for a full page after receiving some updates
+		tokio::time::timeout(std::time::Duration::from_secs(1), wait).await??;
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn debounce_changes() {
+		let mut config = Config::example();
+		config.debounce = Some(std::time::Duration::from_secs(3600));
+		let (mut ldap, mut receiver) = Ldap::new(config, None);
+		let entry = |mail: &str| Arc::new(test_entry("cn=foo", &[1], &[("mail", &[mail])]));
+
+		for (old, new) in [("a", "b"), ("b", "c")] {
+			ldap.send_channel_update(EntryStatus::Changed {
+				old: Some(entry(old)),
+				new: entry(new),
+			})
+			.await;
+		}
+		assert!(receiver.try_recv().is_err(), "Changes should be held back");
+
+		ldap.send_channel_update(EntryStatus::Removed { pid: vec![1], entry: None }).await;
+		assert!(matches!(
+			receiver.try_recv(),
+			Ok(EntryStatus::Changed { old: Some(old), new })
+				if old.attrs["mail"] == ["a"] && new.attrs["mail"] == ["c"]
+		));
+		assert!(matches!(receiver.try_recv(), Ok(EntryStatus::Removed { .. })));
+
+		ldap.send_channel_update(EntryStatus::Changed { old: None, new: entry("d") }).await;
+		ldap.flush_changes().await;
+		assert!(matches!(receiver.try_recv(), Ok(EntryStatus::Changed { old: None, .. })));
+	}
+
+	#[tokio::test]
+	async fn debounce_persist() {
+		let mut config = Config::example();
+		config.debounce = Some(std::time::Duration::from_secs(3600));
+		let (mut ldap, mut receiver) = Ldap::new(config, None);
+		let entry = |pid: u8, enabled: &str| {
+			test_entry(&format!("cn={pid}"), &[pid], &[("enabled", &[enabled])])
+		};
+		let synced = datetime!(2023-10-26 12:00 UTC);
+
+		ldap.process_entries([entry(1, "yes"), entry(2, "yes")]).await;
+		ldap.cache.write().await.last_sync_time = Some(synced);
+		ldap.process_entries([entry(1, "no"), entry(2, "yes")]).await;
+		ldap.cache.write().await.last_sync_time = Some(datetime!(2023-10-26 13:00 UTC));
+		while let Ok(status) = receiver.try_recv() {
+			assert!(matches!(status, EntryStatus::New(_)), "Changes should be held back");
+		}
+
+		let persisted = ldap.persist_cache().await;
+		let pids = persisted.entries.get_expected();
+		assert!(!pids.contains(&vec![1]), "Held back changes should be searched again");
+		assert!(pids.contains(&vec![2]));
+		assert_eq!(persisted.last_sync_time, Some(synced));
+	}
+
+	#[tokio::test]
+	async fn process_entries() {
+		let (mut ldap, mut receiver) = Ldap::new(Config::example(), None);
+		let entry = |pid: u8, enabled: &str| {
+			test_entry(&format!("cn={pid}"), &[pid], &[("enabled", &[enabled])])
+		};
+
+		ldap.process_entries([entry(1, "yes"), entry(2, "yes")]).await;
+		for pid in 1..=2 {
+			assert!(
+				matches!(receiver.try_recv(), Ok(EntryStatus::New(new)) if new.dn == format!("cn={pid}"))
+			);
+		}
+
+		ldap.process_entries([entry(1, "yes"), entry(2, "no")]).await;
+		assert!(
+			matches!(receiver.try_recv(), Ok(EntryStatus::Changed { new, .. }) if new.dn == "cn=2")
+		);
+		assert!(receiver.try_recv().is_err(), "Unchanged and missing entries shouldn't be pushed");
+	}
+
+	#[tokio::test]
+	async fn emit_unchanged() {
+		let mut config = Config::example();
+		config.emit_unchanged = true;
+		let (mut ldap, mut receiver) = Ldap::new(config, None);
+		let entry = test_entry("cn=foo", &[1], &[("enabled", &["yes"])]);
+
+		ldap.process_entries([entry.clone()]).await;
+		assert!(matches!(receiver.try_recv(), Ok(EntryStatus::New(_))));
+		ldap.process_entries([entry]).await;
+		assert!(matches!(receiver.try_recv(), Ok(EntryStatus::Unchanged(pid)) if pid == [1]));
+	}
+
+	#[tokio::test]
+	async fn latency_degradation() {
+		let mut config = Config::example();
+		config.latency_thresholds.bind = Some(std::time::Duration::from_millis(100));
+		let (ldap, _receiver) = Ldap::new(config, None);
+		let mut diagnostics = ldap.diagnostics();
+		let mut other = ldap.diagnostics();
+		let report = |millis| SyncReport {
+			bind_latency: Some(std::time::Duration::from_millis(millis)),
+			..SyncReport::default()
+		};
+
+		ldap.check_latencies(&report(50));
+		assert!(diagnostics.try_recv().is_err());
+		ldap.check_latencies(&report(500));
+		assert!(matches!(
+			diagnostics.try_recv(),
+			Ok(Diagnostic { phase: Phase::Bind, message, .. }) if message.contains("bind latency")
+		));
+		assert!(other.try_recv().is_ok(), "Every receiver should get the diagnostics");
+		ldap.check_latencies(&report(500));
+		assert!(diagnostics.try_recv().is_err(), "Only the degradation itself should be reported");
+	}
+
+	#[tokio::test]
+	async fn dry_run_updates() {
+		let (mut ldap, mut receiver) = Ldap::new(Config::example(), None);
+		let entry = test_entry("cn=foo", &[1], &[]);
+
+		ldap.dry_run = true;
+		let report = ldap.process_entries([entry]).await;
+		assert_eq!(report.updates.new, 1, "Updates should be counted");
+		assert!(receiver.try_recv().is_err(), "Dry runs shouldn't push updates");
+	}
+
+	#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+	async fn snapshot_readers() -> Result<(), Box<dyn std::error::Error>> {
+		use std::sync::atomic::{AtomicBool, Ordering};
+
+		use crate::cache::CacheEntries;
+
+		let mut config = Config::example();
+		config.cache_method = CacheMethod::ModificationTime;
+		let (mut ldap, _receiver) = Ldap::new(config, None);
+		let entry = |pid: u16| test_entry(&format!("cn={pid}"), &pid.to_be_bytes(), &[]);
+
+		// Readers on other threads only ever see the cache between cycles
+		let done = Arc::new(AtomicBool::new(false));
+		let readers: Vec<_> = (0..4)
+			.map(|_| {
+				let (ldap, done) = (ldap.clone(), done.clone());
+				std::thread::spawn(move || {
+					let mut seen = 0;
+					while !done.load(Ordering::SeqCst) {
+						let snapshot = ldap.snapshot();
+						assert_eq!(snapshot.len() % 10, 0, "Snapshots should contain whole cycles");
+						assert!(snapshot.len() >= seen, "Snapshots shouldn't go back in time");
+						seen = snapshot.len();
+						assert_eq!(ldap.cached_pids().len() % 10, 0);
+					}
+				})
+			})
+			.collect();
+		for cycle in 0..20 {
+			ldap.process_entries((cycle * 10..(cycle + 1) * 10).map(entry)).await;
+		}
+		done.store(true, Ordering::SeqCst);
+		for reader in readers {
+			reader.join().map_err(|_| "Reader panicked")?;
+		}
+
+		// Reads don't wait for a running cycle
+		let cache = ldap.cache.write().await;
+		assert_eq!(ldap.snapshot().len(), 200);
+		assert!(ldap.get_entry(&0_u16.to_be_bytes()).is_some());
+
+		// The snapshot shares the entries with the cache instead of copying them
+		let (CacheEntries::Modified(entries), CacheEntries::Modified(published)) =
+			(&cache.entries, &ldap.snapshot().entries)
+		else {
+			return Err("Full entries should be cached".into());
+		};
+		assert!(entries.iter().all(|(pid, entry)| Arc::ptr_eq(entry, &published[pid])));
+		assert_eq!(*ldap.snapshot(), *cache, "Refreshed snapshots should match the cache");
+		Ok(())
+	}
+
+	#[cfg(feature = "test-util")]
+	#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+	async fn dry_run_private_cache() -> Result<(), Box<dyn std::error::Error>> {
+		use std::{
+			sync::atomic::{AtomicBool, Ordering},
+			time::{Duration, Instant},
+		};
+
+		use super::SyncOptions;
+		use crate::testing::people_server;
+
+		let (_server, config) = people_server(&["foo"]).await?;
+		let (mut ldap, _receiver) = Ldap::new(config, None);
+
+		// The dry run waits until a concurrent cycle has synced the entry
+		let synced = Arc::new(AtomicBool::new(false));
+		let mut dry_run = ldap.clone().with_entry_filter({
+			let synced = synced.clone();
+			move |_| {
+				tokio::task::block_in_place(|| {
+					let started = Instant::now();
+					while !synced.load(Ordering::SeqCst)
+						&& started.elapsed() < Duration::from_secs(2)
+					{
+						std::thread::sleep(Duration::from_millis(5));
+					}
+				});
+				true
+			}
+		});
+		let dry_run = tokio::spawn(async move {
+			dry_run
+				.sync_once_with(None, SyncOptions { dry_run: true, ..SyncOptions::default() })
+				.await
+		});
+		assert_eq!(ldap.sync_once(None).await?.updates.new, 1);
+		synced.store(true, Ordering::SeqCst);
+		assert_eq!(dry_run.await??.updates.new, 1);
+		let report = ldap.sync_once(None).await?;
+		assert_eq!(report.updates.new, 0, "The dry run shouldn't have reverted the cache");
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn batches() -> Result<(), Box<dyn std::error::Error>> {
+		let (mut ldap, receiver) = Ldap::new(Config::example(), None);
+		let mut batches = ldap.subscribe_batches();
+		drop(receiver);
+		let entry = |pid: u8| test_entry(&format!("cn={pid}"), &[pid], &[]);
+
+		ldap.process_entries([entry(1), entry(2)]).await;
+		let batch = batches.try_recv()?;
+		assert_eq!(
+			batch.new_entries().map(|entry| entry.dn.as_str()).collect::<Vec<_>>(),
+			["cn=1", "cn=2"]
+		);
+		assert!(lock(&ldap.failed_sends).updates.is_empty(), "Batched updates shouldn't be kept");
+
+		ldap.process_entries([entry(1)]).await;
+		assert!(batches.try_recv().is_err(), "Empty batches shouldn't be sent");
+		Ok(())
+	}
+
+	#[cfg(feature = "test-util")]
+	#[tokio::test(flavor = "multi_thread")]
+	async fn failed_batches() -> Result<(), Box<dyn std::error::Error>> {
+		use std::sync::atomic::{AtomicBool, Ordering};
+
+		use crate::testing::people_server;
+
+		let (server, mut config) = people_server(&["foo", "bar"]).await?;
+		let server = Arc::new(server);
+		config.searches.page_size = Some(1);
+		config.connection.operation_timeout = std::time::Duration::from_millis(200);
+		let (ldap, _receiver) = Ldap::new(config, None);
+		// The server stops answering after the first page of the first cycle
+		let (hanging, hung) = (server.clone(), AtomicBool::new(false));
+		let mut ldap = ldap.with_entry_filter(move |_| {
+			if !hung.swap(true, Ordering::Relaxed) {
+				hanging.hang_searches(true);
+			}
+			true
+		});
+		let mut batches = ldap.subscribe_batches();
+
+		assert!(ldap.sync_once(None).await.is_err());
+		let batch = batches.try_recv()?;
+		assert!(batch.failed);
+		assert_eq!(
+			batch.new_entries().map(|entry| entry.dn.as_str()).collect::<Vec<_>>(),
+			["cn=foo,ou=people"]
+		);
+
+		server.hang_searches(false);
+		ldap.sync_once(None).await?;
+		let batch = batches.try_recv()?;
+		assert!(!batch.failed);
+		assert_eq!(
+			batch.new_entries().map(|entry| entry.dn.as_str()).collect::<Vec<_>>(),
+			["cn=bar,ou=people"],
+			"The updates of the failed cycle shouldn't be sent again"
+		);
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn entry_filter() {
+		let (ldap, mut receiver) = Ldap::new(Config::example(), None);
+		let mut ldap = ldap.with_entry_filter(|entry| {
+			entry.attr_first("mail").is_some_and(|mail| mail.ends_with("@example.com"))
+		});
+		let entry =
+			|pid: u8, mail: &str| test_entry(&format!("cn={pid}"), &[pid], &[("mail", &[mail])]);
+
+		let report =
+			ldap.process_entries([entry(1, "foo@example.com"), entry(2, "bar@example.org")]).await;
+		assert_eq!(report.updates.new, 1);
+		assert!(matches!(receiver.try_recv(), Ok(EntryStatus::New(entry)) if entry.dn == "cn=1"));
+		assert!(receiver.try_recv().is_err());
+		assert_eq!(ldap.snapshot().len(), 1, "Filtered entries shouldn't be cached");
+	}
+
+	#[tokio::test]
+	async fn get_cached() {
+		let mut config = Config::example();
+		config.cache_method = CacheMethod::ModificationTime;
+		let (mut ldap, _receiver) = Ldap::new(config, None);
+		let entry = test_entry("cn=foo", &[1], &[]);
+		ldap.process_entries([entry.clone()]).await;
+		assert_eq!(ldap.get_cached(&[1]).await.map(|entry| entry.dn), Some("cn=foo".to_owned()));
+		assert!(ldap.get_cached(&[2]).await.is_none());
+
+		let (mut ldap, _receiver) = Ldap::new(Config::example(), None);
+		ldap.process_entries([entry]).await;
+		assert!(ldap.get_cached(&[1]).await.is_none(), "Hashed caches don't keep entries");
+	}
+
+	#[tokio::test]
+	async fn acknowledged_updates() -> Result<(), Box<dyn std::error::Error>> {
+		let (mut ldap, _receiver) = Ldap::new(Config::example(), None);
+		let mut acked = ldap.subscribe_acked();
+		let entry = |name: &str, pid: u8| test_entry(&format!("cn={name}"), &[pid], &[]);
+		let (before, after) = (datetime!(2024-01-01 0:00 UTC), datetime!(2024-01-02 0:00 UTC));
+		ldap.cache.write().await.last_sync_time = Some(before);
+		ldap.process_entries([entry("foo", 1), entry("bar", 2)]).await;
+		ldap.cache.write().await.last_sync_time = Some(after);
+		let first = acked.try_recv()?;
+		let second = acked.try_recv()?;
+		first.ack.ack();
+		let persisted = ldap.persist_cache().await;
+		assert!(persisted.dn(&[1]).is_some());
+		assert!(persisted.dn(&[2]).is_none(), "Unacknowledged entries shouldn't be persisted");
+		assert_eq!(
+			persisted.last_sync_time,
+			Some(before),
+			"Unacknowledged entries should be searched again after a restart"
+		);
+
+		drop(second);
+		ldap.redeliver_unacknowledged().await;
+		let again = acked.try_recv()?;
+		assert!(matches!(&again.status, EntryStatus::New(entry) if entry.dn == "cn=bar"));
+		assert!(acked.try_recv().is_err());
+		assert_eq!(ldap.persist_cache().await.last_sync_time, Some(before));
+		again.ack.ack();
+		let persisted = ldap.persist_cache().await;
+		assert!(persisted.dn(&[2]).is_some());
+		assert_eq!(persisted.last_sync_time, Some(after));
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn acknowledged_updates_superseded() -> Result<(), Box<dyn std::error::Error>> {
+		let mut config = Config::example();
+		config.attributes.attrs_to_track.push("mail".to_owned());
+		let (mut ldap, _receiver) = Ldap::new(config, None);
+		let mut acked = ldap.subscribe_acked();
+		let entry = |mail: &str| test_entry("cn=foo", &[1], &[("mail", &[mail])]);
+		ldap.process_entries([entry("old@example.com")]).await;
+		ldap.process_entries([entry("new@example.com")]).await;
+		let new = acked.try_recv()?;
+		let changed = acked.try_recv()?;
+		assert!(matches!(changed.status, EntryStatus::Changed { .. }));
+		drop(new);
+		ldap.redeliver_unacknowledged().await;
+		assert!(acked.try_recv().is_err(), "Superseded updates shouldn't be delivered again");
+		changed.ack.ack();
+		assert!(ldap.persist_cache().await.dn(&[1]).is_some());
+
+		// Detached updates are only acknowledged by pid and sequence number
+		ldap.process_entries([entry("other@example.com")]).await;
+		let changed = acked.try_recv()?;
+		let seq = changed.ack.detach();
+		ldap.redeliver_unacknowledged().await;
+		assert!(acked.try_recv().is_err(), "Detached updates shouldn't be delivered again");
+		assert!(ldap.persist_cache().await.dn(&[1]).is_none());
+		assert!(!ldap.ack(&[2], seq), "The pid has to match");
+		assert!(ldap.ack(&[1], seq));
+		assert!(!ldap.ack(&[1], seq), "The update was already acknowledged");
+		assert!(ldap.persist_cache().await.dn(&[1]).is_some());
+
+		// Without a receiver, updates are not kept for acknowledgment
+		drop(acked);
+		ldap.process_entries([entry("old@example.com")]).await;
+		assert!(lock(&ldap.acked_sender).is_none(), "The closed sender should be cleared");
+		assert_eq!(lock(&ldap.pending_acks).pids().count(), 0);
+		let mut acked = ldap.subscribe_acked();
+		ldap.process_entries([entry("new@example.com")]).await;
+		assert!(matches!(acked.try_recv()?.status, EntryStatus::Changed { .. }));
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn entry_transform() -> Result<(), Box<dyn std::error::Error>> {
+		let mut config = Config::example();
+		config.attributes.attrs_to_track.push("mail".to_owned());
+		config.cache_method = CacheMethod::ModificationTime;
+		let (ldap, mut receiver) = Ldap::new(config, None);
+		let mut ldap = ldap.with_entry_transform(|mut entry| {
+			entry.attrs.remove("userPassword");
+			for mail in entry.attrs.get_mut("mail").into_iter().flatten() {
+				*mail = mail.to_lowercase();
+			}
+			entry
+		});
+		let entry = |mail: &str| {
+			test_entry("cn=foo", &[1], &[("mail", &[mail]), ("userPassword", &["secret"])])
+		};
+
+		ldap.process_entries([entry("Foo@Example.com")]).await;
+		let Ok(EntryStatus::New(new)) = receiver.try_recv() else {
+			return Err("Expected a new entry".into());
+		};
+		assert_eq!(new.attr_first("mail"), Some("foo@example.com"));
+		assert_eq!(new.attr_first("userPassword"), None);
+		let cached = ldap.snapshot().entry(&[1]).ok_or("Entry should be cached")?;
+		assert_eq!(cached.attr_first("userPassword"), None, "Redacted values shouldn't be cached");
+
+		let report = ldap.process_entries([entry("FOO@example.com")]).await;
+		assert_eq!(report.updates.changed, 0, "Normalized values should be compared");
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn disabled_entries() {
+		let mut config = Config::example();
+		config.attributes.disabled = Some(DisabledCondition::Equals {
+			attribute: "enabled".to_owned(),
+			value: "FALSE".to_owned(),
+		});
+		let (mut ldap, mut receiver) = Ldap::new(config, None);
+		let entry = |pid: u8, enabled: &str| {
+			test_entry(&format!("cn={pid}"), &[pid], &[("enabled", &[enabled])])
+		};
+
+		ldap.process_entries([entry(1, "TRUE"), entry(2, "FALSE")]).await;
+		assert!(matches!(receiver.try_recv(), Ok(EntryStatus::New(entry)) if entry.dn == "cn=1"));
+		assert!(receiver.try_recv().is_err(), "Disabled entries shouldn't be emitted");
+
+		ldap.process_entries([entry(1, "FALSE"), entry(2, "FALSE")]).await;
+		assert!(matches!(receiver.try_recv(), Ok(EntryStatus::Removed { pid, .. }) if pid == [1]));
+		assert!(receiver.try_recv().is_err(), "Removals should only be reported once");
+		assert!(ldap.snapshot().is_empty());
+
+		ldap.process_entries([entry(1, "TRUE")]).await;
+		assert!(matches!(receiver.try_recv(), Ok(EntryStatus::New(entry)) if entry.dn == "cn=1"));
+	}
+
+	#[tokio::test]
+	async fn removed_entries() {
+		let mut config = Config::example();
+		config.cache_method = CacheMethod::ModificationTime;
+		config.attributes.rename = HashMap::from([("enabled".to_owned(), "active".to_owned())]);
+		config.attributes.disabled = Some(DisabledCondition::Equals {
+			attribute: "enabled".to_owned(),
+			value: "FALSE".to_owned(),
+		});
+		let (mut ldap, mut receiver) = Ldap::new(config, None);
+		let entry = |mtime: &str, enabled: &str| {
+			test_entry("cn=foo", b"foo", &[("mtime", &[mtime]), ("enabled", &[enabled])])
+		};
+
+		ldap.process_entries([entry("20231026120000Z", "TRUE")]).await;
+		assert!(matches!(receiver.try_recv(), Ok(EntryStatus::New(_))));
+		ldap.process_entries([entry("20231026130000Z", "FALSE")]).await;
+		assert!(
+			matches!(
+				receiver.try_recv(),
+				Ok(EntryStatus::Removed { pid, entry: Some(entry) })
+					if pid == b"foo" && entry.dn == "cn=foo" && entry.attr_first("active") == Some("TRUE")
+			),
+			"Removals should carry the last known version of the entry"
+		);
+	}
+
+	#[tokio::test]
+	async fn incompatible_cache() -> Result<(), Box<dyn std::error::Error>> {
+		let mut config = Config::example();
+		let (mut ldap, _receiver) = Ldap::new(config.clone(), None);
+		ldap.process_entries([test_entry("cn=foo,ou=people", b"foo", &[])]).await;
+		let cache = ldap.persist_cache().await;
+		assert!(Ldap::try_new(config.clone(), Some(cache.clone())).is_ok());
+
+		config.attributes.pid = "entryUUID".to_owned();
+		assert!(matches!(
+			Ldap::try_new(config.clone(), Some(cache.clone())),
+			Err(Error::IncompatibleCacheConfig(_))
+		));
+		let (ldap, _receiver) = Ldap::new(config, Some(cache));
+		assert!(ldap.cached_pids().is_empty(), "The incompatible cache should be cleared");
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn skipped_entries() {
+		let (mut ldap, mut receiver) = Ldap::new(Config::example(), None);
+		let mut diagnostics = ldap.diagnostics();
+		let entry = crate::SearchEntry {
+			dn: "cn=foo,ou=people".to_owned(),
+			attrs: HashMap::from([("cn".to_owned(), vec!["foo".to_owned()])]),
+			bin_attrs: HashMap::new(),
+		};
+		let report = ldap.process_entries([entry]).await;
+		assert!(matches!(
+			receiver.try_recv(),
+			Ok(EntryStatus::Skipped { dn, reason: SkipReason::MissingPid }) if dn == "cn=foo,ou=people"
+		));
+		assert_eq!(report.updates.skipped, 1);
+		assert!(diagnostics.try_recv().is_ok(), "Skipped entries should be reported as warnings");
+		assert!(ldap.cached_pids().is_empty());
+	}
+
+	#[tokio::test]
+	async fn pid_conflicts() {
+		let (mut ldap, mut receiver) = Ldap::new(Config::example(), None);
+		let entry = |dn: &str| test_entry(dn, b"foo", &[("cn", &[dn])]);
+		let report =
+			ldap.process_entries([entry("cn=foo,ou=people"), entry("cn=bar,ou=people")]).await;
+		assert!(
+			matches!(receiver.try_recv(), Ok(EntryStatus::New(entry)) if entry.dn == "cn=foo,ou=people")
+		);
+		assert!(matches!(
+			receiver.try_recv(),
+			Ok(EntryStatus::PidConflict { pid, dn, conflicting_dn })
+				if pid == b"foo" && dn == "cn=foo,ou=people" && conflicting_dn == "cn=bar,ou=people"
+		));
+		assert_eq!(report.updates.pid_conflicts, 1);
+		assert_eq!(ldap.snapshot().dn(b"foo"), Some("cn=foo,ou=people"));
+
+		// The same entry can be received twice in a cycle
+		let report = ldap
+			.process_entries([
+				entry("cn=foo,ou=people"),
+				entry("cn=foo,ou=people"),
+				entry("cn=bar,ou=people"),
+			])
+			.await;
+		assert_eq!(report.updates.pid_conflicts, 1);
+		assert!(matches!(
+			receiver.try_recv(),
+			Ok(EntryStatus::PidConflict { conflicting_dn, .. }) if conflicting_dn == "cn=bar,ou=people"
+		));
+
+		// The entry at the cached DN wins regardless of the order
+		let report =
+			ldap.process_entries([entry("cn=bar,ou=people"), entry("cn=foo,ou=people")]).await;
+		assert_eq!(report.updates.pid_conflicts, 1);
+		assert!(matches!(
+			receiver.try_recv(),
+			Ok(EntryStatus::PidConflict { dn, conflicting_dn, .. })
+				if dn == "cn=foo,ou=people" && conflicting_dn == "cn=bar,ou=people"
+		));
+		assert!(receiver.try_recv().is_err(), "The entry shouldn't be moved back and forth");
+		assert!(
+			lock(&ldap.cycle_pids).received.is_empty(),
+			"Pids should be dropped after the cycle"
+		);
+
+		// Pids of previous cycles don't conflict, the entry was moved
+		let report = ldap.process_entries([entry("cn=bar,ou=people")]).await;
+		assert_eq!(report.updates.pid_conflicts, 0);
+		assert!(matches!(receiver.try_recv(), Ok(EntryStatus::Moved { .. })));
+		assert!(receiver.try_recv().is_err());
+	}
+
+	#[tokio::test]
+	async fn membership_changes() {
+		let mut config = Config::example();
+		config.cache_method = CacheMethod::ModificationTime;
+		config.attributes.members = Some("member".to_owned());
+		config.attributes.disabled = Some(DisabledCondition::Equals {
+			attribute: "enabled".to_owned(),
+			value: "FALSE".to_owned(),
+		});
+		let (mut ldap, mut receiver) = Ldap::new(config, None);
+		let group = |mtime: &str, enabled: &str, members: &[&str]| {
+			test_entry(
+				"cn=admins,ou=groups",
+				b"admins",
+				&[("mtime", &[mtime]), ("enabled", &[enabled]), ("member", members)],
+			)
+		};
+		let mut next_changes = || {
+			let mut changes = Vec::new();
+			while let Ok(status) = receiver.try_recv() {
+				if let EntryStatus::MembershipChanged { group, group_dn, member, change } = status {
+					assert_eq!((&*group, &*group_dn), (&b"admins"[..], "cn=admins,ou=groups"));
+					changes.push((member, change));
+				}
+			}
+			changes
+		};
+
+		ldap.process_entries([group("20231026120000Z", "TRUE", &["cn=foo", "cn=bar"])]).await;
+		assert_eq!(
+			next_changes(),
+			[
+				("cn=foo".to_owned(), MembershipChange::Added),
+				("cn=bar".to_owned(), MembershipChange::Added),
+			]
+		);
+
+		let report =
+			ldap.process_entries([group("20231026130000Z", "TRUE", &["CN=Bar", "cn=baz"])]).await;
+		assert_eq!(
+			next_changes(),
+			[
+				("cn=foo".to_owned(), MembershipChange::Removed),
+				("cn=baz".to_owned(), MembershipChange::Added),
+			]
+		);
+		assert_eq!(report.updates.membership_changed, 2);
+
+		ldap.process_entries([group("20231026130000Z", "TRUE", &["cn=qux"])]).await;
+		assert_eq!(next_changes(), [], "Unchanged entries shouldn't be compared");
+
+		ldap.process_entries([group("20231026140000Z", "FALSE", &[])]).await;
+		assert_eq!(
+			next_changes(),
+			[
+				("CN=Bar".to_owned(), MembershipChange::Removed),
+				("cn=baz".to_owned(), MembershipChange::Removed),
+			],
+			"Removing a group should remove all members"
+		);
+	}
+
+	#[cfg(feature = "test-util")]
+	#[tokio::test]
+	async fn resume_paged_search() -> Result<(), Box<dyn std::error::Error>> {
+		use crate::{
+			cache::PagedSearchProgress,
+			testing::{cache, search_entry, MockServer},
+		};
+
+		let user = |name: &str, enabled: &str| {
+			search_entry(
+				&format!("cn={name},ou=people"),
+				"objectGUID",
+				name.as_bytes(),
+				&[("objectClass", &["person"]), ("enabled", &[enabled])],
+			)
+		};
+		let mut config = Config::example();
+		let server = MockServer::start().await?;
+		config.url = server.url();
+		config.searches.page_size = Some(1);
+		config.searches.resume_paged_search = true;
+
+		// Interrupted after the first page of a full sync, with `foo` seen
+		let old = ["foo", "bar", "baz", "qux"].map(|name| user(name, "yes"));
+		let started = datetime!(2023-10-26 12:00:00 UTC);
+		let mut cache = cache(&config.cache_method, &config.attributes, old, None)?;
+		cache.missing.remove(b"foo".as_slice());
+		cache.paged_search = Some(PagedSearchProgress {
+			filter: "(objectClass=person)".to_owned(),
+			full_sync: true,
+			started,
+			cookie: b"1".to_vec(),
+			entries: 1,
+		});
+		server.add(user("foo", "no"));
+		server.add(user("bar", "no"));
+		server.add(user("baz", "yes"));
+
+		let (mut ldap, mut receiver) = Ldap::new(config, Some(cache));
+		let report = ldap.sync_once(None).await?;
+		assert_eq!(report.resumed_from, Some(started));
+		assert_eq!(report.entries_matched, Some(3));
+		assert_eq!(report.resources.pages_fetched, 2, "The first page shouldn't be fetched again");
+		assert!(matches!(
+			receiver.try_recv(),
+			Ok(EntryStatus::Changed { new, .. }) if new.dn == "cn=bar,ou=people"
+		));
+		assert!(
+			matches!(receiver.try_recv(), Ok(EntryStatus::Removed { pid, .. }) if pid == b"qux")
+		);
+		assert!(receiver.try_recv().is_err());
+
+		let cache = ldap.persist_cache().await;
+		assert_eq!(cache.paged_search, None, "The search should be complete");
+		assert_eq!(cache.last_full_sync_time, Some(started));
+		Ok(())
+	}
+
+	#[cfg(feature = "test-util")]
+	#[tokio::test]
+	async fn connection_reuse() -> Result<(), Box<dyn std::error::Error>> {
+		use crate::testing::people_server;
+
+		let (server, mut config) = people_server(&["foo"]).await?;
+		config.connection.reuse = true;
+		config.connection.keepalive = Some(std::time::Duration::from_millis(10));
+
+		let (mut ldap, _receiver) = Ldap::new(config, None);
+		assert!(ldap.sync_once(None).await?.bind_latency.is_some());
+		tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+		assert_eq!(ldap.sync_once(None).await?.bind_latency, None);
+		assert_eq!(server.connections(), 1);
+
+		// A dropped connection is replaced transparently
+		server.close_connections();
+		assert!(ldap.sync_once(None).await?.bind_latency.is_some());
+		assert_eq!(server.connections(), 2);
+
+		// Changed settings aren't bypassed by the kept connection
+		let mut config = (*ldap.config).clone();
+		config.searches.page_size = Some(10);
+		ldap.update_config(config);
+		assert!(ldap.sync_once(None).await?.bind_latency.is_some());
+		assert_eq!(server.connections(), 3);
+		server.set_credentials("admin", "secret");
+		let mut config = (*ldap.config).clone();
+		config.search_password = "rotated".to_owned();
+		ldap.update_config(config);
+		assert!(ldap.sync_once(None).await.is_err(), "The new credentials should be used");
+		Ok(())
+	}
+
+	#[cfg(feature = "test-util")]
+	#[tokio::test]
+	async fn config_scope_change() -> Result<(), Box<dyn std::error::Error>> {
+		use crate::testing::people_server;
+
+		let (_server, mut config) = people_server(&["foo"]).await?;
+		config.check_for_deleted_entries = false;
+		let (mut ldap, _receiver) = Ldap::new(config.clone(), None);
+		ldap.sync_once(None).await?;
+		let last_sync_time = Some(datetime!(2100-01-01 00:00 UTC));
+		assert_eq!(ldap.sync_once(last_sync_time).await?.entries_received, 0);
+
+		// The time passed for the previous scope is ignored
+		config.searches.user_filter = "(|(objectClass=person)(objectClass=group))".to_owned();
+		ldap.update_config(config);
+		let report = ldap.sync_once(last_sync_time).await?;
+		assert_eq!(report.entries_received, 1, "A changed scope should need a full sync");
+		Ok(())
+	}
+
+	#[cfg(feature = "test-util")]
+	#[tokio::test]
+	async fn retry_transient_errors() -> Result<(), Box<dyn std::error::Error>> {
+		use crate::testing::people_server;
+
+		let (server, mut config) = people_server(&["foo"]).await?;
+		config.retry.attempts = 1;
+
+		let (mut ldap, mut receiver) = Ldap::new(config, None);
+		server.reject_searches(1);
+		let report = ldap.sync_once(None).await?;
+		assert_eq!(report.retries, 1);
+		assert_eq!(report.entries_matched, Some(1));
+		assert!(
+			matches!(receiver.try_recv(), Ok(EntryStatus::New(entry)) if entry.dn == "cn=foo,ou=people")
+		);
+
+		server.reject_searches(2);
+		let err = ldap.sync_once(None).await.err().ok_or("The retry should fail as well")?;
+		assert!(err.is_retryable());
+		Ok(())
+	}
+
+	#[cfg(feature = "test-util")]
+	#[tokio::test]
+	async fn size_limit_exceeded() -> Result<(), Box<dyn std::error::Error>> {
+		use crate::testing::people_server;
+
+		let (server, mut config) = people_server(&["foo", "bar", "baz"]).await?;
+
+		let (mut ldap, mut receiver) = Ldap::new(config.clone(), None);
+		let mut diagnostics = ldap.diagnostics();
+		ldap.sync_once(None).await?;
+		server.set_size_limit(Some(2));
+		let report = ldap.sync_once(None).await?;
+		assert!(report.size_limit_exceeded);
+		assert_eq!(report.entries_matched, None);
+		assert!(matches!(
+			diagnostics.try_recv(),
+			Ok(Diagnostic { phase: Phase::Search, message, .. }) if message.contains("size limit")
+		));
+		let updates: Vec<_> = std::iter::from_fn(|| receiver.try_recv().ok()).collect();
+		assert_eq!(updates.len(), 3, "Truncated results must not be reported as removals");
+
+		config.searches.size_limit_page_size = Some(2);
+		let (mut ldap, _receiver) = Ldap::new(config, None);
+		let report = ldap.sync_once(None).await?;
+		assert!(!report.size_limit_exceeded);
+		assert_eq!(report.entries_matched, Some(3));
+		assert_eq!(report.resources.pages_fetched, 2);
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn pid_normalization() -> Result<(), Box<dyn std::error::Error>> {
+		let mut config = Config::example();
+		config.cache_method = CacheMethod::ModificationTime;
+		config.attributes.pid_normalization = PidNormalization::Guid;
+		let (mut ldap, mut receiver) = Ldap::new(config, None);
+		let entry = |text_pid: Option<&str>, binary_pid: Option<&[u8]>| crate::SearchEntry {
+			dn: "cn=foo".to_owned(),
+			attrs: [("mtime", Some("20231026120000Z")), ("objectGUID", text_pid)]
+				.into_iter()
+				.filter_map(|(name, value)| Some((name.to_owned(), vec![value?.to_owned()])))
+				.collect(),
+			bin_attrs: binary_pid
+				.map(|pid| ("objectGUID".to_owned(), vec![pid.to_vec()]))
+				.into_iter()
+				.collect(),
+		};
+		let guid: &[u8] = &[
+			0xae, 0x4f, 0x1d, 0xf8, 0xec, 0x7d, 0xd0, 0x11, 0xa7, 0x65, 0x00, 0xa0, 0xc9, 0x1e,
+			0x6b, 0xf6,
+		];
+		let uuid = "f81d4fae-7dec-11d0-a765-00a0c91e6bf6";
+		ldap.process_entries([entry(None, Some(guid))]).await;
+		assert!(matches!(
+			receiver.try_recv(),
+			Ok(EntryStatus::New(entry)) if entry.attr_first("objectGUID") == Some(uuid)
+		));
+
+		let report = ldap
+			.process_entries([entry(Some("{F81D4FAE-7DEC-11D0-A765-00A0C91E6BF6}"), None)])
+			.await;
+		assert_eq!(report.updates.new + report.updates.changed, 0, "The identity should be kept");
+		assert!(ldap.get_cached(guid).await.is_some());
+		assert!(ldap.get_cached(uuid.as_bytes()).await.is_some());
+		Ok(())
+	}
+
+	#[cfg(feature = "test-util")]
+	#[tokio::test]
+	async fn deadline() -> Result<(), Box<dyn std::error::Error>> {
+		use super::SyncOptions;
+		use crate::{error::Error, testing::MockServer};
+
+		let mut config = Config::example();
+		let server = MockServer::start().await?;
+		config.url = server.url();
+		let (mut ldap, _receiver) = Ldap::new(config, None);
+		server.hang_searches(true);
+		let deadline = std::time::Duration::from_millis(100);
+		let options = SyncOptions { deadline: Some(deadline), ..SyncOptions::default() };
+		let result = ldap.sync_once_with(None, options.clone()).await;
+		assert!(matches!(result, Err(Error::Deadline(elapsed)) if elapsed == deadline));
+		tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+		assert_eq!(server.open_connections(), 0, "The connection should be dropped");
+
+		server.hang_searches(false);
+		ldap.sync_once_with(None, options).await?;
+		Ok(())
+	}
+
+	#[cfg(feature = "test-util")]
+	#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+	async fn deadline_processing() -> Result<(), Box<dyn std::error::Error>> {
+		use std::time::Duration;
+
+		use super::SyncOptions;
+		use crate::testing::people_server;
+
+		let (_server, config) = people_server(&["foo", "bar"]).await?;
+		// Processing the entries takes longer than the deadline
+		let (ldap, mut receiver) = Ldap::new(config, None);
+		let mut ldap = ldap.with_entry_filter(|_| {
+			tokio::task::block_in_place(|| std::thread::sleep(Duration::from_millis(150)));
+			true
+		});
+		let deadline = Duration::from_millis(100);
+		let options = SyncOptions { deadline: Some(deadline), ..SyncOptions::default() };
+		let report = ldap.sync_once_with(None, options).await?;
+		assert_eq!(report.updates.new, 2, "Processing entries shouldn't be cut short");
+		assert!(receiver.try_recv().is_ok());
+		assert!(receiver.try_recv().is_ok());
+		Ok(())
+	}
+
+	#[cfg(feature = "test-util")]
+	#[tokio::test]
+	async fn removal_lookup() -> Result<(), Box<dyn std::error::Error>> {
+		use crate::{
+			config::RemovalConfirmation,
+			testing::{cache, search_entry, MockServer},
+		};
+
+		let user = |dn: &str, pid: &str| {
+			search_entry(dn, "objectGUID", pid.as_bytes(), &[("objectClass", &["person"])])
+		};
+		let server = MockServer::start().await?;
+		let mut config = Config::example();
+		config.url = server.url();
+		config.searches.user_base = "ou=people".to_owned();
+		config.removal_confirmation = RemovalConfirmation::Lookup;
+		// `bar` is still present, but out of the scope of the search
+		server.add(user("cn=foo,ou=people", "foo"));
+		server.add(user("cn=bar,ou=other", "bar"));
+		server.add(user("cn=baz,ou=people", "new-baz"));
+		let old = [
+			user("cn=foo,ou=people", "foo"),
+			user("cn=bar,ou=other", "bar"),
+			user("cn=baz,ou=people", "baz"),
+			user("cn=qux,ou=people", "qux"),
+		];
+		let cache = cache(&config.cache_method, &config.attributes, old, None)?;
+
+		let (mut ldap, mut receiver) = Ldap::new(config, Some(cache));
+		ldap.sync_once(None).await?;
+		let mut removed = Vec::new();
+		while let Ok(status) = receiver.try_recv() {
+			if let EntryStatus::Removed { pid, .. } = status {
+				removed.push(String::from_utf8(pid)?);
+			}
+		}
+		removed.sort();
+		assert_eq!(
+			removed,
+			["bar", "baz", "qux"],
+			"Entries which are gone or out of scope should be removed"
+		);
+		Ok(())
+	}
+
+	#[cfg(feature = "test-util")]
+	#[tokio::test]
+	async fn bind_methods() -> Result<(), Box<dyn std::error::Error>> {
+		use crate::{config::BindMethod, error::Error, testing::MockServer};
+
+		let server = MockServer::start().await?;
+		server.set_credentials("admin", "secret");
+		let mut config = Config::example();
+		config.url = server.url();
+		let (ldap, _receiver) = Ldap::new(config.clone(), None);
+		assert_eq!(ldap.who_am_i().await?, "dn:admin");
+
+		let mut wrong_password = config.clone();
+		wrong_password.search_password = String::from("wrong");
+		let (mut ldap, _receiver) = Ldap::new(wrong_password, None);
+		assert!(
+			matches!(
+				ldap.who_am_i().await,
+				Err(Error::Ldap(ldap3::LdapError::LdapResult { result })) if result.rc == 49
+			),
+			"Binding with a wrong password should fail"
+		);
+		assert!(ldap.sync_once(None).await.is_err());
+		assert!(
+			lock(&ldap.latencies).bind.percentile(95).is_some(),
+			"The latency of failed binds should be tracked"
+		);
+
+		config.bind_method = BindMethod::Anonymous;
+		config.search_user = String::new();
+		config.search_password = String::new();
+		let (ldap, _receiver) = Ldap::new(config.clone(), None);
+		assert_eq!(ldap.who_am_i().await?, "");
+
+		config.bind_method = BindMethod::Simple;
+		let (ldap, _receiver) = Ldap::new(config, None);
+		assert!(
+			matches!(ldap.who_am_i().await, Err(Error::Credentials(_))),
+			"Simple binds without credentials should fail instead of binding anonymously"
+		);
+		Ok(())
+	}
+
+	#[cfg(feature = "test-util")]
+	#[tokio::test]
+	async fn search_raw() -> Result<(), Box<dyn std::error::Error>> {
+		use ldap3::Scope;
+
+		use crate::testing::{search_entry, MockServer};
+
+		let server = MockServer::start().await?;
+		for name in ["foo", "bar"] {
+			server.add(search_entry(
+				&format!("cn={name},ou=people"),
+				"objectGUID",
+				name.as_bytes(),
+				&[("objectClass", &["person"]), ("mail", &[&format!("{name}@example.com")])],
+			));
+		}
+		let mut config = Config::example();
+		config.url = server.url();
+		let (ldap, mut receiver) = Ldap::new(config, None);
+
+		let entries =
+			ldap.search_raw("cn=foo,ou=people", Scope::Base, "(objectClass=*)", &["mail"]).await?;
+		assert_eq!(entries.len(), 1);
+		assert_eq!(entries[0].attr_first("mail"), Some("foo@example.com"));
+		let entries =
+			ldap.search_raw("ou=people", Scope::Subtree, "(mail=bar@example.com)", &[]).await?;
+		assert_eq!(
+			entries.iter().map(|entry| &*entry.dn).collect::<Vec<_>>(),
+			["cn=bar,ou=people"]
+		);
+		assert!(receiver.try_recv().is_err(), "Searches shouldn't emit updates");
+		assert!(ldap.snapshot().is_empty(), "Searches shouldn't touch the cache");
+		Ok(())
+	}
+
+	#[cfg(feature = "test-util")]
+	#[tokio::test]
+	async fn adaptive_page_size() -> Result<(), Box<dyn std::error::Error>> {
+		use crate::{config::AdaptivePageSize, testing::people_server};
+
+		let (server, mut config) = people_server(&["a", "b", "c", "d", "e"]).await?;
+		config.searches.page_size = Some(8);
+		server.set_page_size_limit(Some(3));
+
+		let (mut ldap, _receiver) = Ldap::new(config.clone(), None);
+		assert!(ldap.sync_once(None).await.is_err(), "The page size is only adapted if enabled");
+
+		config.searches.adaptive_page_size = Some(AdaptivePageSize { min: 2, max: Some(8) });
+		let (mut ldap, _receiver) = Ldap::new(config, None);
+		let report = ldap.sync_once(None).await?;
+		assert_eq!(report.page_size, Some(2));
+		assert_eq!(report.entries_received, 5);
+		assert_eq!(report.resources.pages_fetched, 3);
+
+		server.set_page_size_limit(None);
+		for page_size in [4, 8, 8] {
+			assert_eq!(ldap.sync_once(None).await?.page_size, Some(page_size));
+		}
+		Ok(())
+	}
+
+	#[cfg(feature = "test-util")]
+	#[tokio::test]
+	async fn initial_sync_signal() -> Result<(), Box<dyn std::error::Error>> {
+		use crate::testing::people_server;
+
+		let (server, config) = people_server(&["foo", "bar"]).await?;
+
+		let (mut ldap, _receiver) = Ldap::new(config, None);
+		let mut initial_sync = ldap.subscribe_initial_sync();
+		server.set_size_limit(Some(1));
+		ldap.sync_once(None).await?;
+		assert!(!*initial_sync.borrow_and_update(), "A truncated sync isn't complete");
+
+		server.set_size_limit(None);
+		ldap.sync_once(None).await?;
+		assert!(initial_sync.has_changed()?);
+		assert!(*initial_sync.borrow_and_update());
+		Ok(())
+	}
+
+	#[cfg(feature = "test-util")]
+	#[tokio::test]
+	async fn poller_state() -> Result<(), Box<dyn std::error::Error>> {
+		use std::time::Duration;
+
+		use crate::{diagnostics::PollerState, testing::people_server};
+
+		let (_server, config) = people_server(&["foo"]).await?;
+
+		let (mut ldap, _receiver) = Ldap::new(config, None);
+		let mut state = ldap.subscribe_state();
+		assert_eq!(*state.borrow_and_update(), PollerState::Idle);
+		ldap.sync_once(None).await?;
+		assert!(state.has_changed()?);
+		assert_eq!(*state.borrow_and_update(), PollerState::Idle);
+
+		let mut unreachable = Config::example();
+		unreachable.url = url::Url::parse("ldap://127.0.0.1:1")?;
+		let (mut failing, _receiver) = Ldap::new(unreachable, None);
+		assert!(failing.sync_once(None).await.is_err());
+		assert!(matches!(failing.state(), PollerState::Failed { .. }));
+
+		let mut client = ldap.clone();
+		let sync = tokio::spawn(async move { client.sync(Duration::from_secs(3600)).await });
+		let sleeping = tokio::time::timeout(
+			Duration::from_secs(10),
+			state.wait_for(|state| matches!(state, PollerState::Sleeping { .. })),
+		)
+		.await??
+		.clone();
+		sync.abort();
+		let PollerState::Sleeping { until } = sleeping else {
+			unreachable!("Only a sleeping state is waited for")
+		};
+		assert!(until > time::OffsetDateTime::now_utc() + Duration::from_secs(3000));
+		Ok(())
+	}
+
+	#[cfg(feature = "test-util")]
+	#[tokio::test]
+	async fn invalidate() -> Result<(), Box<dyn std::error::Error>> {
+		use crate::testing::people_server;
+
+		let (server, mut config) = people_server(&["foo", "bar"]).await?;
+		config.check_for_deleted_entries = false;
+
+		let (mut ldap, mut receiver) = Ldap::new(config, None);
+		ldap.sync_once(None).await?;
+		assert!(matches!(receiver.try_recv(), Ok(EntryStatus::New(_))));
+		assert!(matches!(receiver.try_recv(), Ok(EntryStatus::New(_))));
+
+		assert!(ldap.invalidate(b"foo").await);
+		assert!(ldap.invalidate(b"bar").await);
+		assert!(!ldap.invalidate(b"baz").await);
+		assert!(server.remove("cn=bar,ou=people"));
+		// The mark is persisted
+		let cache = ldap.persist_cache().await;
+		let (mut ldap, mut receiver) = Ldap::new((*ldap.config).clone(), Some(cache));
+		// The incremental search doesn't return the unmodified entry
+		ldap.sync_once(Some(time::OffsetDateTime::now_utc())).await?;
+		let mut updates = [receiver.try_recv()?, receiver.try_recv()?];
+		updates.sort_by_key(|update| matches!(update, EntryStatus::Removed { .. }));
+		assert!(matches!(
+			&updates[0],
+			EntryStatus::New(entry) if entry.dn == "cn=foo,ou=people"
+		));
+		assert!(matches!(
+			&updates[1],
+			EntryStatus::Removed { pid, .. } if pid == b"bar"
+		));
+		assert!(receiver.try_recv().is_err());
+		assert!(ldap.cache.read().await.invalidated.is_empty());
+
+		ldap.sync_once(None).await?;
+		assert!(receiver.try_recv().is_err(), "Invalidated entries are emitted once");
+		Ok(())
+	}
+
+	#[cfg(feature = "test-util")]
+	#[tokio::test]
+	async fn recheck() -> Result<(), Box<dyn std::error::Error>> {
+		use crate::testing::people_server;
+
+		let (server, mut config) = people_server(&["foo", "bar"]).await?;
+		config.check_for_deleted_entries = false;
+
+		let (mut ldap, mut receiver) = Ldap::new(config, None);
+		ldap.sync_once(None).await?;
+		assert!(matches!(receiver.try_recv(), Ok(EntryStatus::New(_))));
+		assert!(matches!(receiver.try_recv(), Ok(EntryStatus::New(_))));
+		let synced = ldap.snapshot().provenance(b"foo").ok_or("Provenance should be recorded")?;
+		assert_eq!(ldap.cache_stats().entries, 2);
+
+		assert!(ldap.recheck(b"foo").await);
+		assert!(ldap.recheck(b"bar").await);
+		assert!(!ldap.recheck(b"baz").await);
+		assert!(server.remove("cn=bar,ou=people"));
+		// The incremental search returns neither entry
+		ldap.sync_once(Some(time::OffsetDateTime::now_utc())).await?;
+		assert!(matches!(
+			receiver.try_recv(),
+			Ok(EntryStatus::Removed { pid, .. }) if pid == b"bar"
+		));
+		assert!(receiver.try_recv().is_err(), "The unchanged entry shouldn't be emitted");
+		let rechecked = ldap.snapshot().provenance(b"foo").ok_or("Provenance should be kept")?;
+		assert_eq!(rechecked.first_seen, synced.first_seen);
+		assert!(rechecked.last_confirmed > synced.last_confirmed);
+		assert_eq!(ldap.cache_stats().entries, 1);
+		assert!(lock(&ldap.rechecks).is_empty());
+		Ok(())
+	}
+
+	#[cfg(feature = "test-util")]
+	#[tokio::test]
+	async fn last_sync_info() -> Result<(), Box<dyn std::error::Error>> {
+		use crate::testing::people_server;
+
+		let (server, config) = people_server(&["foo", "bar"]).await?;
+
+		let (mut ldap, _receiver) = Ldap::new(config, None);
+		assert_eq!(ldap.last_sync_info(), None);
+		ldap.sync_once(None).await?;
+		let info = ldap.last_sync_info().ok_or("No sync info")?;
+		assert_eq!((info.new, info.changed, info.removed, info.errors), (2, 0, 0, 0));
+		assert_eq!(info.finished_at - info.started_at, info.duration);
+
+		drop(server);
+		assert!(ldap.sync_once(None).await.is_err());
+		let failed = ldap.last_sync_info().ok_or("No sync info")?;
+		assert_eq!((failed.new, failed.errors), (0, 1));
+		assert!(failed.started_at >= info.finished_at);
+		Ok(())
+	}
+
+	#[cfg(feature = "test-util")]
+	#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+	async fn compare_workers() -> Result<(), Box<dyn std::error::Error>> {
+		use crate::testing::{search_entry, MockServer};
+
+		let mut config = Config::example();
+		let server = MockServer::start().await?;
+		config.url = server.url();
+		config.compare_workers = Some(3);
+		config.searches.page_size = Some(4);
+		for id in 0..20 {
+			server.add(search_entry(
+				&format!("cn={id},ou=people"),
+				"objectGUID",
+				format!("{id}").as_bytes(),
+				&[("objectClass", &["person"]), ("mtime", &["yesterday"])],
+			));
+		}
+
+		let (mut ldap, mut receiver) = Ldap::new(config, None);
+		let report = ldap.sync_once(None).await?;
+		assert_eq!(report.updates.new, 20);
+		assert_eq!(report.unparsable_updated, 20);
+		assert_eq!(report.unparsable_updated_samples.len(), SyncReport::MAX_SAMPLES);
+		let mut dns = Vec::new();
+		while let Ok(EntryStatus::New(entry)) = receiver.try_recv() {
+			dns.push(entry.dn.clone());
+		}
+		dns.sort_unstable();
+		dns.dedup();
+		assert_eq!(dns.len(), 20, "Every entry should be pushed once");
+		Ok(())
+	}
+
+	#[cfg(feature = "test-util")]
+	#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+	async fn compare_workers_concurrent() -> Result<(), Box<dyn std::error::Error>> {
+		use std::{
+			sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+			time::{Duration, Instant},
+		};
+
+		use crate::testing::{search_entry, MockServer};
+
+		let mut config = Config::example();
+		let server = MockServer::start().await?;
+		config.url = server.url();
+		config.compare_workers = Some(2);
+		for id in 0..4 {
+			server.add(search_entry(
+				&format!("cn={id},ou=people"),
+				"objectGUID",
+				format!("{id}").as_bytes(),
+				&[("objectClass", &["person"])],
+			));
+		}
+
+		// Every entry waits until another one is being processed as well
+		let in_flight = Arc::new(AtomicUsize::new(0));
+		let concurrent = Arc::new(AtomicBool::new(false));
+		let (ldap, mut receiver) = Ldap::new(config, None);
+		let mut ldap = ldap.with_entry_filter({
+			let (in_flight, concurrent) = (in_flight.clone(), concurrent.clone());
+			move |_| {
+				if in_flight.fetch_add(1, Ordering::SeqCst) > 0 {
+					concurrent.store(true, Ordering::SeqCst);
+				}
+				// Lets the runtime move the other tasks to another thread
+				tokio::task::block_in_place(|| {
+					let started = Instant::now();
+					while !concurrent.load(Ordering::SeqCst)
+						&& started.elapsed() < Duration::from_secs(2)
+					{
+						std::thread::sleep(Duration::from_millis(5));
+					}
+				});
+				in_flight.fetch_sub(1, Ordering::SeqCst);
+				true
+			}
+		});
+		let report = ldap.sync_once(None).await?;
+		assert!(concurrent.load(Ordering::SeqCst), "Two entries should be processed at once");
+		assert_eq!(report.updates.new, 4);
+		let mut received = 0;
+		while receiver.try_recv().is_ok() {
+			received += 1;
+		}
+		assert_eq!(received, 4);
+		Ok(())
+	}
+
+	#[cfg(feature = "test-util")]
+	#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+	async fn compare_workers_duplicates() -> Result<(), Box<dyn std::error::Error>> {
+		use crate::testing::{people_server, search_entry};
+
+		let (_server, mut config) = people_server(&["foo", "bar"]).await?;
+		config.compare_workers = Some(4);
+		let (ldap, mut receiver) = Ldap::new(config, None);
+		// The server returns the same entry twice, and both copies are
+		// processed at once
+		let barrier = std::sync::Barrier::new(2);
+		let mut ldap = ldap
+			.with_entry_transform(|mut entry| {
+				entry.dn = "cn=foo,ou=people".to_owned();
+				entry.attrs.insert("objectGUID".to_owned(), vec!["foo".to_owned()]);
+				entry
+			})
+			.with_entry_filter(move |_| {
+				tokio::task::block_in_place(|| barrier.wait());
+				true
+			});
+		let report = ldap.sync_once(None).await?;
+		assert_eq!(report.updates.new, 1);
+		assert!(matches!(receiver.try_recv(), Ok(EntryStatus::New(_))));
+		assert!(receiver.try_recv().is_err(), "The entry should only be emitted once");
+
+		// Regardless of how the workers interleave, only the first copy is
+		// compared
+		let entry = search_entry("cn=foo,ou=people", "objectGUID", b"foo", &[]);
+		assert!(matches!(ldap.pid_conflict(&entry).await, super::PidCheck::Unique));
+		assert!(matches!(ldap.pid_conflict(&entry).await, super::PidCheck::Duplicate));
+		Ok(())
 	}
 }
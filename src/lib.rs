@@ -18,12 +18,14 @@
 //! A minimal example of running the client might look like so:
 //! ```no_run
 //! # async fn run() -> Result<(), Box<dyn std::error::Error>> {
-//! use std::time::Duration;
+//! use std::{collections::HashMap, time::Duration};
 //!
 //! use ldap_poller::{
 //! 	config::{
-//! 		AttributeConfig, CacheMethod, Config, ConnectionConfig, Searches,
-//! 		TLSConfig,
+//! 		AttributeConfig, BindMethod, CacheMethod, Config, ConnectionConfig,
+//! 		DnChangeMode, LatencyThresholds, PersistenceConfig,
+//! 		PidNormalization, RemovalConfirmation, RetryConfig, Searches,
+//! 		TLSConfig, TimestampFormat,
 //! 	},
 //! 	ldap::Ldap,
 //! };
@@ -37,23 +39,42 @@
 //! 		timeout: 5,
 //! 		tls: TLSConfig {
 //! 			root_certificates_path: None,
+//! 			system_roots: None,
 //! 			client_key_path: None,
 //! 			client_certificate_path: None,
 //! 			starttls: false,
 //! 			no_tls_verify: false,
+//! 			session_resumption: false,
+//! 			reload_certificates: false,
+//! 			disable_sni: false,
+//! 			verify_ip_sans: false,
+//! 			tls_server_name: None,
 //! 		},
 //! 		operation_timeout: Duration::from_secs(5),
+//! 		reuse: false,
+//! 		keepalive: None,
 //! 	},
 //! 	search_user: "admin".to_owned(),
 //! 	search_password: "verysecret".to_owned(),
+//! 	bind_method: BindMethod::Simple,
 //! 	searches: Searches {
 //! 		user_base: "ou=people,dc=example,dc=com".to_owned(),
 //! 		user_filter: "(objectClass=inetOrgPerson)".to_owned(),
 //! 		page_size: None,
+//! 		resume_paged_search: false,
+//! 		size_limit_page_size: None,
+//! 		adaptive_page_size: None,
+//! 		incremental_filter: None,
+//! 		sync_overlap: None,
+//! 		proxy_authz: None,
+//! 		controls: Vec::new(),
 //! 	},
 //! 	attributes: AttributeConfig {
 //! 		pid: "objectGUID".to_owned(),
+//! 		pid_normalization: PidNormalization::None,
 //! 		updated: Some("mtime".to_owned()),
+//! 		updated_format: TimestampFormat::Auto,
+//! 		usn: None,
 //! 		additional: vec![
 //! 			"cn".to_owned(),
 //! 			"admin".to_owned(),
@@ -61,9 +82,31 @@
 //! 		],
 //! 		filter_attributes: true,
 //! 		attrs_to_track: vec!["enabled".to_owned()],
+//! 		rename: HashMap::new(),
+//! 		dn_changes: DnChangeMode::Moved,
+//! 		operational_metadata: false,
+//! 		schema_matching: false,
+//! 		comparison: HashMap::new(),
+//! 		max_cached_size: HashMap::new(),
+//! 		uncached: Vec::new(),
+//! 		disabled: None,
+//! 		members: None,
+//! 		max_values: None,
 //! 	},
 //! 	cache_method: CacheMethod::ModificationTime,
 //! 	check_for_deleted_entries: false,
+//! 	full_sync_interval: None,
+//! 	removal_confirmation: RemovalConfirmation::None,
+//! 	tombstones: None,
+//! 	persistence: PersistenceConfig::default(),
+//! 	canary: None,
+//! 	debounce: None,
+//! 	emit_unchanged: false,
+//! 	latency_thresholds: LatencyThresholds::default(),
+//! 	compare_workers: None,
+//! 	retry: RetryConfig::default(),
+//! 	resend_interval: None,
+//! 	max_failed_sends: None,
 //! };
 //!
 //! let (mut client, mut receiver) = Ldap::new(config.clone(), None);
@@ -78,6 +121,19 @@
 //! # }
 //! ```
 //!
+//! # Features
+//! * `tls-native` (default): Use the platform's native TLS library, e.g.
+//!   OpenSSL on Linux.
+//! * `tls-rustls`: Use [rustls](https://docs.rs/rustls) instead, e.g. for
+//!   static musl builds. Requires disabling the default features, since only
+//!   one TLS backend can be enabled.
+//! * `metrics`: Publish statistics about every sync cycle, such as the
+//!   [resource usage](report::ResourceUsage), via the [metrics](https://docs.rs/metrics)
+//!   facade.
+//! * `test-util`: Fixtures for unit-testing code consuming the updates of the
+//!   poller without a directory server, and an in-process mock directory
+//!   server, see the `testing` module.
+//!
 //! # Limitations
 //! * This library (currently) does not make use of any controls (i.e.
 //!   extensions) such as [persistent search] or [content synchronization] for
@@ -86,17 +142,38 @@
 //!   approach.
 //! * [secrecy](https://docs.rs/secrecy) is not used for storing the search user
 //!   password, it probably should be
-//! * Does not currently have any handling for user entries being removed from
-//!   the directory tree.
+//! * Detecting entries removed from the directory tree requires either a full
+//!   search on every sync, or a server which keeps tombstones of deleted
+//!   entries (see [`config::TombstoneConfig`]).
 //!
 //! [persistent search]: https://datatracker.ietf.org/doc/html/draft-ietf-ldapext-psearch-03
 //! [content synchronization]: https://www.rfc-editor.org/rfc/rfc4533.html
 
+#[cfg(not(any(feature = "tls-native", feature = "tls-rustls")))]
+compile_error!(r#"One of the features "tls-native" and "tls-rustls" must be enabled"#);
+
+pub mod ack;
+pub mod audit;
 mod cache;
+pub mod codec;
 pub mod config;
+pub mod credentials;
+pub mod diagnostics;
+pub mod dn;
 pub mod entry;
 pub mod error;
+pub mod export;
+pub mod filter;
+pub mod import;
 pub mod ldap;
+pub mod membership;
+pub mod pipeline;
+pub mod report;
+pub mod schedule;
+pub mod schema;
+#[cfg(feature = "test-util")]
+pub mod testing;
+mod tls;
 
 pub use ldap3::{self, SearchEntry};
 
@@ -0,0 +1,92 @@
+//! Membership changes derived from group entries, see
+//! [`crate::config::AttributeConfig::members`]
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{dn::Dn, ldap::EntryStatus};
+
+/// Whether a member was added to or removed from a group, see
+/// [`EntryStatus::MembershipChanged`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MembershipChange {
+	/// The member was added to the group
+	Added,
+	/// The member was removed from the group
+	Removed,
+}
+
+/// The updates for the members which differ between the previous and the
+/// current members of a group, removals first. Members which are DNs are
+/// compared like DNs, other values as they are.
+pub(crate) fn membership_updates(
+	group: &[u8],
+	group_dn: &str,
+	old: &[String],
+	new: &[String],
+) -> Vec<EntryStatus> {
+	let (old_keys, new_keys): (HashSet<_>, HashSet<_>) = (
+		old.iter().map(|member| member_key(member)).collect(),
+		new.iter().map(|member| member_key(member)).collect(),
+	);
+	let removed = old
+		.iter()
+		.filter(|member| !new_keys.contains(&member_key(member)))
+		.map(|member| (member, MembershipChange::Removed));
+	let added = new
+		.iter()
+		.filter(|member| !old_keys.contains(&member_key(member)))
+		.map(|member| (member, MembershipChange::Added));
+	removed
+		.chain(added)
+		.map(|(member, change)| EntryStatus::MembershipChanged {
+			group: group.to_owned(),
+			group_dn: group_dn.to_owned(),
+			member: member.clone(),
+			change,
+		})
+		.collect()
+}
+
+/// The form of a member by which members are compared
+fn member_key(member: &str) -> String {
+	Dn::parse(member).map_or_else(|_| member.to_owned(), |dn| dn.normalized())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{membership_updates, MembershipChange};
+	use crate::ldap::EntryStatus;
+
+	#[test]
+	fn updates() {
+		let members =
+			|members: &[&str]| members.iter().map(|&member| member.to_owned()).collect::<Vec<_>>();
+		let old = members(&["cn=foo,ou=people", "cn=bar,ou=people"]);
+		let new = members(&["CN=Bar, ou=People", "cn=baz,ou=people"]);
+		let updates = membership_updates(b"admins", "cn=admins,ou=groups", &old, &new);
+		let changes: Vec<_> = updates
+			.iter()
+			.filter_map(|update| match update {
+				EntryStatus::MembershipChanged { group, group_dn, member, change }
+					if group == b"admins" && group_dn == "cn=admins,ou=groups" =>
+				{
+					Some((member.as_str(), *change))
+				}
+				_ => None,
+			})
+			.collect();
+		assert_eq!(
+			changes,
+			[
+				("cn=foo,ou=people", MembershipChange::Removed),
+				("cn=baz,ou=people", MembershipChange::Added),
+			],
+			"Members should be compared like DNs"
+		);
+
+		assert_eq!(membership_updates(b"admins", "cn=admins", &old, &old).len(), 0);
+		assert_eq!(membership_updates(b"admins", "cn=admins", &[], &old).len(), 2);
+	}
+}
@@ -0,0 +1,213 @@
+//! Several sync pipelines for different types of objects in one client
+use std::collections::HashMap;
+
+use tokio::sync::mpsc;
+
+use crate::{
+	config::{Config, ConfigProblem, PipelineConfig},
+	error::Error,
+	ldap::{EntryStatus, Ldap},
+	schedule::SyncSchedule,
+	Cache,
+};
+
+/// An update of a pipeline, tagged with the name of the pipeline
+#[derive(Debug, Clone)]
+pub struct PipelineUpdate {
+	/// The name of the pipeline, see [`PipelineConfig::name`]
+	pub pipeline: String,
+	/// The update
+	pub status: EntryStatus,
+}
+
+/// Several named sync pipelines, e.g. for users, groups and devices, each
+/// with its own searches, attributes and cache, synced one after the other.
+/// The attributes may differ between the pipelines, e.g. the pid of groups
+/// can be another attribute than that of users. The pipelines share the TLS
+/// settings, and with
+/// [`crate::config::ConnectionConfig::reuse`] a single connection. Updates
+/// of all pipelines are delivered on one channel, tagged with the name of
+/// their pipeline.
+#[derive(Debug)]
+pub struct Pipelines {
+	/// The clients of the pipelines by name, in the configured order
+	pipelines: Vec<(String, Ldap)>,
+}
+
+impl Pipelines {
+	/// Create the pipelines from a base configuration, whose searches,
+	/// attributes and cache method are replaced by those of each pipeline,
+	/// and the saved caches of the pipelines by name. Must be called within
+	/// a tokio runtime, as updates are forwarded from each pipeline by a
+	/// task. Fails if two pipelines have the same name, as their caches and
+	/// updates couldn't be told apart.
+	pub fn new(
+		config: &Config,
+		pipelines: Vec<PipelineConfig>,
+		mut caches: HashMap<String, Cache>,
+	) -> Result<(Self, mpsc::Receiver<PipelineUpdate>), Error> {
+		if let Some(name) = duplicate_name(&pipelines) {
+			return Err(Error::Invalid(format!("Duplicate pipeline name {name}")));
+		}
+		let (sender, receiver) = mpsc::channel(1024);
+		let mut clients: Vec<(String, Ldap)> = Vec::with_capacity(pipelines.len());
+		for pipeline in pipelines {
+			let cache = caches.remove(&pipeline.name);
+			let (mut ldap, mut updates) = Ldap::new(pipeline.apply(config), cache);
+			if let Some((_, first)) = clients.first() {
+				ldap.share_connection(first);
+			}
+			let sender = sender.clone();
+			let name = pipeline.name.clone();
+			tokio::spawn(async move {
+				while let Some(status) = updates.recv().await {
+					let update = PipelineUpdate { pipeline: name.clone(), status };
+					if sender.send(update).await.is_err() {
+						break;
+					}
+				}
+			});
+			clients.push((pipeline.name, ldap));
+		}
+		Ok((Pipelines { pipelines: clients }, receiver))
+	}
+
+	/// Check the pipelines for problems like [`Config::validate`], including
+	/// the configuration of each pipeline applied to the base configuration.
+	/// Problems of a pipeline are prefixed with its name.
+	#[must_use]
+	pub fn validate(config: &Config, pipelines: &[PipelineConfig]) -> Vec<ConfigProblem> {
+		let mut problems = Vec::new();
+		if let Some(name) = duplicate_name(pipelines) {
+			problems.push(ConfigProblem {
+				field: "pipelines.name",
+				message: format!("The pipeline name {name} is used more than once"),
+			});
+		}
+		for pipeline in pipelines {
+			problems.extend(pipeline.apply(config).validate().into_iter().map(|problem| {
+				ConfigProblem {
+					message: format!("Pipeline {}: {}", pipeline.name, problem.message),
+					..problem
+				}
+			}));
+		}
+		problems
+	}
+
+	/// The names of the pipelines, in the configured order
+	pub fn names(&self) -> impl Iterator<Item = &str> {
+		self.pipelines.iter().map(|(name, _)| name.as_str())
+	}
+
+	/// The client of the pipeline with the given name, e.g. to persist its
+	/// cache
+	#[must_use]
+	pub fn get(&self, name: &str) -> Option<&Ldap> {
+		self.pipelines.iter().find(|(pipeline, _)| pipeline == name).map(|(_, ldap)| ldap)
+	}
+
+	/// The client of the pipeline with the given name, e.g. to perform a
+	/// single sync of the pipeline
+	#[must_use]
+	pub fn get_mut(&mut self, name: &str) -> Option<&mut Ldap> {
+		self.pipelines.iter_mut().find(|(pipeline, _)| pipeline == name).map(|(_, ldap)| ldap)
+	}
+
+	/// Sync all pipelines repeatedly forever like [`Ldap::sync`], one after
+	/// the other. A failed cycle of one pipeline doesn't hold up the others,
+	/// its errors are reported as diagnostics of the pipeline's client, see
+	/// [`Ldap::diagnostics`] and [`Pipelines::get`].
+	pub async fn sync(&mut self, schedule: impl Into<SyncSchedule>) -> ! {
+		let schedule = schedule.into();
+		loop {
+			for (_, ldap) in &mut self.pipelines {
+				ldap.sync_scheduled(&schedule).await;
+			}
+			tokio::time::sleep(schedule.delay(time::OffsetDateTime::now_utc())).await;
+		}
+	}
+}
+
+/// The first name which is used by more than one pipeline
+fn duplicate_name(pipelines: &[PipelineConfig]) -> Option<&str> {
+	let mut names = std::collections::HashSet::new();
+	pipelines.iter().map(|pipeline| pipeline.name.as_str()).find(|name| !names.insert(*name))
+}
+
+#[cfg(all(test, feature = "test-util"))]
+mod tests {
+	use std::collections::HashMap;
+
+	use super::{PipelineUpdate, Pipelines};
+	use crate::{
+		config::{Config, PipelineConfig},
+		ldap::EntryStatus,
+		testing::{people_server, search_entry},
+	};
+
+	/// A pipeline searching the given base with the given filter, with the
+	/// attributes of the configuration
+	fn pipeline(config: &Config, name: &str, base: &str, filter: &str) -> PipelineConfig {
+		let mut searches = config.searches.clone();
+		searches.user_base = base.to_owned();
+		searches.user_filter = filter.to_owned();
+		PipelineConfig {
+			name: name.to_owned(),
+			searches,
+			attributes: config.attributes.clone(),
+			cache_method: None,
+		}
+	}
+
+	#[tokio::test]
+	async fn tagged_updates() -> Result<(), Box<dyn std::error::Error>> {
+		let (server, mut config) = people_server(&["foo"]).await?;
+		server.add(search_entry(
+			"cn=admins,ou=groups",
+			"objectGUID",
+			b"admins",
+			&[("objectClass", &["groupOfNames"])],
+		));
+		config.connection.reuse = true;
+		let pipelines = vec![
+			pipeline(&config, "users", "ou=people", "(objectClass=person)"),
+			pipeline(&config, "groups", "ou=groups", "(objectClass=groupOfNames)"),
+		];
+		let (mut pipelines, mut receiver) = Pipelines::new(&config, pipelines, HashMap::new())?;
+		assert_eq!(pipelines.names().collect::<Vec<_>>(), ["users", "groups"]);
+
+		for name in ["users", "groups"] {
+			pipelines.get_mut(name).ok_or("Missing pipeline")?.sync_once(None).await?;
+		}
+		for (name, dn) in [("users", "cn=foo,ou=people"), ("groups", "cn=admins,ou=groups")] {
+			assert!(matches!(
+				receiver.recv().await,
+				Some(PipelineUpdate { pipeline, status: EntryStatus::New(entry) })
+					if pipeline == name && entry.dn == dn
+			));
+		}
+		assert_eq!(server.connections(), 1, "The pipelines should share the connection");
+		Ok(())
+	}
+
+	#[tokio::test]
+	async fn pipeline_names() {
+		let config = Config::example();
+		let base = &config.searches.user_base;
+		let pipelines = vec![
+			pipeline(&config, "users", base, "(objectClass=person)"),
+			pipeline(&config, "groups", base, "(objectClass=groupOfNames"),
+			pipeline(&config, "users", base, "(objectClass=device)"),
+		];
+		assert!(Pipelines::new(&config, pipelines.clone(), HashMap::new()).is_err());
+
+		let problems = Pipelines::validate(&config, &pipelines);
+		assert_eq!(
+			problems.iter().map(|problem| problem.field).collect::<Vec<_>>(),
+			["pipelines.name", "searches.user_filter"]
+		);
+		assert!(problems[1].message.starts_with("Pipeline groups: "), "{}", problems[1]);
+		assert_eq!(Pipelines::validate(&config, &pipelines[..2]).len(), 1);
+	}
+}
@@ -0,0 +1,397 @@
+//! Reports about performed sync cycles
+use std::{collections::VecDeque, time::Duration};
+
+use ldap3::SearchEntry;
+use time::OffsetDateTime;
+
+use crate::ldap::EntryStatus;
+
+/// Summary of a single sync cycle
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncReport {
+	/// Number of entries whose modification time attribute could not be
+	/// parsed. These entries are compared by all of their attributes instead.
+	pub unparsable_updated: usize,
+	/// DNs of some of the entries with an unparsable modification time,
+	/// limited to [`SyncReport::MAX_SAMPLES`]
+	pub unparsable_updated_samples: Vec<String>,
+	/// Number of parsed modification times without a fractional second
+	pub updated_whole_seconds: usize,
+	/// Number of parsed modification times with a fractional second
+	pub updated_subsecond: usize,
+	/// The highest update sequence number of the received entries, see
+	/// [`crate::config::AttributeConfig::usn`]
+	pub highest_usn: Option<u64>,
+	/// Number of entries matched by the search filter if the cycle performed
+	/// a full search. A sudden drop usually means a filter or ACL
+	/// misconfiguration on the server.
+	pub entries_matched: Option<u64>,
+	/// Number of entries received from the search during the cycle
+	pub entries_received: u64,
+	/// Time taken to bind
+	pub bind_latency: Option<Duration>,
+	/// Time from sending the search request until the first page of results
+	/// was received
+	pub first_page_latency: Option<Duration>,
+	/// The start time of the paged search resumed by this cycle, see
+	/// [`crate::config::Searches::resume_paged_search`]. Entries on the pages
+	/// received before the interruption may have changed since, so this
+	/// should be used as the time of the sync.
+	pub resumed_from: Option<OffsetDateTime>,
+	/// Set if the server truncated the search results because of its size
+	/// limit. Removals weren't detected then, and the cycle doesn't count as
+	/// complete sync.
+	pub size_limit_exceeded: bool,
+	/// The page size of the search, unless it wasn't paged. Differs from the
+	/// configured one if it was adapted to the limits of the server, see
+	/// [`crate::config::Searches::adaptive_page_size`].
+	pub page_size: Option<i32>,
+	/// Number of times the cycle was retried after a transient error, see
+	/// [`crate::config::RetryConfig`]
+	pub retries: u32,
+	/// Number of updates pushed during the cycle
+	pub updates: UpdateCounts,
+	/// Resources used during the cycle
+	pub resources: ResourceUsage,
+}
+
+impl SyncReport {
+	/// The maximum number of sample DNs kept per reported problem
+	pub const MAX_SAMPLES: usize = 10;
+
+	/// Record an entry with an unparsable modification time
+	pub(crate) fn record_unparsable_updated(&mut self, dn: &str) {
+		self.unparsable_updated += 1;
+		if self.unparsable_updated_samples.len() < Self::MAX_SAMPLES {
+			self.unparsable_updated_samples.push(dn.to_owned());
+		}
+	}
+
+	/// Record a parsed modification time
+	pub(crate) fn record_updated(&mut self, updated: OffsetDateTime) {
+		if updated.nanosecond() == 0 {
+			self.updated_whole_seconds += 1;
+		} else {
+			self.updated_subsecond += 1;
+		}
+	}
+
+	/// Record the update sequence number of an entry
+	pub(crate) fn record_usn(&mut self, usn: u64) {
+		self.highest_usn = self.highest_usn.max(Some(usn));
+	}
+
+	/// Add what was recorded while processing entries on a separate task,
+	/// see [`crate::config::Config::compare_workers`]
+	pub(crate) fn merge_processed(&mut self, other: SyncReport) {
+		self.unparsable_updated += other.unparsable_updated;
+		let samples = Self::MAX_SAMPLES.saturating_sub(self.unparsable_updated_samples.len());
+		self.unparsable_updated_samples
+			.extend(other.unparsable_updated_samples.into_iter().take(samples));
+		self.updated_whole_seconds += other.updated_whole_seconds;
+		self.updated_subsecond += other.updated_subsecond;
+		self.highest_usn = self.highest_usn.max(other.highest_usn);
+		let (updates, other_updates) = (&mut self.updates, other.updates);
+		updates.new += other_updates.new;
+		updates.changed += other_updates.changed;
+		updates.removed += other_updates.removed;
+		updates.moved += other_updates.moved;
+		updates.unchanged += other_updates.unchanged;
+		updates.membership_changed += other_updates.membership_changed;
+		updates.skipped += other_updates.skipped;
+		updates.pid_conflicts += other_updates.pid_conflicts;
+		self.resources.lock_hold_total += other.resources.lock_hold_total;
+		self.resources.lock_hold_max =
+			self.resources.lock_hold_max.max(other.resources.lock_hold_max);
+	}
+
+	/// Whether the server seems to store modification times with only second
+	/// granularity, i.e. none of the received modification times had a
+	/// fractional second. This can't be told if no entries were received.
+	#[must_use]
+	pub fn has_coarse_updated(&self) -> bool {
+		self.updated_whole_seconds > 0 && self.updated_subsecond == 0
+	}
+
+	/// Publish the report via the `metrics` facade
+	#[cfg(feature = "metrics")]
+	pub(crate) fn record_metrics(&self) {
+		if let Some(entries_matched) = self.entries_matched {
+			metrics::gauge!("ldap_poller_entries_matched").set(entries_matched as f64);
+		}
+		if let Some(page_size) = self.page_size {
+			metrics::gauge!("ldap_poller_page_size").set(f64::from(page_size));
+		}
+		self.resources.record_metrics();
+	}
+}
+
+/// Number of updates pushed during a single sync cycle, by kind. Changes
+/// held back by [`crate::config::Config::debounce`] are counted as well.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[allow(missing_docs)]
+pub struct UpdateCounts {
+	pub new: u64,
+	pub changed: u64,
+	pub removed: u64,
+	pub moved: u64,
+	pub unchanged: u64,
+	pub membership_changed: u64,
+	pub skipped: u64,
+	pub pid_conflicts: u64,
+}
+
+impl UpdateCounts {
+	/// Count an update
+	pub(crate) fn record(&mut self, status: &EntryStatus) {
+		let count = match status {
+			EntryStatus::New(_) => &mut self.new,
+			EntryStatus::Changed { .. } => &mut self.changed,
+			EntryStatus::Removed { .. } => &mut self.removed,
+			EntryStatus::Moved { .. } => &mut self.moved,
+			EntryStatus::Unchanged(_) => &mut self.unchanged,
+			EntryStatus::MembershipChanged { .. } => &mut self.membership_changed,
+			EntryStatus::Skipped { .. } => &mut self.skipped,
+			EntryStatus::PidConflict { .. } => &mut self.pid_conflicts,
+		};
+		*count += 1;
+	}
+}
+
+/// Summary of the most recent sync cycle, see
+/// [`crate::ldap::Ldap::last_sync_info`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyncInfo {
+	/// When the cycle started
+	pub started_at: OffsetDateTime,
+	/// When the cycle finished, successfully or not
+	pub finished_at: OffsetDateTime,
+	/// How long the cycle took
+	pub duration: Duration,
+	/// Number of new entries pushed
+	pub new: u64,
+	/// Number of changed entries pushed
+	pub changed: u64,
+	/// Number of removed entries pushed
+	pub removed: u64,
+	/// Number of moved entries pushed
+	pub moved: u64,
+	/// Number of received entries which were skipped, see
+	/// [`crate::ldap::EntryStatus::Skipped`]
+	pub skipped: u64,
+	/// Number of problems during the cycle: warnings sent as
+	/// [`crate::diagnostics::Diagnostic`]s, and the error if the cycle failed
+	pub errors: u64,
+}
+
+impl SyncInfo {
+	/// Summarize a cycle which started at the given time, with the report
+	/// of the updates pushed until it finished or failed
+	pub(crate) fn new(
+		started_at: OffsetDateTime,
+		duration: Duration,
+		report: &SyncReport,
+		errors: u64,
+	) -> Self {
+		let updates = &report.updates;
+		SyncInfo {
+			started_at,
+			finished_at: started_at + duration,
+			duration,
+			new: updates.new,
+			changed: updates.changed,
+			removed: updates.removed,
+			moved: updates.moved,
+			skipped: updates.skipped,
+			errors,
+		}
+	}
+}
+
+/// Resources used during a single sync cycle, for capacity planning
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResourceUsage {
+	/// Approximate size of the received entries (DNs, attribute names and
+	/// values) in bytes
+	pub bytes_received: u64,
+	/// Number of result pages fetched. A search without paging counts as a
+	/// single page.
+	pub pages_fetched: u64,
+	/// Peak estimated size in bytes of the entries which were pushed to the
+	/// channel but not yet received by the consumer
+	pub peak_in_flight_bytes: u64,
+	/// Total time the cache lock was held by the sync
+	pub lock_hold_total: Duration,
+	/// Longest single time the cache lock was held by the sync
+	pub lock_hold_max: Duration,
+}
+
+impl ResourceUsage {
+	/// Record a received entry
+	pub(crate) fn record_entry(&mut self, entry: &SearchEntry) {
+		let attrs = entry
+			.attrs
+			.iter()
+			.map(|(name, values)| name.len() + values.iter().map(String::len).sum::<usize>());
+		let bin_attrs = entry
+			.bin_attrs
+			.iter()
+			.map(|(name, values)| name.len() + values.iter().map(Vec::len).sum::<usize>());
+		let size = entry.dn.len() + attrs.chain(bin_attrs).sum::<usize>();
+		self.bytes_received += u64::try_from(size).unwrap_or(u64::MAX);
+	}
+
+	/// Record the number of entries currently waiting in the channel, given
+	/// the number of entries received so far
+	pub(crate) fn record_in_flight(&mut self, queued: usize, received: u64) {
+		if received == 0 {
+			return;
+		}
+		let queued = u64::try_from(queued).unwrap_or(u64::MAX);
+		let in_flight = self.bytes_received / received * queued;
+		self.peak_in_flight_bytes = self.peak_in_flight_bytes.max(in_flight);
+	}
+
+	/// Record how long the cache lock was held
+	pub(crate) fn record_lock_hold(&mut self, duration: Duration) {
+		self.lock_hold_total += duration;
+		self.lock_hold_max = self.lock_hold_max.max(duration);
+	}
+
+	/// Publish the resource usage via the `metrics` facade
+	#[cfg(feature = "metrics")]
+	pub(crate) fn record_metrics(&self) {
+		metrics::histogram!("ldap_poller_cycle_bytes_received").record(self.bytes_received as f64);
+		metrics::histogram!("ldap_poller_cycle_pages_fetched").record(self.pages_fetched as f64);
+		metrics::histogram!("ldap_poller_cycle_peak_in_flight_bytes")
+			.record(self.peak_in_flight_bytes as f64);
+		metrics::histogram!("ldap_poller_cycle_lock_hold_seconds")
+			.record(self.lock_hold_total.as_secs_f64());
+		metrics::histogram!("ldap_poller_cycle_lock_hold_max_seconds")
+			.record(self.lock_hold_max.as_secs_f64());
+	}
+}
+
+/// Latencies of the most recent sync cycles, for rolling percentiles
+#[derive(Debug, Default)]
+pub(crate) struct Latencies {
+	/// Bind latencies
+	pub(crate) bind: LatencyWindow,
+	/// First page latencies
+	pub(crate) first_page: LatencyWindow,
+}
+
+/// A rolling window of latencies
+#[derive(Debug, Default)]
+pub(crate) struct LatencyWindow {
+	/// The most recent latencies, oldest first
+	samples: VecDeque<Duration>,
+	/// Whether the 95th percentile exceeded the threshold at the last check
+	degraded: bool,
+}
+
+impl LatencyWindow {
+	/// The number of latencies kept
+	pub(crate) const SIZE: usize = 50;
+
+	/// Record a latency, dropping the oldest one if the window is full
+	pub(crate) fn record(&mut self, latency: Duration) {
+		if self.samples.len() >= Self::SIZE {
+			self.samples.pop_front();
+		}
+		self.samples.push_back(latency);
+	}
+
+	/// The given percentile of the latencies in the window, using the
+	/// nearest-rank method
+	pub(crate) fn percentile(&self, percentile: usize) -> Option<Duration> {
+		let mut samples: Vec<_> = self.samples.iter().copied().collect();
+		samples.sort_unstable();
+		let rank = (samples.len() * percentile.min(100)).div_ceil(100);
+		samples.get(rank.saturating_sub(1)).copied()
+	}
+
+	/// Check whether the 95th percentile exceeds the threshold. Returns the
+	/// new state only if it changed since the last check.
+	pub(crate) fn update_degraded(&mut self, threshold: Duration) -> Option<bool> {
+		let degraded = self.percentile(95).is_some_and(|p95| p95 > threshold);
+		(std::mem::replace(&mut self.degraded, degraded) != degraded).then_some(degraded)
+	}
+
+	/// Publish the percentiles via the `metrics` facade
+	#[cfg(feature = "metrics")]
+	pub(crate) fn record_metrics(&self, operation: &'static str) {
+		for (percentile, label) in [(50, "p50"), (95, "p95"), (99, "p99")] {
+			if let Some(latency) = self.percentile(percentile) {
+				metrics::gauge!("ldap_poller_latency_seconds", "operation" => operation, "percentile" => label)
+					.set(latency.as_secs_f64());
+			}
+		}
+		metrics::gauge!("ldap_poller_latency_degraded", "operation" => operation)
+			.set(if self.degraded { 1.0 } else { 0.0 });
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{collections::HashMap, time::Duration};
+
+	use ldap3::SearchEntry;
+	use time::macros::datetime;
+
+	use super::{LatencyWindow, ResourceUsage, SyncReport};
+
+	#[test]
+	fn resource_usage() {
+		let mut usage = ResourceUsage::default();
+		let entry = SearchEntry {
+			dn: "cn=foo".to_owned(),
+			attrs: HashMap::from([("cn".to_owned(), vec!["foo".to_owned()])]),
+			bin_attrs: HashMap::from([("guid".to_owned(), vec![vec![0; 16]])]),
+		};
+		usage.record_entry(&entry);
+		usage.record_entry(&entry);
+		assert_eq!(usage.bytes_received, 2 * (6 + 2 + 3 + 4 + 16));
+
+		usage.record_in_flight(3, 2);
+		usage.record_in_flight(1, 2);
+		assert_eq!(usage.peak_in_flight_bytes, 3 * 31);
+
+		usage.record_lock_hold(Duration::from_millis(5));
+		usage.record_lock_hold(Duration::from_millis(2));
+		assert_eq!(usage.lock_hold_total, Duration::from_millis(7));
+		assert_eq!(usage.lock_hold_max, Duration::from_millis(5));
+	}
+
+	#[test]
+	fn coarse_updated() {
+		let mut report = SyncReport::default();
+		assert!(!report.has_coarse_updated(), "Nothing can be told without entries");
+
+		report.record_updated(datetime!(2023-10-26 12:00:00 UTC));
+		report.record_updated(datetime!(2023-10-26 12:00:05 UTC));
+		assert!(report.has_coarse_updated());
+
+		report.record_updated(datetime!(2023-10-26 12:00:05.25 UTC));
+		assert!(!report.has_coarse_updated(), "A single fractional second should be enough");
+	}
+
+	#[test]
+	fn latency_window() {
+		let mut window = LatencyWindow::default();
+		assert_eq!(window.percentile(95), None);
+		assert_eq!(window.update_degraded(Duration::from_millis(100)), None);
+
+		for millis in 1..=100 {
+			window.record(Duration::from_millis(millis));
+		}
+		assert_eq!(window.samples.len(), LatencyWindow::SIZE, "Old latencies should be dropped");
+		assert_eq!(window.percentile(50), Some(Duration::from_millis(75)));
+		assert_eq!(window.percentile(95), Some(Duration::from_millis(98)));
+		assert_eq!(window.percentile(100), Some(Duration::from_millis(100)));
+
+		assert_eq!(window.update_degraded(Duration::from_millis(90)), Some(true));
+		assert_eq!(window.update_degraded(Duration::from_millis(90)), None, "Only changes count");
+		assert_eq!(window.update_degraded(Duration::from_millis(100)), Some(false));
+	}
+}
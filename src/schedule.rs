@@ -0,0 +1,241 @@
+//! Scheduling of repeated syncs
+use std::{str::FromStr, time::Duration};
+
+use rand::Rng;
+use time::{OffsetDateTime, Time};
+
+use crate::error::Error;
+
+/// When [`crate::ldap::Ldap::sync`] performs syncs
+#[derive(Debug, Clone)]
+pub enum SyncSchedule {
+	/// Wait a fixed interval between syncs, plus a random delay of up to
+	/// `jitter` so that many pollers don't hit the directory at once
+	Interval {
+		/// The interval between the end of a sync and the next one
+		interval: Duration,
+		/// The maximum additional random delay
+		jitter: Duration,
+	},
+	/// Sync at the times matching a cron-like expression, see
+	/// [`CronSchedule`]
+	Cron(CronSchedule),
+}
+
+impl SyncSchedule {
+	/// How long to wait after `now` until the next sync
+	#[must_use]
+	pub fn delay(&self, now: OffsetDateTime) -> Duration {
+		match self {
+			SyncSchedule::Interval { interval, jitter } if jitter.is_zero() => *interval,
+			SyncSchedule::Interval { interval, jitter } => {
+				*interval + rand::thread_rng().gen_range(Duration::ZERO..=*jitter)
+			}
+			SyncSchedule::Cron(cron) => cron
+				.next_after(now)
+				.map_or(Duration::MAX, |next| (next - now).try_into().unwrap_or_default()),
+		}
+	}
+
+	/// Whether syncs may happen more than once per second
+	pub(crate) fn is_sub_second(&self) -> bool {
+		matches!(self, SyncSchedule::Interval { interval, .. } if *interval < Duration::from_secs(1))
+	}
+}
+
+impl From<Duration> for SyncSchedule {
+	fn from(interval: Duration) -> Self {
+		SyncSchedule::Interval { interval, jitter: Duration::ZERO }
+	}
+}
+
+impl From<CronSchedule> for SyncSchedule {
+	fn from(cron: CronSchedule) -> Self {
+		SyncSchedule::Cron(cron)
+	}
+}
+
+/// A cron-like schedule with the five fields minute, hour, day of month,
+/// month and day of week (0 is Sunday), evaluated in UTC. Each field is
+/// either `*` or a comma-separated list of values or ranges like `1-5`, all
+/// of which may have a step like `*/15`. As with cron, if both day of month
+/// and day of week are restricted, days matching either of them match.
+///
+/// ```
+/// use ldap_poller::schedule::CronSchedule;
+///
+/// // Every 15 minutes during office hours, and once at 3 am
+/// let schedule: CronSchedule = "*/15 8-17 * * 1-5".parse()?;
+/// let nightly: CronSchedule = "0 3 * * *".parse()?;
+/// # Ok::<(), ldap_poller::error::Error>(())
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronSchedule {
+	/// Matching minutes, as a bit set
+	minutes: u64,
+	/// Matching hours, as a bit set
+	hours: u64,
+	/// Matching days of the month, as a bit set
+	days: u64,
+	/// Matching months, as a bit set
+	months: u64,
+	/// Matching days of the week, as a bit set
+	weekdays: u64,
+	/// Whether the day of month field is `*`
+	any_day: bool,
+	/// Whether the day of week field is `*`
+	any_weekday: bool,
+}
+
+impl CronSchedule {
+	/// Get the first matching minute after the given time, or `None` if there
+	/// is none within the next years (e.g. for February 30th)
+	#[must_use]
+	pub fn next_after(&self, time: OffsetDateTime) -> Option<OffsetDateTime> {
+		let time = time.to_offset(time::UtcOffset::UTC);
+		let mut next =
+			time.replace_second(0).ok()?.replace_nanosecond(0).ok()? + time::Duration::MINUTE;
+		// Days are skipped one at a time, so this covers leap days
+		let limit = time + time::Duration::days(366 * 5);
+		while next < limit {
+			if !self.matches_day(next) {
+				next = next.replace_time(Time::MIDNIGHT) + time::Duration::DAY;
+			} else if !bit(self.hours, next.hour()) {
+				next = next.replace_minute(0).ok()? + time::Duration::HOUR;
+			} else if !bit(self.minutes, next.minute()) {
+				next += time::Duration::MINUTE;
+			} else {
+				return Some(next);
+			}
+		}
+		None
+	}
+
+	/// Whether the day of the given time matches
+	fn matches_day(&self, time: OffsetDateTime) -> bool {
+		let day = bit(self.days, time.day());
+		let weekday = bit(self.weekdays, time.weekday().number_days_from_sunday());
+		bit(self.months, u8::from(time.month()))
+			&& match (self.any_day, self.any_weekday) {
+				(false, false) => day || weekday,
+				_ => day && weekday,
+			}
+	}
+}
+
+/// Whether the given bit is set
+fn bit(set: u64, value: u8) -> bool {
+	set & (1 << value) != 0
+}
+
+/// Parse a field of a cron expression with the given range of values into a
+/// bit set
+fn parse_field(field: &str, min: u8, max: u8) -> Result<u64, Error> {
+	let invalid = || Error::Invalid(format!("Invalid cron field: {field}"));
+	let mut set = 0;
+	for part in field.split(',') {
+		let (range, step) = match part.split_once('/') {
+			Some((range, step)) => (range, step.parse::<u8>().map_err(|_| invalid())?),
+			None => (part, 1),
+		};
+		let (start, end) = match range.split_once('-') {
+			_ if range == "*" => (min, max),
+			Some((start, end)) => {
+				(start.parse().map_err(|_| invalid())?, end.parse().map_err(|_| invalid())?)
+			}
+			None => {
+				let value = range.parse().map_err(|_| invalid())?;
+				(value, value)
+			}
+		};
+		if step == 0 || start < min || end > max || start > end {
+			return Err(invalid());
+		}
+		for value in (start..=end).step_by(step.into()) {
+			set |= 1 << value;
+		}
+	}
+	Ok(set)
+}
+
+impl FromStr for CronSchedule {
+	type Err = Error;
+
+	fn from_str(expression: &str) -> Result<Self, Self::Err> {
+		let fields: Vec<_> = expression.split_whitespace().collect();
+		let [minutes, hours, days, months, weekdays] = fields[..] else {
+			return Err(Error::Invalid(format!("Expected five cron fields: {expression}")));
+		};
+		Ok(CronSchedule {
+			minutes: parse_field(minutes, 0, 59)?,
+			hours: parse_field(hours, 0, 23)?,
+			days: parse_field(days, 1, 31)?,
+			months: parse_field(months, 1, 12)?,
+			weekdays: parse_field(weekdays, 0, 6)?,
+			any_day: days == "*",
+			any_weekday: weekdays == "*",
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use time::macros::datetime;
+
+	use super::{CronSchedule, SyncSchedule};
+
+	#[test]
+	fn cron() -> Result<(), Box<dyn std::error::Error>> {
+		let schedule: CronSchedule = "*/15 8-17 * * 1-5".parse()?;
+		// Friday evening to Monday morning
+		assert_eq!(
+			schedule.next_after(datetime!(2023-10-20 17:45:30 UTC)),
+			Some(datetime!(2023-10-23 08:00 UTC))
+		);
+		assert_eq!(
+			schedule.next_after(datetime!(2023-10-23 08:00 UTC)),
+			Some(datetime!(2023-10-23 08:15 UTC))
+		);
+
+		// Either the 1st or a Sunday
+		let schedule: CronSchedule = "0 3 1 * 0".parse()?;
+		assert_eq!(
+			schedule.next_after(datetime!(2023-10-26 12:00 UTC)),
+			Some(datetime!(2023-10-29 03:00 UTC))
+		);
+		assert_eq!(
+			schedule.next_after(datetime!(2023-10-29 12:00 UTC)),
+			Some(datetime!(2023-11-01 03:00 UTC))
+		);
+
+		let schedule: CronSchedule = "0 0 29 2 *".parse()?;
+		assert_eq!(
+			schedule.next_after(datetime!(2023-10-26 12:00 UTC)),
+			Some(datetime!(2024-02-29 00:00 UTC))
+		);
+		let schedule: CronSchedule = "0 0 30 2 *".parse()?;
+		assert_eq!(schedule.next_after(datetime!(2023-10-26 12:00 UTC)), None);
+
+		for invalid in ["* * * *", "60 * * * *", "*/0 * * * *", "5-1 * * * *", "a * * * *"] {
+			assert!(invalid.parse::<CronSchedule>().is_err(), "{invalid} should be rejected");
+		}
+
+		Ok(())
+	}
+
+	#[test]
+	fn jitter() {
+		let now = datetime!(2023-10-26 12:00 UTC);
+		let schedule = SyncSchedule::Interval {
+			interval: Duration::from_secs(60),
+			jitter: Duration::from_secs(10),
+		};
+		for _ in 0..100 {
+			let delay = schedule.delay(now);
+			assert!(delay >= Duration::from_secs(60) && delay <= Duration::from_secs(70));
+		}
+		assert_eq!(SyncSchedule::from(Duration::from_secs(5)).delay(now), Duration::from_secs(5));
+	}
+}
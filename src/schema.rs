@@ -0,0 +1,234 @@
+//! Equality matching rules from the subschema of the server (RFC 4512), used
+//! to compare attribute values like the server does, see
+//! [`crate::config::AttributeConfig::schema_matching`].
+use std::{borrow::Cow, collections::HashMap};
+
+use crate::dn::Dn;
+
+/// How values of an attribute are compared, derived from the `EQUALITY`
+/// matching rule of its attribute type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchingRule {
+	/// `caseIgnoreMatch` and `caseIgnoreIA5Match`: case and insignificant
+	/// spaces are ignored
+	CaseIgnore,
+	/// `caseExactMatch` and `caseExactIA5Match`: insignificant spaces are
+	/// ignored
+	CaseExact,
+	/// `numericStringMatch`: all spaces are ignored
+	NumericString,
+	/// `telephoneNumberMatch`: case, spaces and hyphens are ignored
+	TelephoneNumber,
+	/// `distinguishedNameMatch`: case and spaces around separators are
+	/// ignored
+	DistinguishedName,
+	/// Any other rule, values are compared byte by byte
+	Exact,
+}
+
+impl MatchingRule {
+	/// The rule with the given name or OID
+	fn from_name(name: &str) -> Self {
+		match name.to_ascii_lowercase().as_str() {
+			"caseignorematch"
+			| "2.5.13.2"
+			| "caseignoreia5match"
+			| "1.3.6.1.4.1.1466.109.114.2" => MatchingRule::CaseIgnore,
+			"caseexactmatch" | "2.5.13.5" | "caseexactia5match" | "1.3.6.1.4.1.1466.109.114.1" => {
+				MatchingRule::CaseExact
+			}
+			"numericstringmatch" | "2.5.13.8" => MatchingRule::NumericString,
+			"telephonenumbermatch" | "2.5.13.20" => MatchingRule::TelephoneNumber,
+			"distinguishednamematch" | "2.5.13.1" => MatchingRule::DistinguishedName,
+			_ => MatchingRule::Exact,
+		}
+	}
+
+	/// Normalize a value, so that values matching according to the rule are
+	/// equal. Values which aren't valid UTF-8 are left as they are.
+	#[must_use]
+	pub fn normalize<'a>(&self, value: &'a [u8]) -> Cow<'a, [u8]> {
+		let Ok(text) = std::str::from_utf8(value) else {
+			return Cow::Borrowed(value);
+		};
+		let normalized = match self {
+			MatchingRule::Exact => return Cow::Borrowed(value),
+			MatchingRule::CaseIgnore => collapse_spaces(&text.to_lowercase()),
+			MatchingRule::CaseExact => collapse_spaces(text),
+			MatchingRule::NumericString => text.replace(' ', ""),
+			MatchingRule::TelephoneNumber => text.replace([' ', '-'], "").to_lowercase(),
+			MatchingRule::DistinguishedName => match Dn::parse(text) {
+				Ok(dn) => dn.normalized(),
+				Err(_) => text.to_lowercase(),
+			},
+		};
+		if normalized.as_bytes() == value {
+			Cow::Borrowed(value)
+		} else {
+			Cow::Owned(normalized.into_bytes())
+		}
+	}
+}
+
+/// Remove leading and trailing spaces and collapse inner runs of spaces
+pub(crate) fn collapse_spaces(text: &str) -> String {
+	text.split(' ').filter(|word| !word.is_empty()).collect::<Vec<_>>().join(" ")
+}
+
+/// The equality matching rules of the attribute types of a server
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Schema {
+	/// Matching rules by lowercase attribute name
+	rules: HashMap<String, MatchingRule>,
+}
+
+/// An attribute type definition, as far as it's relevant for matching
+#[derive(Debug, Default)]
+struct AttributeType {
+	/// The names of the attribute type
+	names: Vec<String>,
+	/// The name or OID of the equality matching rule
+	equality: Option<String>,
+	/// The name of the supertype, whose matching rule is inherited
+	sup: Option<String>,
+}
+
+impl Schema {
+	/// The attribute of the RootDSE holding the DN of the subschema entry
+	pub(crate) const SUBSCHEMA_SUBENTRY: &'static str = "subschemaSubentry";
+
+	/// The attribute of the subschema entry holding the attribute types
+	pub(crate) const ATTRIBUTE_TYPES: &'static str = "attributeTypes";
+
+	/// Build the schema from the values of the `attributeTypes` attribute of
+	/// the subschema entry. Malformed definitions are skipped.
+	#[must_use]
+	pub fn from_attribute_types<S: AsRef<str>>(definitions: &[S]) -> Self {
+		let types: Vec<_> =
+			definitions.iter().filter_map(|definition| parse(definition.as_ref())).collect();
+		let by_name: HashMap<_, _> = types
+			.iter()
+			.flat_map(|attribute_type| {
+				attribute_type
+					.names
+					.iter()
+					.map(move |name| (name.to_ascii_lowercase(), attribute_type))
+			})
+			.collect();
+		// Matching rules are inherited from the supertype, if not specified
+		let resolve = |attribute_type: &AttributeType| {
+			let mut current = attribute_type;
+			for _ in 0..16 {
+				if let Some(equality) = &current.equality {
+					return MatchingRule::from_name(equality);
+				}
+				match current.sup.as_ref().and_then(|sup| by_name.get(&sup.to_ascii_lowercase())) {
+					Some(sup) => current = sup,
+					None => break,
+				}
+			}
+			MatchingRule::Exact
+		};
+		let rules = by_name
+			.iter()
+			.map(|(name, attribute_type)| (name.clone(), resolve(attribute_type)))
+			.collect();
+		Schema { rules }
+	}
+
+	/// The matching rule of an attribute, [`MatchingRule::Exact`] if unknown
+	#[must_use]
+	pub fn matching_rule(&self, attr: &str) -> MatchingRule {
+		self.rules.get(&attr.to_ascii_lowercase()).copied().unwrap_or(MatchingRule::Exact)
+	}
+}
+
+/// Split an attribute type definition into parentheses, quoted strings and
+/// bare words
+fn tokenize(definition: &str) -> Vec<&str> {
+	let mut tokens = Vec::new();
+	let mut rest = definition.trim_start();
+	while let Some(first) = rest.chars().next() {
+		let len = match first {
+			'(' | ')' => 1,
+			'\'' => rest[1..].find('\'').map_or(rest.len(), |end| end + 2),
+			_ => rest
+				.find(|char: char| char.is_whitespace() || char == '(' || char == ')')
+				.unwrap_or(rest.len()),
+		};
+		tokens.push(&rest[..len]);
+		rest = rest[len..].trim_start();
+	}
+	tokens
+}
+
+/// Parse an attribute type definition (RFC 4512 section 4.1.2)
+fn parse(definition: &str) -> Option<AttributeType> {
+	let tokens = tokenize(definition);
+	let unquote = |token: &str| token.trim_matches('\'').to_owned();
+	let mut tokens = tokens.iter().copied();
+	if tokens.next()? != "(" {
+		return None;
+	}
+	// The numeric OID
+	tokens.next()?;
+	let mut attribute_type = AttributeType::default();
+	while let Some(token) = tokens.next() {
+		match token.to_ascii_uppercase().as_str() {
+			"NAME" => match tokens.next()? {
+				"(" => {
+					for name in tokens.by_ref().take_while(|token| *token != ")") {
+						attribute_type.names.push(unquote(name));
+					}
+				}
+				name => attribute_type.names.push(unquote(name)),
+			},
+			"EQUALITY" => attribute_type.equality = Some(unquote(tokens.next()?)),
+			"SUP" => attribute_type.sup = Some(unquote(tokens.next()?)),
+			_ => {}
+		}
+	}
+	Some(attribute_type)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{MatchingRule, Schema};
+
+	#[test]
+	fn matching_rules() {
+		let schema = Schema::from_attribute_types(&[
+			"( 2.5.4.41 NAME 'name' EQUALITY caseIgnoreMatch SUBSTR caseIgnoreSubstringsMatch \
+			 SYNTAX 1.3.6.1.4.1.1466.115.121.1.15{32768} )",
+			"( 2.5.4.3 NAME ( 'cn' 'commonName' ) DESC 'RFC4519: common name(s)' SUP name )",
+			"( 0.9.2342.19200300.100.1.3 NAME ( 'mail' 'rfc822Mailbox' ) EQUALITY \
+			 caseIgnoreIA5Match SYNTAX 1.3.6.1.4.1.1466.115.121.1.26{256} )",
+			"( 2.5.4.20 NAME 'telephoneNumber' EQUALITY telephoneNumberMatch )",
+			"( 2.16.840.1.113730.3.1.216 NAME 'userPKCS12' SYNTAX 1.3.6.1.4.1.1466.115.121.1.5 )",
+			"malformed",
+		]);
+		assert_eq!(schema.matching_rule("commonName"), MatchingRule::CaseIgnore);
+		assert_eq!(schema.matching_rule("MAIL"), MatchingRule::CaseIgnore);
+		assert_eq!(schema.matching_rule("userPKCS12"), MatchingRule::Exact);
+		assert_eq!(schema.matching_rule("unknown"), MatchingRule::Exact);
+
+		let normalize = |attr: &str, value: &str| {
+			String::from_utf8(schema.matching_rule(attr).normalize(value.as_bytes()).into_owned())
+				.unwrap_or_default()
+		};
+		assert_eq!(normalize("cn", "  John   Doe "), "john doe");
+		assert_eq!(normalize("telephoneNumber", "+49 30-1234"), "+49301234");
+		assert_eq!(normalize("userPKCS12", " A "), " A ");
+		assert_eq!(
+			String::from_utf8_lossy(
+				&MatchingRule::DistinguishedName.normalize(b"CN=John  Doe , OU=People")
+			),
+			"cn=john doe,ou=people"
+		);
+		assert_ne!(
+			MatchingRule::DistinguishedName.normalize(br"CN=Doe\, John,OU=People"),
+			MatchingRule::DistinguishedName.normalize(b"CN=Doe,CN=John,OU=People"),
+			"Escaped separators should not split the DN",
+		);
+	}
+}
@@ -0,0 +1,125 @@
+//! Fixtures for unit-testing code which consumes the updates of the poller,
+//! without a directory server, and an in-process [`MockServer`] to test the
+//! poller end to end. Only available with the `test-util` feature.
+use std::{collections::HashMap, sync::Arc};
+
+use ldap3::SearchEntry;
+use time::OffsetDateTime;
+
+mod server;
+
+pub use self::server::MockServer;
+pub use crate::cache::SerializedSearchEntry;
+use crate::{
+	cache::Cache,
+	config::{AttributeConfig, CacheMethod},
+	error::Error,
+	ldap::EntryStatus,
+};
+
+/// Build a search entry with the given DN, binary persistent ID and text
+/// attributes, like the directory would return it
+#[must_use]
+pub fn search_entry(
+	dn: &str,
+	pid_attribute: &str,
+	pid: &[u8],
+	attributes: &[(&str, &[&str])],
+) -> SearchEntry {
+	SearchEntry {
+		dn: dn.to_owned(),
+		attrs: attributes
+			.iter()
+			.map(|(name, values)| {
+				((*name).to_owned(), values.iter().map(|value| (*value).to_owned()).collect())
+			})
+			.collect(),
+		bin_attrs: HashMap::from([(pid_attribute.to_owned(), vec![pid.to_owned()])]),
+	}
+}
+
+impl EntryStatus {
+	/// An [`EntryStatus::Changed`] for an entry whose previous version is
+	/// known
+	#[must_use]
+	pub fn changed(old: SearchEntry, new: SearchEntry) -> Self {
+		EntryStatus::Changed { old: Some(Arc::new(old)), new: Arc::new(new) }
+	}
+
+	/// An [`EntryStatus::Moved`] for an entry which was previously found
+	/// under `old_dn`
+	#[must_use]
+	pub fn moved(pid: &[u8], old_dn: &str, entry: SearchEntry) -> Self {
+		EntryStatus::Moved {
+			pid: pid.to_owned(),
+			old_dn: old_dn.to_owned(),
+			new_dn: entry.dn.clone(),
+			entry: Arc::new(entry),
+		}
+	}
+}
+
+/// Build a cache containing the given entries, as if they were received in a
+/// sync at `last_sync_time`, e.g. to pass to [`crate::ldap::Ldap::new`] or
+/// to test persistence
+pub fn cache(
+	cache_method: &CacheMethod,
+	attributes_config: &AttributeConfig,
+	entries: impl IntoIterator<Item = SearchEntry>,
+	last_sync_time: Option<OffsetDateTime>,
+) -> Result<Cache, Error> {
+	let mut cache = Cache::from_entries(cache_method, attributes_config, entries)?;
+	cache.last_sync_time = last_sync_time;
+	Ok(cache)
+}
+
+/// Start a [`MockServer`] serving a `person` entry `cn={name},ou=people` with
+/// the persistent ID `name` for each of the given names, and an example
+/// config pointing at it
+#[cfg(test)]
+pub(crate) async fn people_server(
+	names: &[&str],
+) -> Result<(MockServer, crate::config::Config), Error> {
+	let server = MockServer::start().await?;
+	for name in names {
+		server.add(search_entry(
+			&format!("cn={name},ou=people"),
+			"objectGUID",
+			name.as_bytes(),
+			&[("objectClass", &["person"])],
+		));
+	}
+	let mut config = crate::config::Config::example();
+	config.url = server.url();
+	Ok((server, config))
+}
+
+#[cfg(test)]
+mod tests {
+	#![allow(clippy::unwrap_used)]
+	use time::macros::datetime;
+
+	use super::{cache, search_entry, EntryStatus};
+	use crate::{config::Config, entry::SearchEntryExt};
+
+	#[test]
+	fn fixtures() {
+		let config = Config::example();
+		let entry = search_entry(
+			"cn=foo,ou=people",
+			&config.attributes.pid,
+			b"foo",
+			&[("mtime", &["20231026120000Z"]), ("enabled", &["TRUE"])],
+		);
+		assert_eq!(entry.bin_attr_first("objectGUID"), Some(&b"foo"[..]));
+		assert_eq!(entry.attr_first("enabled"), Some("TRUE"));
+
+		let moved = EntryStatus::moved(b"foo", "cn=bar,ou=people", entry.clone());
+		assert!(matches!(moved, EntryStatus::Moved { new_dn, .. } if new_dn == "cn=foo,ou=people"));
+
+		let time = datetime!(2023-10-26 12:00:00 UTC);
+		let cache = cache(&config.cache_method, &config.attributes, [entry], Some(time)).unwrap();
+		assert_eq!(cache.last_sync_time, Some(time));
+		assert!(cache.entries.get_expected().contains(b"foo".as_slice()));
+	}
+}
@@ -0,0 +1,752 @@
+//! An in-memory directory server speaking just enough LDAP for the poller:
+//! simple binds, searches with the common filter types and the simple paged
+//! results control, and the "Who am I?" extended operation.
+use std::{
+	net::SocketAddr,
+	sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use ldap3::{
+	asn1::{parse_tag, StructureTag, TagClass, PL},
+	SearchEntry,
+};
+use time::OffsetDateTime;
+use tokio::{
+	io::{AsyncReadExt, AsyncWriteExt},
+	net::{TcpListener, TcpStream},
+	task::JoinHandle,
+};
+use url::Url;
+
+use crate::{config::TIME_FORMAT, dn::Dn, error::Error, ldap::PAGED_RESULTS_OID};
+
+/// OID of the "Who am I?" extended operation
+const WHO_AM_I_OID: &str = "1.3.6.1.4.1.4203.1.11.3";
+
+/// Operational attributes maintained by the server, which are only returned
+/// if explicitly requested
+const OPERATIONAL_ATTRIBUTES: [&str; 2] = ["createTimestamp", "modifyTimestamp"];
+
+/// Result codes used in responses
+mod result_code {
+	/// The operation succeeded
+	pub(super) const SUCCESS: u8 = 0;
+	/// The search returned only part of the results
+	pub(super) const SIZE_LIMIT_EXCEEDED: u8 = 4;
+	/// The request exceeded a limit of the server
+	pub(super) const ADMIN_LIMIT_EXCEEDED: u8 = 11;
+	/// The server is too busy to perform the operation
+	pub(super) const BUSY: u8 = 51;
+	/// The bind credentials were wrong
+	pub(super) const INVALID_CREDENTIALS: u8 = 49;
+	/// The operation isn't supported by the mock server
+	pub(super) const UNWILLING_TO_PERFORM: u8 = 53;
+}
+
+/// An in-memory directory server for testing code using the poller without
+/// a real server, listening on a random local port. Entries are served in
+/// the order they were added. Anonymous binds always succeed, simple binds
+/// succeed unless [`MockServer::set_credentials`] was called.
+///
+/// The server maintains `createTimestamp` and `modifyTimestamp` of its
+/// entries, which are only returned if requested explicitly, like on a real
+/// server. The server stops when it is dropped.
+#[derive(Debug)]
+pub struct MockServer {
+	/// The directory shared with the connections
+	directory: Arc<Directory>,
+	/// The address the server listens on
+	address: SocketAddr,
+	/// The task accepting connections
+	task: JoinHandle<()>,
+}
+
+/// State of the directory
+#[derive(Debug, Default)]
+struct Directory {
+	/// The entries, in the order they were added
+	entries: Mutex<Vec<SearchEntry>>,
+	/// The only accepted simple bind credentials, if restricted
+	credentials: Mutex<Option<(String, String)>>,
+	/// The maximum number of entries returned by searches which aren't paged
+	size_limit: Mutex<Option<usize>>,
+	/// The maximum page size accepted by paged searches
+	page_size_limit: Mutex<Option<usize>>,
+	/// Number of the next searches for entries rejected as busy
+	rejected_searches: Mutex<usize>,
+	/// Whether searches are left unanswered
+	hang_searches: Mutex<bool>,
+	/// The tasks serving the accepted connections
+	connections: Mutex<Vec<JoinHandle<std::io::Result<()>>>>,
+}
+
+impl MockServer {
+	/// Start the server
+	pub async fn start() -> Result<Self, Error> {
+		let listener = TcpListener::bind("127.0.0.1:0").await?;
+		let address = listener.local_addr()?;
+		let directory = Arc::new(Directory::default());
+		let task = tokio::spawn({
+			let directory = directory.clone();
+			async move {
+				while let Ok((stream, _)) = listener.accept().await {
+					let connection = tokio::spawn(serve(directory.clone(), stream));
+					lock(&directory.connections).push(connection);
+				}
+			}
+		});
+		Ok(MockServer { directory, address, task })
+	}
+
+	/// The URL to connect to, to be used as [`crate::config::Config::url`]
+	#[must_use]
+	pub fn url(&self) -> Url {
+		let mut url = Url::parse("ldap://127.0.0.1").unwrap_or_else(|_| unreachable!());
+		let _ = url.set_port(Some(self.address.port()));
+		url
+	}
+
+	/// Only accept simple binds with the given DN and password
+	pub fn set_credentials(&self, dn: &str, password: &str) {
+		*lock(&self.directory.credentials) = Some((dn.to_owned(), password.to_owned()));
+	}
+
+	/// Add an entry, replacing any entry with the same DN
+	pub fn add(&self, mut entry: SearchEntry) {
+		let now = timestamp();
+		for attr in OPERATIONAL_ATTRIBUTES {
+			entry.attrs.entry(attr.to_owned()).or_insert_with(|| vec![now.clone()]);
+		}
+		let mut entries = lock(&self.directory.entries);
+		match entries.iter_mut().find(|existing| existing.dn.eq_ignore_ascii_case(&entry.dn)) {
+			Some(existing) => *existing = entry,
+			None => entries.push(entry),
+		}
+	}
+
+	/// Replace the values of a text attribute of the entry with the given DN,
+	/// removing the attribute if no values are given. Returns whether the
+	/// entry exists.
+	#[must_use]
+	pub fn modify(&self, dn: &str, attr: &str, values: &[&str]) -> bool {
+		self.modify_entry(dn, |entry| {
+			entry.attrs.retain(|name, _| !name.eq_ignore_ascii_case(attr));
+			entry.bin_attrs.retain(|name, _| !name.eq_ignore_ascii_case(attr));
+			if !values.is_empty() {
+				entry.attrs.insert(
+					attr.to_owned(),
+					values.iter().map(|value| (*value).to_owned()).collect(),
+				);
+			}
+		})
+	}
+
+	/// Move the entry with the given DN to a new DN, keeping its attributes.
+	/// Returns whether the entry exists.
+	#[must_use]
+	pub fn rename(&self, dn: &str, new_dn: &str) -> bool {
+		self.modify_entry(dn, |entry| new_dn.clone_into(&mut entry.dn))
+	}
+
+	/// Remove the entry with the given DN, returning whether it existed
+	#[must_use]
+	pub fn remove(&self, dn: &str) -> bool {
+		let mut entries = lock(&self.directory.entries);
+		let count = entries.len();
+		entries.retain(|entry| !entry.dn.eq_ignore_ascii_case(dn));
+		entries.len() != count
+	}
+
+	/// Get the entries currently stored, including operational attributes
+	#[must_use]
+	pub fn entries(&self) -> Vec<SearchEntry> {
+		lock(&self.directory.entries).clone()
+	}
+
+	/// Reject the next `count` searches for entries with the result code
+	/// busy, e.g. to test retries. Reads of the RootDSE aren't affected.
+	pub fn reject_searches(&self, count: usize) {
+		*lock(&self.directory.rejected_searches) = count;
+	}
+
+	/// Leave all searches unanswered while `hang` is set, like an overloaded
+	/// or stuck server, e.g. to test timeouts
+	pub fn hang_searches(&self, hang: bool) {
+		*lock(&self.directory.hang_searches) = hang;
+	}
+
+	/// Truncate the results of searches which aren't paged to `limit`
+	/// entries with the result code sizeLimitExceeded, like the size limit of
+	/// a real server
+	pub fn set_size_limit(&self, limit: Option<usize>) {
+		*lock(&self.directory.size_limit) = limit;
+	}
+
+	/// Reject paged searches with a page size above `limit` with the result
+	/// code adminLimitExceeded, like the limit of a real server
+	pub fn set_page_size_limit(&self, limit: Option<usize>) {
+		*lock(&self.directory.page_size_limit) = limit;
+	}
+
+	/// The number of connections accepted so far
+	#[must_use]
+	pub fn connections(&self) -> usize {
+		lock(&self.directory.connections).len()
+	}
+
+	/// The number of connections which are still open
+	#[must_use]
+	pub fn open_connections(&self) -> usize {
+		lock(&self.directory.connections)
+			.iter()
+			.filter(|connection| !connection.is_finished())
+			.count()
+	}
+
+	/// Close all open connections, e.g. to simulate a firewall dropping idle
+	/// connections
+	pub fn close_connections(&self) {
+		for connection in lock(&self.directory.connections).iter() {
+			connection.abort();
+		}
+	}
+
+	/// Modify the entry with the given DN and update its modification time
+	fn modify_entry(&self, dn: &str, modify: impl FnOnce(&mut SearchEntry)) -> bool {
+		let mut entries = lock(&self.directory.entries);
+		let Some(entry) = entries.iter_mut().find(|entry| entry.dn.eq_ignore_ascii_case(dn)) else {
+			return false;
+		};
+		modify(entry);
+		entry.attrs.insert("modifyTimestamp".to_owned(), vec![timestamp()]);
+		true
+	}
+}
+
+impl Drop for MockServer {
+	fn drop(&mut self) {
+		self.task.abort();
+		self.close_connections();
+	}
+}
+
+/// Lock a mutex, ignoring poisoning by panicking tests
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+	mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The current time as a modification timestamp
+fn timestamp() -> String {
+	OffsetDateTime::now_utc().format(&TIME_FORMAT).unwrap_or_default()
+}
+
+/// Serve a connection until it is closed or unbound
+async fn serve(directory: Arc<Directory>, mut stream: TcpStream) -> std::io::Result<()> {
+	let mut buffer = Vec::new();
+	let mut bound_dn = String::new();
+	loop {
+		let message = match parse_tag(&buffer) {
+			_ if buffer.is_empty() => None,
+			Ok((rest, message)) => {
+				let consumed = buffer.len() - rest.len();
+				buffer.drain(..consumed);
+				Some(message)
+			}
+			Err(err) if err.is_incomplete() => None,
+			Err(_) => return Ok(()),
+		};
+		let Some(message) = message else {
+			let mut chunk = [0; 4096];
+			let read = stream.read(&mut chunk).await?;
+			if read == 0 {
+				return Ok(());
+			}
+			buffer.extend_from_slice(chunk.get(..read).unwrap_or_default());
+			continue;
+		};
+		let Some(responses) = handle(&directory, &mut bound_dn, message) else {
+			return Ok(());
+		};
+		let mut bytes = Vec::new();
+		for response in &responses {
+			encode(response, &mut bytes);
+		}
+		stream.write_all(&bytes).await?;
+	}
+}
+
+/// Handle a request message, returning the response messages, or `None` if
+/// the connection should be closed
+fn handle(
+	directory: &Directory,
+	bound_dn: &mut String,
+	message: StructureTag,
+) -> Option<Vec<StructureTag>> {
+	let mut parts = constructed(message)?.into_iter();
+	let id = integer(&primitive(parts.next()?)?);
+	let operation = parts.next()?;
+	let controls = parts.next().and_then(constructed).unwrap_or_default();
+	let wrap = |operation: StructureTag, controls: Option<StructureTag>| {
+		let mut message = vec![integer_tag(id), operation];
+		message.extend(controls.map(|controls| context(0, PL::C(vec![controls]))));
+		sequence(message)
+	};
+	let responses = match (operation.class, operation.id) {
+		(TagClass::Application, 0) => {
+			let result = bind(directory, bound_dn, constructed(operation)?);
+			vec![wrap(application(1, PL::C(ldap_result(result))), None)]
+		}
+		(TagClass::Application, 2) => return None,
+		(TagClass::Application, 3) if *lock(&directory.hang_searches) => Vec::new(),
+		(TagClass::Application, 3) => {
+			let (entries, done, paged) = search(directory, constructed(operation)?, &controls)?;
+			let mut responses: Vec<_> =
+				entries.into_iter().map(|entry| wrap(entry, None)).collect();
+			responses.push(wrap(application(5, PL::C(ldap_result(done))), paged));
+			responses
+		}
+		// Abandon requests have no response
+		(TagClass::Application, 16) => Vec::new(),
+		(TagClass::Application, 23) => {
+			let mut response =
+				ldap_result((result_code::UNWILLING_TO_PERFORM, "Unsupported extended operation"));
+			let oid = constructed(operation)?.into_iter().next().and_then(primitive);
+			if oid.as_deref() == Some(WHO_AM_I_OID.as_bytes()) {
+				let authz_id =
+					if bound_dn.is_empty() { String::new() } else { format!("dn:{bound_dn}") };
+				response = ldap_result((result_code::SUCCESS, ""));
+				response.push(context(11, PL::P(authz_id.into_bytes())));
+			}
+			vec![wrap(application(24, PL::C(response)), None)]
+		}
+		// Other requests are answered with the response of the next ID
+		(TagClass::Application, id) => {
+			let result = (result_code::UNWILLING_TO_PERFORM, "Unsupported operation");
+			vec![wrap(application(id + 1, PL::C(ldap_result(result))), None)]
+		}
+		_ => return None,
+	};
+	Some(responses)
+}
+
+/// Handle a bind request, returning the result
+fn bind(
+	directory: &Directory,
+	bound_dn: &mut String,
+	request: Vec<StructureTag>,
+) -> (u8, &'static str) {
+	let mut request = request.into_iter().skip(1);
+	let dn = request.next().and_then(primitive).unwrap_or_default();
+	let dn = String::from_utf8_lossy(&dn).into_owned();
+	let Some(StructureTag { class: TagClass::Context, id: 0, payload: PL::P(password) }) =
+		request.next()
+	else {
+		return (result_code::UNWILLING_TO_PERFORM, "Only simple binds are supported");
+	};
+	let anonymous = dn.is_empty() && password.is_empty();
+	let accepted = match &*lock(&directory.credentials) {
+		Some((expected_dn, expected_password)) => {
+			anonymous
+				|| (expected_dn.eq_ignore_ascii_case(&dn)
+					&& expected_password.as_bytes() == password.as_slice())
+		}
+		None => true,
+	};
+	if !accepted {
+		return (result_code::INVALID_CREDENTIALS, "Invalid credentials");
+	}
+	*bound_dn = dn;
+	(result_code::SUCCESS, "")
+}
+
+/// Matching entries, the result, and the paged results control of a search
+type SearchResult = (Vec<StructureTag>, (u8, &'static str), Option<StructureTag>);
+
+/// Handle a search request. Returns `None` if the request is malformed.
+fn search(
+	directory: &Directory,
+	request: Vec<StructureTag>,
+	controls: &[StructureTag],
+) -> Option<SearchResult> {
+	let [base, scope, _, _, _, _, filter, attributes] = <[_; 8]>::try_from(request).ok()?;
+	let base = String::from_utf8(primitive(base)?).ok()?;
+	let scope = integer(&primitive(scope)?);
+	let attributes: Vec<String> = constructed(attributes)?
+		.into_iter()
+		.filter_map(primitive)
+		.map(|attr| String::from_utf8_lossy(&attr).into_owned())
+		.collect();
+
+	if base.is_empty() && scope == 0 {
+		let root_dse = SearchEntry {
+			dn: String::new(),
+			attrs: [
+				("vendorName".to_owned(), vec!["ldap-poller".to_owned()]),
+				("supportedLDAPVersion".to_owned(), vec!["3".to_owned()]),
+				("supportedControl".to_owned(), vec![PAGED_RESULTS_OID.to_owned()]),
+				("supportedExtension".to_owned(), vec![WHO_AM_I_OID.to_owned()]),
+			]
+			.into(),
+			bin_attrs: [].into(),
+		};
+		let entry = search_result_entry(&root_dse, &["*".to_owned()]);
+		return Some((vec![entry], (result_code::SUCCESS, ""), None));
+	}
+
+	let mut rejected = lock(&directory.rejected_searches);
+	if *rejected > 0 {
+		*rejected -= 1;
+		return Some((Vec::new(), (result_code::BUSY, "Busy"), None));
+	}
+	drop(rejected);
+
+	let all_entries = lock(&directory.entries);
+	let entries: Vec<_> = all_entries
+		.iter()
+		.filter(|entry| in_scope(&entry.dn, &base, scope) && matches(&filter, entry))
+		.collect();
+	// Only the returned entries are encoded, so that paging through large
+	// directories isn't quadratic
+	let encode_all = |entries: &[&SearchEntry]| {
+		entries.iter().map(|entry| search_result_entry(entry, &attributes)).collect()
+	};
+
+	let Some((size, offset)) = paged_results_request(controls) else {
+		return match *lock(&directory.size_limit) {
+			Some(limit) if entries.len() > limit => {
+				let truncated = encode_all(&entries[..limit]);
+				Some((truncated, (result_code::SIZE_LIMIT_EXCEEDED, "Size limit exceeded"), None))
+			}
+			_ => Some((encode_all(&entries), (result_code::SUCCESS, ""), None)),
+		};
+	};
+	if lock(&directory.page_size_limit).is_some_and(|limit| size > limit) {
+		let result = (result_code::ADMIN_LIMIT_EXCEEDED, "Administrative limit exceeded");
+		return Some((Vec::new(), result, None));
+	}
+	let page: Vec<_> =
+		encode_all(&entries[offset.min(entries.len())..(offset + size.max(1)).min(entries.len())]);
+	let next = offset + page.len();
+	let cookie = if next < entries.len() { next.to_string().into_bytes() } else { Vec::new() };
+	let mut value = Vec::new();
+	encode(&sequence(vec![integer_tag(0), octet_string(cookie)]), &mut value);
+	let control =
+		sequence(vec![octet_string(PAGED_RESULTS_OID.as_bytes().to_vec()), octet_string(value)]);
+	Some((page, (result_code::SUCCESS, ""), Some(control)))
+}
+
+/// Get the page size and offset of a paged search from the request controls
+fn paged_results_request(controls: &[StructureTag]) -> Option<(usize, usize)> {
+	controls.iter().find_map(|control| {
+		let mut parts = constructed(control.clone())?.into_iter();
+		if primitive(parts.next()?)? != PAGED_RESULTS_OID.as_bytes() {
+			return None;
+		}
+		// The criticality is optional
+		let value = parts.filter_map(primitive).next_back()?;
+		let (_, value) = parse_tag(&value).ok()?;
+		let mut value = constructed(value)?.into_iter();
+		let size = usize::try_from(integer(&primitive(value.next()?)?)).unwrap_or(0);
+		let cookie = primitive(value.next()?)?;
+		let offset = String::from_utf8(cookie).ok()?.parse().unwrap_or(0);
+		Some((size, offset))
+	})
+}
+
+/// Whether an entry is within the scope of a search
+fn in_scope(dn: &str, base: &str, scope: i64) -> bool {
+	let (Ok(dn), Ok(base)) = (Dn::parse(dn), Dn::parse(base)) else {
+		return false;
+	};
+	if !dn.is_under(&base) {
+		return false;
+	}
+	match scope {
+		0 => dn.rdns().len() == base.rdns().len(),
+		1 => dn.rdns().len() == base.rdns().len() + 1,
+		_ => true,
+	}
+}
+
+/// Whether an entry matches a search filter
+fn matches(filter: &StructureTag, entry: &SearchEntry) -> bool {
+	let StructureTag { class: TagClass::Context, id, payload } = filter else {
+		return false;
+	};
+	match (id, payload) {
+		(0, PL::C(filters)) => filters.iter().all(|filter| matches(filter, entry)),
+		(1, PL::C(filters)) => filters.iter().any(|filter| matches(filter, entry)),
+		(2, PL::C(filters)) => filters.first().is_some_and(|filter| !matches(filter, entry)),
+		(3 | 5 | 6 | 8, PL::C(assertion)) => {
+			let [StructureTag { payload: PL::P(attr), .. }, StructureTag { payload: PL::P(asserted), .. }] =
+				assertion.as_slice()
+			else {
+				return false;
+			};
+			values(entry, &String::from_utf8_lossy(attr))
+				.into_iter()
+				.any(|value| compare(*id, value, asserted))
+		}
+		(4, PL::C(substrings)) => {
+			let [StructureTag { payload: PL::P(attr), .. }, StructureTag { payload: PL::C(parts), .. }] =
+				substrings.as_slice()
+			else {
+				return false;
+			};
+			values(entry, &String::from_utf8_lossy(attr))
+				.into_iter()
+				.any(|value| matches_substrings(value, parts))
+		}
+		(7, PL::P(attr)) => !values(entry, &String::from_utf8_lossy(attr)).is_empty(),
+		_ => false,
+	}
+}
+
+/// Compare an attribute value with an asserted value for an equality (3),
+/// greater or equal (5), less or equal (6) or approximate (8) match. Values
+/// are compared numerically if both are integers, and case-insensitively
+/// otherwise.
+fn compare(filter_type: u64, value: &[u8], asserted: &[u8]) -> bool {
+	let number = |value: &[u8]| std::str::from_utf8(value).ok()?.parse::<i128>().ok();
+	let ordering = match (number(value), number(asserted)) {
+		(Some(value), Some(asserted)) => value.cmp(&asserted),
+		_ => value.to_ascii_lowercase().cmp(&asserted.to_ascii_lowercase()),
+	};
+	match filter_type {
+		5 => ordering.is_ge(),
+		6 => ordering.is_le(),
+		_ => ordering.is_eq(),
+	}
+}
+
+/// Whether a value matches the initial (0), any (1) and final (2) parts of a
+/// substrings filter, case-insensitively
+fn matches_substrings(value: &[u8], parts: &[StructureTag]) -> bool {
+	let mut rest = value.to_ascii_lowercase();
+	for part in parts {
+		let PL::P(substring) = &part.payload else {
+			return false;
+		};
+		let substring = substring.to_ascii_lowercase();
+		let remaining = match part.id {
+			0 => rest.strip_prefix(substring.as_slice()).map(<[u8]>::to_vec),
+			1 => rest
+				.windows(substring.len().max(1))
+				.position(|window| window == substring.as_slice())
+				.map(|start| rest.split_off(start).split_off(substring.len())),
+			_ => rest.ends_with(&substring).then(Vec::new),
+		};
+		let Some(remaining) = remaining else {
+			return false;
+		};
+		rest = remaining;
+	}
+	true
+}
+
+/// All values of an attribute of an entry, matching its name
+/// case-insensitively
+fn values<'a>(entry: &'a SearchEntry, attr: &str) -> Vec<&'a [u8]> {
+	let attrs = entry
+		.attrs
+		.iter()
+		.filter(|(name, _)| name.eq_ignore_ascii_case(attr))
+		.flat_map(|(_, values)| values.iter().map(String::as_bytes));
+	let bin_attrs = entry
+		.bin_attrs
+		.iter()
+		.filter(|(name, _)| name.eq_ignore_ascii_case(attr))
+		.flat_map(|(_, values)| values.iter().map(Vec::as_slice));
+	attrs.chain(bin_attrs).collect()
+}
+
+/// Build a search result entry with the requested attributes
+fn search_result_entry(entry: &SearchEntry, requested: &[String]) -> StructureTag {
+	let is_requested = |name: &str| {
+		let operational = OPERATIONAL_ATTRIBUTES.iter().any(|attr| attr.eq_ignore_ascii_case(name));
+		requested.iter().any(|attr| attr.eq_ignore_ascii_case(name))
+			|| (operational && requested.iter().any(|attr| attr == "+"))
+			|| (!operational && (requested.is_empty() || requested.iter().any(|attr| attr == "*")))
+	};
+	let attrs = entry
+		.attrs
+		.iter()
+		.map(|(name, values)| {
+			(name, values.iter().map(|value| value.as_bytes().to_vec()).collect())
+		})
+		.chain(entry.bin_attrs.iter().map(|(name, values)| (name, values.clone())))
+		.filter(|(name, _)| is_requested(name))
+		.map(|(name, values): (&String, Vec<Vec<u8>>)| {
+			let values = values.into_iter().map(octet_string).collect();
+			sequence(vec![octet_string(name.as_bytes().to_vec()), set(values)])
+		})
+		.collect();
+	application(4, PL::C(vec![octet_string(entry.dn.as_bytes().to_vec()), sequence(attrs)]))
+}
+
+/// The components of an LDAP result with the given code and message
+fn ldap_result((code, message): (u8, &str)) -> Vec<StructureTag> {
+	vec![
+		StructureTag { class: TagClass::Universal, id: 10, payload: PL::P(vec![code]) },
+		octet_string(Vec::new()),
+		octet_string(message.as_bytes().to_vec()),
+	]
+}
+
+/// The components of a constructed value
+fn constructed(tag: StructureTag) -> Option<Vec<StructureTag>> {
+	tag.expect_constructed()
+}
+
+/// The content of a primitive value
+fn primitive(tag: StructureTag) -> Option<Vec<u8>> {
+	tag.expect_primitive()
+}
+
+/// Decode a two's complement integer
+fn integer(bytes: &[u8]) -> i64 {
+	let initial = if bytes.first().is_some_and(|byte| byte & 0x80 != 0) { -1 } else { 0 };
+	bytes.iter().fold(initial, |value, byte| (value << 8) | i64::from(*byte))
+}
+
+/// An integer value
+fn integer_tag(value: i64) -> StructureTag {
+	let bytes = value.to_be_bytes();
+	// Drop leading bytes which only repeat the sign
+	let start = (0..bytes.len() - 1)
+		.find(|&index| {
+			let (byte, next) = (bytes[index], bytes[index + 1]);
+			!((byte == 0 && next & 0x80 == 0) || (byte == 0xff && next & 0x80 != 0))
+		})
+		.unwrap_or(bytes.len() - 1);
+	StructureTag { class: TagClass::Universal, id: 2, payload: PL::P(bytes[start..].to_vec()) }
+}
+
+/// An octet string value
+fn octet_string(bytes: Vec<u8>) -> StructureTag {
+	StructureTag { class: TagClass::Universal, id: 4, payload: PL::P(bytes) }
+}
+
+/// A sequence of values
+fn sequence(inner: Vec<StructureTag>) -> StructureTag {
+	StructureTag { class: TagClass::Universal, id: 16, payload: PL::C(inner) }
+}
+
+/// A set of values
+fn set(inner: Vec<StructureTag>) -> StructureTag {
+	StructureTag { class: TagClass::Universal, id: 17, payload: PL::C(inner) }
+}
+
+/// An application-tagged value
+fn application(id: u64, payload: PL) -> StructureTag {
+	StructureTag { class: TagClass::Application, id, payload }
+}
+
+/// A context-tagged value
+fn context(id: u64, payload: PL) -> StructureTag {
+	StructureTag { class: TagClass::Context, id, payload }
+}
+
+/// Encode a value with BER. Only tag numbers below 31 are supported, which
+/// covers all tags used by LDAP.
+fn encode(tag: &StructureTag, out: &mut Vec<u8>) {
+	let content = match &tag.payload {
+		PL::P(bytes) => bytes.clone(),
+		PL::C(inner) => {
+			let mut content = Vec::new();
+			for tag in inner {
+				encode(tag, &mut content);
+			}
+			content
+		}
+	};
+	let constructed = if matches!(tag.payload, PL::C(_)) { 0x20 } else { 0 };
+	let id = u8::try_from(tag.id).unwrap_or(0x1f) & 0x1f;
+	out.push(((tag.class as u8) << 6) | constructed | id);
+	match u8::try_from(content.len()) {
+		Ok(len) if len < 0x80 => out.push(len),
+		_ => {
+			let len = content.len().to_be_bytes();
+			let len = &len[len.iter().take_while(|byte| **byte == 0).count()..];
+			out.push(0x80 | u8::try_from(len.len()).unwrap_or(0));
+			out.extend_from_slice(len);
+		}
+	}
+	out.extend_from_slice(&content);
+}
+
+#[cfg(test)]
+mod tests {
+	#![allow(clippy::unwrap_used)]
+	use super::{in_scope, integer, integer_tag, MockServer, PL};
+	use crate::{
+		config::Config,
+		ldap::{EntryStatus, Ldap},
+		testing::search_entry,
+	};
+
+	#[test]
+	fn integers() {
+		for value in [0, 1, 127, 128, 255, 256, -1, -128, -129, 65_535, i64::MAX, i64::MIN] {
+			let PL::P(bytes) = integer_tag(value).payload else { unreachable!() };
+			assert_eq!(integer(&bytes), value);
+		}
+		assert!(matches!(integer_tag(128).payload, PL::P(bytes) if bytes == [0, 128]));
+	}
+
+	#[test]
+	fn scopes() {
+		assert!(in_scope("cn=foo,ou=people", "ou=People", 2));
+		assert!(in_scope("cn=foo,ou=people", "ou=people", 1));
+		assert!(!in_scope("cn=foo,ou=sub,ou=people", "ou=people", 1));
+		assert!(in_scope("cn=foo,ou=sub,ou=people", "ou=people", 2));
+		assert!(!in_scope("cn=foo,ou=otherpeople", "ou=people", 2));
+		assert!(in_scope("ou=people", "ou=people", 0));
+	}
+
+	#[tokio::test]
+	async fn sync_against_mock_server() -> Result<(), Box<dyn std::error::Error>> {
+		let server = MockServer::start().await?;
+		let user = |name: &str, enabled: &str| {
+			search_entry(
+				&format!("cn={name},ou=people"),
+				"objectGUID",
+				name.as_bytes(),
+				&[("objectClass", &["person"]), ("cn", &[name]), ("enabled", &[enabled])],
+			)
+		};
+		server.add(user("foo", "yes"));
+		server.add(user("bar", "yes"));
+		server.add(search_entry("cn=baz,ou=groups", "objectGUID", b"baz", &[]));
+
+		let mut config = Config::example();
+		config.url = server.url();
+		config.searches.page_size = Some(1);
+		let (mut ldap, mut receiver) = Ldap::new(config, None);
+
+		let report = ldap.sync_once(None).await?;
+		assert_eq!(report.resources.pages_fetched, 2, "Searches should be paged");
+		let server_info = ldap.server_info().unwrap();
+		assert_eq!(server_info.vendor_name.as_deref(), Some("ldap-poller"));
+		assert!(!server_info.lacks_paged_results());
+		for name in ["foo", "bar"] {
+			assert!(matches!(
+				receiver.try_recv(),
+				Ok(EntryStatus::New(entry)) if entry.dn == format!("cn={name},ou=people")
+			));
+		}
+		assert!(receiver.try_recv().is_err());
+
+		assert!(server.modify("cn=foo,ou=people", "enabled", &["no"]));
+		assert!(server.remove("cn=bar,ou=people"));
+		ldap.sync_once(None).await?;
+		assert!(matches!(receiver.try_recv(), Ok(EntryStatus::Changed { .. })));
+		assert!(
+			matches!(receiver.try_recv(), Ok(EntryStatus::Removed { pid, .. }) if pid == b"bar")
+		);
+
+		server.set_credentials("admin", "secret");
+		assert_eq!(ldap.who_am_i().await?, "dn:admin");
+		Ok(())
+	}
+}
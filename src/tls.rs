@@ -0,0 +1,407 @@
+//! TLS connectors for the supported TLS backends
+use std::{borrow::Cow, path::Path, time::SystemTime};
+#[cfg(feature = "tls-rustls")]
+use std::{net::IpAddr, sync::Arc};
+
+use crate::{config::TLSConfig, error::Error};
+
+impl TLSConfig {
+	/// Modification times of the configured certificate and key files, to
+	/// detect when they were rotated
+	pub(crate) async fn certificate_mtimes(&self) -> Result<Vec<Option<SystemTime>>, Error> {
+		let mut mtimes = Vec::new();
+		for (path, what) in [
+			(self.root_certificates_path.as_deref().map(Cow::Borrowed), "root certificate"),
+			(self.client_certificate_path()?, "client certificate"),
+			(self.client_key_path()?, "client key"),
+		] {
+			mtimes.push(match path {
+				Some(path) => Some(
+					tokio::fs::metadata(&path)
+						.await
+						.and_then(|metadata| metadata.modified())
+						.map_err(|err| file_error(err, what, &path))?,
+				),
+				None => None,
+			});
+		}
+		Ok(mtimes)
+	}
+
+	/// Whether the defaults of the TLS backend have to be replaced
+	fn is_customized(&self) -> bool {
+		self.root_certificates_path.is_some()
+			|| self.client_certificate_path.is_some()
+			|| self.client_key_path.is_some()
+			|| self.disable_sni
+			|| self.tls_server_name.is_some()
+	}
+
+	/// The path of the client certificate, with its reference resolved
+	fn client_certificate_path(&self) -> Result<Option<Cow<'_, Path>>, Error> {
+		self.client_certificate_path.as_deref().map(resolve_path).transpose()
+	}
+
+	/// The path of the client key, with its reference resolved
+	fn client_key_path(&self) -> Result<Option<Cow<'_, Path>>, Error> {
+		self.client_key_path.as_deref().map(resolve_path).transpose()
+	}
+
+	/// Read the client certificate and key files, if configured
+	async fn client_identity(&self) -> Result<Option<(Vec<u8>, Vec<u8>)>, Error> {
+		match (self.client_certificate_path()?, self.client_key_path()?) {
+			(Some(cert_path), Some(key_path)) => Ok(Some((
+				read_file(&cert_path, "client certificate").await?,
+				read_file(&key_path, "client key").await?,
+			))),
+			(None, None) => Ok(None),
+			_ => Err(Error::Invalid(
+				"Both a client certificate and key file in PKCS8 format must be specified"
+					.to_owned(),
+			)),
+		}
+	}
+
+	/// Build a native-tls connector, unless the default one can be used
+	#[cfg(feature = "tls-native")]
+	pub(crate) async fn native_tls_connector(
+		&self,
+	) -> Result<Option<native_tls::TlsConnector>, Error> {
+		if self.tls_server_name.is_some() {
+			return Err(Error::Invalid(
+				"Overriding the TLS server name requires the tls-rustls feature".to_owned(),
+			));
+		}
+		if !self.is_customized() {
+			return Ok(None);
+		}
+		let mut connector = native_tls::TlsConnector::builder();
+		connector.danger_accept_invalid_certs(self.no_tls_verify);
+		connector.use_sni(!self.disable_sni);
+
+		if let Some(path) = &self.root_certificates_path {
+			let root_certificate =
+				native_tls::Certificate::from_pem(&read_file(path, "root certificate").await?)
+					.map_err(|err| {
+						Error::Invalid(format!(
+							"Could not parse root certificate {}: {err}",
+							path.display()
+						))
+					})?;
+			connector.add_root_certificate(root_certificate);
+			connector.disable_built_in_roots(!self.system_roots.unwrap_or(true));
+		}
+
+		if let Some((cert, key)) = self.client_identity().await? {
+			let identity = native_tls::Identity::from_pkcs8(&cert, &key).map_err(|err| {
+				Error::Invalid(format!("Could not parse client certificate or key: {err}"))
+			})?;
+			connector.identity(identity);
+		}
+
+		let connector = connector.build().map_err(|err| {
+			Error::Invalid(format!("Could not build TlsConnector with custom settings: {err}"))
+		})?;
+		Ok(Some(connector))
+	}
+
+	/// Build a rustls client configuration, unless the default one can be used
+	#[cfg(feature = "tls-rustls")]
+	pub(crate) async fn rustls_config(&self) -> Result<Option<Arc<rustls::ClientConfig>>, Error> {
+		use rustls::{client::WebPkiVerifier, Certificate, PrivateKey, RootCertStore, ServerName};
+
+		if !self.is_customized() && !self.verify_ip_sans {
+			return Ok(None);
+		}
+		let server_name =
+			match &self.tls_server_name {
+				Some(name) => Some(ServerName::try_from(name.as_str()).map_err(|err| {
+					Error::Invalid(format!("Invalid TLS server name {name}: {err}"))
+				})?),
+				None => None,
+			};
+
+		let mut roots = RootCertStore::empty();
+		let mut certificates = Vec::new();
+		if let Some(path) = &self.root_certificates_path {
+			let pem = read_file(path, "root certificate").await?;
+			certificates = parse_pem_certificates(&pem, "root certificate", path)?;
+			if certificates.is_empty() {
+				return Err(Error::Invalid(format!(
+					"No root certificate found in {}",
+					path.display()
+				)));
+			}
+		}
+		for certificate in &certificates {
+			roots
+				.add(certificate)
+				.map_err(|err| Error::Invalid(format!("Could not add root certificate: {err}")))?;
+		}
+		if self.root_certificates_path.is_none() || self.system_roots.unwrap_or(false) {
+			let system_roots: Vec<_> = rustls_native_certs::load_native_certs()?
+				.into_iter()
+				.map(|certificate| certificate.0)
+				.collect();
+			// A single malformed system certificate shouldn't prevent connecting
+			let (_, invalid) = roots.add_parsable_certificates(&system_roots);
+			if invalid > 0 {
+				tracing::warn!("Ignored {invalid} unparsable system root certificates");
+			}
+		}
+
+		let builder = rustls::ClientConfig::builder()
+			.with_safe_defaults()
+			.with_root_certificates(roots.clone());
+		let mut config = match self.client_identity().await? {
+			Some((cert, key)) => {
+				let invalid = |err| {
+					Error::Invalid(format!("Could not parse client certificate or key: {err}"))
+				};
+				let certificates = rustls_pemfile::certs(&mut cert.as_slice()).map_err(invalid)?;
+				let key = rustls_pemfile::pkcs8_private_keys(&mut key.as_slice())
+					.map_err(invalid)?
+					.into_iter()
+					.next()
+					.ok_or_else(|| Error::Invalid("No PKCS8 client key found".to_owned()))?;
+				builder.with_client_auth_cert(
+					certificates.into_iter().map(Certificate).collect(),
+					PrivateKey(key),
+				)?
+			}
+			None => builder.with_no_client_auth(),
+		};
+		config.enable_sni = !self.disable_sni;
+		match server_name {
+			Some(server_name) => {
+				let verifier = WebPkiVerifier::new(roots, None);
+				config.dangerous().set_certificate_verifier(Arc::new(ServerNameOverride {
+					server_name,
+					verifier,
+				}));
+			}
+			None if self.verify_ip_sans => {
+				let verifier = WebPkiVerifier::new(roots, None);
+				config.dangerous().set_certificate_verifier(Arc::new(IpSanVerifier { verifier }));
+			}
+			None => {}
+		}
+		if self.no_tls_verify {
+			config.dangerous().set_certificate_verifier(Arc::new(NoVerification));
+		}
+		Ok(Some(Arc::new(config)))
+	}
+}
+
+/// Convert a connection error, reporting a server certificate rejected by
+/// the `verify_ip_sans` verifier clearly
+pub(crate) fn connect_error(err: ldap3::LdapError) -> Error {
+	#[cfg(feature = "tls-rustls")]
+	if let ldap3::LdapError::Io { source } = &err {
+		// tokio-rustls wraps the error of the verifier in an IO error
+		let mismatch = source
+			.get_ref()
+			.and_then(|err| err.downcast_ref::<rustls::Error>())
+			.and_then(|err| match err {
+				rustls::Error::InvalidCertificate(rustls::CertificateError::Other(err)) => {
+					err.downcast_ref::<IpSanMismatch>()
+				}
+				_ => None,
+			});
+		if let Some(mismatch) = mismatch {
+			return Error::Invalid(mismatch.to_string());
+		}
+	}
+	err.into()
+}
+
+/// The server certificate has no IP address SAN for the host of the URL
+#[cfg(feature = "tls-rustls")]
+#[derive(thiserror::Error, Debug)]
+#[error("The server certificate has no IP address SAN for {0}")]
+struct IpSanMismatch(IpAddr);
+
+/// Parse the certificates of a PEM file
+#[cfg(feature = "tls-rustls")]
+fn parse_pem_certificates(
+	pem: &[u8],
+	what: &str,
+	path: &Path,
+) -> Result<Vec<rustls::Certificate>, Error> {
+	rustls_pemfile::certs(&mut &pem[..])
+		.map(|certificates| certificates.into_iter().map(rustls::Certificate).collect())
+		.map_err(|err| Error::Invalid(format!("Could not parse {what} {}: {err}", path.display())))
+}
+
+/// Certificate verifier checking the certificate against the configured
+/// `tls_server_name` rather than the host of the URL
+#[cfg(feature = "tls-rustls")]
+struct ServerNameOverride {
+	/// The name to verify the certificate against
+	server_name: rustls::ServerName,
+	/// The default verifier
+	verifier: rustls::client::WebPkiVerifier,
+}
+
+#[cfg(feature = "tls-rustls")]
+impl rustls::client::ServerCertVerifier for ServerNameOverride {
+	fn verify_server_cert(
+		&self,
+		end_entity: &rustls::Certificate,
+		intermediates: &[rustls::Certificate],
+		_server_name: &rustls::ServerName,
+		scts: &mut dyn Iterator<Item = &[u8]>,
+		ocsp_response: &[u8],
+		now: SystemTime,
+	) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+		self.verifier.verify_server_cert(
+			end_entity,
+			intermediates,
+			&self.server_name,
+			scts,
+			ocsp_response,
+			now,
+		)
+	}
+}
+
+/// Certificate verifier for `verify_ip_sans`, telling a certificate without
+/// an IP address SAN for the host of the URL apart from other failures
+#[cfg(feature = "tls-rustls")]
+struct IpSanVerifier {
+	/// The default verifier
+	verifier: rustls::client::WebPkiVerifier,
+}
+
+#[cfg(feature = "tls-rustls")]
+impl rustls::client::ServerCertVerifier for IpSanVerifier {
+	fn verify_server_cert(
+		&self,
+		end_entity: &rustls::Certificate,
+		intermediates: &[rustls::Certificate],
+		server_name: &rustls::ServerName,
+		scts: &mut dyn Iterator<Item = &[u8]>,
+		ocsp_response: &[u8],
+		now: SystemTime,
+	) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+		self.verifier
+			.verify_server_cert(end_entity, intermediates, server_name, scts, ocsp_response, now)
+			.map_err(|err| ip_san_mismatch(err, server_name))
+	}
+}
+
+/// Mark a verification failure as an IP address SAN mismatch if the
+/// certificate isn't valid for the IP address it's verified against
+#[cfg(feature = "tls-rustls")]
+fn ip_san_mismatch(err: rustls::Error, server_name: &rustls::ServerName) -> rustls::Error {
+	use rustls::{CertificateError, ServerName};
+
+	match (err, server_name) {
+		(
+			rustls::Error::InvalidCertificate(CertificateError::NotValidForName),
+			ServerName::IpAddress(ip),
+		) => {
+			rustls::Error::InvalidCertificate(CertificateError::Other(Arc::new(IpSanMismatch(*ip))))
+		}
+		(err, _) => err,
+	}
+}
+
+/// Certificate verifier accepting any certificate, for `no_tls_verify`
+#[cfg(feature = "tls-rustls")]
+#[derive(Debug)]
+struct NoVerification;
+
+#[cfg(feature = "tls-rustls")]
+impl rustls::client::ServerCertVerifier for NoVerification {
+	fn verify_server_cert(
+		&self,
+		_end_entity: &rustls::Certificate,
+		_intermediates: &[rustls::Certificate],
+		_server_name: &rustls::ServerName,
+		_scts: &mut dyn Iterator<Item = &[u8]>,
+		_ocsp_response: &[u8],
+		_now: SystemTime,
+	) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+		Ok(rustls::client::ServerCertVerified::assertion())
+	}
+}
+
+/// Resolve a reference in the path of a client certificate or key
+fn resolve_path(path: &Path) -> Result<Cow<'_, Path>, Error> {
+	crate::config::secret::resolve_path(path).map_err(Error::Invalid)
+}
+
+/// Read a certificate or key file
+async fn read_file(path: &Path, what: &str) -> Result<Vec<u8>, Error> {
+	tokio::fs::read(path).await.map_err(|err| file_error(err, what, path))
+}
+
+/// Add the file to an IO error, keeping its kind
+fn file_error(err: std::io::Error, what: &str, path: &Path) -> Error {
+	Error::Io(std::io::Error::new(
+		err.kind(),
+		format!("Could not read {what} {}: {err}", path.display()),
+	))
+}
+
+#[cfg(all(test, feature = "tls-rustls"))]
+mod tests {
+	#![allow(clippy::unwrap_used)]
+	use crate::{config::Config, error::Error};
+
+	#[tokio::test]
+	async fn tls_server_name() {
+		let mut tls = Config::example().connection.tls;
+		tls.tls_server_name = Some("ldap.example.com".to_owned());
+		assert!(tls.rustls_config().await.unwrap().is_some());
+
+		tls.tls_server_name = Some("not a name".to_owned());
+		assert!(matches!(tls.rustls_config().await, Err(Error::Invalid(_))));
+	}
+
+	#[test]
+	fn ip_san_errors() {
+		use std::{
+			net::{IpAddr, Ipv4Addr, Ipv6Addr},
+			sync::Arc,
+		};
+
+		use rustls::{CertificateError, ServerName};
+
+		use super::{connect_error, ip_san_mismatch, IpSanMismatch};
+
+		let not_valid = || rustls::Error::InvalidCertificate(CertificateError::NotValidForName);
+		let connect = |err: rustls::Error| {
+			connect_error(std::io::Error::new(std::io::ErrorKind::InvalidData, err).into())
+		};
+		for ip in [IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), IpAddr::V6(Ipv6Addr::LOCALHOST)] {
+			let err = ip_san_mismatch(not_valid(), &ServerName::IpAddress(ip));
+			assert!(
+				matches!(connect(err), Error::Invalid(message) if message.contains(&ip.to_string()))
+			);
+		}
+
+		let dns_name = ServerName::try_from("ldap.example.com").unwrap();
+		assert!(matches!(connect(ip_san_mismatch(not_valid(), &dns_name)), Error::Ldap(_)));
+		let ip = ServerName::IpAddress(IpAddr::V4(Ipv4Addr::LOCALHOST));
+		let expired = rustls::Error::InvalidCertificate(CertificateError::Expired);
+		assert!(matches!(connect(ip_san_mismatch(expired, &ip)), Error::Ldap(_)));
+		let other =
+			CertificateError::Other(Arc::new(IpSanMismatch(IpAddr::V4(Ipv4Addr::LOCALHOST))));
+		assert!(matches!(connect(rustls::Error::InvalidCertificate(other)), Error::Invalid(_)));
+		let refused = std::io::Error::from(std::io::ErrorKind::ConnectionRefused);
+		assert!(matches!(connect_error(refused.into()), Error::Ldap(_)));
+	}
+
+	#[tokio::test]
+	async fn verify_ip_sans() {
+		let mut tls = Config::example().connection.tls;
+		tls.root_certificates_path = None;
+		tls.client_certificate_path = None;
+		tls.client_key_path = None;
+		assert!(tls.rustls_config().await.unwrap().is_none());
+		tls.verify_ip_sans = true;
+		assert!(tls.rustls_config().await.unwrap().is_some());
+	}
+}
@@ -1,3 +1,4 @@
+//! Integration tests against the LDAP server of the docker setup
 #![allow(
 	clippy::dbg_macro,
 	clippy::expect_used,
@@ -8,11 +9,15 @@
 	clippy::bool_assert_comparison
 )]
 use core::panic;
-use std::{error::Error, path::PathBuf, time::Duration};
+use std::{collections::HashMap, error::Error, path::PathBuf, time::Duration};
 
 use ldap_poller::{
-	config::{AttributeConfig, CacheMethod, Config, ConnectionConfig, Searches, TLSConfig},
-	ldap::{EntryStatus, Ldap},
+	config::{
+		AttributeConfig, BindMethod, CacheMethod, Config, ConnectionConfig, DnChangeMode,
+		LatencyThresholds, PersistenceConfig, PidNormalization, RemovalConfirmation, RetryConfig,
+		Searches, TLSConfig, TimestampFormat,
+	},
+	ldap::{EntryStatus, Ldap, SyncOptions},
 	SearchEntryExt,
 };
 use serial_test::serial;
@@ -44,6 +49,11 @@ fn setup_ldap_poller(
 	check_for_deleted_entries: bool,
 	tls: bool,
 ) -> LdapPollerSetup {
+	spawn_ldap_poller(test_config(check_for_deleted_entries, tls), sync_once, cache)
+}
+
+#[must_use]
+fn test_config(check_for_deleted_entries: bool, tls: bool) -> Config {
 	let url = {
 		if tls {
 			Url::parse("ldaps://localhost:1336").unwrap()
@@ -59,10 +69,18 @@ fn setup_ldap_poller(
 				client_key_path: Some(PathBuf::from("docker-env/certs/client.key")),
 				client_certificate_path: Some(PathBuf::from("docker-env/certs/client.crt")),
 				root_certificates_path: Some(PathBuf::from("docker-env/certs/RootCA.crt")),
+				system_roots: None,
 				starttls: false,
 				no_tls_verify: false,
+				session_resumption: false,
+				reload_certificates: false,
+				disable_sni: false,
+				verify_ip_sans: false,
+				tls_server_name: None,
 			},
 			operation_timeout: Duration::from_secs(5),
+			reuse: false,
+			keepalive: None,
 		};
 		if !tls {
 			c.tls.client_certificate_path = None;
@@ -72,19 +90,30 @@ fn setup_ldap_poller(
 		c
 	};
 
-	let config = Config {
+	Config {
 		url,
 		connection,
 		search_user: String::new(),
 		search_password: String::new(),
+		bind_method: BindMethod::Anonymous,
 		searches: Searches {
 			user_base: "ou=users,dc=example,dc=org".to_owned(),
 			user_filter: "(objectClass=inetOrgPerson)".to_owned(),
 			page_size: None,
+			resume_paged_search: false,
+			size_limit_page_size: None,
+			adaptive_page_size: None,
+			incremental_filter: None,
+			sync_overlap: None,
+			proxy_authz: None,
+			controls: Vec::new(),
 		},
 		attributes: AttributeConfig {
 			pid: "cn".to_owned(),
+			pid_normalization: PidNormalization::None,
 			updated: Some("modifyTimestamp".to_owned()),
+			updated_format: TimestampFormat::Auto,
+			usn: None,
 			additional: vec![
 				"displayName".to_owned(),
 				"admin".to_owned(),
@@ -92,11 +121,40 @@ fn setup_ldap_poller(
 			],
 			attrs_to_track: vec!["admin".into()],
 			filter_attributes: true,
+			rename: HashMap::new(),
+			dn_changes: DnChangeMode::Moved,
+			operational_metadata: false,
+			schema_matching: false,
+			comparison: HashMap::new(),
+			max_cached_size: HashMap::new(),
+			uncached: Vec::new(),
+			disabled: None,
+			members: None,
+			max_values: None,
 		},
 		cache_method: CacheMethod::ModificationTime,
 		check_for_deleted_entries,
-	};
+		full_sync_interval: None,
+		removal_confirmation: RemovalConfirmation::None,
+		tombstones: None,
+		persistence: PersistenceConfig::default(),
+		canary: None,
+		debounce: None,
+		emit_unchanged: false,
+		latency_thresholds: LatencyThresholds::default(),
+		compare_workers: None,
+		retry: RetryConfig::default(),
+		resend_interval: None,
+		max_failed_sends: None,
+	}
+}
 
+#[must_use]
+fn spawn_ldap_poller(
+	config: Config,
+	sync_once: bool,
+	cache: Option<ldap_poller::Cache>,
+) -> LdapPollerSetup {
 	let (client, receiver) = Ldap::new(config.clone(), cache);
 	let mut client_clone = client.clone();
 
@@ -118,10 +176,10 @@ async fn ldap_user_sync_once_test() -> Result<(), Box<dyn Error>> {
 	let tracing_filter = EnvFilter::default().add_directive(LevelFilter::DEBUG.into());
 	tracing_subscriber::fmt().with_env_filter(tracing_filter).init();
 
-	sync_one_test(false).await
+	sync_one_test(test_config(false, false)).await
 }
 
-async fn sync_one_test(tls: bool) -> Result<(), Box<dyn Error>> {
+async fn sync_one_test(config: Config) -> Result<(), Box<dyn Error>> {
 	let mut ldap = ldap_connect(false).await?;
 	let _ = ldap_delete_organizational_unit(&mut ldap, "users").await;
 
@@ -134,7 +192,7 @@ async fn sync_one_test(tls: bool) -> Result<(), Box<dyn Error>> {
 	ldap_user_add_attribute(&mut ldap, "user03", "displayName", "MyName3").await?;
 
 	let LdapPollerSetup { mut receiver, ldap: _, config: _, thread_handle } =
-		setup_ldap_poller(true, None, false, tls);
+		spawn_ldap_poller(config, true, None);
 
 	let mut users = vec![];
 	while let Some(entry) = receiver.recv().await {
@@ -214,7 +272,7 @@ async fn ldap_user_sync_create_test() -> Result<(), Box<dyn Error>> {
 
 	while let Some(entry) = receiver.recv().await {
 		match entry {
-			EntryStatus::Removed(id) => {
+			EntryStatus::Removed { pid: id, .. } => {
 				deleted_users.push(id);
 			}
 			_ => panic!("Unexpected entry status"),
@@ -316,7 +374,7 @@ async fn ldap_user_sync_modification_test() -> Result<(), Box<dyn Error>> {
 
 	if let Some(entry) = receiver.recv().await {
 		match entry {
-			EntryStatus::Removed(id) => {
+			EntryStatus::Removed { pid: id, .. } => {
 				assert_eq!(id, "user01".as_bytes());
 			}
 			_ => panic!("Unexpected entry status"),
@@ -392,5 +450,173 @@ async fn ldap_user_sync_cache_test() -> Result<(), Box<dyn Error>> {
 #[tokio::test]
 #[serial]
 async fn ldap_tls_test() -> Result<(), Box<dyn Error>> {
-	sync_one_test(true).await
+	sync_one_test(test_config(false, true)).await
+}
+
+#[ignore = "docker"]
+#[tokio::test]
+#[serial]
+async fn ldap_sasl_external_tls_test() -> Result<(), Box<dyn Error>> {
+	let mut config = test_config(false, true);
+	config.bind_method = BindMethod::SaslExternal;
+	sync_one_test(config).await
+}
+
+#[ignore = "docker"]
+#[tokio::test]
+#[serial]
+async fn ldap_ldapi_test() -> Result<(), Box<dyn Error>> {
+	let mut config = test_config(false, false);
+	config.url = Url::parse("ldapi://docker-env%2Frun%2Fldapi")?;
+	config.bind_method = BindMethod::SaslExternal;
+	sync_one_test(config).await
+}
+
+#[ignore = "docker"]
+#[tokio::test]
+#[serial]
+async fn ldap_wait_for_initial_sync_test() -> Result<(), Box<dyn Error>> {
+	let mut ldap = ldap_connect(false).await?;
+	let _ = ldap_delete_organizational_unit(&mut ldap, "users").await;
+
+	ldap_add_organizational_unit(&mut ldap, "users").await?;
+	ldap_add_user(&mut ldap, "user01", "User1").await?;
+
+	let LdapPollerSetup { mut receiver, ldap: ldap_poller, config: _, thread_handle } =
+		setup_ldap_poller(false, None, false, false);
+
+	tokio::time::timeout(Duration::from_secs(10), ldap_poller.wait_for_initial_sync()).await?;
+	assert!(matches!(receiver.try_recv()?, EntryStatus::New(_)));
+
+	ldap_delete_user(&mut ldap, "user01").await?;
+	ldap_delete_organizational_unit(&mut ldap, "users").await?;
+	ldap.unbind().await?;
+	thread_handle.abort();
+
+	Ok(())
+}
+
+#[ignore = "docker"]
+#[tokio::test(flavor = "multi_thread")]
+#[serial]
+async fn ldap_snapshot_test() -> Result<(), Box<dyn Error>> {
+	let mut ldap = ldap_connect(false).await?;
+	let _ = ldap_delete_organizational_unit(&mut ldap, "users").await;
+
+	ldap_add_organizational_unit(&mut ldap, "users").await?;
+	ldap_add_user(&mut ldap, "user01", "User1").await?;
+	ldap_user_add_attribute(&mut ldap, "user01", "displayName", "MyName1").await?;
+
+	let LdapPollerSetup { mut receiver, ldap: ldap_poller, config: _, thread_handle } =
+		setup_ldap_poller(false, None, false, false);
+
+	// Read snapshots concurrently with the running sync, until the entry is
+	// published
+	let readers: Vec<_> = (0..4)
+		.map(|_| {
+			let ldap_poller = ldap_poller.clone();
+			tokio::spawn(async move {
+				loop {
+					if let Some(entry) = ldap_poller.get_entry(b"user01") {
+						return entry;
+					}
+					tokio::task::yield_now().await;
+				}
+			})
+		})
+		.collect();
+
+	assert!(matches!(receiver.recv().await, Some(EntryStatus::New(_))));
+	for reader in readers {
+		let entry = tokio::time::timeout(Duration::from_secs(10), reader).await??;
+		assert_eq!(entry.attr_first("displayName").unwrap(), "MyName1");
+	}
+
+	ldap_delete_user(&mut ldap, "user01").await?;
+	ldap_delete_organizational_unit(&mut ldap, "users").await?;
+	ldap.unbind().await?;
+	thread_handle.abort();
+
+	Ok(())
+}
+
+#[ignore = "docker"]
+#[tokio::test]
+#[serial]
+async fn ldap_check_connection_test() -> Result<(), Box<dyn Error>> {
+	let (client, _receiver) = Ldap::new(test_config(false, true), None);
+	let check = client.check_connection(true).await.unwrap();
+	assert!(check.tls.encrypted);
+	assert!(check.tls.verified);
+	let server = check.server.unwrap();
+	assert!(server.naming_contexts.contains(&"dc=example,dc=org".to_owned()));
+
+	let mut config = test_config(false, false);
+	config.url = Url::parse("ldap://localhost:1")?;
+	let (client, _receiver) = Ldap::new(config, None);
+	let diagnostic = client.check_connection(false).await.unwrap_err();
+	assert_eq!(diagnostic.phase, ldap_poller::diagnostics::Phase::Connect);
+	Ok(())
+}
+
+#[cfg(feature = "tls-rustls")]
+#[ignore = "docker"]
+#[tokio::test]
+#[serial]
+async fn ldap_ip_san_test() -> Result<(), Box<dyn Error>> {
+	let mut config = test_config(false, true);
+	// The certificate of the docker setup only has a DNS SAN for localhost
+	config.url = Url::parse("ldaps://127.0.0.1:1336")?;
+	config.connection.tls.verify_ip_sans = true;
+	let (mut client, _receiver) = Ldap::new(config, None);
+	let err = client.sync_once(None).await.unwrap_err();
+	assert!(err.to_string().contains("no IP address SAN for 127.0.0.1"), "{err}");
+	Ok(())
+}
+
+#[ignore = "docker"]
+#[tokio::test]
+#[serial]
+async fn ldap_who_am_i_test() -> Result<(), Box<dyn Error>> {
+	let (client, _receiver) = Ldap::new(test_config(false, false), None);
+	assert_eq!(client.who_am_i().await?, "");
+	assert_eq!(client.check_connection(false).await.unwrap().authz_id.as_deref(), Some(""));
+
+	let mut config = test_config(false, false);
+	config.bind_method = BindMethod::Simple;
+	config.search_user = "cn=admin,dc=example,dc=org".to_owned();
+	config.search_password = "adminpassword".to_owned();
+	let (client, _receiver) = Ldap::new(config, None);
+	assert_eq!(client.who_am_i().await?, "dn:cn=admin,dc=example,dc=org");
+	Ok(())
+}
+
+#[ignore = "docker"]
+#[tokio::test]
+#[serial]
+async fn ldap_sync_once_with_test() -> Result<(), Box<dyn Error>> {
+	let mut ldap = ldap_connect(false).await?;
+	let _ = ldap_delete_organizational_unit(&mut ldap, "users").await;
+	ldap_add_organizational_unit(&mut ldap, "users").await?;
+	ldap_add_user(&mut ldap, "user01", "User1").await?;
+	ldap_add_user(&mut ldap, "user02", "User2").await?;
+
+	let (mut client, mut receiver) = Ldap::new(test_config(false, false), None);
+	let dry_run = SyncOptions { dry_run: true, ..SyncOptions::default() };
+	let report = client.sync_once_with(None, dry_run).await?;
+	assert_eq!(report.updates.new, 2);
+	assert!(receiver.try_recv().is_err(), "Dry runs shouldn't push updates");
+
+	let filtered = SyncOptions { filter: Some("(cn=User1)".to_owned()), ..SyncOptions::default() };
+	let report = client.sync_once_with(None, filtered).await?;
+	assert_eq!(report.updates.new, 1, "The dry run shouldn't have modified the cache");
+	assert!(
+		matches!(receiver.try_recv(), Ok(EntryStatus::New(entry)) if entry.attr_first("cn") == Some("User1"))
+	);
+
+	ldap_delete_user(&mut ldap, "user01").await?;
+	ldap_delete_user(&mut ldap, "user02").await?;
+	ldap_delete_organizational_unit(&mut ldap, "users").await?;
+	ldap.unbind().await?;
+	Ok(())
 }